@@ -0,0 +1,10 @@
+fn main() -> std::io::Result<()> {
+    // use the vendored protoc unless one is given, so building doesn't need it installed
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().map_err(std::io::Error::other)?;
+        unsafe { std::env::set_var("PROTOC", protoc) };
+    }
+    println!("cargo:rerun-if-changed=proto/bgprtr.proto");
+    tonic_prost_build::configure()
+        .compile_protos(&["proto/bgprtr.proto"], &["proto"])
+}
@@ -0,0 +1,366 @@
+// Talks to a running bgprtr over its control socket.
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+#[path = "../control/protocol.rs"]
+mod protocol;
+
+use protocol::*;
+
+#[derive(Debug, Parser)]
+#[command(name = "bgpctl", version, about = "Control a running bgprtr")]
+struct Cli {
+    /// Control socket of the bgprtr instance
+    #[arg(short, long, value_name = "PATH", default_value = "bgprtr.sock", global = true)]
+    socket: PathBuf,
+
+    /// Print the raw JSON response instead of a table
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show neighbors and routes
+    #[command(subcommand)]
+    Show(ShowCommand),
+    /// Reset a session, or refresh its routes without a reset
+    #[command(subcommand)]
+    Clear(ClearCommand),
+    /// Administratively shut down or enable a neighbor
+    Neighbor {
+        ip: Ipv4Addr,
+        #[arg(value_enum)]
+        action: NeighborAction,
+    },
+    /// Announce a prefix to every neighbor
+    Announce { prefix: String },
+    /// Withdraw a prefix announced by us
+    Withdraw { prefix: String },
+    /// Write MRT files
+    #[command(subcommand)]
+    Dump(DumpCommand),
+    /// Try out a policy without applying it
+    #[command(subcommand)]
+    Test(TestCommand),
+    /// Reload the daemon's config file, like SIGHUP but reporting whether it loaded
+    Reload,
+}
+
+#[derive(Debug, Subcommand)]
+enum TestCommand {
+    /// What a policy would do to the routes as the neighbors sent them, or to one route given as JSON
+    Policy {
+        name: String,
+        /// Evaluate for this neighbor instead of the one each route was learned from
+        #[arg(long)]
+        neighbor: Option<Ipv4Addr>,
+        /// Only routes learned from this peer
+        #[arg(long)]
+        peer: Option<Ipv4Addr>,
+        /// Only this prefix, or the longest match for an address
+        #[arg(long)]
+        prefix: Option<String>,
+        /// A route like the ones show rib --json prints, tested instead of the RIB
+        #[arg(long, value_name = "JSON", value_parser = parse_route)]
+        route: Option<Box<RouteEntry>>,
+        /// Take the policy from this config file instead of the running config
+        #[arg(long, value_name = "FILE", value_parser = read_config)]
+        config: Option<String>,
+    },
+}
+
+fn parse_route(route: &str) -> Result<Box<RouteEntry>, String> {
+    serde_json::from_str(route).map_err(|e| e.to_string())
+}
+
+// sent to the daemon as it is, so the file only has to be readable here
+fn read_config(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("unable to read {} - {}", path, e))
+}
+
+#[derive(Debug, Subcommand)]
+enum DumpCommand {
+    /// TABLE_DUMP_V2 of the Adj-RIBs-In and the Loc-RIB, into the daemon's mrt_dump directory
+    Rib,
+}
+
+#[derive(Debug, Subcommand)]
+enum ShowCommand {
+    /// Our AS, router id and default attributes
+    Global,
+    Neighbors,
+    /// One line per neighbor, like "show bgp summary"
+    Summary,
+    Neighbor { ip: Ipv4Addr },
+    /// The whole RIB, one prefix, or the longest match for an address
+    Rib { prefix: Option<String> },
+    AdjRibIn {
+        peer: Ipv4Addr,
+        /// The routes as the neighbor sent them, before the import filters (needs soft_reconfiguration_inbound)
+        #[arg(long)]
+        received: bool,
+    },
+    AdjRibOut { peer: Ipv4Addr },
+    /// The RPKI caches, how many VRPs they gave us and how the RIB validates against them
+    Rpki,
+}
+
+#[derive(Debug, Subcommand)]
+enum ClearCommand {
+    Neighbor {
+        ip: Ipv4Addr,
+        #[command(subcommand)]
+        soft: Option<SoftCommand>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SoftCommand {
+    /// "in" asks the peer for a route refresh, "out" re-sends our routes
+    Soft {
+        #[arg(value_enum)]
+        direction: Direction,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum NeighborAction {
+    Shutdown,
+    Enable,
+}
+
+impl Command {
+    fn to_request(&self) -> ControlRequest {
+        match self {
+            Command::Show(ShowCommand::Global) => ControlRequest::ShowGlobalSettings,
+            Command::Show(ShowCommand::Neighbors) => ControlRequest::ShowNeighbors,
+            Command::Show(ShowCommand::Summary) => ControlRequest::ShowSummary,
+            Command::Show(ShowCommand::Neighbor { ip }) => ControlRequest::ShowNeighbor { ip: *ip },
+            Command::Show(ShowCommand::Rib { prefix }) => ControlRequest::ShowRib { prefix: prefix.clone() },
+            Command::Show(ShowCommand::AdjRibIn { peer, received }) => ControlRequest::ShowAdjRibIn { peer: *peer, received: *received },
+            Command::Show(ShowCommand::AdjRibOut { peer }) => ControlRequest::ShowAdjRibOut { peer: *peer },
+            Command::Show(ShowCommand::Rpki) => ControlRequest::ShowRpki,
+            Command::Clear(ClearCommand::Neighbor { ip, soft }) => ControlRequest::ClearNeighbor {
+                ip: *ip,
+                soft: soft.as_ref().map(|SoftCommand::Soft { direction }| match direction {
+                    Direction::In => SoftDirection::In,
+                    Direction::Out => SoftDirection::Out,
+                }),
+            },
+            Command::Neighbor { ip, action: NeighborAction::Shutdown } => ControlRequest::NeighborShutdown { ip: *ip },
+            Command::Neighbor { ip, action: NeighborAction::Enable } => ControlRequest::NeighborEnable { ip: *ip },
+            Command::Announce { prefix } => ControlRequest::Announce { prefix: prefix.clone() },
+            Command::Withdraw { prefix } => ControlRequest::Withdraw { prefix: prefix.clone() },
+            Command::Dump(DumpCommand::Rib) => ControlRequest::DumpRib,
+            Command::Reload => ControlRequest::Reload,
+            Command::Test(TestCommand::Policy { name, neighbor, peer, prefix, route, config }) => ControlRequest::TestPolicy {
+                policy: name.clone(),
+                candidate: config.clone(),
+                neighbor: *neighbor,
+                peer: *peer,
+                prefix: prefix.clone(),
+                route: route.clone(),
+            },
+        }
+    }
+}
+
+fn send_request(socket: &PathBuf, request: &ControlRequest) -> Result<String, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("Unable to connect to {} - {}", socket.display(), e))?;
+    let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(|e| format!("Unable to send request - {}", e))?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).map_err(|e| format!("Unable to read response - {}", e))?;
+    if response.is_empty() {
+        return Err("bgprtr closed the connection without answering".to_string());
+    }
+    Ok(response)
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect::<Vec<_>>().join("  ").trim_end().to_string()
+    };
+    println!("{}", format_row(headers.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+}
+
+fn or_dash<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map_or("-".to_string(), |v| v.to_string())
+}
+
+fn print_neighbors(neighbors: &[NeighborSummary]) {
+    let rows: Vec<Vec<String>> = neighbors.iter().map(|n| vec![
+        n.ip.to_string(),
+        or_dash(&n.as_num),
+        if n.admin_shutdown { format!("{} (shutdown)", n.state) } else { n.state.clone() },
+        n.prefixes_received.to_string(),
+        n.prefixes_advertised.to_string(),
+        if n.dynamic { "dynamic".to_string() } else { "static".to_string() },
+        or_dash(&n.description),
+    ]).collect();
+    print_table(&["Neighbor", "AS", "State", "PfxRcvd", "PfxSent", "Type", "Description"], &rows);
+}
+
+fn print_neighbor(n: &NeighborDetail) {
+    let s = &n.summary;
+    println!("Neighbor {}, AS {}, {} ({})", s.ip, or_dash(&s.as_num), n.peer_type, if s.dynamic { "dynamic" } else { "static" });
+    println!("  Description: {}", or_dash(&s.description));
+    println!("  State: {}{}", s.state, if s.admin_shutdown { ", administratively shut down" } else { "" });
+    if let Some(established_secs) = n.established_secs {
+        println!("  Established for {}s", established_secs);
+    }
+    println!("  Hold time: {}s, keepalive time: {}s", n.hold_time, n.keepalive_time);
+    println!("  Connect retry time: {}s, retries: {}, idle hold time: {}s, delay open: {}", n.connect_retry_time, n.connect_retry_counter,
+             n.idle_hold_time, n.delay_open_time.map_or("off".to_string(), |t| format!("{}s", t)));
+    println!("  Passive: {}, next hop self: {}, soft reconfiguration inbound: {}", n.passive, n.next_hop_self, n.soft_reconfiguration_inbound);
+    println!("  Damp peer oscillations: {}, oscillations: {}", n.damp_peer_oscillations, n.oscillation_count);
+    println!("  Negotiated capabilities: {}", if n.negotiated_capabilities.is_empty() { "-".to_string() } else { n.negotiated_capabilities.join(", ") });
+    println!("  Prefixes received: {}, advertised: {}", s.prefixes_received, s.prefixes_advertised);
+    println!("  Prefixes announced to us: {}, withdrawn: {}", n.prefixes_announced, n.prefixes_withdrawn);
+    if let Some(established_since) = n.established_since {
+        println!("  Established since {} (unix time)", established_since);
+    }
+    println!("  Last state change: {}, flaps: {}", n.last_state_change_secs.map_or("never".to_string(), |secs| format!("{}s ago", secs)), n.flaps);
+    let counts = |counts: &BTreeMap<String, u64>| counts.iter().map(|(t, count)| format!("{} {}", t, count)).collect::<Vec<_>>().join(", ");
+    println!("  Messages received: {}", counts(&n.messages_received));
+    println!("  Messages sent: {}", counts(&n.messages_sent));
+    println!("  Last notification received: {}", or_dash(&n.last_notification_received));
+    println!("  Last notification sent: {}", or_dash(&n.last_notification_sent));
+    if !n.recent_transitions.is_empty() {
+        println!("  Recent state changes:");
+        for transition in &n.recent_transitions {
+            println!("    {}", transition);
+        }
+    }
+}
+
+fn print_global_settings(g: &GlobalSettingsView) {
+    println!("AS {}, router id {}, BGP version {}", g.my_as, g.router_id, g.version);
+    println!("  Next hop: {}", g.next_hop);
+    println!("  Default local preference: {}, default MED: {}", g.default_local_preference, g.default_med);
+    println!("  Capabilities: {}", g.capabilities.join(", "));
+}
+
+fn print_routes(routes: &[RouteEntry]) {
+    let rows: Vec<Vec<String>> = routes.iter().map(|r| vec![
+        if r.best { ">".to_string() } else { String::new() },
+        r.prefix.clone(),
+        r.next_hop.to_string(),
+        or_dash(&r.local_pref),
+        or_dash(&r.med),
+        r.as_path.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" "),
+        r.origin.clone(),
+        r.learned_from.map_or("local".to_string(), |ip| ip.to_string()),
+        or_dash(&r.rpki),
+    ]).collect();
+    print_table(&["", "Prefix", "Next Hop", "LocPrf", "MED", "AS Path", "Origin", "From", "RPKI"], &rows);
+}
+
+fn print_rpki(rpki: &RpkiView) {
+    println!("VRPs: {}, reject invalid: {}, prefer valid: {}", rpki.vrps, rpki.reject_invalid, rpki.prefer_valid);
+    println!("Paths: {} valid, {} invalid, {} not found", rpki.valid, rpki.invalid, rpki.not_found);
+    let rows: Vec<Vec<String>> = rpki.caches.iter().map(|c| vec![
+        c.address.clone(),
+        if c.connected { format!("up (version {})", c.version) } else { "down".to_string() },
+        or_dash(&c.session_id),
+        or_dash(&c.serial),
+        c.vrps.to_string(),
+        c.last_update_secs.map_or("never".to_string(), |secs| format!("{}s ago", secs)),
+    ]).collect();
+    print_table(&["Cache", "State", "Session", "Serial", "VRPs", "Last Update"], &rows);
+}
+
+// what the policy changed, old -> new
+fn route_changes(before: &RouteEntry, after: &RouteEntry) -> String {
+    let as_path = |r: &RouteEntry| r.as_path.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ");
+    let communities = |r: &RouteEntry| if r.communities.is_empty() { "-".to_string() } else { r.communities.join(" ") };
+    let mut changes = Vec::new();
+    let mut compare = |name: &str, before: String, after: String| {
+        if before != after {
+            changes.push(format!("{} {} -> {}", name, before, after));
+        }
+    };
+    compare("next hop", before.next_hop.to_string(), after.next_hop.to_string());
+    compare("local pref", or_dash(&before.local_pref), or_dash(&after.local_pref));
+    compare("MED", or_dash(&before.med), or_dash(&after.med));
+    compare("AS path", as_path(before), as_path(after));
+    compare("communities", communities(before), communities(after));
+    if changes.is_empty() { "-".to_string() } else { changes.join(", ") }
+}
+
+fn print_policy_test(entries: &[PolicyTestEntry]) {
+    let rows: Vec<Vec<String>> = entries.iter().map(|e| vec![
+        e.before.prefix.clone(),
+        e.before.learned_from.map_or("local".to_string(), |ip| ip.to_string()),
+        e.action.clone(),
+        e.term.clone().unwrap_or_else(|| "(default)".to_string()),
+        route_changes(&e.before, &e.after),
+    ]).collect();
+    print_table(&["Prefix", "From", "Action", "Term", "Changes"], &rows);
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let response_line = match send_request(&cli.socket, &cli.command.to_request()) {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let response: ControlResponse = match serde_json::from_str(&response_line) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error: unable to parse response - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.json {
+        println!("{}", response_line.trim_end());
+        return match response {
+            ControlResponse::Error(_) => ExitCode::FAILURE,
+            _ => ExitCode::SUCCESS,
+        };
+    }
+    match response {
+        ControlResponse::GlobalSettings(global_settings) => print_global_settings(&global_settings),
+        ControlResponse::Neighbors(neighbors) => print_neighbors(&neighbors),
+        ControlResponse::Summary(summary) => println!("{}", summary),
+        ControlResponse::Neighbor(neighbor) => print_neighbor(&neighbor),
+        ControlResponse::Routes(routes) => print_routes(&routes),
+        ControlResponse::PolicyTest(entries) => print_policy_test(&entries),
+        ControlResponse::Rpki(rpki) => print_rpki(&rpki),
+        ControlResponse::Done(message) => println!("{}", message),
+        ControlResponse::Error(message) => {
+            eprintln!("Error: {}", message);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,388 @@
+use tracing::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::sync::mpsc::Sender;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use crate::channels::{BgpEvent, PeerDownReason, PeerInfo};
+use crate::config::BmpServerConfig;
+use crate::control::as_to_u32;
+use crate::messages::BGPVersion;
+use crate::messages::open::OpenMessage;
+use crate::messages::optional_parameters::Capability;
+use crate::messages::update::UpdateMessage;
+use crate::neighbors::Neighbor;
+use crate::process::{BGPProcess, GlobalSettings, ProcessCommand};
+use crate::routes::{RouteV4, NLRI};
+
+// BMP (RFC 7854) client. We connect out to every configured station and stream it what the neighbors send us
+// (the pre-policy Adj-RIB-In, the UPDATEs as they came in) and our best paths (the RFC 9069 Loc-RIB), built from the
+// events the rest of the daemon already publishes. A station that goes away is retried until it comes back, each new connection
+// starts with a full dump.
+
+const BMP_VERSION: u8 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// message types
+const ROUTE_MONITORING: u8 = 0;
+const STATISTICS_REPORT: u8 = 1;
+const PEER_DOWN: u8 = 2;
+const PEER_UP: u8 = 3;
+const INITIATION: u8 = 4;
+
+// per-peer header
+const PEER_TYPE_GLOBAL: u8 = 0;
+const PEER_TYPE_LOC_RIB: u8 = 3;
+const PEER_FLAG_AS2: u8 = 0x20;
+
+// TLVs
+const INFO_SYS_DESCR: u16 = 1;
+const INFO_SYS_NAME: u16 = 2;
+const INFO_TABLE_NAME: u16 = 3;
+const STAT_ADJ_RIB_IN_ROUTES: u16 = 7;
+const STAT_LOC_RIB_ROUTES: u16 = 8;
+
+// what a Statistics Report carries for one neighbor
+#[derive(Debug, Clone)]
+pub struct PeerStatistics {
+    pub peer: PeerInfo,
+    pub adj_rib_in_routes: u64,
+    pub loc_rib_routes: u64,
+}
+
+pub fn run_bmp_clients(bmp_servers: &[BmpServerConfig], global_settings: &GlobalSettings, tx_process_command: &Sender<ProcessCommand>,
+                       tx_bgp_events: &broadcast::Sender<BgpEvent>) {
+    for bmp_server in bmp_servers {
+        tokio::spawn(run_bmp_client(bmp_server.clone(), global_settings.clone(), tx_process_command.clone(), tx_bgp_events.clone()));
+    }
+}
+
+async fn run_bmp_client(bmp_server: BmpServerConfig, global_settings: GlobalSettings, tx_process_command: Sender<ProcessCommand>,
+                        tx_bgp_events: broadcast::Sender<BgpEvent>) {
+    let socket_addr = SocketAddr::new(bmp_server.address, bmp_server.port);
+    loop {
+        match TcpStream::connect(socket_addr).await {
+            Ok(stream) => {
+                info!("Connected to BMP station {}", socket_addr);
+                match run_bmp_session(stream, &bmp_server, &global_settings, &tx_process_command, &tx_bgp_events).await {
+                    Ok(()) => info!("BMP station {} closed the connection", socket_addr),
+                    Err(e) => warn!("Lost BMP station {} - {}", socket_addr, e),
+                }
+                if tx_process_command.is_closed() {
+                    return;
+                }
+            },
+            Err(e) => debug!("Unable to connect to BMP station {} - {}", socket_addr, e),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn run_bmp_session(stream: TcpStream, bmp_server: &BmpServerConfig, global_settings: &GlobalSettings,
+                         tx_process_command: &Sender<ProcessCommand>, tx_bgp_events: &broadcast::Sender<BgpEvent>) -> io::Result<()> {
+    let (mut read_stream, mut write_stream) = stream.into_split();
+    // subscribe before the dump so nothing that changes while it's built gets lost
+    let mut rx_bgp_events = tx_bgp_events.subscribe();
+    let snapshot = request(tx_process_command, ProcessCommand::BmpSnapshot).await?;
+
+    write_stream.write_all(&initiation_message()).await?;
+    write_stream.write_all(&loc_rib_peer_up_message(global_settings)).await?;
+    for event in &snapshot {
+        write_event(&mut write_stream, global_settings, event).await?;
+    }
+    debug!("Sent {} events to BMP station {} as the initial dump", snapshot.len(), bmp_server.address);
+
+    let mut statistics_timer = interval(Duration::from_secs(bmp_server.statistics_interval.max(1) as u64));
+    statistics_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick is immediate, the dump already told the station everything
+    statistics_timer.tick().await;
+    let mut read_buf = [0u8; 512];
+    loop {
+        tokio::select! {
+            event = rx_bgp_events.recv() => match event {
+                Ok(event) => write_event(&mut write_stream, global_settings, &event).await?,
+                // the station would be missing routes, start over with a fresh dump
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    return Err(io::Error::other(format!("fell {} events behind", skipped)));
+                },
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = statistics_timer.tick(), if bmp_server.statistics_interval > 0 => {
+                for statistics in request(tx_process_command, ProcessCommand::BmpStatistics).await? {
+                    write_stream.write_all(&statistics_report_message(&statistics)).await?;
+                }
+            },
+            // stations don't send anything, this is only here to notice them going away
+            read = read_stream.read(&mut read_buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+async fn request<T>(tx_process_command: &Sender<ProcessCommand>, command: fn(oneshot::Sender<T>) -> ProcessCommand) -> io::Result<T> {
+    let (tx_response, rx_response) = oneshot::channel();
+    if tx_process_command.send(command(tx_response)).await.is_err() {
+        return Err(io::Error::other("BGP process is shutting down"));
+    }
+    rx_response.await.map_err(|_| io::Error::other("BGP process dropped the request"))
+}
+
+async fn write_event(write_stream: &mut OwnedWriteHalf, global_settings: &GlobalSettings, event: &BgpEvent) -> io::Result<()> {
+    let message = match event {
+        BgpEvent::PeerUp { peer, local, remote, sent_open, received_open } => peer_up_message(peer, local, remote, sent_open, received_open),
+        BgpEvent::PeerDown { peer, reason } => peer_down_message(peer, reason),
+        BgpEvent::UpdateReceived { peer, pdu } => route_monitoring_message(&peer_header(peer), pdu),
+        BgpEvent::BestPathChanged { nlri, best_path } => match loc_rib_update(global_settings, nlri, best_path.as_ref()) {
+            Some(pdu) => route_monitoring_message(&loc_rib_peer_header(global_settings), &pdu),
+            None => return Ok(()),
+        },
+        BgpEvent::PeerStateChanged { .. } => return Ok(()),
+    };
+    write_stream.write_all(&message).await
+}
+
+// the Loc-RIB always carries 4 byte ASNs
+fn loc_rib_update(global_settings: &GlobalSettings, nlri: &NLRI, best_path: Option<&RouteV4>) -> Option<Vec<u8>> {
+    let capabilities = Some(vec![Capability::Extended4ByteASN(global_settings.my_as as u32)]);
+    let message = match best_path {
+        Some(route) => UpdateMessage::from_route(route, &capabilities),
+        None => UpdateMessage::new(None, 0, Some(vec![nlri.clone()]), 0, None, None, &capabilities),
+    };
+    match message {
+        Ok(message) => Some(message.convert_to_bytes(&capabilities)),
+        Err(e) => {
+            error!("Unable to encode the best path for {:?} for BMP - {:?}", nlri, e);
+            None
+        }
+    }
+}
+
+fn common_header(message_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(6 + body.len());
+    message.push(BMP_VERSION);
+    message.extend((6 + body.len() as u32).to_be_bytes());
+    message.push(message_type);
+    message.extend(body);
+    message
+}
+
+fn tlv(tlv_type: u16, value: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + value.len());
+    bytes.extend(tlv_type.to_be_bytes());
+    bytes.extend((value.len() as u16).to_be_bytes());
+    bytes.extend(value);
+    bytes
+}
+
+// v4 addresses sit in the last 4 of the 16 bytes
+fn address_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes = [0u8; 16];
+            bytes[12..].copy_from_slice(&ip.octets());
+            bytes
+        },
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn per_peer_header(peer_type: u8, flags: u8, address: Ipv4Addr, as_num: u32, router_id: Ipv4Addr) -> Vec<u8> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut header = Vec::with_capacity(42);
+    header.push(peer_type);
+    header.push(flags);
+    // peer distinguisher, only used for VRFs
+    header.extend([0u8; 8]);
+    header.extend(address_bytes(IpAddr::V4(address)));
+    header.extend(as_num.to_be_bytes());
+    header.extend(router_id.octets());
+    header.extend((timestamp.as_secs() as u32).to_be_bytes());
+    header.extend(timestamp.subsec_micros().to_be_bytes());
+    header
+}
+
+fn peer_header(peer: &PeerInfo) -> Vec<u8> {
+    let flags = if peer.four_byte_asn { 0 } else { PEER_FLAG_AS2 };
+    per_peer_header(PEER_TYPE_GLOBAL, flags, peer.ip, as_to_u32(&peer.as_num), peer.router_id)
+}
+
+// RFC 9069, the Loc-RIB is a peer with no address that has our own AS and router id
+fn loc_rib_peer_header(global_settings: &GlobalSettings) -> Vec<u8> {
+    per_peer_header(PEER_TYPE_LOC_RIB, 0, Ipv4Addr::UNSPECIFIED, global_settings.my_as as u32, global_settings.identifier)
+}
+
+fn initiation_message() -> Vec<u8> {
+    let sys_name = nix::unistd::gethostname().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|_| "bgprtr".to_string());
+    let mut body = tlv(INFO_SYS_DESCR, format!("bgprtr {}", env!("CARGO_PKG_VERSION")).as_bytes());
+    body.extend(tlv(INFO_SYS_NAME, sys_name.as_bytes()));
+    common_header(INITIATION, &body)
+}
+
+fn peer_up_message(peer: &PeerInfo, local: &SocketAddr, remote: &SocketAddr, sent_open: &[u8], received_open: &[u8]) -> Vec<u8> {
+    let mut body = peer_header(peer);
+    body.extend(address_bytes(local.ip()));
+    body.extend(local.port().to_be_bytes());
+    body.extend(remote.port().to_be_bytes());
+    body.extend(sent_open);
+    body.extend(received_open);
+    common_header(PEER_UP, &body)
+}
+
+// there's no session behind the Loc-RIB, RFC 9069 has us make up an Open and send it both ways
+fn loc_rib_peer_up_message(global_settings: &GlobalSettings) -> Vec<u8> {
+    let open = OpenMessage::new(BGPVersion::V4, global_settings.my_as, 0, global_settings.identifier, 0, None)
+        .and_then(|open| open.convert_to_bytes())
+        .unwrap_or_default();
+    let mut body = loc_rib_peer_header(global_settings);
+    body.extend([0u8; 16]);
+    body.extend([0u8; 4]);
+    body.extend(&open);
+    body.extend(&open);
+    body.extend(tlv(INFO_TABLE_NAME, b"global"));
+    common_header(PEER_UP, &body)
+}
+
+fn peer_down_message(peer: &PeerInfo, reason: &PeerDownReason) -> Vec<u8> {
+    let mut body = peer_header(peer);
+    match reason {
+        PeerDownReason::LocalNotification(pdu) => {
+            body.push(1);
+            body.extend(pdu);
+        },
+        PeerDownReason::LocalNoNotification(event) => {
+            body.push(2);
+            body.extend(event.to_be_bytes());
+        },
+        PeerDownReason::RemoteNotification(pdu) => {
+            body.push(3);
+            body.extend(pdu);
+        },
+        PeerDownReason::RemoteNoNotification => body.push(4),
+        PeerDownReason::Deconfigured => body.push(5),
+    }
+    common_header(PEER_DOWN, &body)
+}
+
+fn route_monitoring_message(header: &[u8], pdu: &[u8]) -> Vec<u8> {
+    let mut body = header.to_vec();
+    body.extend(pdu);
+    common_header(ROUTE_MONITORING, &body)
+}
+
+fn statistics_report_message(statistics: &PeerStatistics) -> Vec<u8> {
+    let mut body = peer_header(&statistics.peer);
+    body.extend(2u32.to_be_bytes());
+    body.extend(tlv(STAT_ADJ_RIB_IN_ROUTES, &statistics.adj_rib_in_routes.to_be_bytes()));
+    body.extend(tlv(STAT_LOC_RIB_ROUTES, &statistics.loc_rib_routes.to_be_bytes()));
+    common_header(STATISTICS_REPORT, &body)
+}
+
+// what a station that just connected needs to catch up, as the events it would have seen
+pub async fn snapshot(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>) -> Vec<BgpEvent> {
+    let mut events = Vec::new();
+    for neighbor_arc in running_neighbors.values() {
+        let neighbor = neighbor_arc.lock().await;
+        if !neighbor.is_established() {
+            continue;
+        }
+        let (Some(peer_up), Some(peer)) = (neighbor.peer_up_event(), neighbor.peer_info()) else { continue };
+        events.push(peer_up);
+        // the station only hears the UPDATEs from here on, the import filters changed what we have and left no copy
+        let Some(adj_rib_in) = neighbor.adj_rib_in_as_received() else {
+            debug!("{} doesn't keep its routes as received, BMP stations only get the ones it sends from now on", neighbor.ip);
+            continue;
+        };
+        for route in adj_rib_in.values() {
+            match UpdateMessage::from_route(route, &neighbor.negotiated_capabilities) {
+                Ok(message) => events.push(BgpEvent::UpdateReceived { peer: peer.clone(), pdu: message.convert_to_bytes(&neighbor.negotiated_capabilities) }),
+                Err(e) => error!("Unable to encode {:?} from {} for BMP - {:?}", route.nlri, neighbor.ip, e),
+            }
+        }
+    }
+    let bgp_proc = bgp_proc_arc.lock().await;
+    for (nlri, paths) in &bgp_proc.local_rib {
+        events.push(BgpEvent::BestPathChanged { nlri: nlri.clone(), best_path: paths.first().cloned() });
+    }
+    events
+}
+
+pub async fn statistics(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>) -> Vec<PeerStatistics> {
+    let mut peers = Vec::new();
+    for neighbor_arc in running_neighbors.values() {
+        let neighbor = neighbor_arc.lock().await;
+        if let Some(peer) = neighbor.peer_info() && neighbor.is_established() {
+            peers.push((peer, neighbor.adj_rib_in.len() as u64));
+        }
+    }
+    let bgp_proc = bgp_proc_arc.lock().await;
+    peers.into_iter().map(|(peer, adj_rib_in_routes)| {
+        let loc_rib_routes = bgp_proc.local_rib.values().filter(|paths| paths.first().and_then(|route| route.learned_from) == Some(peer.ip)).count() as u64;
+        PeerStatistics { peer, adj_rib_in_routes, loc_rib_routes }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+    use crate::process::fake_process_loop;
+    use crate::messages::optional_parameters::OptionalParameters;
+    use crate::messages::update::AS;
+    use crate::rpki::Vrps;
+
+    async fn read_message(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 6];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], BMP_VERSION);
+        let len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let mut body = vec![0u8; len - 6];
+        stream.read_exact(&mut body).await.unwrap();
+        (header[5], body)
+    }
+
+    #[tokio::test]
+    async fn test_bmp_session() {
+        let station = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bmp_server = BmpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: station.local_addr().unwrap().port(), statistics_interval: 0 };
+        let global_settings = GlobalSettings {
+            my_as: 65000, identifier: Ipv4Addr::new(10, 0, 0, 1), next_hop_ip: Ipv4Addr::new(10, 0, 0, 1), version: BGPVersion::V4,
+            default_local_preference: 100, default_med: 0, optional_parameters: OptionalParameters { capabilities: Vec::new() }, vrps: Vrps::default(),
+        };
+        let peer = PeerInfo { ip: Ipv4Addr::new(10, 0, 0, 2), as_num: AS::AS2(65001), router_id: Ipv4Addr::new(10, 0, 0, 2), four_byte_asn: false };
+        let tx_bgp_events = broadcast::channel(16).0;
+        // the dump is a single neighbor route
+        let snapshot_peer = peer.clone();
+        let tx_process_command = fake_process_loop(move |command| {
+            if let ProcessCommand::BmpSnapshot(tx_response) = command {
+                let pdu = UpdateMessage::new(None, 0, Some(vec![NLRI::from_str("192.0.2.0/24").unwrap()]), 0, None, None, &None).unwrap().convert_to_bytes(&None);
+                let _ = tx_response.send(vec![BgpEvent::UpdateReceived { peer: snapshot_peer.clone(), pdu }]);
+            }
+        });
+        run_bmp_clients(&[bmp_server], &global_settings, &tx_process_command, &tx_bgp_events);
+        let (mut stream, _) = station.accept().await.unwrap();
+
+        assert_eq!(read_message(&mut stream).await.0, INITIATION);
+        let (message_type, body) = read_message(&mut stream).await;
+        assert_eq!((message_type, body[0]), (PEER_UP, PEER_TYPE_LOC_RIB));
+        let (message_type, body) = read_message(&mut stream).await;
+        assert_eq!((message_type, body[0], body[1]), (ROUTE_MONITORING, PEER_TYPE_GLOBAL, PEER_FLAG_AS2));
+        assert_eq!(&body[26..30], &65001u32.to_be_bytes());
+        // a withdraw, 19 byte header then 2 bytes of withdrawn routes length
+        assert_eq!(body.len(), 42 + 19 + 2 + 4 + 2);
+
+        tx_bgp_events.send(BgpEvent::PeerDown { peer, reason: PeerDownReason::LocalNoNotification(10) }).unwrap();
+        let (message_type, body) = read_message(&mut stream).await;
+        assert_eq!(message_type, PEER_DOWN);
+        assert_eq!(&body[42..], &[2, 0, 10]);
+    }
+}
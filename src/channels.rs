@@ -1,8 +1,12 @@
+use std::net::{Ipv4Addr, SocketAddr};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use crate::errors::EventError;
 use crate::routes::{RouteV4, NLRI};
 use crate::neighbors::PeerType;
+use crate::config::EffectiveNeighborConfig;
+use crate::finite_state_machine::State;
+use crate::messages::update::AS;
 
 pub struct NeighborChannelWatcher {
 
@@ -21,6 +25,42 @@ pub enum ChannelWatcherMessage {
     NoMessageWaiting
 }
 
+// broadcast to whoever is watching the daemon, e.g. the gRPC WatchEvent stream, it's fine if nobody is
+#[derive(Debug, Clone)]
+pub enum BgpEvent {
+    PeerStateChanged { peer_ip: Ipv4Addr, as_num: AS, old_state: State, new_state: State },
+    // best_path is None once the prefix has no paths left
+    BestPathChanged { nlri: NLRI, best_path: Option<RouteV4> },
+    // the session reached Established, with both Opens as they went over the wire
+    PeerUp { peer: PeerInfo, local: SocketAddr, remote: SocketAddr, sent_open: Vec<u8>, received_open: Vec<u8> },
+    PeerDown { peer: PeerInfo, reason: PeerDownReason },
+    // an UPDATE exactly as the neighbor sent it
+    UpdateReceived { peer: PeerInfo, pdu: Vec<u8> },
+}
+
+// who an established session is with, what BMP puts in its per-peer header
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub ip: Ipv4Addr,
+    pub as_num: AS,
+    pub router_id: Ipv4Addr,
+    // false means the AS_PATHs in its UPDATEs use 2 byte ASNs
+    pub four_byte_asn: bool,
+}
+
+// the reasons from RFC 7854 section 4.9
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerDownReason {
+    // the NOTIFICATION PDU we sent before closing it
+    LocalNotification(Vec<u8>),
+    // we closed it without a NOTIFICATION, the RFC 4271 event that did it
+    LocalNoNotification(u16),
+    // the NOTIFICATION PDU the peer sent
+    RemoteNotification(Vec<u8>),
+    RemoteNoNotification,
+    Deconfigured,
+}
+
 #[derive(Debug)]
 pub enum TCPChannelMessage {
     DropTCP
@@ -30,7 +70,13 @@ pub enum ChannelMessage {
     WithdrawRoute(Vec<NLRI>),
     //NeighborDown,
     NeighborUp,
-    TcpEstablished(TcpStream)
+    TcpEstablished(TcpStream),
+    // from a config reload, the bool tells the neighbor to reset its session
+    UpdateConfig(Box<EffectiveNeighborConfig>, bool),
+    // the neighbor was removed from the config
+    Shutdown,
+    // a dynamic neighbor's Open told us whether it's iBGP or eBGP, best path needs to know
+    PeerType(PeerType),
 }
 
 impl NeighborChannel {
@@ -41,6 +87,13 @@ impl NeighborChannel {
         Ok(())
     }
 
+    pub async fn set_peer_type(&mut self, peer_type: PeerType, tx_channel_watcher: &mpsc::Sender<ChannelWatcherMessage>) -> Result<(), EventError> {
+        self.peer_type = peer_type.clone();
+        self.tx.send(ChannelMessage::PeerType(peer_type)).await.map_err(|_| EventError::ChannelDown)?;
+        tx_channel_watcher.send(ChannelWatcherMessage::MessageWaiting).await.map_err(|_| EventError::ChannelDown)?;
+        Ok(())
+    }
+
     // pub async fn take_down(&mut self) -> Result<(), EventError> {
     //     self.is_active = false;
     //     self.tx.send(ChannelMessage::NeighborDown).await.map_err(|_| EventError::ChannelDown)?;
@@ -73,18 +126,18 @@ impl NeighborChannel {
         //}
     }
 
-    pub fn recv_tcp_conn_from_bgp_proc(&mut self) -> Option<TcpStream> {
-        //println!("executing recv_tcp_conn_from_bgp_proc");
-        if let Ok(ChannelMessage::TcpEstablished(tcp_stream)) = self.rx.try_recv() {
-            return Some(tcp_stream)
-        }
-        None
-    }
-
     pub fn send_tcp_conn_to_neighbor(&self, tcp_stream: TcpStream) -> Result<(), EventError> {
         //println!("executing send_tcp_conn_to_neighbor");
         // This func doesn't need ChannelWatcherMessage because it runs only in run_process_loop.
         // ChannelWatcherMessage is really for sending an unlock signal from neighbor to proc loop.
         self.tx.try_send(ChannelMessage::TcpEstablished(tcp_stream)).map_err(|_| EventError::ChannelDown)
     }
+
+    pub fn send_config_to_neighbor(&self, config: EffectiveNeighborConfig, reset_session: bool) -> Result<(), EventError> {
+        self.tx.try_send(ChannelMessage::UpdateConfig(Box::new(config), reset_session)).map_err(|_| EventError::ChannelDown)
+    }
+
+    pub fn send_shutdown_to_neighbor(&self) -> Result<(), EventError> {
+        self.tx.try_send(ChannelMessage::Shutdown).map_err(|_| EventError::ChannelDown)
+    }
 }
\ No newline at end of file
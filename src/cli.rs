@@ -0,0 +1,119 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::{Parser, ValueEnum};
+
+use crate::config::ListenConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    // one JSON object per line, for shipping to a log collector
+    Json,
+}
+
+// an address on its own keeps the port from the config, "addr:port" or "[v6]:port" sets both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenOverride {
+    pub address: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl FromStr for ListenOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = IpAddr::from_str(s) {
+            return Ok(ListenOverride { address, port: None });
+        }
+        match SocketAddr::from_str(s) {
+            Ok(socket_addr) => Ok(ListenOverride { address: socket_addr.ip(), port: Some(socket_addr.port()) }),
+            Err(_) => Err(format!("{} is not an address or address:port", s)),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "bgprtr", version, about = "A BGP daemon")]
+pub struct Cli {
+    /// Config file to load, and to reload on SIGHUP
+    #[arg(short, long, value_name = "PATH", default_value = "bgp_config.toml")]
+    pub config: PathBuf,
+
+    /// Listen on this address instead of the configured ones, can be given more than once
+    #[arg(short, long, value_name = "ADDR[:PORT]")]
+    pub listen: Vec<ListenOverride>,
+
+    /// Lowest level that gets logged
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Per-target levels on top of --log-level, e.g. "fsm=debug,codec=trace" (targets are fsm, rib and codec)
+    #[arg(long, value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+
+    /// Format of the log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append the log to this file instead of writing it to stdout
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Validate the config file and exit, non-zero if it has problems
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Detach from the terminal and run in the background
+    #[arg(short, long)]
+    pub daemon: bool,
+
+    /// Unix socket bgpctl connects to
+    #[arg(short = 's', long, value_name = "PATH", default_value = "bgprtr.sock")]
+    pub control_socket: PathBuf,
+
+    /// Write the process id to this file, it is removed again on shutdown
+    #[arg(short, long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+}
+
+impl Cli {
+    // the last port given wins, addresses without one keep the port from the config
+    pub fn apply_listen_overrides(&self, listen_config: &mut ListenConfig) {
+        if self.listen.is_empty() {
+            return;
+        }
+        listen_config.addresses = self.listen.iter().map(|l| l.address).collect();
+        if let Some(port) = self.listen.iter().rev().find_map(|l| l.port) {
+            listen_config.port = port;
+        }
+        listen_config.passive = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_overrides() {
+        let cli = Cli::parse_from(["bgprtr", "-c", "r2.toml", "--listen", "127.0.0.2", "--listen", "[::1]:11179"]);
+        assert_eq!(cli.config, PathBuf::from("r2.toml"));
+        let mut listen_config = ListenConfig { passive: false, ..Default::default() };
+        cli.apply_listen_overrides(&mut listen_config);
+        assert_eq!(listen_config.addresses, vec![IpAddr::from_str("127.0.0.2").unwrap(), IpAddr::from_str("::1").unwrap()]);
+        assert_eq!(listen_config.port, 11179);
+        assert!(listen_config.passive);
+        assert!(Cli::try_parse_from(["bgprtr", "--listen", "bogus"]).is_err());
+    }
+}
@@ -1,38 +1,501 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::fs;
+use std::fmt;
+use std::str::FromStr;
 use std::ops::Mul;
-use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 use toml;
 
 //use crate::messages::update::AS;
+use tracing::{error, warn};
 use crate::routes::*;
+use crate::errors::ConfigError;
+use crate::policy::{Policies, Policy};
+use crate::plugins::{Plugin, Plugins};
+use crate::rpki::RpkiState;
+use crate::scripting::{Script, Scripts};
 //used to handle the toml configurations
 
-pub fn read_config_file(file_name: &str) -> Config {
-    let toml_content = fs::read_to_string(file_name).unwrap();
-    let config: Config = toml::from_str(&toml_content).unwrap();
-    config
+pub mod validation;
 
+// a config that passed validation, with the policies, plugins and scripts it refers to compiled along the way
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub policies: Policies,
+    pub plugins: Plugins,
+    pub scripts: Scripts,
+}
+
+// reads, parses and validates the config, every problem found is printed with its location
+pub fn load_config_file(file_name: &str, previous_plugins: &Plugins, previous_scripts: &Scripts) -> Result<LoadedConfig, ConfigError> {
+    let toml_content = fs::read_to_string(file_name).map_err(|e| {
+        error!("Unable to read config file {} - {}", file_name, e);
+        ConfigError::UnableToReadFile
+    })?;
+    validation::validate_config(&toml_content, previous_plugins, previous_scripts).map_err(|problems| {
+        for problem in &problems {
+            error!("{}: {}", file_name, problem);
+        }
+        ConfigError::InvalidConfig
+    })
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub process_config: ProcessConfig,
     pub neighbors_config: Vec<NeighborConfig>,
-    pub net_advertisements_config: Vec<NetAdvertisementsConfig>
+    pub net_advertisements_config: Vec<NetAdvertisementsConfig>,
+    #[serde(default)]
+    pub listen_ranges_config: Vec<ListenRangeConfig>,
+    #[serde(default)]
+    pub peer_groups_config: Vec<PeerGroupConfig>,
+    #[serde(default)]
+    pub bmp_servers_config: Vec<BmpServerConfig>,
+    #[serde(default)]
+    pub mrt_replays_config: Vec<MrtReplayConfig>,
+    #[serde(default)]
+    pub prefix_lists_config: Vec<PrefixListConfig>,
+    #[serde(default)]
+    pub as_path_lists_config: Vec<AsPathListConfig>,
+    #[serde(default)]
+    pub policies_config: Vec<PolicyConfig>,
+    #[serde(default)]
+    pub rpki_config: RpkiConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ProcessConfig {
     pub my_as: u16,
     pub router_id: String,
     pub next_hop_ip: String,
     pub default_local_preference: u32,
     pub default_med: u32,
-    pub capabilities_config: CapabilitiesConfig
+    pub capabilities_config: CapabilitiesConfig,
+    #[serde(default)]
+    pub listen: ListenConfig,
+    // the management API is off unless this table is present
+    pub http_api: Option<HttpApiConfig>,
+    // same for the gRPC API
+    pub grpc_api: Option<GrpcApiConfig>,
+    // and for the Prometheus /metrics endpoint
+    pub metrics: Option<MetricsConfig>,
+    // MRT table dumps, on demand with bgpctl and every interval
+    pub mrt_dump: Option<MrtDumpConfig>,
+    // BGP4MP archive for the neighbors that turn on archive_messages
+    pub mrt_archive: Option<MrtArchiveConfig>,
+    // limits for import_script/export_script
+    #[serde(default)]
+    pub scripting: ScriptingConfig,
+    // and for import_plugin/export_plugin
+    #[serde(default)]
+    pub wasm: WasmConfig,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    // v4 and v6 addresses are both fine, use "::" for a dual-stack listener
+    #[serde(default = "default_listen_addresses")]
+    pub addresses: Vec<IpAddr>,
+    #[serde(default = "default_listen_port")]
+    pub port: u16,
+    // false disables listening entirely
+    #[serde(default = "default_passive")]
+    pub passive: bool,
+}
+
+fn default_listen_addresses() -> Vec<IpAddr> {
+    vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+}
+
+fn default_listen_port() -> u16 {
+    179
+}
+
+fn default_passive() -> bool {
+    true
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            addresses: default_listen_addresses(),
+            port: default_listen_port(),
+            passive: default_passive(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpApiConfig {
+    // there's no auth on the API, only move it off localhost behind something that adds it
+    #[serde(default = "default_http_api_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_http_api_port")]
+    pub port: u16,
+}
+
+fn default_http_api_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_http_api_port() -> u16 {
+    8179
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcApiConfig {
+    // no auth or TLS here either
+    #[serde(default = "default_http_api_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_grpc_api_port")]
+    pub port: u16,
+}
+
+fn default_grpc_api_port() -> u16 {
+    50051
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    // set it to the address Prometheus scrapes from, the counters aren't sensitive
+    #[serde(default = "default_http_api_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_metrics_port")]
+    pub port: u16,
+}
+
+fn default_metrics_port() -> u16 {
+    9179
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MrtDumpConfig {
+    pub directory: PathBuf,
+    // seconds between dumps, 0 only dumps when asked to
+    #[serde(default = "default_mrt_dump_interval")]
+    pub interval: u32,
+    // dumps of each kind to keep around, 0 keeps all of them
+    #[serde(default = "default_mrt_dump_keep")]
+    pub keep: usize,
+    // gzip, bgpdump reads them as they are
+    #[serde(default)]
+    pub compress: bool,
+}
+
+fn default_mrt_dump_interval() -> u32 {
+    7200
+}
+
+fn default_mrt_dump_keep() -> usize {
+    24
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MrtArchiveConfig {
+    pub directory: PathBuf,
+    // seconds each updates file covers
+    #[serde(default = "default_mrt_archive_rotate_interval")]
+    pub rotate_interval: u32,
+    // 0 keeps all of them
+    #[serde(default = "default_mrt_archive_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub compress: bool,
+}
+
+fn default_mrt_archive_rotate_interval() -> u32 {
+    900
+}
+
+// a day's worth at the default interval
+fn default_mrt_archive_keep() -> usize {
+    96
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptingConfig {
+    // the CPU budget for one route, Rhai counts about one operation per expression
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    #[serde(default = "default_script_max_call_levels")]
+    pub max_call_levels: usize,
+    // for what the script builds, data files from load_data don't count
+    #[serde(default = "default_script_max_size")]
+    pub max_string_size: usize,
+    #[serde(default = "default_script_max_size")]
+    pub max_array_size: usize,
+    #[serde(default = "default_script_max_size")]
+    pub max_map_size: usize,
+    // seconds between looking for changed scripts and data files, 0 leaves it to reloads
+    #[serde(default = "default_script_check_interval")]
+    pub check_interval: u16,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_script_max_call_levels() -> usize {
+    16
+}
+
+fn default_script_max_size() -> usize {
+    4096
+}
+
+fn default_script_check_interval() -> u16 {
+    5
+}
+
+impl Default for ScriptingConfig {
+    fn default() -> Self {
+        ScriptingConfig {
+            max_operations: default_script_max_operations(),
+            max_call_levels: default_script_max_call_levels(),
+            max_string_size: default_script_max_size(),
+            max_array_size: default_script_max_size(),
+            max_map_size: default_script_max_size(),
+            check_interval: default_script_check_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WasmConfig {
+    // the CPU budget for one route, wasmi charges about one unit per instruction
+    #[serde(default = "default_wasm_fuel")]
+    pub fuel: u64,
+    // MiB of linear memory a module can grow to
+    #[serde(default = "default_wasm_max_memory")]
+    pub max_memory: usize,
+}
+
+fn default_wasm_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_wasm_max_memory() -> usize {
+    64
+}
+
+impl Default for WasmConfig {
+    fn default() -> Self {
+        WasmConfig {
+            fuel: default_wasm_fuel(),
+            max_memory: default_wasm_max_memory(),
+        }
+    }
+}
+
+// a filter function in a WebAssembly module, see plugins.rs for what it has to look like
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    pub module: PathBuf,
+    pub function: String,
+}
+
+// an MRT file whose peers show up as virtual neighbors, their routes go through the RIBs like any other
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MrtReplayConfig {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub timing: ReplayTiming,
+    // only these peers from the file, all of them when left out
+    pub peers: Option<Vec<Ipv4Addr>>,
+    // the virtual neighbors take its import policy, plugin and script, none of them when left out
+    pub peer_group: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayTiming {
+    // everything as fast as the RIBs take it
+    #[default]
+    Fast,
+    // records go in as far apart as their timestamps are
+    Original,
+}
+
+// a named set of prefixes for policy terms to match on, written out in prefixes or kept in a file
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixListConfig {
+    pub name: String,
+    #[serde(default)]
+    pub prefixes: Vec<PrefixListEntryConfig>,
+    // e.g. bgpq4 output, read again when it changes
+    pub file: Option<PathBuf>,
+    // by default bgpq4_json for .json files and plain for anything else
+    pub format: Option<ListFileFormat>,
+}
+
+// the ASNs a route's origin AS has to be one of, e.g. an AS-SET expanded by bgpq4
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AsPathListConfig {
+    pub name: String,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    pub file: Option<PathBuf>,
+    pub format: Option<ListFileFormat>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFileFormat {
+    // bgpq4 -j, an object holding one named array
+    Bgpq4Json,
+    // one prefix or ASN per line, # starts a comment
+    Plain,
+}
+
+// without ge/le only the prefix itself matches, with them anything inside it whose length falls in between
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixListEntryConfig {
+    #[serde(rename = "prefix")]
+    pub nlri: NLRI,
+    pub ge: Option<u8>,
+    pub le: Option<u8>,
+}
+
+// the terms run in order and the first one with an action decides, default_action covers the routes none decide on
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub name: String,
+    #[serde(default)]
+    pub terms: Vec<PolicyTermConfig>,
+    #[serde(default)]
+    pub default_action: PolicyAction,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyTermConfig {
+    pub name: String,
+    // every condition that's set has to match, leave it out to match every route
+    #[serde(default, rename = "match")]
+    pub conditions: PolicyMatchConfig,
+    #[serde(default)]
+    pub set: PolicySetConfig,
+    // without one the route moves on to the next term, keeping what this one set
+    pub action: Option<PolicyAction>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyMatchConfig {
+    pub prefix_list: Option<String>,
+    // runs against the path as space separated ASNs, _ stands for the start, the end or the space between two ASNs
+    pub as_path: Option<String>,
+    // a name from as_path_lists_config, the AS that originated the route has to be in it
+    pub as_path_list: Option<String>,
+    // the route has to carry at least one of these, "65000:100" or no-export, no-advertise, no-export-subconfed
+    pub community: Option<Vec<String>>,
+    pub origin: Option<PolicyOrigin>,
+    // prefixes the next hop has to fall in
+    pub next_hop: Option<Vec<NLRI>>,
+    // the neighbor the route came from on import, the one it's going to on export
+    pub neighbor: Option<Vec<Ipv4Addr>>,
+    // the route's origin validation state, "valid", "invalid" or "not_found"
+    pub rpki: Option<RpkiState>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PolicySetConfig {
+    pub local_pref: Option<u32>,
+    pub med: Option<u32>,
+    // replaces the route's communities, add_communities and remove_communities go after it
+    pub communities: Option<Vec<String>>,
+    pub add_communities: Option<Vec<String>>,
+    pub remove_communities: Option<Vec<String>>,
+    pub next_hop: Option<Ipv4Addr>,
+    // how many more times our AS goes in front of the path
+    pub prepend: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    #[default]
+    Accept,
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyOrigin {
+    Igp,
+    Egp,
+    Incomplete,
+}
+
+// a BMP station we connect out to and stream the RIBs at
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BmpServerConfig {
+    pub address: IpAddr,
+    #[serde(default = "default_bmp_port")]
+    pub port: u16,
+    // seconds between Statistics Reports, 0 turns them off
+    #[serde(default = "default_bmp_statistics_interval")]
+    pub statistics_interval: u16,
+}
+
+// route origin validation, nothing is validated without caches and every route is not_found
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RpkiConfig {
+    #[serde(default)]
+    pub caches: Vec<RpkiCacheConfig>,
+    // invalid paths are never the best path
+    #[serde(default)]
+    pub reject_invalid: bool,
+    // valid beats not_found beats invalid before local pref is looked at
+    #[serde(default)]
+    pub prefer_valid: bool,
+}
+
+// an RPKI-to-Router (RFC 8210) cache, e.g. Routinator, rpki-client with StayRTR or FORT
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RpkiCacheConfig {
+    pub address: IpAddr,
+    #[serde(default = "default_rpki_cache_port")]
+    pub port: u16,
+}
+
+fn default_rpki_cache_port() -> u16 {
+    323
+}
+
+fn default_bmp_port() -> u16 {
+    11019
+}
+
+fn default_bmp_statistics_interval() -> u16 {
+    60
 }
 
 #[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(deny_unknown_fields)]
 pub struct CapabilitiesConfig {
     pub route_refresh_prestandard: bool,
     pub route_refresh: bool,
@@ -42,6 +505,7 @@ pub struct CapabilitiesConfig {
 }
 
 #[derive(Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
 pub struct MultiProtocolExtensionsConfig {
     pub ipv4_unicast: bool,
     pub ipv4_multicast: bool,
@@ -81,7 +545,7 @@ impl MultiProtocolExtensionsConfig {
                 mp_ex_config.ipv6_vpn = true;
             },
             (_, _) => {
-                println!("MultiProtocol afi and safi not recognized, defaulting");
+                warn!("MultiProtocol afi and safi not recognized, defaulting");
             }
 
 
@@ -92,16 +556,434 @@ impl MultiProtocolExtensionsConfig {
 }
 
 
+// anything left out here falls back to the neighbor's peer group, then to the process/FSM defaults
 #[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct NeighborConfig {
     pub ip: String,
-    pub as_num: u16,
+    pub peer_group: Option<String>,
+    pub description: Option<String>,
+    pub as_num: Option<u16>,
+    pub hello_time: Option<u16>,
+    pub hold_time: Option<u16>,
+    // false means we go to Connect on start and open the connection to the peer ourselves, on the port we listen on,
+    // retrying every ConnectRetryTimer instead of only waiting in Active for the peer. Listen ranges only ever wait
+    pub passive: Option<bool>,
+    // rewrite the next hop to our next_hop_ip on routes we send this neighbor
+    pub next_hop_self: Option<bool>,
+    pub damp_peer_oscillations: Option<bool>,
+    // seconds, the IdleHoldTimer starts here and doubles on every flap up to max_idle_hold_time
+    pub idle_hold_time: Option<u16>,
+    pub max_idle_hold_time: Option<u16>,
+    // seconds the session has to stay Established before the backoff resets
+    pub idle_hold_stable_time: Option<u16>,
+    // hold our Open until the DelayOpenTimer fires or the peer's Open arrives first
+    pub delay_open: Option<bool>,
+    pub delay_open_time: Option<u16>,
+    pub capabilities_config: Option<CapabilitiesConfig>,
+    // every message in and out goes to the [process_config.mrt_archive] files
+    pub archive_messages: Option<bool>,
+    // keep the routes as the neighbor sent them, so new import filters can run without asking it for them again
+    pub soft_reconfiguration_inbound: Option<bool>,
+    // names from policies_config, import runs on the routes the neighbor sends us and export on the ones we send it
+    pub import_policy: Option<String>,
+    pub export_policy: Option<String>,
+    // WebAssembly filters that run after the policies
+    pub import_plugin: Option<PluginConfig>,
+    pub export_plugin: Option<PluginConfig>,
+    // Rhai scripts that run after the plugins, see scripting.rs
+    pub import_script: Option<PathBuf>,
+    pub export_script: Option<PathBuf>,
+}
+
+// same knobs as NeighborConfig, neighbors and listen ranges pick it up with peer_group = "<name>"
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PeerGroupConfig {
+    pub name: String,
+    pub description: Option<String>,
+    pub as_num: Option<u16>,
+    pub hello_time: Option<u16>,
+    pub hold_time: Option<u16>,
+    pub passive: Option<bool>,
+    pub next_hop_self: Option<bool>,
+    pub damp_peer_oscillations: Option<bool>,
+    pub idle_hold_time: Option<u16>,
+    pub max_idle_hold_time: Option<u16>,
+    pub idle_hold_stable_time: Option<u16>,
+    pub delay_open: Option<bool>,
+    pub delay_open_time: Option<u16>,
+    pub capabilities_config: Option<CapabilitiesConfig>,
+    pub archive_messages: Option<bool>,
+    pub soft_reconfiguration_inbound: Option<bool>,
+    pub import_policy: Option<String>,
+    pub export_policy: Option<String>,
+    pub import_plugin: Option<PluginConfig>,
+    pub export_plugin: Option<PluginConfig>,
+    pub import_script: Option<PathBuf>,
+    pub export_script: Option<PathBuf>,
+}
+
+// accepts connections from any peer inside the prefix and builds a neighbor for it on the fly
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenRangeConfig {
+    #[serde(rename = "prefix")]
+    pub nlri: NLRI,
+    pub peer_group: Option<String>,
+    // the peer's AS in its Open has to fall between these, leave both out to use the peer group's AS or accept any AS
+    pub min_as: Option<u16>,
+    pub max_as: Option<u16>,
+    pub hello_time: Option<u16>,
+    pub hold_time: Option<u16>,
+    pub max_neighbors: Option<usize>,
+}
+
+// per-neighbor knobs after the peer group and the global defaults have been applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborSettings {
+    pub description: Option<String>,
     pub hello_time: u16,
     pub hold_time: u16,
+    pub passive: bool,
+    pub next_hop_self: bool,
+    pub damp_peer_oscillations: bool,
+    // None keeps the FSM default
+    pub idle_hold_time: Option<u16>,
+    pub max_idle_hold_time: Option<u16>,
+    pub idle_hold_stable_time: Option<u16>,
+    pub delay_open: bool,
+    pub delay_open_time: Option<u16>,
+    pub capabilities_config: CapabilitiesConfig,
+    pub archive_messages: bool,
+    pub soft_reconfiguration_inbound: bool,
+    // None lets every route through untouched
+    pub import_policy: Option<Arc<Policy>>,
+    pub export_policy: Option<Arc<Policy>>,
+    pub import_plugin: Option<Plugin>,
+    pub export_plugin: Option<Plugin>,
+    pub import_script: Option<Arc<Script>>,
+    pub export_script: Option<Arc<Script>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveNeighborConfig {
+    pub ip: Ipv4Addr,
+    pub as_num: u16,
+    pub peer_group: Option<String>,
+    pub settings: NeighborSettings,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveMrtReplayConfig {
+    pub replay: MrtReplayConfig,
+    // what every virtual neighbor of the file gets
+    pub settings: NeighborSettings,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveListenRangeConfig {
+    pub nlri: NLRI,
+    pub min_as: u16,
+    pub max_as: u16,
+    pub max_neighbors: Option<usize>,
+    pub peer_group: Option<String>,
+    pub settings: NeighborSettings,
+}
+
+fn get_peer_group<'a>(peer_groups: &'a [PeerGroupConfig], name: &Option<String>) -> Result<Option<&'a PeerGroupConfig>, ConfigError> {
+    match name {
+        Some(name) => match peer_groups.iter().find(|pg| &pg.name == name) {
+            Some(pg) => Ok(Some(pg)),
+            None => {
+                error!("Peer group {} is not configured", name);
+                Err(ConfigError::PeerGroupNotFound)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+fn get_policy(policies: &Policies, name: &Option<String>, not_found: ConfigError) -> Result<Option<Arc<Policy>>, ConfigError> {
+    match name {
+        Some(name) => match policies.get(name) {
+            Some(policy) => Ok(Some(policy.clone())),
+            None => {
+                error!("Policy {} is not configured", name);
+                Err(not_found)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+fn get_script(scripts: &Scripts, path: &Option<PathBuf>, not_found: ConfigError) -> Result<Option<Arc<Script>>, ConfigError> {
+    match path {
+        Some(path) => match scripts.get(path) {
+            Some(script) => Ok(Some(script.clone())),
+            None => {
+                error!("Script {} is not loaded", path.display());
+                Err(not_found)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+fn get_plugin(plugins: &Plugins, config: &Option<PluginConfig>, not_found: ConfigError) -> Result<Option<Plugin>, ConfigError> {
+    match config {
+        Some(config) => match plugins.get(&config.module) {
+            Some(module) if module.has_filter(&config.function) => Ok(Some(Plugin { module: module.clone(), function: config.function.clone() })),
+            _ => {
+                error!("Plugin {} in {} is not loaded", config.function, config.module.display());
+                Err(not_found)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+impl NeighborConfig {
+    pub fn resolve(&self, peer_groups: &[PeerGroupConfig], default_capabilities: CapabilitiesConfig, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Result<EffectiveNeighborConfig, ConfigError> {
+        let ip = Ipv4Addr::from_str(&self.ip).map_err(|_| ConfigError::BadNeighborIP)?;
+        let pg = get_peer_group(peer_groups, &self.peer_group)?;
+        let as_num = self.as_num.or(pg.and_then(|pg| pg.as_num)).ok_or(ConfigError::MissingNeighborAS)?;
+        let settings = NeighborSettings {
+            description: self.description.clone().or(pg.and_then(|pg| pg.description.clone())),
+            hello_time: self.hello_time.or(pg.and_then(|pg| pg.hello_time)).ok_or(ConfigError::MissingNeighborTimers)?,
+            hold_time: self.hold_time.or(pg.and_then(|pg| pg.hold_time)).ok_or(ConfigError::MissingNeighborTimers)?,
+            passive: self.passive.or(pg.and_then(|pg| pg.passive)).unwrap_or(true),
+            next_hop_self: self.next_hop_self.or(pg.and_then(|pg| pg.next_hop_self)).unwrap_or(false),
+            damp_peer_oscillations: self.damp_peer_oscillations.or(pg.and_then(|pg| pg.damp_peer_oscillations)).unwrap_or(false),
+            idle_hold_time: self.idle_hold_time.or(pg.and_then(|pg| pg.idle_hold_time)),
+            max_idle_hold_time: self.max_idle_hold_time.or(pg.and_then(|pg| pg.max_idle_hold_time)),
+            idle_hold_stable_time: self.idle_hold_stable_time.or(pg.and_then(|pg| pg.idle_hold_stable_time)),
+            delay_open: self.delay_open.or(pg.and_then(|pg| pg.delay_open)).unwrap_or(false),
+            delay_open_time: self.delay_open_time.or(pg.and_then(|pg| pg.delay_open_time)),
+            capabilities_config: self.capabilities_config.or(pg.and_then(|pg| pg.capabilities_config)).unwrap_or(default_capabilities),
+            archive_messages: self.archive_messages.or(pg.and_then(|pg| pg.archive_messages)).unwrap_or(false),
+            soft_reconfiguration_inbound: self.soft_reconfiguration_inbound.or(pg.and_then(|pg| pg.soft_reconfiguration_inbound)).unwrap_or(false),
+            import_policy: get_policy(policies, &self.import_policy.clone().or(pg.and_then(|pg| pg.import_policy.clone())), ConfigError::ImportPolicyNotFound)?,
+            export_policy: get_policy(policies, &self.export_policy.clone().or(pg.and_then(|pg| pg.export_policy.clone())), ConfigError::ExportPolicyNotFound)?,
+            import_plugin: get_plugin(plugins, &self.import_plugin.clone().or(pg.and_then(|pg| pg.import_plugin.clone())), ConfigError::ImportPluginNotFound)?,
+            export_plugin: get_plugin(plugins, &self.export_plugin.clone().or(pg.and_then(|pg| pg.export_plugin.clone())), ConfigError::ExportPluginNotFound)?,
+            import_script: get_script(scripts, &self.import_script.clone().or(pg.and_then(|pg| pg.import_script.clone())), ConfigError::ImportScriptNotFound)?,
+            export_script: get_script(scripts, &self.export_script.clone().or(pg.and_then(|pg| pg.export_script.clone())), ConfigError::ExportScriptNotFound)?,
+        };
+        Ok(EffectiveNeighborConfig {
+            ip,
+            as_num,
+            peer_group: self.peer_group.clone(),
+            settings,
+        })
+    }
+}
+
+// everything but the timers comes from the peer group, for neighbors that only show up at runtime
+fn peer_group_settings(pg: Option<&PeerGroupConfig>, hello_time: Option<u16>, hold_time: Option<u16>, default_capabilities: CapabilitiesConfig,
+                       policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Result<NeighborSettings, ConfigError> {
+    Ok(NeighborSettings {
+        description: pg.and_then(|pg| pg.description.clone()),
+        hello_time: hello_time.or(pg.and_then(|pg| pg.hello_time)).ok_or(ConfigError::MissingNeighborTimers)?,
+        hold_time: hold_time.or(pg.and_then(|pg| pg.hold_time)).ok_or(ConfigError::MissingNeighborTimers)?,
+        passive: pg.and_then(|pg| pg.passive).unwrap_or(true),
+        next_hop_self: pg.and_then(|pg| pg.next_hop_self).unwrap_or(false),
+        damp_peer_oscillations: pg.and_then(|pg| pg.damp_peer_oscillations).unwrap_or(false),
+        idle_hold_time: pg.and_then(|pg| pg.idle_hold_time),
+        max_idle_hold_time: pg.and_then(|pg| pg.max_idle_hold_time),
+        idle_hold_stable_time: pg.and_then(|pg| pg.idle_hold_stable_time),
+        delay_open: pg.and_then(|pg| pg.delay_open).unwrap_or(false),
+        delay_open_time: pg.and_then(|pg| pg.delay_open_time),
+        capabilities_config: pg.and_then(|pg| pg.capabilities_config).unwrap_or(default_capabilities),
+        archive_messages: pg.and_then(|pg| pg.archive_messages).unwrap_or(false),
+        soft_reconfiguration_inbound: pg.and_then(|pg| pg.soft_reconfiguration_inbound).unwrap_or(false),
+        import_policy: get_policy(policies, &pg.and_then(|pg| pg.import_policy.clone()), ConfigError::ImportPolicyNotFound)?,
+        export_policy: get_policy(policies, &pg.and_then(|pg| pg.export_policy.clone()), ConfigError::ExportPolicyNotFound)?,
+        import_plugin: get_plugin(plugins, &pg.and_then(|pg| pg.import_plugin.clone()), ConfigError::ImportPluginNotFound)?,
+        export_plugin: get_plugin(plugins, &pg.and_then(|pg| pg.export_plugin.clone()), ConfigError::ExportPluginNotFound)?,
+        import_script: get_script(scripts, &pg.and_then(|pg| pg.import_script.clone()), ConfigError::ImportScriptNotFound)?,
+        export_script: get_script(scripts, &pg.and_then(|pg| pg.export_script.clone()), ConfigError::ExportScriptNotFound)?,
+    })
+}
+
+impl ListenRangeConfig {
+    pub fn resolve(&self, peer_groups: &[PeerGroupConfig], default_capabilities: CapabilitiesConfig, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Result<EffectiveListenRangeConfig, ConfigError> {
+        let pg = get_peer_group(peer_groups, &self.peer_group)?;
+        let pg_as_num = pg.and_then(|pg| pg.as_num);
+        let settings = peer_group_settings(pg, self.hello_time, self.hold_time, default_capabilities, policies, plugins, scripts)?;
+        Ok(EffectiveListenRangeConfig {
+            nlri: self.nlri.clone(),
+            min_as: self.min_as.or(pg_as_num).unwrap_or(0),
+            max_as: self.max_as.or(pg_as_num).unwrap_or(u16::MAX),
+            max_neighbors: self.max_neighbors,
+            peer_group: self.peer_group.clone(),
+            settings,
+        })
+    }
+}
+
+impl MrtReplayConfig {
+    pub fn resolve(&self, peer_groups: &[PeerGroupConfig], default_capabilities: CapabilitiesConfig, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Result<EffectiveMrtReplayConfig, ConfigError> {
+        let pg = get_peer_group(peer_groups, &self.peer_group)?;
+        // a virtual neighbor never has a session, the timers only have to be valid ones
+        let settings = peer_group_settings(pg, Some(30), Some(90), default_capabilities, policies, plugins, scripts)?;
+        Ok(EffectiveMrtReplayConfig { replay: self.clone(), settings })
+    }
+}
+
+impl NeighborSettings {
+    // an import policy that matches on rpki has to run again whenever the VRPs change, so it needs the routes as received
+    // as much as soft_reconfiguration_inbound does
+    pub fn keeps_routes_as_received(&self) -> bool {
+        self.soft_reconfiguration_inbound || self.import_policy.as_ref().is_some_and(|policy| policy.uses_rpki())
+    }
+}
+
+impl fmt::Display for NeighborSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(description) = &self.description {
+            writeln!(f, "  description: {}", description)?;
+        }
+        writeln!(f, "  hello_time: {}, hold_time: {}", self.hello_time, self.hold_time)?;
+        writeln!(f, "  passive: {}, next_hop_self: {}", self.passive, self.next_hop_self)?;
+        writeln!(f, "  damp_peer_oscillations: {}, idle_hold_time: {:?}, max_idle_hold_time: {:?}, idle_hold_stable_time: {:?}",
+                 self.damp_peer_oscillations, self.idle_hold_time, self.max_idle_hold_time, self.idle_hold_stable_time)?;
+        writeln!(f, "  delay_open: {}, delay_open_time: {:?}", self.delay_open, self.delay_open_time)?;
+        writeln!(f, "  archive_messages: {}, soft_reconfiguration_inbound: {}", self.archive_messages, self.soft_reconfiguration_inbound)?;
+        writeln!(f, "  import_policy: {}, export_policy: {}",
+                 self.import_policy.as_ref().map_or("none", |policy| policy.name()),
+                 self.export_policy.as_ref().map_or("none", |policy| policy.name()))?;
+        if self.import_plugin.is_some() || self.export_plugin.is_some() {
+            writeln!(f, "  import_plugin: {}, export_plugin: {}",
+                     self.import_plugin.as_ref().map_or("none".to_string(), |plugin| plugin.to_string()),
+                     self.export_plugin.as_ref().map_or("none".to_string(), |plugin| plugin.to_string()))?;
+        }
+        if self.import_script.is_some() || self.export_script.is_some() {
+            writeln!(f, "  import_script: {}, export_script: {}",
+                     self.import_script.as_ref().map_or("none".into(), |script| script.path().to_string_lossy()),
+                     self.export_script.as_ref().map_or("none".into(), |script| script.path().to_string_lossy()))?;
+        }
+        write!(f, "  capabilities: {:?}", self.capabilities_config)
+    }
+}
+
+impl fmt::Display for EffectiveNeighborConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "neighbor {} as {}", self.ip, self.as_num)?;
+        if let Some(peer_group) = &self.peer_group {
+            write!(f, " peer-group {}", peer_group)?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.settings)
+    }
+}
+
+impl fmt::Display for EffectiveListenRangeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "listen range {}/{} as {}-{}", self.nlri.prefix, self.nlri.len, self.min_as, self.max_as)?;
+        if let Some(peer_group) = &self.peer_group {
+            write!(f, " peer-group {}", peer_group)?;
+        }
+        if let Some(max_neighbors) = self.max_neighbors {
+            write!(f, " max-neighbors {}", max_neighbors)?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.settings)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct NetAdvertisementsConfig {
     #[serde(rename = "prefix")]
     pub nlri: NLRI
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [process_config]
+        my_as = 2
+        router_id = "1.1.1.1"
+        next_hop_ip = "10.0.0.1"
+        default_local_preference = 100
+        default_med = 0
+        [process_config.capabilities_config]
+        route_refresh_prestandard = false
+        route_refresh = false
+        enhanced_route_refresh = false
+        extended_4byte_asn = true
+        [process_config.capabilities_config.multi_protocol_extensions_config]
+        ipv4_unicast = true
+        ipv4_multicast = false
+        ipv4_vpn = false
+        ipv6_unicast = false
+        ipv6_multicast = false
+        ipv6_vpn = false
+
+        [[peer_groups_config]]
+        name = "ixp"
+        as_num = 65000
+        hello_time = 10
+        hold_time = 30
+        next_hop_self = true
+        soft_reconfiguration_inbound = true
+
+        [[neighbors_config]]
+        ip = "10.0.0.24"
+        peer_group = "ixp"
+        hold_time = 90
+
+        [[neighbors_config]]
+        ip = "10.0.0.25"
+        peer_group = "missing"
+
+        [[net_advertisements_config]]
+        prefix = "1.1.1.1/32"
+    "#;
+
+    #[test]
+    fn test_neighbor_inherits_from_peer_group_and_overrides() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let default_capabilities = config.process_config.capabilities_config;
+        let enc = config.neighbors_config[0].resolve(&config.peer_groups_config, default_capabilities, &Policies::new(), &Plugins::new(), &Scripts::new()).unwrap();
+        assert_eq!(enc.as_num, 65000);
+        assert_eq!(enc.settings.hello_time, 10);
+        assert_eq!(enc.settings.hold_time, 90);
+        assert!(enc.settings.next_hop_self);
+        assert!(enc.settings.soft_reconfiguration_inbound);
+        assert!(enc.settings.passive);
+        assert_eq!(enc.settings.capabilities_config, default_capabilities);
+    }
+
+    #[test]
+    fn test_neighbor_with_unknown_peer_group() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let default_capabilities = config.process_config.capabilities_config;
+        let res = config.neighbors_config[1].resolve(&config.peer_groups_config, default_capabilities, &Policies::new(), &Plugins::new(), &Scripts::new());
+        assert_eq!(res, Err(ConfigError::PeerGroupNotFound));
+    }
+
+    #[test]
+    fn test_rpki_import_policy_keeps_routes_as_received() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let default_capabilities = config.process_config.capabilities_config;
+        let policies = crate::policy::compile_candidate_policies(r#"
+            [[policies_config]]
+            name = "drop-invalid"
+            [[policies_config.terms]]
+            name = "invalid"
+            match = { rpki = "invalid" }
+            action = "reject"
+        "#).unwrap();
+        let neighbor_config = NeighborConfig {
+            import_policy: Some("drop-invalid".to_string()),
+            soft_reconfiguration_inbound: Some(false),
+            ..config.neighbors_config[0].clone()
+        };
+        let enc = neighbor_config.resolve(&config.peer_groups_config, default_capabilities, &policies, &Plugins::new(), &Scripts::new()).unwrap();
+        assert!(!enc.settings.soft_reconfiguration_inbound);
+        assert!(enc.settings.keeps_routes_as_received());
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::str::FromStr;
+
+use toml::Spanned;
+use toml::de::{DeTable, DeValue, ValueDeserializer};
+
+use crate::config::*;
+use crate::errors::ConfigError;
+use crate::policy::{self, Policies};
+use crate::plugins::{self, Plugins};
+use crate::scripting::{self, Scripts};
+use crate::routes::NLRI;
+
+// one thing wrong with the config, with where it is in the file so the user doesn't have to go looking
+#[derive(Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub path: String,
+    // 1 based line and column
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((line, col)) = self.location {
+            write!(f, "line {}, column {}: ", line, col)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum PathSegment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+struct Validator<'a> {
+    toml_content: &'a str,
+    document: Option<DeTable<'a>>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<'a> Validator<'a> {
+    fn location_from_span(&self, span: Range<usize>) -> (usize, usize) {
+        let before = &self.toml_content[..span.start.min(self.toml_content.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        (line, col)
+    }
+
+    fn span_of(&self, path: &[PathSegment]) -> Option<Range<usize>> {
+        // goes as deep as it can, a field that isn't set (e.g. it comes from the peer group) points at its table instead
+        let document = self.document.as_ref()?;
+        let mut span = None;
+        let mut current: Option<&DeValue> = None;
+        for segment in path {
+            let next = match (segment, current) {
+                (PathSegment::Key(key), None) => document.get(*key),
+                (PathSegment::Key(key), Some(DeValue::Table(table))) => table.get(*key),
+                (PathSegment::Index(idx), Some(DeValue::Array(array))) => array.get(*idx),
+                _ => None,
+            };
+            match next {
+                Some(value) => {
+                    span = Some(value.span());
+                    current = Some(value.get_ref());
+                },
+                None => break,
+            }
+        }
+        span
+    }
+
+    fn report(&mut self, path: &[PathSegment], message: String) {
+        let location = self.span_of(path).map(|span| self.location_from_span(span));
+        let path = path.iter().fold(String::new(), |mut acc, segment| {
+            match segment {
+                PathSegment::Key(key) => {
+                    if !acc.is_empty() {
+                        acc.push('.');
+                    }
+                    acc.push_str(key);
+                },
+                PathSegment::Index(idx) => acc.push_str(&format!("[{}]", idx)),
+            }
+            acc
+        });
+        self.problems.push(ConfigProblem { path, location, message });
+    }
+
+    // serde stops at the first bad field, so every section and every entry of the lists is deserialized on its own to
+    // find all of them
+    fn schema_problems(&self) -> Vec<ConfigProblem> {
+        let Some(document) = &self.document else {
+            return Vec::new()
+        };
+        let mut problems: Vec<ConfigProblem> = ["process_config", "neighbors_config", "net_advertisements_config"].iter()
+            .filter(|key| document.get(**key).is_none())
+            .map(|key| ConfigProblem { path: String::new(), location: None, message: format!("missing field `{}`", key) })
+            .collect();
+        for (key, value) in document.iter() {
+            let key: &str = key.get_ref();
+            let problem = match key {
+                "process_config" => self.schema_problem::<ProcessConfig>(key.to_string(), value),
+                "rpki_config" => self.schema_problem::<RpkiConfig>(key.to_string(), value),
+                "neighbors_config" => self.list_schema_problem::<NeighborConfig>(key, value),
+                "net_advertisements_config" => self.list_schema_problem::<NetAdvertisementsConfig>(key, value),
+                "listen_ranges_config" => self.list_schema_problem::<ListenRangeConfig>(key, value),
+                "peer_groups_config" => self.list_schema_problem::<PeerGroupConfig>(key, value),
+                "bmp_servers_config" => self.list_schema_problem::<BmpServerConfig>(key, value),
+                "mrt_replays_config" => self.list_schema_problem::<MrtReplayConfig>(key, value),
+                "prefix_lists_config" => self.list_schema_problem::<PrefixListConfig>(key, value),
+                "as_path_lists_config" => self.list_schema_problem::<AsPathListConfig>(key, value),
+                "policies_config" => self.list_schema_problem::<PolicyConfig>(key, value),
+                _ => vec![ConfigProblem { path: key.to_string(), location: Some(self.location_from_span(value.span())),
+                                          message: format!("unknown field `{}`", key) }],
+            };
+            problems.extend(problem);
+        }
+        // in the order they're in the file
+        problems.sort_by_key(|problem| problem.location);
+        problems
+    }
+
+    fn schema_problem<T: serde::de::DeserializeOwned>(&self, path: String, value: &Spanned<DeValue<'a>>) -> Vec<ConfigProblem> {
+        match T::deserialize(ValueDeserializer::from(value.clone())) {
+            Ok(_) => Vec::new(),
+            Err(e) => vec![ConfigProblem { path, location: e.span().map(|span| self.location_from_span(span)), message: e.message().to_string() }],
+        }
+    }
+
+    fn list_schema_problem<T: serde::de::DeserializeOwned>(&self, key: &str, value: &Spanned<DeValue<'a>>) -> Vec<ConfigProblem> {
+        match value.get_ref() {
+            DeValue::Array(entries) => entries.iter().enumerate()
+                .flat_map(|(idx, entry)| self.schema_problem::<T>(format!("{}[{}]", key, idx), entry))
+                .collect(),
+            _ => self.schema_problem::<Vec<T>>(key.to_string(), value),
+        }
+    }
+
+    fn check_timers(&mut self, table: &str, idx: usize, hello_time: Option<u16>, hold_time: Option<u16>) {
+        if let Some(hold_time) = hold_time && (hold_time == 1 || hold_time == 2) {
+            self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("hold_time")],
+                        format!("hold_time {} is not allowed, it has to be 0 or at least 3", hold_time));
+        }
+        if let Some(hello_time) = hello_time && hello_time < 1 {
+            self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("hello_time")],
+                        "hello_time has to be at least 1".to_string());
+        }
+        if let (Some(hello_time), Some(hold_time)) = (hello_time, hold_time) && hold_time != 0 && hello_time >= hold_time {
+            self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("hello_time")],
+                        format!("hello_time {} has to be less than hold_time {}", hello_time, hold_time));
+        }
+    }
+
+    fn check_prefix(&mut self, table: &str, idx: usize, nlri: &NLRI) {
+        let mask = u32::MAX << (32 - nlri.len as u32);
+        if nlri.prefix.to_bits() & !mask != 0 {
+            self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("prefix")],
+                        format!("{}/{} has host bits set, did you mean {}/{}?", nlri.prefix, nlri.len, Ipv4Addr::from_bits(nlri.prefix.to_bits() & mask), nlri.len));
+        }
+    }
+
+    // hands back what it compiled, so the process uses exactly what was checked
+    fn check_config(&mut self, config: &Config, previous_plugins: &Plugins, previous_scripts: &Scripts) -> (Policies, Plugins, Scripts) {
+        let process_config = &config.process_config;
+        if Ipv4Addr::from_str(&process_config.router_id).is_err() {
+            self.report(&[PathSegment::Key("process_config"), PathSegment::Key("router_id")],
+                        format!("router_id {} is not an IPv4 address", process_config.router_id));
+        }
+        if Ipv4Addr::from_str(&process_config.next_hop_ip).is_err() {
+            self.report(&[PathSegment::Key("process_config"), PathSegment::Key("next_hop_ip")],
+                        format!("next_hop_ip {} is not an IPv4 address", process_config.next_hop_ip));
+        }
+
+        let mut peer_group_names = HashSet::new();
+        for (idx, pg) in config.peer_groups_config.iter().enumerate() {
+            if !peer_group_names.insert(pg.name.as_str()) {
+                self.report(&[PathSegment::Key("peer_groups_config"), PathSegment::Index(idx), PathSegment::Key("name")],
+                            format!("peer group {} is configured more than once", pg.name));
+            }
+            self.check_timers("peer_groups_config", idx, pg.hello_time, pg.hold_time);
+        }
+
+        let (policies, policy_problems) = policy::compile_policies(&config.prefix_lists_config, &config.as_path_lists_config, &config.policies_config);
+        for problem in policy_problems {
+            self.report(&problem.path, problem.message);
+        }
+        let (plugins, plugin_problems) = plugins::compile_plugins(config, previous_plugins);
+        for problem in plugin_problems {
+            self.report(&problem.path, problem.message);
+        }
+        let (scripts, script_problems) = scripting::compile_scripts(config, previous_scripts);
+        for problem in script_problems {
+            self.report(&problem.path, problem.message);
+        }
+
+        let default_capabilities = process_config.capabilities_config;
+        let mut neighbor_ips = HashSet::new();
+        for (idx, nc) in config.neighbors_config.iter().enumerate() {
+            match Ipv4Addr::from_str(&nc.ip) {
+                Ok(ip) => {
+                    if !neighbor_ips.insert(ip) {
+                        self.report(&[PathSegment::Key("neighbors_config"), PathSegment::Index(idx), PathSegment::Key("ip")],
+                                    format!("neighbor {} is configured more than once", ip));
+                    }
+                },
+                Err(_) => {
+                    self.report(&[PathSegment::Key("neighbors_config"), PathSegment::Index(idx), PathSegment::Key("ip")],
+                                format!("{} is not an IPv4 address", nc.ip));
+                    continue;
+                }
+            }
+            match nc.resolve(&config.peer_groups_config, default_capabilities, &policies, &plugins, &scripts) {
+                Ok(enc) => self.check_timers("neighbors_config", idx, Some(enc.settings.hello_time), Some(enc.settings.hold_time)),
+                Err(e) => self.report_resolve_error("neighbors_config", idx, e),
+            }
+        }
+
+        for (idx, lr) in config.listen_ranges_config.iter().enumerate() {
+            self.check_prefix("listen_ranges_config", idx, &lr.nlri);
+            match lr.resolve(&config.peer_groups_config, default_capabilities, &policies, &plugins, &scripts) {
+                Ok(elr) => {
+                    self.check_timers("listen_ranges_config", idx, Some(elr.settings.hello_time), Some(elr.settings.hold_time));
+                    if elr.min_as > elr.max_as {
+                        self.report(&[PathSegment::Key("listen_ranges_config"), PathSegment::Index(idx), PathSegment::Key("min_as")],
+                                    format!("min_as {} is greater than max_as {}", elr.min_as, elr.max_as));
+                    }
+                },
+                Err(e) => self.report_resolve_error("listen_ranges_config", idx, e),
+            }
+        }
+
+        for (idx, replay) in config.mrt_replays_config.iter().enumerate() {
+            if let Err(e) = replay.resolve(&config.peer_groups_config, default_capabilities, &policies, &plugins, &scripts) {
+                self.report_resolve_error("mrt_replays_config", idx, e);
+            }
+        }
+
+        let mut networks = HashSet::new();
+        for (idx, net) in config.net_advertisements_config.iter().enumerate() {
+            self.check_prefix("net_advertisements_config", idx, &net.nlri);
+            if !networks.insert(&net.nlri) {
+                self.report(&[PathSegment::Key("net_advertisements_config"), PathSegment::Index(idx), PathSegment::Key("prefix")],
+                            format!("{}/{} is advertised more than once", net.nlri.prefix, net.nlri.len));
+            }
+        }
+        (policies, plugins, scripts)
+    }
+
+    fn report_resolve_error(&mut self, table: &str, idx: usize, e: ConfigError) {
+        match e {
+            ConfigError::PeerGroupNotFound => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("peer_group")],
+                            "peer_group is not configured in peer_groups_config".to_string());
+            },
+            ConfigError::MissingNeighborAS => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("as_num")],
+                            "as_num is missing and the peer group doesn't set one".to_string());
+            },
+            ConfigError::MissingNeighborTimers => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx)],
+                            "hello_time and hold_time have to be set here or in the peer group".to_string());
+            },
+            ConfigError::ImportPolicyNotFound => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("import_policy")],
+                            "import_policy is not a working policy in policies_config".to_string());
+            },
+            ConfigError::ExportPolicyNotFound => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key("export_policy")],
+                            "export_policy is not a working policy in policies_config".to_string());
+            },
+            // compile_plugins/compile_scripts already said why, at the neighbor or peer group that names them
+            ConfigError::ImportPluginNotFound | ConfigError::ExportPluginNotFound
+            | ConfigError::ImportScriptNotFound | ConfigError::ExportScriptNotFound => {},
+            e => {
+                self.report(&[PathSegment::Key(table), PathSegment::Index(idx)], format!("{:?}", e));
+            }
+        }
+    }
+}
+
+// runs the TOML parse and every semantic check we have, collecting all the problems instead of stopping at the first one.
+// plugins and scripts unchanged since `previous_plugins`/`previous_scripts` were loaded are reused
+pub fn validate_config(toml_content: &str, previous_plugins: &Plugins, previous_scripts: &Scripts) -> Result<LoadedConfig, Vec<ConfigProblem>> {
+    let mut validator = Validator {
+        toml_content,
+        document: None,
+        problems: Vec::new(),
+    };
+    match DeTable::parse(toml_content) {
+        Ok(document) => validator.document = Some(document.into_inner()),
+        Err(e) => {
+            let location = e.span().map(|span| validator.location_from_span(span));
+            return Err(vec![ConfigProblem { path: String::new(), location, message: e.message().to_string() }]);
+        }
+    }
+
+    let config: Config = match toml::from_str(toml_content) {
+        Ok(config) => config,
+        Err(e) => {
+            let mut problems = validator.schema_problems();
+            if problems.is_empty() {
+                let location = e.span().map(|span| validator.location_from_span(span));
+                problems.push(ConfigProblem { path: String::new(), location, message: e.message().to_string() });
+            }
+            return Err(problems);
+        }
+    };
+
+    let (policies, plugins, scripts) = validator.check_config(&config, previous_plugins, previous_scripts);
+    if validator.problems.is_empty() {
+        Ok(LoadedConfig { config, policies, plugins, scripts })
+    } else {
+        Err(validator.problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROCESS_CONFIG: &str = r#"
+[process_config]
+my_as = 2
+router_id = "1.1.1.1"
+next_hop_ip = "10.0.0.1"
+default_local_preference = 100
+default_med = 0
+[process_config.capabilities_config]
+route_refresh_prestandard = false
+route_refresh = false
+enhanced_route_refresh = false
+extended_4byte_asn = true
+[process_config.capabilities_config.multi_protocol_extensions_config]
+ipv4_unicast = true
+ipv4_multicast = false
+ipv4_vpn = false
+ipv6_unicast = false
+ipv6_multicast = false
+ipv6_vpn = false
+
+[[net_advertisements_config]]
+prefix = "1.1.1.1/32"
+"#;
+
+    #[test]
+    fn test_validate_config_reports_every_problem_with_location() {
+        let toml_content = format!("{}{}", PROCESS_CONFIG, r#"
+[[neighbors_config]]
+ip = "10.0.0.24"
+as_num = 1
+hello_time = 1
+hold_time = 2
+
+[[neighbors_config]]
+ip = "10.0.0.24"
+as_num = 1
+hello_time = 30
+hold_time = 30
+"#);
+        let problems = validate_config(&toml_content, &Plugins::new(), &Scripts::new()).unwrap_err();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0].path, "neighbors_config[0].hold_time");
+        assert_eq!(problems[0].location, Some((28, 13)));
+        assert_eq!(problems[1].path, "neighbors_config[1].ip");
+        assert_eq!(problems[2].path, "neighbors_config[1].hello_time");
+    }
+
+    #[test]
+    fn test_validate_config_rejects_unknown_keys() {
+        let toml_content = format!("{}{}", PROCESS_CONFIG, r#"
+[[neighbors_config]]
+ip = "10.0.0.24"
+as_num = 1
+hello_time = 30
+hold_tiem = 90
+"#);
+        let problems = validate_config(&toml_content, &Plugins::new(), &Scripts::new()).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("hold_tiem"));
+        assert_eq!(problems[0].location.map(|(line, _)| line), Some(28));
+    }
+
+    #[test]
+    fn test_validate_config_reports_every_schema_problem() {
+        let toml_content = format!("{}{}", PROCESS_CONFIG, r#"
+[[neighbors_config]]
+ip = "10.0.0.24"
+as_num = "one"
+hello_time = 30
+hold_time = 90
+
+[[neighbors_config]]
+ip = "10.0.0.25"
+as_num = 1
+hello_time = 30
+hold_tiem = 90
+
+[[policies_config]]
+name = "from-ixp"
+default_action = "maybe"
+
+[bogus]
+"#);
+        let problems = validate_config(&toml_content, &Plugins::new(), &Scripts::new()).unwrap_err();
+        let paths: Vec<&str> = problems.iter().map(|problem| problem.path.as_str()).collect();
+        assert_eq!(paths, ["neighbors_config[0]", "neighbors_config[1]", "policies_config[0]", "bogus"]);
+        assert_eq!(problems[0].location.map(|(line, _)| line), Some(26));
+        assert!(problems[1].message.contains("hold_tiem"));
+        assert!(problems[2].message.contains("maybe"));
+    }
+}
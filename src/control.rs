@@ -0,0 +1,527 @@
+use tracing::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio::sync::mpsc::Sender;
+use crate::channels::{ChannelWatcherMessage, NeighborChannel};
+use crate::errors::*;
+use crate::messages::optional_parameters::Capability;
+use crate::config::PolicyAction;
+use crate::messages::update::{AsPath, AsPathSegment, AsPathSegmentType, NextHop, LocalPref, MultiExitDisc, Origin, OriginType, AS};
+use crate::mrt::table_dump;
+use crate::neighbors::{self, Neighbor, PeerType};
+use crate::policy::{self, PolicyContext};
+use crate::process::{BGPProcess, GlobalSettings, ProcessCommand};
+use crate::routes::{RouteV4, NLRI};
+use crate::rpki::RpkiState;
+
+pub mod protocol;
+
+use protocol::*;
+
+// accepts bgpctl connections and hands every request to the process loop
+pub async fn run_control_socket_loop(listener: UnixListener, tx_process_command: Sender<ProcessCommand>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let tx_process_command = tx_process_command.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_control_connection(stream, tx_process_command).await {
+                        debug!("Control connection closed - {}", e);
+                    }
+                });
+            },
+            Err(e) => {
+                error!("Unable to accept control connection - {}", e);
+            }
+        }
+    }
+}
+
+pub async fn bind_control_socket(path: &Path) -> Result<UnixListener, ProcessError> {
+    if path.exists() {
+        // a socket nobody answers on is left over from an instance that didn't shut down cleanly
+        if UnixStream::connect(path).await.is_ok() {
+            error!("Control socket {} is in use by another instance", path.display());
+            return Err(ProcessError::UnableToBindControlSocket);
+        }
+        warn!("Removing stale control socket {}", path.display());
+        std::fs::remove_file(path).map_err(|_| ProcessError::UnableToBindControlSocket)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| {
+        error!("Unable to bind control socket {} - {}", path.display(), e);
+        ProcessError::UnableToBindControlSocket
+    })?;
+    // whoever can talk to the socket can shut down neighbors, keep it to the owner
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).map_err(|_| ProcessError::UnableToBindControlSocket)?;
+    info!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+// one JSON request per line, answered with one JSON response per line
+async fn handle_control_connection(stream: UnixStream, tx_process_command: Sender<ProcessCommand>) -> std::io::Result<()> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                debug!("Control request {:?}", request);
+                let (tx_response, rx_response) = oneshot::channel();
+                if tx_process_command.send(ProcessCommand::Control(request, tx_response)).await.is_err() {
+                    ControlResponse::Error("BGP process is shutting down".to_string())
+                } else {
+                    rx_response.await.unwrap_or_else(|_| ControlResponse::Error("BGP process dropped the request".to_string()))
+                }
+            },
+            Err(e) => ControlResponse::Error(format!("Bad request - {}", e)),
+        };
+        let mut bytes = serde_json::to_vec(&response).map_err(std::io::Error::other)?;
+        bytes.push(b'\n');
+        write_half.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+// runs inside the process loop, so it sees the same neighbors and RIB the loop does
+pub async fn handle_control_request(request: ControlRequest, bgp_proc_arc: &Arc<Mutex<BGPProcess>>,
+                                    all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>,
+                                    running_neighbors: &mut HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>,
+                                    all_neighbors_channels_arc: &Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
+                                    tx_channel_watcher: &Sender<ChannelWatcherMessage>) -> ControlResponse {
+    // dynamic neighbors that went away only leave our clone of their Arc behind
+    running_neighbors.retain(|_, neighbor_arc| Arc::strong_count(neighbor_arc) > 1);
+    match request {
+        ControlRequest::ShowGlobalSettings => {
+            let bgp_proc = bgp_proc_arc.lock().await;
+            ControlResponse::GlobalSettings(global_settings_view(&bgp_proc.global_settings))
+        },
+        ControlRequest::ShowNeighbors => {
+            let mut summaries: Vec<NeighborSummary> = all_neighbors.values().map(neighbor_summary).collect();
+            for neighbor_arc in running_neighbors.values() {
+                summaries.push(neighbor_summary(&*neighbor_arc.lock().await));
+            }
+            summaries.sort_by_key(|s| s.ip);
+            ControlResponse::Neighbors(summaries)
+        },
+        ControlRequest::ShowSummary => {
+            let mut summary = neighbors::summary_header();
+            let mut running_guards = Vec::new();
+            for neighbor_arc in running_neighbors.values() {
+                running_guards.push(neighbor_arc.lock().await);
+            }
+            let mut neighbors: Vec<&Neighbor> = all_neighbors.values().chain(running_guards.iter().map(|guard| &**guard)).collect();
+            neighbors.sort_by_key(|n| n.ip);
+            for neighbor in neighbors {
+                summary.push('\n');
+                summary.push_str(&neighbor.to_string());
+            }
+            ControlResponse::Summary(summary)
+        },
+        ControlRequest::ShowNeighbor { ip } => {
+            with_neighbor(ip, all_neighbors, running_neighbors, |n| ControlResponse::Neighbor(Box::new(neighbor_detail(n)))).await
+        },
+        ControlRequest::ShowRib { prefix } => {
+            let bgp_proc = bgp_proc_arc.lock().await;
+            match prefix {
+                None => {
+                    let mut routes = global_rib_entries(&bgp_proc.local_rib, bgp_proc.adj_rib_in.values().flatten());
+                    sort_routes(&mut routes);
+                    ControlResponse::Routes(routes)
+                },
+                Some(prefix) => match lookup_rib(&bgp_proc.adj_rib_in, &prefix) {
+                    Ok(routes) => ControlResponse::Routes(global_rib_entries(&bgp_proc.local_rib, routes.iter())),
+                    Err(e) => ControlResponse::Error(e),
+                },
+            }
+        },
+        ControlRequest::ShowAdjRibIn { peer, received } => {
+            with_neighbor(peer, all_neighbors, running_neighbors, |n| match (received, &n.adj_rib_in_pre_policy) {
+                (false, _) => rib_response(n.adj_rib_in.values()),
+                (true, Some(adj_rib_in_pre_policy)) => rib_response(adj_rib_in_pre_policy.values()),
+                (true, None) => ControlResponse::Error(format!("Neighbor {} doesn't keep the routes it sent, turn on soft_reconfiguration_inbound", peer)),
+            }).await
+        },
+        ControlRequest::ShowAdjRibOut { peer } => {
+            with_neighbor(peer, all_neighbors, running_neighbors, |n| rib_response(n.adj_rib_out.values())).await
+        },
+        ControlRequest::ClearNeighbor { ip, soft } => {
+            let Some(neighbor_arc) = running_neighbors.get(&ip) else {
+                return match all_neighbors.contains_key(&ip) {
+                    true => ControlResponse::Error(format!("Neighbor {} has no session to clear", ip)),
+                    false => ControlResponse::Error(format!("No neighbor {}", ip)),
+                }
+            };
+            let mut neighbor = neighbor_arc.lock().await;
+            let result = match soft {
+                None => {
+                    neighbor.reset_session();
+                    Ok(())
+                },
+                Some(SoftDirection::In) => neighbor.soft_reset_in().await,
+                Some(SoftDirection::Out) => neighbor.resend_adj_rib_out(),
+            };
+            match result {
+                Ok(_) => {
+                    info!("Cleared neighbor {} ({:?})", ip, soft);
+                    ControlResponse::Done(format!("Cleared neighbor {}", ip))
+                },
+                Err(e) => ControlResponse::Error(format!("Unable to clear neighbor {} - {:?}", ip, e)),
+            }
+        },
+        ControlRequest::NeighborShutdown { ip } => {
+            if with_neighbor_mut(ip, all_neighbors, running_neighbors, |n| n.shutdown()).await.is_none() {
+                return ControlResponse::Error(format!("No neighbor {}", ip));
+            }
+            info!("Neighbor {} shut down from the control socket", ip);
+            ControlResponse::Done(format!("Shut down neighbor {}", ip))
+        },
+        ControlRequest::NeighborEnable { ip } => {
+            match with_neighbor_mut(ip, all_neighbors, running_neighbors, |n| n.enable()).await {
+                None => return ControlResponse::Error(format!("No neighbor {}", ip)),
+                Some(false) => return ControlResponse::Error(format!("Neighbor {} isn't shut down, nothing to enable", ip)),
+                Some(true) => {},
+            }
+            info!("Neighbor {} enabled from the control socket", ip);
+            ControlResponse::Done(format!("Enabled neighbor {}", ip))
+        },
+        ControlRequest::DumpRib => {
+            let Some(mrt_dump_config) = bgp_proc_arc.lock().await.process_config.mrt_dump.clone() else {
+                return ControlResponse::Error("MRT dumps aren't configured, add [process_config.mrt_dump]".to_string());
+            };
+            let snapshot = table_dump::take_snapshot(bgp_proc_arc, all_neighbors, running_neighbors).await;
+            let directory = mrt_dump_config.directory.display().to_string();
+            table_dump::spawn_table_dump(mrt_dump_config, snapshot);
+            ControlResponse::Done(format!("Writing the rib and loc-rib dumps to {}, the file names and any error go to the log", directory))
+        },
+        ControlRequest::Announce { prefix } => {
+            let nlri = match NLRI::from_str(&prefix) {
+                Ok(nlri) => nlri,
+                Err(e) => return ControlResponse::Error(e),
+            };
+            let all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+            let mut bgp_proc = bgp_proc_arc.lock().await;
+            if bgp_proc.is_local_network(&nlri) {
+                return ControlResponse::Error(format!("{} is already announced", prefix));
+            }
+            info!("Announcing {:?} from the control socket", nlri);
+            bgp_proc.announced_networks.push(nlri.clone());
+            bgp_proc.announce_local_networks(&all_neighbors_channels, vec![nlri]).await;
+            ControlResponse::Done(format!("Announced {}", prefix))
+        },
+        ControlRequest::Withdraw { prefix } => {
+            let nlri = match NLRI::from_str(&prefix) {
+                Ok(nlri) => nlri,
+                Err(e) => return ControlResponse::Error(e),
+            };
+            let all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+            let mut bgp_proc = bgp_proc_arc.lock().await;
+            if !bgp_proc.is_local_network(&nlri) {
+                return ControlResponse::Error(format!("{} is not announced by us", prefix));
+            }
+            info!("Withdrawing {:?} from the control socket", nlri);
+            // a withdrawn configured network comes back on the next reload if it is still in the config
+            bgp_proc.announced_networks.retain(|n| n != &nlri);
+            bgp_proc.configured_networks.retain(|n| n.nlri != nlri);
+            bgp_proc.withdraw_local_networks(&all_neighbors_channels, vec![nlri]).await;
+            ControlResponse::Done(format!("Withdrew {}", prefix))
+        },
+        ControlRequest::TestPolicy { policy, candidate, neighbor, peer, prefix, route } => {
+            // big lists take a while to compile, nothing has to wait on the process for that
+            let candidate_policies = match candidate.as_deref().map(policy::compile_candidate_policies).transpose() {
+                Ok(candidate_policies) => candidate_policies,
+                Err(e) => return ControlResponse::Error(e),
+            };
+            // the neighbors before the process, nothing may hold the process lock while it waits for a neighbor.
+            // an import policy has to see the routes as they came in, not what the running one made of them
+            let mut peer_ases: HashMap<Ipv4Addr, u32> = all_neighbors.values().map(|n| (n.ip, as_to_u32(&n.as_num))).collect();
+            let mut as_received: HashMap<NLRI, Vec<RouteV4>> = HashMap::new();
+            let mut not_kept = Vec::new();
+            for neighbor_arc in running_neighbors.values() {
+                let neighbor = neighbor_arc.lock().await;
+                peer_ases.insert(neighbor.ip, as_to_u32(&neighbor.as_num));
+                if route.is_some() || peer.is_some_and(|peer| peer != neighbor.ip) {
+                    continue;
+                }
+                match neighbor.adj_rib_in_as_received() {
+                    Some(adj_rib_in) => for received in adj_rib_in.values() {
+                        let mut received = received.clone();
+                        received.learned_from = Some(neighbor.ip);
+                        // what the neighbor validates it against before its import policy
+                        received.rpki_state = neighbor.global_settings.vrps.validate_route(&received, neighbor.global_settings.my_as);
+                        as_received.entry(received.nlri.clone()).or_default().push(received);
+                    },
+                    None if !neighbor.adj_rib_in.is_empty() => not_kept.push(neighbor.ip.to_string()),
+                    None => {},
+                }
+            }
+            if !not_kept.is_empty() {
+                not_kept.sort();
+                return ControlResponse::Error(format!("The routes from {} went through import filters and weren't kept as received, \
+                    turn on soft_reconfiguration_inbound or test against another peer", not_kept.join(", ")));
+            }
+            let bgp_proc = bgp_proc_arc.lock().await;
+            let Some(policy_arc) = candidate_policies.as_ref().unwrap_or(&bgp_proc.policies).get(&policy).cloned() else {
+                return ControlResponse::Error(format!("No policy {}", policy));
+            };
+            let (vrps, my_as) = (&bgp_proc.global_settings.vrps, bgp_proc.global_settings.my_as);
+            let routes: Vec<RouteV4> = match (route, prefix) {
+                (Some(entry), _) => match entry_route(&entry) {
+                    Ok(mut route) if entry.rpki.is_none() => {
+                        route.rpki_state = vrps.validate_route(&route, my_as);
+                        vec![route]
+                    },
+                    Ok(route) => vec![route],
+                    Err(e) => return ControlResponse::Error(e),
+                },
+                (None, Some(prefix)) => match lookup_rib(&as_received, &prefix) {
+                    Ok(routes) => routes.clone(),
+                    Err(e) => return ControlResponse::Error(e),
+                },
+                (None, None) => as_received.into_values().flatten().collect(),
+            };
+            // the dry run only needs the routes we already copied
+            drop(bgp_proc);
+            let context = |route: &RouteV4| {
+                let neighbor = neighbor.or(route.learned_from).unwrap_or(Ipv4Addr::UNSPECIFIED);
+                PolicyContext { neighbor, peer_as: peer_ases.get(&neighbor).copied().unwrap_or(0), my_as, import: true }
+            };
+            let routes = routes.iter().filter(|route| peer.is_none() || route.learned_from == peer);
+            let mut entries: Vec<PolicyTestEntry> = policy_arc.dry_run(routes, context).into_iter().map(|dry_run| PolicyTestEntry {
+                action: match dry_run.result.action {
+                    PolicyAction::Accept => "accept".to_string(),
+                    PolicyAction::Reject => "reject".to_string(),
+                },
+                term: dry_run.result.term,
+                before: route_entry(&dry_run.before),
+                after: route_entry(&dry_run.result.route),
+            }).collect();
+            entries.sort_by_key(|entry| (prefix_key(&entry.before.prefix), entry.before.learned_from));
+            ControlResponse::PolicyTest(entries)
+        },
+        ControlRequest::ShowRpki => {
+            let bgp_proc = bgp_proc_arc.lock().await;
+            let vrps = &bgp_proc.global_settings.vrps;
+            let caches = bgp_proc.rpki_config.caches.iter().map(|cache| {
+                let address = SocketAddr::new(cache.address, cache.port);
+                let status = vrps.status(address);
+                RpkiCacheView {
+                    address: address.to_string(),
+                    connected: status.connected,
+                    version: status.version,
+                    session_id: status.session_id,
+                    serial: status.serial,
+                    vrps: status.vrps,
+                    last_update_secs: status.last_update.and_then(|t| t.elapsed().ok()).map(|d| d.as_secs()),
+                }
+            }).collect();
+            let count = |rpki_state: RpkiState| bgp_proc.adj_rib_in.values().flatten().filter(|route| route.rpki_state == rpki_state).count();
+            ControlResponse::Rpki(RpkiView {
+                caches,
+                vrps: vrps.count(),
+                reject_invalid: bgp_proc.rpki_config.reject_invalid,
+                prefer_valid: bgp_proc.rpki_config.prefer_valid,
+                valid: count(RpkiState::Valid),
+                invalid: count(RpkiState::Invalid),
+                not_found: count(RpkiState::NotFound),
+            })
+        },
+        ControlRequest::Reload => {
+            match BGPProcess::reload_config(bgp_proc_arc, all_neighbors, all_neighbors_channels_arc, tx_channel_watcher).await {
+                Ok(_) => ControlResponse::Done("Reloaded config".to_string()),
+                Err(e) => ControlResponse::Error(format!("Unable to reload config, keeping the running config - {:?}, the daemon log has the details", e)),
+            }
+        },
+    }
+}
+
+async fn with_neighbor<F>(ip: Ipv4Addr, all_neighbors: &HashMap<Ipv4Addr, Neighbor>,
+                          running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>, f: F) -> ControlResponse
+    where F: FnOnce(&Neighbor) -> ControlResponse {
+    if let Some(neighbor) = all_neighbors.get(&ip) {
+        return f(neighbor);
+    }
+    match running_neighbors.get(&ip) {
+        Some(neighbor_arc) => f(&*neighbor_arc.lock().await),
+        None => ControlResponse::Error(format!("No neighbor {}", ip)),
+    }
+}
+
+// false if there's no such neighbor
+// None when there's no such neighbor
+async fn with_neighbor_mut<F, T>(ip: Ipv4Addr, all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>,
+                                 running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>, f: F) -> Option<T>
+    where F: FnOnce(&mut Neighbor) -> T {
+    if let Some(neighbor) = all_neighbors.get_mut(&ip) {
+        Some(f(neighbor))
+    } else if let Some(neighbor_arc) = running_neighbors.get(&ip) {
+        Some(f(&mut *neighbor_arc.lock().await))
+    } else {
+        None
+    }
+}
+
+// "10.0.0.0/8" is an exact lookup, "10.1.2.3" is the longest prefix containing it
+fn lookup_rib<'a>(rib: &'a HashMap<NLRI, Vec<RouteV4>>, prefix: &str) -> Result<&'a Vec<RouteV4>, String> {
+    let not_found = || format!("No route for {}", prefix);
+    if prefix.contains('/') {
+        let nlri = NLRI::from_str(prefix)?;
+        return rib.get(&nlri).ok_or_else(not_found);
+    }
+    let ip = Ipv4Addr::from_str(prefix).map_err(|_| format!("{} is not a prefix or an address", prefix))?;
+    rib.iter()
+        .filter(|(nlri, _)| nlri.contains(ip))
+        .max_by_key(|(nlri, _)| nlri.len)
+        .map(|(_, routes)| routes)
+        .ok_or_else(not_found)
+}
+
+fn rib_response<'a>(routes: impl Iterator<Item = &'a RouteV4>) -> ControlResponse {
+    let mut routes: Vec<RouteEntry> = routes.map(route_entry).collect();
+    sort_routes(&mut routes);
+    ControlResponse::Routes(routes)
+}
+
+fn sort_routes(routes: &mut [RouteEntry]) {
+    routes.sort_by_key(|r| prefix_key(&r.prefix));
+}
+
+fn prefix_key(prefix: &str) -> (Option<Ipv4Addr>, Option<u8>) {
+    let (prefix, len) = prefix.split_once('/').unwrap_or((prefix, "0"));
+    (Ipv4Addr::from_str(prefix).ok(), len.parse::<u8>().ok())
+}
+
+pub fn as_to_u32(as_num: &AS) -> u32 {
+    match as_num {
+        AS::AS2(as_num) => *as_num as u32,
+        AS::AS4(as_num) => *as_num,
+    }
+}
+
+// every path we have, with the one in the local RIB marked best
+fn global_rib_entries<'a>(local_rib: &HashMap<NLRI, Vec<RouteV4>>, routes: impl Iterator<Item = &'a RouteV4>) -> Vec<RouteEntry> {
+    routes.map(|route| RouteEntry {
+        best: local_rib.get(&route.nlri).is_some_and(|best_paths| best_paths.contains(route)),
+        ..route_entry(route)
+    }).collect()
+}
+
+pub fn route_entry(route: &RouteV4) -> RouteEntry {
+    RouteEntry {
+        prefix: format!("{}/{}", route.nlri.prefix, route.nlri.len),
+        next_hop: route.next_hop.ipv4addr(),
+        as_path: route.as_path.as_path_segment.as_list.iter().map(as_to_u32).collect(),
+        origin: format!("{:?}", route.origin.origin_type),
+        local_pref: route.local_pref.as_ref().map(|lp| lp.value),
+        med: route.multi_exit_disc.as_ref().map(|med| med.value),
+        learned_from: route.learned_from,
+        best: false,
+        communities: route.communities.iter().map(|community| policy::format_community(*community)).collect(),
+        rpki: Some(route.rpki_state.name().to_string()),
+    }
+}
+
+// the other way around, for a route written by hand or copied from show rib --json
+fn entry_route(entry: &RouteEntry) -> Result<RouteV4, String> {
+    let origin_type = match entry.origin.to_ascii_lowercase().as_str() {
+        "igp" => OriginType::IGP,
+        "egp" => OriginType::EGP,
+        "incomplete" => OriginType::Incomplete,
+        origin => return Err(format!("{} is not an origin, use IGP, EGP or Incomplete", origin)),
+    };
+    let number_of_as = u8::try_from(entry.as_path.len()).map_err(|_| "as_path can't be longer than 255 ASNs".to_string())?;
+    let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as,
+                                              as_list: entry.as_path.iter().map(|as_num| AS::AS4(*as_num)).collect() });
+    let mut route = RouteV4::new(NLRI::from_str(&entry.prefix)?, Origin::new(origin_type), as_path, NextHop::new(entry.next_hop),
+                                 entry.local_pref.map(LocalPref::new), entry.med.map(MultiExitDisc::new), None, None);
+    route.communities = entry.communities.iter().map(|community| policy::parse_community(community)).collect::<Result<_, _>>()?;
+    route.learned_from = entry.learned_from;
+    if let Some(rpki) = &entry.rpki {
+        route.rpki_state = RpkiState::from_str(rpki)?;
+    }
+    Ok(route)
+}
+
+fn neighbor_summary(neighbor: &Neighbor) -> NeighborSummary {
+    // a dynamic neighbor's AS is a placeholder until its Open is processed
+    let as_num = match neighbor.is_dynamic() && neighbor.negotiated_capabilities.is_none() {
+        true => None,
+        false => Some(as_to_u32(&neighbor.as_num)),
+    };
+    NeighborSummary {
+        ip: neighbor.ip,
+        as_num,
+        state: neighbor.fsm.state.to_string(),
+        description: neighbor.description.clone(),
+        dynamic: neighbor.is_dynamic(),
+        admin_shutdown: neighbor.admin_shutdown,
+        prefixes_received: neighbor.adj_rib_in.len(),
+        prefixes_advertised: neighbor.adj_rib_out.len(),
+    }
+}
+
+fn neighbor_detail(neighbor: &Neighbor) -> NeighborDetail {
+    let negotiated_capabilities = neighbor.negotiated_capabilities.iter().flatten().map(capability_name).collect();
+    let stats = &neighbor.stats;
+    NeighborDetail {
+        summary: neighbor_summary(neighbor),
+        peer_type: match neighbor.peer_type {
+            PeerType::Internal => "internal".to_string(),
+            PeerType::External => "external".to_string(),
+        },
+        hold_time: neighbor.fsm.hold_time,
+        keepalive_time: neighbor.fsm.keepalive_time,
+        connect_retry_time: neighbor.fsm.connect_retry_time,
+        connect_retry_counter: neighbor.fsm.connect_retry_counter,
+        idle_hold_time: neighbor.fsm.idle_hold_time,
+        delay_open_time: neighbor.fsm.delay_open.then_some(neighbor.fsm.delay_open_time),
+        established_secs: neighbor.established_for().map(|t| t.as_secs()),
+        passive: neighbor.fsm.passive_tcp_establishment,
+        next_hop_self: neighbor.next_hop_self,
+        soft_reconfiguration_inbound: neighbor.adj_rib_in_pre_policy.is_some(),
+        damp_peer_oscillations: neighbor.fsm.damp_peer_oscillations,
+        oscillation_count: neighbor.fsm.oscillation_count,
+        negotiated_capabilities,
+        established_since: stats.established_since.filter(|_| neighbor.is_established()).and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+        last_state_change_secs: stats.last_state_change.map(|t| neighbors::stats::elapsed(t).as_secs()),
+        flaps: stats.flaps,
+        messages_received: stats.messages_received.by_type().into_iter().map(|(t, count)| (t.to_string(), count)).collect(),
+        messages_sent: stats.messages_sent.by_type().into_iter().map(|(t, count)| (t.to_string(), count)).collect(),
+        prefixes_announced: stats.prefixes_received,
+        prefixes_withdrawn: stats.withdraws_received,
+        last_notification_received: stats.last_notification_received.as_ref().map(|n| n.to_string()),
+        last_notification_sent: stats.last_notification_sent.as_ref().map(|n| n.to_string()),
+        recent_transitions: stats.transitions.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+fn global_settings_view(global_settings: &GlobalSettings) -> GlobalSettingsView {
+    GlobalSettingsView {
+        my_as: global_settings.my_as,
+        router_id: global_settings.identifier,
+        next_hop: global_settings.next_hop_ip,
+        version: global_settings.version.to_u8(),
+        default_local_preference: global_settings.default_local_preference,
+        default_med: global_settings.default_med,
+        capabilities: global_settings.optional_parameters.capabilities.iter().map(capability_name).collect(),
+    }
+}
+
+fn capability_name(capability: &Capability) -> String {
+    match capability {
+        Capability::MultiprotocolExtensions(_) => "multiprotocol-extensions".to_string(),
+        Capability::RouteRefreshPreStandard => "route-refresh-prestandard".to_string(),
+        Capability::RouteRefresh => "route-refresh".to_string(),
+        Capability::EnhancedRouteRefresh => "enhanced-route-refresh".to_string(),
+        Capability::Extended4ByteASN(_) => "4-byte-asn".to_string(),
+    }
+}
@@ -0,0 +1,204 @@
+// The requests and responses that go over the control socket, one JSON object per line each way.
+// bgpctl builds this file too, so it can only depend on std and serde.
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SoftDirection {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    ShowGlobalSettings,
+    ShowNeighbors,
+    // "show bgp summary" style text, rendered by the daemon
+    ShowSummary,
+    ShowNeighbor { ip: Ipv4Addr },
+    // a prefix shows that entry, a plain address shows the longest match for it
+    ShowRib { prefix: Option<String> },
+    // received is the routes as the neighbor sent them, before the import filters
+    ShowAdjRibIn {
+        peer: Ipv4Addr,
+        #[serde(default)]
+        received: bool,
+    },
+    ShowAdjRibOut { peer: Ipv4Addr },
+    ClearNeighbor { ip: Ipv4Addr, soft: Option<SoftDirection> },
+    NeighborShutdown { ip: Ipv4Addr },
+    NeighborEnable { ip: Ipv4Addr },
+    Announce { prefix: String },
+    Withdraw { prefix: String },
+    // writes an MRT TABLE_DUMP_V2 of the RIBs now
+    DumpRib,
+    // runs a policy over the routes as the neighbors sent them, or over `route` alone, without applying it
+    TestPolicy {
+        policy: String,
+        // TOML with prefix_lists_config and policies_config to take the policy from instead of the running config
+        candidate: Option<String>,
+        // the neighbor to evaluate for, otherwise the one each route was learned from
+        neighbor: Option<Ipv4Addr>,
+        // only routes learned from this peer
+        peer: Option<Ipv4Addr>,
+        // like show rib, a prefix or the longest match for an address
+        prefix: Option<String>,
+        route: Option<Box<RouteEntry>>,
+    },
+    ShowRpki,
+    // same as SIGHUP, but the caller hears whether the config loaded
+    Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GlobalSettingsView {
+    pub my_as: u16,
+    pub router_id: Ipv4Addr,
+    pub next_hop: Ipv4Addr,
+    pub version: u8,
+    pub default_local_preference: u32,
+    pub default_med: u32,
+    // what we put in our Open unless a neighbor overrides it
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NeighborSummary {
+    pub ip: Ipv4Addr,
+    // None for a dynamic neighbor that hasn't sent its Open yet
+    pub as_num: Option<u32>,
+    pub state: String,
+    pub description: Option<String>,
+    pub dynamic: bool,
+    pub admin_shutdown: bool,
+    pub prefixes_received: usize,
+    pub prefixes_advertised: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NeighborDetail {
+    #[serde(flatten)]
+    pub summary: NeighborSummary,
+    pub peer_type: String,
+    pub hold_time: u16,
+    pub keepalive_time: u16,
+    pub connect_retry_time: u16,
+    pub connect_retry_counter: u16,
+    pub idle_hold_time: u16,
+    pub delay_open_time: Option<u16>,
+    pub established_secs: Option<u64>,
+    pub passive: bool,
+    pub next_hop_self: bool,
+    pub soft_reconfiguration_inbound: bool,
+    pub damp_peer_oscillations: bool,
+    pub oscillation_count: u32,
+    pub negotiated_capabilities: Vec<String>,
+    // unix time
+    pub established_since: Option<u64>,
+    pub last_state_change_secs: Option<u64>,
+    pub flaps: u64,
+    pub messages_received: BTreeMap<String, u64>,
+    pub messages_sent: BTreeMap<String, u64>,
+    // every prefix announced to us, prefixes_received is what we hold now
+    pub prefixes_announced: u64,
+    pub prefixes_withdrawn: u64,
+    pub last_notification_received: Option<String>,
+    pub last_notification_sent: Option<String>,
+    // oldest first
+    pub recent_transitions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteEntry {
+    pub prefix: String,
+    pub next_hop: Ipv4Addr,
+    pub as_path: Vec<u32>,
+    pub origin: String,
+    pub local_pref: Option<u32>,
+    pub med: Option<u32>,
+    // None for routes we originate
+    #[serde(default)]
+    pub learned_from: Option<Ipv4Addr>,
+    // in the local RIB, only ever set for the global RIB
+    #[serde(default)]
+    pub best: bool,
+    #[serde(default)]
+    pub communities: Vec<String>,
+    // "valid", "invalid" or "not_found", a hand-written route without it is validated against the VRPs
+    #[serde(default)]
+    pub rpki: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicyTestEntry {
+    // "accept" or "reject"
+    pub action: String,
+    // the term that decided, None when the route fell through to the default action
+    pub term: Option<String>,
+    pub before: RouteEntry,
+    // with the policy's sets applied
+    pub after: RouteEntry,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpkiCacheView {
+    pub address: String,
+    pub connected: bool,
+    // the RTR version the session speaks
+    pub version: u8,
+    pub session_id: Option<u16>,
+    pub serial: Option<u32>,
+    pub vrps: usize,
+    pub last_update_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpkiView {
+    pub caches: Vec<RpkiCacheView>,
+    // what the caches sent together, each VRP once
+    pub vrps: usize,
+    pub reject_invalid: bool,
+    pub prefer_valid: bool,
+    // paths in the RIB by validation state
+    pub valid: usize,
+    pub invalid: usize,
+    pub not_found: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse {
+    GlobalSettings(GlobalSettingsView),
+    Neighbors(Vec<NeighborSummary>),
+    Summary(String),
+    Neighbor(Box<NeighborDetail>),
+    Routes(Vec<RouteEntry>),
+    PolicyTest(Vec<PolicyTestEntry>),
+    Rpki(RpkiView),
+    Done(String),
+    Error(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request = ControlRequest::ClearNeighbor { ip: Ipv4Addr::new(10, 0, 0, 2), soft: Some(SoftDirection::In) };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"command":"clear_neighbor","ip":"10.0.0.2","soft":"in"}"#);
+        assert_eq!(serde_json::from_str::<ControlRequest>(&line).unwrap(), request);
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"show_rib","prefix":null}"#).unwrap();
+        assert_eq!(request, ControlRequest::ShowRib { prefix: None });
+        // a hand-written route only needs the mandatory attributes
+        let line = r#"{"command":"test_policy","policy":"from-upstream","route":{"prefix":"192.0.2.0/24","next_hop":"10.0.0.2","as_path":[65001],"origin":"IGP"}}"#;
+        let ControlRequest::TestPolicy { route: Some(route), candidate: None, .. } = serde_json::from_str(line).unwrap() else { panic!("not a policy test") };
+        assert_eq!((route.local_pref, route.learned_from, route.communities.len()), (None, None, 0));
+    }
+}
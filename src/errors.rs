@@ -12,7 +12,8 @@ pub enum NeighborError {
     UnableToRemoveNeighbor,
     ConfiguredNeighborNotFound,
     TCPConnDied,
-    ASNumMismatch
+    ASNumMismatch,
+    RouteRefreshNotNegotiated,
 }
 
 #[derive(PartialEq, Debug)]
@@ -62,9 +63,31 @@ pub enum EventError {
 #[derive(PartialEq, Debug)]
 pub enum ProcessError {
     BadNLRILen,
-    AS2Unhandled,
     AS4Unhandled,
     ASNumLenMismatch,
+    UnableToBindListener,
+    UnableToBindControlSocket,
+    UnableToBindHttpApi,
+    UnableToBindGrpcApi,
+    UnableToBindMetrics,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ConfigError {
+    PeerGroupNotFound,
+    MissingNeighborAS,
+    MissingNeighborTimers,
+    BadNeighborIP,
+    UnableToReadFile,
+    InvalidConfig,
+    BadRouterId,
+    BadNextHopIP,
+    ImportPolicyNotFound,
+    ExportPolicyNotFound,
+    ImportPluginNotFound,
+    ExportPluginNotFound,
+    ImportScriptNotFound,
+    ExportScriptNotFound,
 }
 
 #[derive(PartialEq, Debug)]
@@ -74,6 +97,7 @@ pub enum BGPError {
     Process(ProcessError),
     Timer(TimerError),
     Event(EventError),
+    Config(ConfigError),
 }
 
 impl From<ProcessError> for BGPError {
@@ -99,6 +123,10 @@ impl From<TimerError> for BGPError {
     fn from(e: TimerError) -> BGPError { BGPError::Timer(e) }
 }
 
+impl From<ConfigError> for BGPError {
+    fn from(e: ConfigError) -> BGPError { BGPError::Config(e) }
+}
+
 // impl From<MessageError> for NeighborError {
 //     fn from(e: MessageError) -> NeighborError {NeighborError::Message(e)}
 // }
@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Scripts, their data files, plugins and list files are read once and kept until they change on disk. Each remembers
+// the mtime it had when we read it, and a reload only reads again what moved on from that.
+
+// a file that changed and its mtime now, None when it's gone or can't be read
+pub type FileChange = (PathBuf, Option<SystemTime>);
+
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchedFile {
+    pub path: PathBuf,
+    pub modified: Option<SystemTime>,
+}
+
+impl WatchedFile {
+    // call it before reading the file, a write that lands while we read shows up as another change
+    pub fn new(path: &Path) -> Self {
+        WatchedFile { path: path.to_path_buf(), modified: modified(path) }
+    }
+
+    pub fn changed(&self) -> Option<FileChange> {
+        let now = modified(&self.path);
+        (now != self.modified).then(|| (self.path.clone(), now))
+    }
+}
+
+// sorted and each file once, so the process can tell a new change from one it already tried
+pub fn sort_changes(changes: impl IntoIterator<Item = FileChange>) -> Vec<FileChange> {
+    let mut changed: Vec<FileChange> = changes.into_iter().collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
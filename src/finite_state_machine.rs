@@ -1,9 +1,13 @@
+use std::fmt;
 use std::io;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
+use tracing::info;
 use crate::errors::BGPError;
 use crate::messages::{extract_messages_from_rec_data};
 use crate::messages::open::get_neighbor_ipv4_address_from_stream;
@@ -26,6 +30,25 @@ pub enum State {
     Established
 }
 
+impl State {
+    pub const ALL: [State; 6] = [State::Idle, State::Connect, State::Active, State::OpenSent, State::OpenConfirm, State::Established];
+}
+
+// the name the control socket, bgpctl and /metrics show, FromStr reads it back
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for State {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        State::ALL.into_iter().find(|state| state.to_string() == s).ok_or_else(|| format!("{} is not a BGP state", s))
+    }
+}
+
 // will need an FSM per neighbor
 #[derive(Debug)]
 pub struct FSM {
@@ -40,8 +63,13 @@ pub struct FSM {
     pub allow_automatic_start: bool,
     pub allow_automatic_stop: bool,
     pub damp_peer_oscillations: bool,
-    pub idle_hold_time: u16, // initial value for timer
+    pub idle_hold_time: u16, // initial value for timer, doubled every time we damp the peer
     pub idle_hold_timer: Timer,
+    pub initial_idle_hold_time: u16, // idle_hold_time goes back to this once the peer is stable
+    pub max_idle_hold_time: u16, // upper bound for the backoff
+    pub idle_hold_stable_time: u16, // how long the peer has to stay Established before we reset the backoff
+    pub oscillation_count: u32, // num of times we damped the peer since the last reset
+    pub established_at: Option<Instant>,
     pub accept_connections_unconfigured_peers: bool,
     pub passive_tcp_establishment: bool,
     pub track_tcp_state: bool,
@@ -69,6 +97,11 @@ impl Default for FSM {
             damp_peer_oscillations: false,
             idle_hold_time: 5,
             idle_hold_timer: Timer::new(5),
+            initial_idle_hold_time: 5,
+            max_idle_hold_time: 300,
+            idle_hold_stable_time: 300,
+            oscillation_count: 0,
+            established_at: None,
             accept_connections_unconfigured_peers: false,
             passive_tcp_establishment: true,
             track_tcp_state: true,
@@ -88,6 +121,54 @@ impl FSM {
 
     }
 
+    // RFC 4271 8.1.1 leaves the damping method up to us, so we do an exponential backoff of the IdleHoldTimer.
+    // Call this every time the session drops back to Idle with damp_peer_oscillations set.
+    pub fn damp_peer(&mut self) {
+        // a peer that was up longer than the stable time gets a clean slate before we back it off again
+        if let Some(established_at) = self.established_at.take()
+            && established_at.elapsed() >= Duration::from_secs(self.idle_hold_stable_time as u64) {
+            self.reset_idle_hold_time();
+        }
+        self.idle_hold_timer.start(self.idle_hold_time);
+        self.oscillation_count += 1;
+        info!(target: "fsm", "Damping peer for {} seconds, oscillation count is {}", self.idle_hold_time, self.oscillation_count);
+        self.idle_hold_time = self.idle_hold_time.saturating_mul(2).min(self.max_idle_hold_time);
+    }
+
+    pub fn reset_idle_hold_time(&mut self) {
+        self.idle_hold_time = self.initial_idle_hold_time;
+        self.oscillation_count = 0;
+    }
+
+    pub fn reset_idle_hold_time_if_stable(&mut self) {
+        if self.state != State::Established || self.oscillation_count == 0 {
+            return
+        }
+        if let Some(established_at) = self.established_at
+            && established_at.elapsed() >= Duration::from_secs(self.idle_hold_stable_time as u64) {
+            info!(target: "fsm", "Peer has been stable for {} seconds, resetting idle_hold_time", self.idle_hold_stable_time);
+            self.reset_idle_hold_time();
+        }
+    }
+
+    pub fn is_delay_open_timer_running(&self) -> bool {
+        matches!(self.delay_open_timer.is_running(), Ok(true))
+    }
+
+    pub fn is_damped(&self) -> bool {
+        self.damp_peer_oscillations && matches!(self.idle_hold_timer.is_running(), Ok(true))
+    }
+
+    // picks the automatic start event that matches our session attributes
+    pub fn automatic_start_event(&self) -> Event {
+        match (self.damp_peer_oscillations, self.passive_tcp_establishment) {
+            (true, true) => Event::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment,
+            (true, false) => Event::AutomaticStartWithDampPeerOscillations,
+            (false, true) => Event::AutomaticStartWithPassiveTcpEstablishment,
+            (false, false) => Event::AutomaticStart,
+        }
+    }
+
     // //
     // pub fn set_state(&mut self, state: State) -> Result<(), BGPError> {
     //     match state {
@@ -140,4 +221,44 @@ impl FSM {
 //     fn default() -> Self {
 //         FSM::Idle
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damp_peer_doubles_idle_hold_time_up_to_max() {
+        let mut fsm = FSM {
+            damp_peer_oscillations: true,
+            max_idle_hold_time: 30,
+            ..FSM::default()
+        };
+        fsm.damp_peer();
+        assert_eq!(fsm.idle_hold_timer.max_time, Duration::from_secs(5));
+        assert_eq!(fsm.idle_hold_time, 10);
+        fsm.damp_peer();
+        fsm.damp_peer();
+        assert_eq!(fsm.idle_hold_time, 30);
+        fsm.damp_peer();
+        assert_eq!(fsm.idle_hold_timer.max_time, Duration::from_secs(30));
+        assert_eq!(fsm.oscillation_count, 4);
+        assert!(fsm.is_damped());
+    }
+
+    #[test]
+    fn test_damp_peer_resets_after_stable_period() {
+        let mut fsm = FSM {
+            damp_peer_oscillations: true,
+            idle_hold_stable_time: 0,
+            ..FSM::default()
+        };
+        fsm.damp_peer();
+        fsm.damp_peer();
+        assert_eq!(fsm.idle_hold_time, 20);
+        fsm.state = State::Established;
+        fsm.established_at = Some(Instant::now());
+        fsm.reset_idle_hold_time_if_stable();
+        assert_eq!(fsm.idle_hold_time, 5);
+        assert_eq!(fsm.oscillation_count, 0);
+    }
+}
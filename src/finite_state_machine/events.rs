@@ -57,7 +57,7 @@ pub enum Event {
     TcpConnectionFails,
     // BGP message events
     OpenMsg(OpenMessage),
-    BGPOpenWithDelayOpenTimerRunning,
+    BGPOpenWithDelayOpenTimerRunning(OpenMessage),
     BGPHeaderErr,
     BGPOpenMsgErr,
     OpenCollisionDump,
@@ -72,5 +72,79 @@ pub enum Event {
 
 }
 
+impl Event {
+    // the event's number in RFC 4271 section 8.1, 0 for the ones we added
+    pub fn number(&self) -> u16 {
+        match self {
+            Event::ManualStart => 1,
+            Event::ManualStop => 2,
+            Event::AutomaticStart => 3,
+            Event::ManualStartWithPassiveTcpEstablishment => 4,
+            Event::AutomaticStartWithPassiveTcpEstablishment => 5,
+            Event::AutomaticStartWithDampPeerOscillations => 6,
+            Event::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment => 7,
+            Event::AutomaticStop => 8,
+            Event::ConnectRetryTimerExpires => 9,
+            Event::HoldTimerExpires => 10,
+            Event::KeepaliveTimerExpires => 11,
+            Event::DelayOpenTimerExpires => 12,
+            Event::IdleHoldTimerExpires => 13,
+            Event::TcpConnectionValid => 14,
+            Event::TcpCRInvalid => 15,
+            Event::TcpCRAcked => 16,
+            Event::TcpConnectionConfirmed => 17,
+            Event::TcpConnectionFails => 18,
+            Event::OpenMsg(_) => 19,
+            Event::BGPOpenWithDelayOpenTimerRunning(_) => 20,
+            Event::BGPHeaderErr => 21,
+            Event::BGPOpenMsgErr => 22,
+            Event::OpenCollisionDump => 23,
+            Event::NotifMsgVerErr => 24,
+            Event::NotifMsg(_) => 25,
+            Event::KeepAliveMsg => 26,
+            Event::UpdateMsg(_) => 27,
+            Event::UpdateMsgErr => 28,
+            Event::RouteRefreshMsg(_) | Event::SendUpdateMsg => 0,
+        }
+    }
+
+    // just the variant, for logs and history where the message it carries would be noise
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ManualStart => "ManualStart",
+            Event::ManualStop => "ManualStop",
+            Event::AutomaticStart => "AutomaticStart",
+            Event::ManualStartWithPassiveTcpEstablishment => "ManualStartWithPassiveTcpEstablishment",
+            Event::AutomaticStartWithPassiveTcpEstablishment => "AutomaticStartWithPassiveTcpEstablishment",
+            Event::AutomaticStartWithDampPeerOscillations => "AutomaticStartWithDampPeerOscillations",
+            Event::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment => "AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment",
+            Event::AutomaticStop => "AutomaticStop",
+            Event::ConnectRetryTimerExpires => "ConnectRetryTimerExpires",
+            Event::HoldTimerExpires => "HoldTimerExpires",
+            Event::KeepaliveTimerExpires => "KeepaliveTimerExpires",
+            Event::DelayOpenTimerExpires => "DelayOpenTimerExpires",
+            Event::IdleHoldTimerExpires => "IdleHoldTimerExpires",
+            Event::TcpConnectionValid => "TcpConnectionValid",
+            Event::TcpCRInvalid => "TcpCRInvalid",
+            Event::TcpCRAcked => "TcpCRAcked",
+            Event::TcpConnectionConfirmed => "TcpConnectionConfirmed",
+            Event::TcpConnectionFails => "TcpConnectionFails",
+            Event::OpenMsg(_) => "OpenMsg",
+            Event::BGPOpenWithDelayOpenTimerRunning(_) => "BGPOpenWithDelayOpenTimerRunning",
+            Event::BGPHeaderErr => "BGPHeaderErr",
+            Event::BGPOpenMsgErr => "BGPOpenMsgErr",
+            Event::OpenCollisionDump => "OpenCollisionDump",
+            Event::NotifMsgVerErr => "NotifMsgVerErr",
+            Event::NotifMsg(_) => "NotifMsg",
+            Event::KeepAliveMsg => "KeepAliveMsg",
+            Event::UpdateMsg(_) => "UpdateMsg",
+            Event::RouteRefreshMsg(_) => "RouteRefreshMsg",
+            Event::UpdateMsgErr => "UpdateMsgErr",
+            Event::SendUpdateMsg => "SendUpdateMsg",
+        }
+    }
+}
+
+
 
 
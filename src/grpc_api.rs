@@ -0,0 +1,313 @@
+use tracing::{debug, error, info, warn};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, oneshot};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tonic::{Request, Response, Status};
+use crate::channels::BgpEvent;
+use crate::config::GrpcApiConfig;
+use crate::control;
+use crate::control::protocol::*;
+use crate::errors::*;
+use crate::finite_state_machine::State;
+use crate::process::ProcessCommand;
+use crate::routes::NLRI;
+
+pub mod proto {
+    tonic::include_proto!("bgprtrapi");
+}
+
+use proto::bgp_api_server::{BgpApi, BgpApiServer};
+use proto::*;
+
+// Roughly GoBGP's API, for tooling that already speaks it. Like the HTTP API, requests go through the
+// process loop; WatchEvent is the exception and reads the event broadcast directly.
+
+pub async fn bind_grpc_api(grpc_api_config: &GrpcApiConfig) -> Result<TcpListener, ProcessError> {
+    let socket_addr = SocketAddr::new(grpc_api_config.address, grpc_api_config.port);
+    let listener = TcpListener::bind(socket_addr).await.map_err(|e| {
+        error!("Unable to bind gRPC API on {} - {}", socket_addr, e);
+        ProcessError::UnableToBindGrpcApi
+    })?;
+    info!("gRPC API listening on {}", socket_addr);
+    Ok(listener)
+}
+
+pub async fn run_grpc_api(listener: TcpListener, tx_process_command: Sender<ProcessCommand>, tx_bgp_events: broadcast::Sender<BgpEvent>) {
+    let service = BgpApiService { tx_process_command, tx_bgp_events };
+    let result = tonic::transport::Server::builder()
+        .add_service(BgpApiServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    if let Err(e) = result {
+        error!("gRPC API stopped - {}", e);
+    }
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+struct BgpApiService {
+    tx_process_command: Sender<ProcessCommand>,
+    tx_bgp_events: broadcast::Sender<BgpEvent>,
+}
+
+impl BgpApiService {
+    async fn ask(&self, request: ControlRequest) -> Result<ControlResponse, Status> {
+        debug!("gRPC API request {:?}", request);
+        let (tx_response, rx_response) = oneshot::channel();
+        self.tx_process_command.send(ProcessCommand::Control(request, tx_response)).await
+            .map_err(|_| Status::unavailable("BGP process is shutting down"))?;
+        rx_response.await.map_err(|_| Status::internal("BGP process dropped the request"))
+    }
+
+    async fn do_action(&self, request: ControlRequest) -> Result<(), Status> {
+        match self.ask(request).await? {
+            ControlResponse::Done(_) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+fn unexpected(response: ControlResponse) -> Status {
+    match response {
+        ControlResponse::Error(e) => Status::invalid_argument(e),
+        other => Status::internal(format!("Unexpected response {:?}", other)),
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<Ipv4Addr, Status> {
+    Ipv4Addr::from_str(ip).map_err(|_| Status::invalid_argument(format!("{} is not an IPv4 address", ip)))
+}
+
+fn peer_state(state: &State) -> PeerState {
+    match state {
+        State::Idle => PeerState::Idle,
+        State::Connect => PeerState::Connect,
+        State::Active => PeerState::Active,
+        State::OpenSent => PeerState::Opensent,
+        State::OpenConfirm => PeerState::Openconfirm,
+        State::Established => PeerState::Established,
+    }
+}
+
+fn peer(summary: NeighborSummary) -> Peer {
+    let session_state = State::from_str(&summary.state).map_or(PeerState::Unknown, |state| peer_state(&state));
+    Peer {
+        address: summary.ip.to_string(),
+        peer_asn: summary.as_num.unwrap_or(0),
+        session_state: session_state.into(),
+        description: summary.description.unwrap_or_default(),
+        dynamic: summary.dynamic,
+        admin_shutdown: summary.admin_shutdown,
+        received: summary.prefixes_received as u64,
+        advertised: summary.prefixes_advertised as u64,
+    }
+}
+
+fn path(route: RouteEntry) -> Path {
+    let origin = match route.origin.as_str() {
+        "EGP" => Origin::Egp,
+        "Incomplete" => Origin::Incomplete,
+        _ => Origin::Igp,
+    };
+    Path {
+        prefix: route.prefix,
+        next_hop: route.next_hop.to_string(),
+        as_path: route.as_path,
+        origin: origin.into(),
+        local_pref: route.local_pref,
+        med: route.med,
+        neighbor_address: route.learned_from.map(|ip| ip.to_string()).unwrap_or_default(),
+        best: route.best,
+        is_withdraw: false,
+    }
+}
+
+// the routes come sorted by prefix, so every destination's paths are next to each other
+fn destinations(routes: Vec<RouteEntry>, prefixes: &[NLRI]) -> Vec<Destination> {
+    let mut destinations: Vec<Destination> = Vec::new();
+    for route in routes {
+        if !prefixes.is_empty() && !NLRI::from_str(&route.prefix).is_ok_and(|nlri| prefixes.iter().any(|p| nlri.len >= p.len && p.contains(nlri.prefix))) {
+            continue;
+        }
+        match destinations.last_mut() {
+            Some(destination) if destination.prefix == route.prefix => destination.paths.push(path(route)),
+            _ => destinations.push(Destination { prefix: route.prefix.clone(), paths: vec![path(route)] }),
+        }
+    }
+    destinations
+}
+
+fn watch_event(event: BgpEvent, request: &WatchEventRequest) -> Option<WatchEventResponse> {
+    let event = match event {
+        BgpEvent::PeerStateChanged { peer_ip, as_num, old_state, new_state } if request.peer => {
+            watch_event_response::Event::Peer(PeerEvent {
+                address: peer_ip.to_string(),
+                peer_asn: control::as_to_u32(&as_num),
+                old_state: peer_state(&old_state).into(),
+                new_state: peer_state(&new_state).into(),
+            })
+        },
+        BgpEvent::BestPathChanged { nlri, best_path } if request.table => {
+            let path = match best_path {
+                Some(route) => path(RouteEntry { best: true, ..control::route_entry(&route) }),
+                None => Path { prefix: format!("{}/{}", nlri.prefix, nlri.len), is_withdraw: true, ..Default::default() },
+            };
+            watch_event_response::Event::Table(TableEvent { paths: vec![path] })
+        },
+        _ => return None,
+    };
+    Some(WatchEventResponse { event: Some(event) })
+}
+
+#[tonic::async_trait]
+impl BgpApi for BgpApiService {
+    async fn get_bgp(&self, _request: Request<GetBgpRequest>) -> Result<Response<GetBgpResponse>, Status> {
+        match self.ask(ControlRequest::ShowGlobalSettings).await? {
+            ControlResponse::GlobalSettings(g) => Ok(Response::new(GetBgpResponse {
+                global: Some(Global {
+                    asn: g.my_as as u32,
+                    router_id: g.router_id.to_string(),
+                    next_hop: g.next_hop.to_string(),
+                    default_local_preference: g.default_local_preference,
+                    default_med: g.default_med,
+                }),
+            })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    type ListPeerStream = ResponseStream<ListPeerResponse>;
+
+    async fn list_peer(&self, request: Request<ListPeerRequest>) -> Result<Response<Self::ListPeerStream>, Status> {
+        let request = request.into_inner();
+        let summaries = if request.address.is_empty() {
+            match self.ask(ControlRequest::ShowNeighbors).await? {
+                ControlResponse::Neighbors(neighbors) => neighbors,
+                other => return Err(unexpected(other)),
+            }
+        } else {
+            match self.ask(ControlRequest::ShowNeighbor { ip: parse_ip(&request.address)? }).await? {
+                ControlResponse::Neighbor(neighbor) => vec![neighbor.summary],
+                ControlResponse::Error(e) => return Err(Status::not_found(e)),
+                other => return Err(unexpected(other)),
+            }
+        };
+        let responses: Vec<_> = summaries.into_iter().map(|summary| Ok(ListPeerResponse { peer: Some(peer(summary)) })).collect();
+        Ok(Response::new(Box::pin(tokio_stream::iter(responses))))
+    }
+
+    async fn add_path(&self, request: Request<AddPathRequest>) -> Result<Response<AddPathResponse>, Status> {
+        let path = request.into_inner().path.ok_or_else(|| Status::invalid_argument("path is required"))?;
+        self.do_action(ControlRequest::Announce { prefix: path.prefix }).await?;
+        Ok(Response::new(AddPathResponse {}))
+    }
+
+    async fn delete_path(&self, request: Request<DeletePathRequest>) -> Result<Response<DeletePathResponse>, Status> {
+        let path = request.into_inner().path.ok_or_else(|| Status::invalid_argument("path is required"))?;
+        self.do_action(ControlRequest::Withdraw { prefix: path.prefix }).await?;
+        Ok(Response::new(DeletePathResponse {}))
+    }
+
+    type ListPathStream = ResponseStream<ListPathResponse>;
+
+    async fn list_path(&self, request: Request<ListPathRequest>) -> Result<Response<Self::ListPathStream>, Status> {
+        let request = request.into_inner();
+        let prefixes = request.prefixes.iter()
+            .map(|prefix| NLRI::from_str(prefix).map_err(Status::invalid_argument))
+            .collect::<Result<Vec<NLRI>, Status>>()?;
+        let control_request = match request.table_type() {
+            TableType::Global => ControlRequest::ShowRib { prefix: None },
+            TableType::AdjIn => ControlRequest::ShowAdjRibIn { peer: parse_ip(&request.name)?, received: false },
+            TableType::AdjOut => ControlRequest::ShowAdjRibOut { peer: parse_ip(&request.name)? },
+        };
+        let routes = match self.ask(control_request).await? {
+            ControlResponse::Routes(routes) => routes,
+            ControlResponse::Error(e) => return Err(Status::not_found(e)),
+            other => return Err(unexpected(other)),
+        };
+        let responses: Vec<_> = destinations(routes, &prefixes).into_iter()
+            .map(|destination| Ok(ListPathResponse { destination: Some(destination) }))
+            .collect();
+        Ok(Response::new(Box::pin(tokio_stream::iter(responses))))
+    }
+
+    type WatchEventStream = ResponseStream<WatchEventResponse>;
+
+    async fn watch_event(&self, request: Request<WatchEventRequest>) -> Result<Response<Self::WatchEventStream>, Status> {
+        let request = request.into_inner();
+        let events = BroadcastStream::new(self.tx_bgp_events.subscribe()).filter_map(move |event| match event {
+            Ok(event) => watch_event(event, &request).map(Ok),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!("gRPC WatchEvent client is too slow, skipped {} events", skipped);
+                None
+            },
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::bgp_api_client::BgpApiClient;
+    use crate::process::fake_control_loop;
+
+    fn route(prefix: &str, learned_from: Option<Ipv4Addr>, best: bool) -> RouteEntry {
+        RouteEntry { prefix: prefix.to_string(), next_hop: Ipv4Addr::new(10, 0, 0, 1), as_path: vec![65001], origin: "IGP".to_string(), local_pref: Some(100), med: None, learned_from, best, communities: Vec::new(), rpki: None }
+    }
+
+    #[test]
+    fn test_peer_session_state() {
+        let mut summary = NeighborSummary { ip: Ipv4Addr::new(10, 0, 0, 2), as_num: Some(65001), state: State::OpenSent.to_string(), description: None,
+                                            dynamic: false, admin_shutdown: false, prefixes_received: 0, prefixes_advertised: 0 };
+        assert_eq!(peer(summary.clone()).session_state(), PeerState::Opensent);
+        summary.state = "Bogus".to_string();
+        assert_eq!(peer(summary).session_state(), PeerState::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_api_list_path_and_watch_event() {
+        let peer_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let tx_process_command = fake_control_loop(move |request| match request {
+            ControlRequest::ShowRib { prefix: None } => ControlResponse::Routes(vec![
+                route("10.0.0.0/24", None, true), route("10.0.0.0/24", Some(peer_ip), false), route("192.0.2.0/24", Some(peer_ip), true),
+            ]),
+            _ => ControlResponse::Error("No neighbor".to_string()),
+        });
+        let (tx_bgp_events, _) = broadcast::channel(8);
+        let listener = bind_grpc_api(&GrpcApiConfig { address: Ipv4Addr::LOCALHOST.into(), port: 0 }).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_grpc_api(listener, tx_process_command, tx_bgp_events.clone()));
+        let mut client = BgpApiClient::connect(format!("http://{}", addr)).await.unwrap();
+
+        let request = ListPathRequest { table_type: TableType::Global.into(), name: String::new(), prefixes: vec!["10.0.0.0/8".to_string()] };
+        let mut stream = client.list_path(request).await.unwrap().into_inner();
+        let destination = stream.message().await.unwrap().unwrap().destination.unwrap();
+        assert_eq!(destination.prefix, "10.0.0.0/24");
+        assert_eq!(destination.paths.len(), 2);
+        assert!(destination.paths[0].best);
+        assert_eq!(destination.paths[1].neighbor_address, "10.0.0.2");
+        assert!(stream.message().await.unwrap().is_none());
+
+        let request = ListPathRequest { table_type: TableType::AdjIn.into(), name: "10.0.0.9".to_string(), prefixes: vec![] };
+        assert_eq!(client.list_path(request).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        let mut events = client.watch_event(WatchEventRequest { peer: true, table: false }).await.unwrap().into_inner();
+        // only peer events were asked for
+        tx_bgp_events.send(BgpEvent::BestPathChanged { nlri: NLRI::from_str("192.0.2.0/24").unwrap(), best_path: None }).unwrap();
+        tx_bgp_events.send(BgpEvent::PeerStateChanged { peer_ip, as_num: crate::messages::update::AS::AS4(65001), old_state: State::OpenConfirm, new_state: State::Established }).unwrap();
+        match events.message().await.unwrap().unwrap().event.unwrap() {
+            watch_event_response::Event::Peer(event) => {
+                assert_eq!(event.address, "10.0.0.2");
+                assert_eq!(event.new_state(), PeerState::Established);
+            },
+            other => panic!("Expected a peer event, got {:?}", other),
+        }
+    }
+}
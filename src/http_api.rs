@@ -0,0 +1,271 @@
+use tracing::{debug, error, info};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::sync::mpsc::Sender;
+use crate::config::HttpApiConfig;
+use crate::control::protocol::*;
+use crate::errors::*;
+use crate::process::ProcessCommand;
+use crate::routes::NLRI;
+
+// Read-only views and a few actions over HTTP, for automation that would rather not shell out to bgpctl.
+// Everything goes through the process loop the same way the control socket does.
+
+pub async fn bind_http_api(http_api_config: &HttpApiConfig) -> Result<TcpListener, ProcessError> {
+    let socket_addr = SocketAddr::new(http_api_config.address, http_api_config.port);
+    let listener = TcpListener::bind(socket_addr).await.map_err(|e| {
+        error!("Unable to bind HTTP API on {} - {}", socket_addr, e);
+        ProcessError::UnableToBindHttpApi
+    })?;
+    info!("HTTP API listening on {}", socket_addr);
+    Ok(listener)
+}
+
+pub async fn run_http_api(listener: TcpListener, tx_process_command: Sender<ProcessCommand>) {
+    if let Err(e) = axum::serve(listener, router(tx_process_command)).await {
+        error!("HTTP API stopped - {}", e);
+    }
+}
+
+fn router(tx_process_command: Sender<ProcessCommand>) -> Router {
+    Router::new()
+        .route("/api/global", get(get_global_settings))
+        .route("/api/neighbors", get(get_neighbors))
+        .route("/api/neighbors/{ip}", get(get_neighbor))
+        .route("/api/neighbors/{ip}/adj-rib-in", get(get_adj_rib_in))
+        .route("/api/neighbors/{ip}/adj-rib-out", get(get_adj_rib_out))
+        .route("/api/neighbors/{ip}/start", post(post_neighbor_start))
+        .route("/api/neighbors/{ip}/stop", post(post_neighbor_stop))
+        .route("/api/rib", get(get_rib))
+        .route("/api/rpki", get(get_rpki))
+        .route("/api/routes/announce", post(post_announce))
+        .route("/api/routes/withdraw", post(post_withdraw))
+        .route("/api/policies/{name}/test", post(post_policy_test))
+        .route("/api/reload", post(post_reload))
+        .with_state(tx_process_command)
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RibQuery {
+    // only routes inside this prefix, e.g. 10.0.0.0/8
+    prefix: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    // adj-rib-in only, the routes before the import filters
+    #[serde(default)]
+    received: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoutePage {
+    total: usize,
+    offset: usize,
+    routes: Vec<RouteEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrefixBody {
+    prefix: String,
+}
+
+// see ControlRequest::TestPolicy, an empty object tests the running policy against every route as the neighbors sent it
+#[derive(Debug, Deserialize)]
+struct PolicyTestBody {
+    candidate: Option<String>,
+    neighbor: Option<Ipv4Addr>,
+    peer: Option<Ipv4Addr>,
+    prefix: Option<String>,
+    route: Option<Box<RouteEntry>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActionResult {
+    result: String,
+}
+
+async fn ask(tx_process_command: &Sender<ProcessCommand>, request: ControlRequest) -> Result<ControlResponse, ApiError> {
+    debug!("HTTP API request {:?}", request);
+    let (tx_response, rx_response) = oneshot::channel();
+    tx_process_command.send(ProcessCommand::Control(request, tx_response)).await
+        .map_err(|_| ApiError(StatusCode::SERVICE_UNAVAILABLE, "BGP process is shutting down".to_string()))?;
+    rx_response.await.map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, "BGP process dropped the request".to_string()))
+}
+
+fn unexpected(response: ControlResponse) -> ApiError {
+    match response {
+        ControlResponse::Error(e) => ApiError(StatusCode::BAD_REQUEST, e),
+        other => ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("Unexpected response {:?}", other)),
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<Ipv4Addr, ApiError> {
+    Ipv4Addr::from_str(ip).map_err(|_| ApiError(StatusCode::BAD_REQUEST, format!("{} is not an IPv4 address", ip)))
+}
+
+fn page_routes(routes: Vec<RouteEntry>, query: &RibQuery) -> Result<RoutePage, ApiError> {
+    let routes = match &query.prefix {
+        Some(prefix) => {
+            let filter = NLRI::from_str(prefix).map_err(|e| ApiError(StatusCode::BAD_REQUEST, e))?;
+            routes.into_iter()
+                .filter(|r| NLRI::from_str(&r.prefix).is_ok_and(|nlri| nlri.len >= filter.len && filter.contains(nlri.prefix)))
+                .collect()
+        },
+        None => routes,
+    };
+    let total = routes.len();
+    let routes = routes.into_iter().skip(query.offset).take(query.limit.unwrap_or(usize::MAX)).collect();
+    Ok(RoutePage { total, offset: query.offset, routes })
+}
+
+async fn get_global_settings(State(tx): State<Sender<ProcessCommand>>) -> Result<Json<GlobalSettingsView>, ApiError> {
+    match ask(&tx, ControlRequest::ShowGlobalSettings).await? {
+        ControlResponse::GlobalSettings(global_settings) => Ok(Json(global_settings)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn get_neighbors(State(tx): State<Sender<ProcessCommand>>) -> Result<Json<Vec<NeighborSummary>>, ApiError> {
+    match ask(&tx, ControlRequest::ShowNeighbors).await? {
+        ControlResponse::Neighbors(neighbors) => Ok(Json(neighbors)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn get_neighbor(State(tx): State<Sender<ProcessCommand>>, Path(ip): Path<String>) -> Result<Json<NeighborDetail>, ApiError> {
+    let ip = parse_ip(&ip)?;
+    match ask(&tx, ControlRequest::ShowNeighbor { ip }).await? {
+        ControlResponse::Neighbor(neighbor) => Ok(Json(*neighbor)),
+        ControlResponse::Error(e) => Err(ApiError(StatusCode::NOT_FOUND, e)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn get_routes(tx: &Sender<ProcessCommand>, request: ControlRequest, query: &RibQuery) -> Result<Json<RoutePage>, ApiError> {
+    match ask(tx, request).await? {
+        ControlResponse::Routes(routes) => Ok(Json(page_routes(routes, query)?)),
+        ControlResponse::Error(e) => Err(ApiError(StatusCode::NOT_FOUND, e)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn get_rib(State(tx): State<Sender<ProcessCommand>>, Query(query): Query<RibQuery>) -> Result<Json<RoutePage>, ApiError> {
+    get_routes(&tx, ControlRequest::ShowRib { prefix: None }, &query).await
+}
+
+async fn get_rpki(State(tx): State<Sender<ProcessCommand>>) -> Result<Json<RpkiView>, ApiError> {
+    match ask(&tx, ControlRequest::ShowRpki).await? {
+        ControlResponse::Rpki(rpki) => Ok(Json(rpki)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn get_adj_rib_in(State(tx): State<Sender<ProcessCommand>>, Path(ip): Path<String>, Query(query): Query<RibQuery>) -> Result<Json<RoutePage>, ApiError> {
+    let peer = parse_ip(&ip)?;
+    get_routes(&tx, ControlRequest::ShowAdjRibIn { peer, received: query.received }, &query).await
+}
+
+async fn get_adj_rib_out(State(tx): State<Sender<ProcessCommand>>, Path(ip): Path<String>, Query(query): Query<RibQuery>) -> Result<Json<RoutePage>, ApiError> {
+    let peer = parse_ip(&ip)?;
+    get_routes(&tx, ControlRequest::ShowAdjRibOut { peer }, &query).await
+}
+
+async fn do_action(tx: &Sender<ProcessCommand>, request: ControlRequest) -> Result<Json<ActionResult>, ApiError> {
+    match ask(tx, request).await? {
+        ControlResponse::Done(result) => Ok(Json(ActionResult { result })),
+        other => Err(unexpected(other)),
+    }
+}
+
+// start is Event::ManualStart and stop is Event::ManualStop, a stopped neighbor stays down until it is started again
+async fn post_neighbor_start(State(tx): State<Sender<ProcessCommand>>, Path(ip): Path<String>) -> Result<Json<ActionResult>, ApiError> {
+    let ip = parse_ip(&ip)?;
+    do_action(&tx, ControlRequest::NeighborEnable { ip }).await
+}
+
+async fn post_neighbor_stop(State(tx): State<Sender<ProcessCommand>>, Path(ip): Path<String>) -> Result<Json<ActionResult>, ApiError> {
+    let ip = parse_ip(&ip)?;
+    do_action(&tx, ControlRequest::NeighborShutdown { ip }).await
+}
+
+async fn post_announce(State(tx): State<Sender<ProcessCommand>>, Json(body): Json<PrefixBody>) -> Result<Json<ActionResult>, ApiError> {
+    do_action(&tx, ControlRequest::Announce { prefix: body.prefix }).await
+}
+
+async fn post_withdraw(State(tx): State<Sender<ProcessCommand>>, Json(body): Json<PrefixBody>) -> Result<Json<ActionResult>, ApiError> {
+    do_action(&tx, ControlRequest::Withdraw { prefix: body.prefix }).await
+}
+
+async fn post_policy_test(State(tx): State<Sender<ProcessCommand>>, Path(name): Path<String>, Json(body): Json<PolicyTestBody>) -> Result<Json<Vec<PolicyTestEntry>>, ApiError> {
+    let request = ControlRequest::TestPolicy { policy: name, candidate: body.candidate, neighbor: body.neighbor, peer: body.peer, prefix: body.prefix, route: body.route };
+    match ask(&tx, request).await? {
+        ControlResponse::PolicyTest(entries) => Ok(Json(entries)),
+        other => Err(unexpected(other)),
+    }
+}
+
+async fn post_reload(State(tx): State<Sender<ProcessCommand>>) -> Result<Json<ActionResult>, ApiError> {
+    do_action(&tx, ControlRequest::Reload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::process::fake_control_loop;
+
+    fn route(prefix: &str) -> RouteEntry {
+        RouteEntry { prefix: prefix.to_string(), next_hop: Ipv4Addr::new(10, 0, 0, 1), as_path: vec![65001], origin: "IGP".to_string(), local_pref: Some(100), med: None, learned_from: None, best: true, communities: Vec::new(), rpki: None }
+    }
+
+    async fn http_request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_http_api_rib_paging_and_actions() {
+        let tx_process_command = fake_control_loop(|request| match request {
+            ControlRequest::ShowRib { prefix: None } => ControlResponse::Routes(vec![route("10.0.0.0/24"), route("10.0.1.0/24"), route("10.0.2.0/24"), route("192.0.2.0/24")]),
+            ControlRequest::Announce { prefix } => ControlResponse::Done(format!("Announced {}", prefix)),
+            _ => ControlResponse::Error("No neighbor".to_string()),
+        });
+        let listener = bind_http_api(&HttpApiConfig { address: Ipv4Addr::LOCALHOST.into(), port: 0 }).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_http_api(listener, tx_process_command));
+
+        let response = http_request(addr, "GET /api/rib?prefix=10.0.0.0/8&offset=1&limit=1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let page: RoutePage = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.routes, vec![route("10.0.1.0/24")]);
+
+        let body = r#"{"prefix":"192.0.2.0/24"}"#;
+        let response = http_request(addr, &format!("POST /api/routes/announce HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Announced 192.0.2.0/24"));
+
+        let response = http_request(addr, "GET /api/neighbors/10.0.0.9 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
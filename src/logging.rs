@@ -0,0 +1,63 @@
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::sync::Mutex;
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::{Cli, LogFormat, LogLevel};
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+// the level goes first so per-target directives only override it for their own targets
+pub fn build_filter(level: LogLevel, directives: Option<&str>) -> Result<EnvFilter, String> {
+    let level = LevelFilter::from(level);
+    let directives = match directives {
+        Some(directives) => format!("{},{}", level, directives),
+        None => level.to_string(),
+    };
+    EnvFilter::builder().parse(directives).map_err(|e| e.to_string())
+}
+
+// the file writer doesn't spawn a thread like tracing_appender does, so this is safe to call before --daemon forks
+pub fn init_logging(cli: &Cli) -> Result<(), String> {
+    let filter = build_filter(cli.log_level, cli.log_filter.as_deref())?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match (&cli.log_file, cli.log_format) {
+        (Some(log_file), format) => {
+            let file = OpenOptions::new().create(true).append(true).open(log_file)
+                .map_err(|e| format!("Unable to open log file {} - {}", log_file.display(), e))?;
+            let builder = builder.with_writer(Mutex::new(file)).with_ansi(false);
+            match format {
+                LogFormat::Text => builder.init(),
+                LogFormat::Json => builder.json().init(),
+            }
+        },
+        (None, LogFormat::Text) => builder.with_writer(io::stdout).with_ansi(io::stdout().is_terminal()).init(),
+        (None, LogFormat::Json) => builder.with_writer(io::stdout).json().init(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_filter() {
+        let filter = build_filter(LogLevel::Warn, Some("fsm=debug,codec=trace")).unwrap();
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::TRACE));
+        assert_eq!(build_filter(LogLevel::Warn, None).unwrap().max_level_hint(), Some(LevelFilter::WARN));
+        assert!(build_filter(LogLevel::Info, Some("fsm=loud")).is_err());
+    }
+}
@@ -5,8 +5,12 @@ use crate::messages::keepalive::*;
 use crate::messages::open::*;
 use crate::process::*;
 
-use tokio::sync::{Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex};
+use std::path::Path;
 use std::sync::Arc;
+use clap::Parser;
+use tracing::{error, info};
 
 
 
@@ -21,16 +25,134 @@ mod timers;
 mod errors;
 mod config;
 mod channels;
+mod cli;
+mod logging;
+mod control;
+mod http_api;
+mod grpc_api;
+mod metrics;
+mod bmp;
+mod mrt;
+mod policy;
+mod plugins;
+mod scripting;
+mod rpki;
+mod file_watch;
 
-#[tokio::main]
-async fn main()  {
-    //let mut bgp = BGPProcess::new("bgp_config.toml".to_string());
-    let mut bgp: Arc<Mutex<BGPProcess>> = Arc::new(Mutex::new(BGPProcess::new("bgp_config.toml")));
-    println!("{:#?}", bgp);
-    // todo read the IP and port from config file
-    BGPProcess::run_process_loop(bgp, "10.0.0.3", "179").await;
+fn main() {
+    let cli = cli::Cli::parse();
+    // nothing is set up to log through yet, so this one goes straight to stderr
+    if let Err(e) = logging::init_logging(&cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    let config_file_name = cli.config.to_string_lossy().to_string();
+    if cli.check_config {
+        // the problems are already printed by load_config_file
+        match config::load_config_file(&config_file_name, &plugins::Plugins::new(), &scripting::Scripts::new()) {
+            Ok(_) => {
+                info!("{} is valid", config_file_name);
+                std::process::exit(0);
+            },
+            Err(_) => std::process::exit(1),
+        }
+    }
+    let mut bgp_proc = match BGPProcess::new(&config_file_name) {
+        Ok(bgp_proc) => bgp_proc,
+        Err(e) => {
+            error!("Unable to load config {} - {:?}", config_file_name, e);
+            std::process::exit(1);
+        }
+    };
+    cli.apply_listen_overrides(&mut bgp_proc.listen_config);
 
-}
+    // has to happen before the runtime starts its threads, fork only keeps the calling one
+    if cli.daemon {
+        // keep the working directory so relative config and pid file paths still work
+        if let Err(e) = nix::unistd::daemon(true, false) {
+            error!("Unable to run in the background - {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(pid_file) = &cli.pid_file && let Err(e) = write_pid_file(pid_file) {
+        error!("Unable to write pid file {} - {}", pid_file.display(), e);
+        std::process::exit(1);
+    }
 
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let result = runtime.block_on(run(bgp_proc, &cli.control_socket));
+    if let Some(pid_file) = &cli.pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+    if let Err(e) = result {
+        error!("Unable to run the BGP process - {:?}", e);
+        std::process::exit(1);
+    }
+}
 
+async fn run(bgp_proc: BGPProcess, control_socket: &Path) -> Result<(), errors::BGPError> {
+    let http_api_config = bgp_proc.process_config.http_api;
+    let grpc_api_config = bgp_proc.process_config.grpc_api;
+    let metrics_config = bgp_proc.process_config.metrics;
+    let tx_bgp_events = bgp_proc.tx_bgp_events.clone();
+    let bmp_servers = bgp_proc.bmp_servers.clone();
+    let global_settings = bgp_proc.global_settings.clone();
+    let rpki_caches = bgp_proc.rpki_config.caches.clone();
+    let mrt_dump_interval = bgp_proc.process_config.mrt_dump.as_ref().map_or(0, |mrt_dump| mrt_dump.interval);
+    let bgp: Arc<Mutex<BGPProcess>> = Arc::new(Mutex::new(bgp_proc));
+    let (tx_process_command, rx_process_command) = mpsc::channel::<ProcessCommand>(8);
+    // bound before anything else so a second instance on the same socket or port fails straight away
+    let http_listener = match http_api_config {
+        Some(http_api_config) => Some(http_api::bind_http_api(&http_api_config).await?),
+        None => None,
+    };
+    let grpc_listener = match grpc_api_config {
+        Some(grpc_api_config) => Some(grpc_api::bind_grpc_api(&grpc_api_config).await?),
+        None => None,
+    };
+    let metrics_listener = match metrics_config {
+        Some(metrics_config) => Some(metrics::bind_metrics(&metrics_config).await?),
+        None => None,
+    };
+    let control_listener = control::bind_control_socket(control_socket).await?;
+    if let Some(http_listener) = http_listener {
+        tokio::spawn(http_api::run_http_api(http_listener, tx_process_command.clone()));
+    }
+    if let Some(grpc_listener) = grpc_listener {
+        tokio::spawn(grpc_api::run_grpc_api(grpc_listener, tx_process_command.clone(), tx_bgp_events.clone()));
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        tokio::spawn(metrics::run_metrics(metrics_listener, tx_process_command.clone()));
+    }
+    if mrt_dump_interval > 0 {
+        mrt::table_dump::run_table_dump_timer(mrt_dump_interval, tx_process_command.clone());
+    }
+    bmp::run_bmp_clients(&bmp_servers, &global_settings, &tx_process_command, &tx_bgp_events);
+    rpki::rtr::run_rtr_clients(&rpki_caches, &global_settings.vrps, &tx_process_command);
+    run_reload_signal_loop(tx_process_command.clone());
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let result = tokio::select! {
+        result = BGPProcess::run_process_loop(bgp, rx_process_command) => result,
+        _ = control::run_control_socket_loop(control_listener, tx_process_command) => Ok(()),
+        _ = sigterm.recv() => {
+            info!("Received SIGTERM, shutting down");
+            Ok(())
+        },
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received SIGINT, shutting down");
+            Ok(())
+        },
+    };
+    let _ = std::fs::remove_file(control_socket);
+    result
+}
 
+// refuses to start over a pid file of an instance that is still running
+fn write_pid_file(pid_file: &Path) -> std::io::Result<()> {
+    if let Ok(contents) = std::fs::read_to_string(pid_file)
+        && let Ok(pid) = contents.trim().parse::<i32>()
+        && nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None).is_ok() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("process {} is still running", pid)));
+    }
+    std::fs::write(pid_file, format!("{}\n", std::process::id()))
+}
@@ -15,6 +15,7 @@ use open::*;
 use notification::*;
 
 use update::*;
+use tracing::warn;
 use crate::errors::{BGPError, MessageError};
 use crate::messages::route_refresh::{handle_route_refresh_message, RouteRefreshMessage};
 use crate::neighbors::Neighbor;
@@ -32,6 +33,15 @@ pub enum AddressFamily {
     IPv6,
 }
 
+impl AddressFamily {
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            AddressFamily::IPv4 => 1,
+            AddressFamily::IPv6 => 2,
+        }
+    }
+}
+
 
 //
 // #[derive(PartialEq, Debug, Clone)]
@@ -209,7 +219,7 @@ pub fn extract_messages_from_rec_data(tsbuf: &[u8]) -> Result<Vec<Vec<u8>>, Mess
                         u16::from_be_bytes(bytes.try_into().map_err(|_| MessageError::BadInt16Read)?)
                     },
                     None => {
-                        println!("Unable to read message_len, skipping all messages in this TCP payload");
+                        warn!(target: "codec", "Unable to read message_len, skipping all messages in this TCP payload");
                         return Err(MessageError::BadInt16Read)
                     }
                 };
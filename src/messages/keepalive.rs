@@ -2,6 +2,7 @@ use std::net::{ Ipv4Addr };
 use tokio::net::{TcpStream, TcpListener};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::OwnedWriteHalf;
+use tracing::debug;
 use crate::messages::header::*;
 use crate::messages::*;
 
@@ -10,17 +11,18 @@ use crate::messages::*;
 //     Ok(())
 // }
 
-pub async fn send_keepalive(stream: &mut OwnedWriteHalf) -> Result<(), MessageError> {
+// returns the bytes that went out, for the MRT archive
+pub async fn send_keepalive(stream: &mut OwnedWriteHalf) -> Result<Vec<u8>, MessageError> {
     //TODO add peer as input var and match against DB
     //TODO add periodic keepalives
-    println!("Preparing to send Keepalive");
+    debug!(target: "codec", "Preparing to send Keepalive");
     let message = KeepaliveMessage::new()?;
     let message_bytes = message.convert_to_bytes();
     let ts = stream.write_all(&message_bytes[..]).await;
     match ts {
         Ok(_) => {
-            println!("Sent Keepalive");
-            Ok(())
+            debug!(target: "codec", "Sent Keepalive");
+            Ok(message_bytes)
         },
         Err(_) => {
             Err(MessageError::UnableToWriteToTCPStream)
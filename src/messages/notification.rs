@@ -1,7 +1,10 @@
 use std::net::Ipv4Addr;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
 use std::convert::TryFrom;
 
+use tracing::debug;
 use crate::errors::MessageError;
 use crate::messages::{BGPVersion, MessageType};
 use crate::messages::header::MessageHeader;
@@ -57,6 +60,19 @@ impl TryFrom<&[u8]> for NotifErrorCode {
     }
 }
 
+impl NotifErrorCode {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            NotifErrorCode::MessageHeader => 1,
+            NotifErrorCode::OpenMessage => 2,
+            NotifErrorCode::UpdateMessage => 3,
+            NotifErrorCode::HoldTimerExpired => 4,
+            NotifErrorCode::FSM => 5,
+            NotifErrorCode::Cease => 6,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum NotifErrorSubCode {
     MsgHdr(NotifErrorMsgHdrSubCode),
@@ -64,7 +80,76 @@ pub enum NotifErrorSubCode {
     Update(NotifErrorUpdateSubCode),
     HoldTimerExpired,
     FSM,
-    Cease,
+    Cease(NotifErrorCeaseSubCode),
+}
+
+impl NotifErrorSubCode {
+    // Unknown goes out as 0, the unspecific subcode
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            NotifErrorSubCode::MsgHdr(sub_code) => match sub_code {
+                NotifErrorMsgHdrSubCode::ConnectionNotSynchronized => 1,
+                NotifErrorMsgHdrSubCode::BadMessageLength => 2,
+                NotifErrorMsgHdrSubCode::BadMessageType => 3,
+                NotifErrorMsgHdrSubCode::Unknown => 0,
+            },
+            NotifErrorSubCode::Open(sub_code) => match sub_code {
+                NotifErrorOpenSubCode::UnsupportedVersionNumber => 1,
+                NotifErrorOpenSubCode::BadPeerAS => 2,
+                NotifErrorOpenSubCode::BadBGPIdentifier => 3,
+                NotifErrorOpenSubCode::UnsupportedOptionalParameter => 4,
+                NotifErrorOpenSubCode::DeprecatedSubCode => 5,
+                NotifErrorOpenSubCode::UnacceptableHoldTime => 6,
+                NotifErrorOpenSubCode::UnsupportedAFI => 8,
+                NotifErrorOpenSubCode::Unknown => 0,
+            },
+            NotifErrorSubCode::Update(sub_code) => match sub_code {
+                NotifErrorUpdateSubCode::MalformedAttributeList => 1,
+                NotifErrorUpdateSubCode::UnrecognizedWellKnownAttribute => 2,
+                NotifErrorUpdateSubCode::MissingWellKnownAttribute => 3,
+                NotifErrorUpdateSubCode::AttributeFlagsError => 4,
+                NotifErrorUpdateSubCode::AttributeLengthError => 5,
+                NotifErrorUpdateSubCode::InvalidOriginAttribute => 6,
+                NotifErrorUpdateSubCode::DeprecatedSubCode => 7,
+                NotifErrorUpdateSubCode::InvalidNextHopAttribute => 8,
+                NotifErrorUpdateSubCode::OptionalAttributeError => 9,
+                NotifErrorUpdateSubCode::InvalidNetworkField => 10,
+                NotifErrorUpdateSubCode::MalformedASPath => 11,
+                NotifErrorUpdateSubCode::Unknown => 0,
+            },
+            NotifErrorSubCode::HoldTimerExpired | NotifErrorSubCode::FSM => 0,
+            NotifErrorSubCode::Cease(sub_code) => match sub_code {
+                NotifErrorCeaseSubCode::AdministrativeShutdown => 2,
+                NotifErrorCeaseSubCode::PeerDeconfigured => 3,
+                NotifErrorCeaseSubCode::AdministrativeReset => 4,
+                NotifErrorCeaseSubCode::ConnectionCollisionResolution => 7,
+                NotifErrorCeaseSubCode::Unknown => 0,
+            },
+        }
+    }
+}
+
+// RFC 4486, only the ones we send have names
+#[derive(PartialEq, Debug, Clone)]
+pub enum NotifErrorCeaseSubCode {
+    AdministrativeShutdown,
+    PeerDeconfigured,
+    AdministrativeReset,
+    ConnectionCollisionResolution,
+    Unknown,
+}
+
+impl From<u8> for NotifErrorCeaseSubCode {
+
+    fn from(value: u8) -> Self {
+        match value {
+            2 => NotifErrorCeaseSubCode::AdministrativeShutdown,
+            3 => NotifErrorCeaseSubCode::PeerDeconfigured,
+            4 => NotifErrorCeaseSubCode::AdministrativeReset,
+            7 => NotifErrorCeaseSubCode::ConnectionCollisionResolution,
+            _ => NotifErrorCeaseSubCode::Unknown
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -231,11 +316,32 @@ impl NotificationMessage {
             data
         })
     }
+
+    pub fn convert_to_bytes(&self) -> Vec<u8> {
+        let data = self.data.as_deref().unwrap_or_default();
+        let mut message: Vec<u8> = vec![0xFF; 16];
+        message.extend((21 + data.len() as u16).to_be_bytes());
+        message.push(MessageType::Notification.to_u8());
+        message.push(self.error.to_u8());
+        message.push(self.error_subcode.to_u8());
+        message.extend(data);
+        message
+    }
+}
+
+// returns the bytes that went out, for the MRT archive
+pub async fn send_notification(stream: &mut OwnedWriteHalf, message: &NotificationMessage) -> Result<Vec<u8>, MessageError> {
+    debug!(target: "codec", "Sending Notification {:?} {:?}", message.error, message.error_subcode);
+    let message_bytes = message.convert_to_bytes();
+    match stream.write_all(&message_bytes).await {
+        Ok(_) => Ok(message_bytes),
+        Err(_) => Err(MessageError::UnableToWriteToTCPStream),
+    }
 }
 
 
 pub fn extract_notification_message(tsbuf: &Vec<u8>) -> Result<NotificationMessage, MessageError> {
-    println!("Extracting notification message");
+    debug!(target: "codec", "Extracting notification message");
     let message_len = extract_u16_from_bytes(tsbuf, 16, 18)?;
     if message_len < 21 {
         return Err(MessageError::MessageLenTooLow)
@@ -276,7 +382,7 @@ pub fn extract_notification_message(tsbuf: &Vec<u8>) -> Result<NotificationMessa
                     NotifErrorSubCode::FSM
                 },
                 NotifErrorCode::Cease => {
-                    NotifErrorSubCode::Cease
+                    NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::from(*esc))
                 },
             }
 
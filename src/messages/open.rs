@@ -4,6 +4,7 @@ use tokio::net::{TcpStream, TcpListener};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use std::path::Path;
 use tokio::net::tcp::OwnedWriteHalf;
+use tracing::debug;
 use crate::config::MultiProtocolExtensionsConfig;
 use crate::errors::*;
 use crate::errors::MessageError::BadMultiProtocolExtValue;
@@ -63,7 +64,7 @@ pub fn extract_open_message(tsbuf: &Vec<u8>) -> Result<OpenMessage, MessageError
             opt_curr_idx += 1;
 
             let mut cap_curr_idx = opt_curr_idx;
-            // TODO Add the rest of the capabilities like Route Refresh, for now I just want to see the AS4 capability and multiprotocol
+            // TODO Add the rest of the capabilities, for now we only care about AS4, multiprotocol and route refresh
             let cap_type = extract_u8_from_bytes(tsbuf, cap_curr_idx, cap_curr_idx + 1)?;
             cap_curr_idx += 1;
 
@@ -88,6 +89,17 @@ pub fn extract_open_message(tsbuf: &Vec<u8>) -> Result<OpenMessage, MessageError
                 capabilities.push(Capability::MultiprotocolExtensions(mp_ext_val));
             }
 
+            // 2 is route refresh, 128 is the Cisco pre-standard one and 70 is enhanced route refresh, none of them have a value
+            if cap_type == 2 {
+                capabilities.push(Capability::RouteRefresh);
+            }
+            if cap_type == 128 {
+                capabilities.push(Capability::RouteRefreshPreStandard);
+            }
+            if cap_type == 70 {
+                capabilities.push(Capability::EnhancedRouteRefresh);
+            }
+
             // 65 is AS4
             if cap_type == 65 {
                 let as_ex_cap_len = extract_u8_from_bytes(tsbuf, cap_curr_idx, cap_curr_idx + 1)?;
@@ -182,15 +194,16 @@ pub fn get_neighbor_ipv4_address_from_stream(tcp_stream: &TcpStream) -> Result<I
 // }
 
 
-pub async fn send_open(stream: &mut OwnedWriteHalf, message: OpenMessage) -> Result<(), MessageError> {
-    println!("Preparing to send Open");
+// like the other send_ functions this hands back the bytes it wrote
+pub async fn send_open(stream: &mut OwnedWriteHalf, message: OpenMessage) -> Result<Vec<u8>, MessageError> {
+    debug!(target: "codec", "Preparing to send Open");
     match message.convert_to_bytes() {
         Ok(message_bytes) => {
             let res  =stream.write_all(&message_bytes[..]).await;
             match res {
                 Ok(_) => {
-                    println!("Sent Open");
-                    Ok(())
+                    debug!(target: "codec", "Sent Open");
+                    Ok(message_bytes)
                 },
                 Err(_) => {
                     Err(MessageError::UnableToWriteToTCPStream)
@@ -203,14 +216,14 @@ pub async fn send_open(stream: &mut OwnedWriteHalf, message: OpenMessage) -> Res
 
 }
 
-pub async fn send_update(stream: &mut OwnedWriteHalf, message: UpdateMessage, capabilities: &Option<Vec<Capability>>) -> Result<(), MessageError> {
-    println!("Preparing to send Update");
+pub async fn send_update(stream: &mut OwnedWriteHalf, message: UpdateMessage, capabilities: &Option<Vec<Capability>>) -> Result<Vec<u8>, MessageError> {
+    debug!(target: "codec", "Preparing to send Update");
     let message_bytes = message.convert_to_bytes(capabilities);
     let res  =stream.write_all(&message_bytes[..]).await;
     match res {
         Ok(_) => {
-            println!("Sent Update");
-            Ok(())
+            debug!(target: "codec", "Sent Update");
+            Ok(message_bytes)
         },
         Err(_) => {
             Err(MessageError::UnableToWriteToTCPStream)
@@ -13,6 +13,7 @@
 //     parameter_type: ParameterType,
 // }
 
+use tracing::warn;
 use crate::config::MultiProtocolExtensionsConfig;
 use crate::errors::MessageError;
 use crate::messages::notification::NotifErrorMsgHdrSubCode;
@@ -52,7 +53,7 @@ impl MPExtVal {
                 Ok(MPExtVal::IPv6VPN)
             },
             (_, _) => {
-                println!("MultiProtocol afi and safi not recognized, defaulting");
+                warn!(target: "codec", "MultiProtocol afi and safi not recognized, defaulting");
                 Err(MessageError::BadMultiProtocolExtValue)
             }
 
@@ -125,10 +126,16 @@ impl Capability {
                     },
                 }
             },
-            _ => {
-                println!("Unimplemented match arm in Capability::convert_to_bytes()");
-                return Err(MessageError::UnknownCapability)
-            }
+            // the route refresh capabilities have no value, just the code and a 0 len
+            Capability::RouteRefreshPreStandard => {
+                bytes.extend_from_slice(&[0x02, 0x02, 0x80, 0x00]);
+            },
+            Capability::RouteRefresh => {
+                bytes.extend_from_slice(&[0x02, 0x02, 0x02, 0x00]);
+            },
+            Capability::EnhancedRouteRefresh => {
+                bytes.extend_from_slice(&[0x02, 0x02, 0x46, 0x00]);
+            },
 
         }
 
@@ -2,43 +2,59 @@ use std::net::{ Ipv4Addr};
 use tokio::net::{TcpStream, TcpListener};
 //use std::io::{Read, Write};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
 
+use tracing::debug;
 use crate::messages::header::*;
 use crate::messages::*;
 use crate::utils::extract_u16_from_bytes;
 
 pub fn handle_route_refresh_message(tcp_stream: &mut TcpStream, tsbuf: &Vec<u8>) -> Result<(), MessageError> {
     //send_route_refresh(tcp_stream);
-    println!("Handling route refresh message");
+    debug!(target: "codec", "Handling route refresh message");
     // TODO handle route refresh
     match tsbuf.get(19..21) {
         Some(ts) => {
             let afi = extract_u16_from_bytes(tsbuf, 0, 2)?;
             if afi == 1 {
-                println!("Received route refresh for IPv4 AFI");
+                debug!(target: "codec", "Received route refresh for IPv4 AFI");
             }
             else if afi == 1 {
-                println!("Received route refresh for IPv4 AFI");
+                debug!(target: "codec", "Received route refresh for IPv4 AFI");
             }
         },
         None => {
-            println!("No AFI found in route refresh message");
+            debug!(target: "codec", "No AFI found in route refresh message");
             return Err(MessageError::RouteRefreshMissingAFI)
         }
     }
     // readv the adj rib out for the afi, safi pair
-    println!("Handled route refresh message");
+    debug!(target: "codec", "Handled route refresh message");
     Ok(())
 }
 
-pub async fn send_route_refresh(stream: &mut TcpStream, afi: AddressFamily, safi: SAFI) -> Result<(), MessageError> {
-    // TODO
-    println!("Preparing to send RouteRefresh");
+pub async fn send_route_refresh(stream: &mut OwnedWriteHalf, afi: AddressFamily, safi: SAFI) -> Result<Vec<u8>, MessageError> {
+    debug!(target: "codec", "Preparing to send RouteRefresh");
     let message = RouteRefreshMessage::new(afi, safi)?;
     let message_bytes = message.convert_to_bytes();
-    stream.write_all(&message_bytes[..]).await.unwrap();
-    println!("Sent RouteRefresh");
-    Ok(())
+    stream.write_all(&message_bytes[..]).await.map_err(|_| MessageError::UnableToWriteToTCPStream)?;
+    debug!(target: "codec", "Sent RouteRefresh");
+    Ok(message_bytes)
+}
+
+pub fn extract_route_refresh_message(tsbuf: &Vec<u8>) -> Result<RouteRefreshMessage, MessageError> {
+    // header, 2 byte AFI, 1 reserved byte, 1 byte SAFI
+    let afi = match extract_u16_from_bytes(tsbuf, 19, 21) {
+        Ok(1) => AddressFamily::IPv4,
+        Ok(2) => AddressFamily::IPv6,
+        _ => return Err(MessageError::RouteRefreshMissingAFI),
+    };
+    let safi = match tsbuf.get(22) {
+        Some(1) => SAFI::Unicast,
+        Some(2) => SAFI::Multicast,
+        _ => return Err(MessageError::RouteRefreshMissingAFI),
+    };
+    RouteRefreshMessage::new(afi, safi)
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -47,6 +63,15 @@ pub enum SAFI {
     Multicast
 }
 
+impl SAFI {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            SAFI::Unicast => 1,
+            SAFI::Multicast => 2,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct RouteRefreshMessage {
     pub message_header: MessageHeader,
@@ -79,6 +104,11 @@ impl RouteRefreshMessage {
         let msg_type: u8 = message_type.to_u8();
         len += 1;
 
+        let afi_bytes = self.afi.to_u16().to_be_bytes();
+        len += 2;
+        // reserved byte and SAFI
+        len += 2;
+
         // adding len to the vec must come second to last because we need the total len of the payload
         let len_bytes: [u8; 2] = len.to_be_bytes();
         message.push(len_bytes[0]);
@@ -86,6 +116,25 @@ impl RouteRefreshMessage {
 
         message.push(msg_type);
 
+        message.extend(afi_bytes);
+        message.push(0);
+        message.push(self.safi.to_u8());
+
         message
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_refresh_round_trip() {
+        let bytes = RouteRefreshMessage::new(AddressFamily::IPv4, SAFI::Unicast).unwrap().convert_to_bytes();
+        assert_eq!(bytes.len(), 23);
+        assert_eq!(&bytes[16..], &[0x00, 0x17, 0x05, 0x00, 0x01, 0x00, 0x01]);
+        let message = extract_route_refresh_message(&bytes).unwrap();
+        assert_eq!(message.afi, AddressFamily::IPv4);
+        assert_eq!(message.safi, SAFI::Unicast);
+    }
+}
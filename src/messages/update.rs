@@ -5,6 +5,7 @@ use tokio::net::{TcpStream, TcpListener};
 use std::io::{Bytes, Read, Write};
 use std::path::Path;
 use std::thread::current;
+use tracing::{debug, error, trace, warn};
 use crate::errors::{NeighborError, ProcessError};
 use crate::messages::header::*;
 use crate::messages::keepalive::*;
@@ -37,7 +38,7 @@ pub enum Flag {
     Optional(bool), // bit 0
     Transitive(bool), // bit 1
     Partial(bool), // bit 2
-    // TODO handle extended length causing the attribute length field to be 2 bytes
+    // the attribute length field is 2 bytes instead of 1
     ExtendedLength(bool), // bit 3
 }
 
@@ -110,6 +111,10 @@ impl Flags {
         flags
     }
 
+    pub fn is_extended_length(&self) -> bool {
+        self.extended_length == Flag::ExtendedLength(true)
+    }
+
 }
 
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -120,7 +125,8 @@ pub enum TypeCode {
     MultiExitDisc,
     LocalPref,
     AtomicAggregate,
-    Aggregator
+    Aggregator,
+    Communities
 }
 
 impl TypeCode {
@@ -134,6 +140,7 @@ impl TypeCode {
             5 => Ok(TypeCode::LocalPref),
             6 => Ok(TypeCode::AtomicAggregate),
             7 => Ok(TypeCode::Aggregator),
+            8 => Ok(TypeCode::Communities),
             _ => Err(MessageError::BadAttributeTypeCode)
         }
     }
@@ -148,6 +155,7 @@ impl TypeCode {
             TypeCode::LocalPref        =>   5,
             TypeCode::AtomicAggregate  =>   6,
             TypeCode::Aggregator       =>   7,
+            TypeCode::Communities      =>   8,
         }
     }
 }
@@ -232,13 +240,6 @@ pub enum AS {
 }
 
 impl AS {
-    pub fn to_u32(&self) -> Result<u32, ProcessError> {
-        match self {
-            AS::AS2(as_num) => Err(ProcessError::AS2Unhandled),
-            AS::AS4(as_num) => Ok(*as_num)
-        }
-    }
-
     pub fn to_u16(&self) -> Result<u16, ProcessError> {
         match self {
             AS::AS4(as_num) => Err(ProcessError::AS4Unhandled),
@@ -248,6 +249,15 @@ impl AS {
     
 }
 
+impl std::fmt::Display for AS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AS::AS2(as_num) => write!(f, "{}", as_num),
+            AS::AS4(as_num) => write!(f, "{}", as_num),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct AsPath {
     pub category: Category,
@@ -294,13 +304,13 @@ impl AsPath {
         }
 
         // variable as list
+        // paths learned over 2 byte sessions get widened, 4 byte ASNs that don't fit become AS_TRANS
         for as_num in &self.as_path_segment.as_list {
-            if as4_capability {
-                let num = as_num.to_u32()?;
-                bytes.extend(num.to_be_bytes());
-            } else {
-                let num = as_num.to_u16()?;
-                bytes.extend(num.to_be_bytes());
+            match (as4_capability, as_num) {
+                (true, AS::AS2(num)) => bytes.extend((*num as u32).to_be_bytes()),
+                (true, AS::AS4(num)) => bytes.extend(num.to_be_bytes()),
+                (false, AS::AS2(num)) => bytes.extend(num.to_be_bytes()),
+                (false, AS::AS4(num)) => bytes.extend(u16::try_from(*num).unwrap_or(23456).to_be_bytes()),
             }
         }
 
@@ -429,6 +439,10 @@ impl NextHop {
         }
     }
 
+    pub fn ipv4addr(&self) -> Ipv4Addr {
+        self.ipv4addr
+    }
+
 }
 
 
@@ -527,6 +541,29 @@ impl Aggregator {
     }
 }
 
+// RFC 1997, the high 16 bits of each value are the AS and the low 16 bits are local to it
+#[derive(PartialEq, Debug, Clone)]
+pub struct Communities {
+    category: Category,
+    pub values: Vec<u32>, // 4 bytes each
+}
+
+impl Communities {
+    pub fn new(values: Vec<u32>) -> Self {
+        Communities {
+            category: Category::OptionalTransitive,
+            values
+        }
+    }
+
+    pub fn from_vec_u8(bytes: &[u8]) -> Self {
+        Communities {
+            category: Category::OptionalTransitive,
+            values: bytes.chunks_exact(4).map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap())).collect(),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum PAdata {
     Origin(Origin),
@@ -535,7 +572,8 @@ pub enum PAdata {
     MultiExitDisc(MultiExitDisc),
     LocalPref(LocalPref),
     AtomicAggregate(AtomicAggregate),
-    Aggregator(Aggregator)
+    Aggregator(Aggregator),
+    Communities(Communities)
 }
 
 
@@ -581,6 +619,9 @@ impl PAdata {
             },
             TypeCode::Aggregator => {
                 PAdata::Aggregator(Aggregator::from_vec_u8(bytes, capabilities))
+            },
+            TypeCode::Communities => {
+                PAdata::Communities(Communities::from_vec_u8(bytes))
             }
         }
     }
@@ -590,13 +631,15 @@ impl PAdata {
 pub struct PathAttribute {
     pub flags: Flags,
     pub type_code: TypeCode,
-    pub len: u8,
+    // 2 bytes on the wire when the extended length flag is set
+    pub len: u16,
     pub data: PAdata,
     pub pa_data_len: u16
 }
 
 impl PathAttribute {
-    pub fn new(flags: Flags, type_code: TypeCode, len: u8, data: PAdata) -> Self {
+    pub fn new(flags: Flags, type_code: TypeCode, len: u16, data: PAdata) -> Self {
+        let header_len = if flags.is_extended_length() {4} else {3};
 
         let pa_data_len: u16 = match &data {
             Origin(_) => 4,
@@ -605,10 +648,8 @@ impl PathAttribute {
             MultiExitDisc(_) => 7,
             AtomicAggregate(_) => 3,
             Aggregator(_) => 11,
-            _ => {
-                // TODO validate that med, atomicagg, agg are correct
-                panic!("Unhandled PA DATA LEN in PathAttribute::new()");
-            }
+            LocalPref(_) => 7,
+            Communities(_) => header_len + len,
         };
 
         PathAttribute {
@@ -675,7 +716,7 @@ impl PathAttribute {
         flags.transitive = Flag::Transitive(true);
 
         // TODO replace len after testing is finished
-        let len = 2 + as_list_vec.len() as u8 * 4;
+        let len = 2 + as_list_vec.len() as u16 * 4;
         let pa_data_len = 5 + as_list_vec.len() as u16 * 4;
 
         let mut as_list = Vec::new();
@@ -741,7 +782,7 @@ impl PathAttribute {
 
         // TODO replace len after testing is finished
         // flag 1, type 1, len, seg type 1, seg len 1, as list variable
-        let len = 2 + as_path.as_path_segment.number_of_as * as_num_offset;
+        let len = 2 + as_path.as_path_segment.number_of_as as u16 * as_num_offset as u16;
         let pa_data_len = 5 + as_path.as_path_segment.number_of_as as u16 * as_num_offset as u16;
         let data = PAdata::AsPath(as_path);
 
@@ -754,6 +795,81 @@ impl PathAttribute {
         }
     }
 
+    // every attribute a RouteV4 carries, in type code order
+    pub fn all_from_route(route: &RouteV4, capabilities: &Option<Vec<Capability>>) -> Vec<PathAttribute> {
+        let mut path_attributes = vec![
+            PathAttribute::new_origin(route.origin),
+            PathAttribute::new_as_path(route.as_path.clone(), capabilities),
+            PathAttribute::new_next_hop(route.next_hop),
+        ];
+        if let Some(med) = route.multi_exit_disc {
+            path_attributes.push(PathAttribute::new_multi_exit_disc(med));
+        }
+        if let Some(local_pref) = route.local_pref {
+            path_attributes.push(PathAttribute::new_local_pref(local_pref));
+        }
+        if !route.communities.is_empty() {
+            path_attributes.push(PathAttribute::new_communities(route.communities.clone()));
+        }
+        path_attributes
+    }
+
+    pub fn new_communities(values: Vec<u32>) -> Self {
+        let mut flags = Flags::new();
+        flags.optional = Flag::Optional(true);
+        flags.transitive = Flag::Transitive(true);
+
+        let len = values.len() as u16 * 4;
+        // past 63 communities the length no longer fits in a byte
+        if len > u8::MAX as u16 {
+            flags.extended_length = Flag::ExtendedLength(true);
+        }
+        let pa_data_len = if len > u8::MAX as u16 {4} else {3} + len;
+
+        let data = PAdata::Communities(Communities::new(values));
+        PathAttribute {
+            flags,
+            type_code: TypeCode::Communities,
+            len,
+            data,
+            pa_data_len,
+        }
+    }
+
+    pub fn new_local_pref(local_pref: LocalPref) -> Self {
+        let mut flags = Flags::new();
+        flags.transitive = Flag::Transitive(true);
+
+        let len = 4;
+        let pa_data_len = 7;
+
+        let data = PAdata::LocalPref(local_pref);
+        PathAttribute {
+            flags,
+            type_code: TypeCode::LocalPref,
+            len,
+            data,
+            pa_data_len,
+        }
+    }
+
+    pub fn new_multi_exit_disc(med: MultiExitDisc) -> Self {
+        let mut flags = Flags::new();
+        flags.optional = Flag::Optional(true);
+
+        let len = 4;
+        let pa_data_len = 7;
+
+        let data = PAdata::MultiExitDisc(med);
+        PathAttribute {
+            flags,
+            type_code: TypeCode::MultiExitDisc,
+            len,
+            data,
+            pa_data_len,
+        }
+    }
+
     pub fn new_origin(origin: Origin) -> Self {
         let mut flags = Flags::new();
         flags.transitive = Flag::Transitive(true);
@@ -841,8 +957,11 @@ impl PathAttribute {
         pa_bytes.extend(type_code);
 
         // len
-        let len = self.len.to_be_bytes();
-        pa_bytes.extend(len);
+        if self.flags.is_extended_length() {
+            pa_bytes.extend(self.len.to_be_bytes());
+        } else {
+            pa_bytes.extend((self.len as u8).to_be_bytes());
+        }
 
         //data variable
         match &self.data {
@@ -867,6 +986,11 @@ impl PathAttribute {
             },
             PAdata::Aggregator(agg) => {
                 pa_bytes.extend(agg.ipv4addr.to_bits().to_be_bytes());
+            },
+            PAdata::Communities(communities) => {
+                for value in &communities.values {
+                    pa_bytes.extend(value.to_be_bytes());
+                }
             }
         }
 
@@ -937,6 +1061,14 @@ impl PathAttribute {
                     }
                 }
                 None
+            },
+            TypeCode::Communities => {
+                for pa in pa_vec {
+                    if matches!(pa.data, PAdata::Communities(_)) {
+                        return Some(pa.data.clone())
+                    }
+                }
+                None
             }
         }
 
@@ -959,7 +1091,7 @@ pub struct UpdateMessage {
 }
 
 pub fn extract_update_message(tsbuf: &Vec<u8>, optional_parameters: &Option<Vec<Capability>>) -> Result<UpdateMessage, MessageError> {
-    println!("Extracting update message");
+    debug!(target: "codec", "Extracting update message");
     let message_len = extract_u16_from_bytes(tsbuf, 16, 18)?;
     if message_len < 23 {
         return Err(MessageError::MessageLenTooLow)
@@ -967,7 +1099,7 @@ pub fn extract_update_message(tsbuf: &Vec<u8>, optional_parameters: &Option<Vec<
     //println!("message_len: {}", message_len);
     let withdrawn_route_len = extract_u16_from_bytes(tsbuf, 19, 21)?;
     if withdrawn_route_len > 0 {
-        println!("withdrawn route len is : {}", withdrawn_route_len);
+        trace!(target: "codec", "withdrawn route len is : {}", withdrawn_route_len);
     }
     
     let mut current_idx = 0;
@@ -1005,7 +1137,7 @@ pub fn extract_update_message(tsbuf: &Vec<u8>, optional_parameters: &Option<Vec<
             match NLRI::new(route_u32, prefix_len) {
                 Ok(nl) => { routes.push(nl); },
                 Err(e) => {
-                    println!("Error: {:#?}", e);
+                    error!(target: "codec", "{:?}", e);
                 }
             }
             // regardless we need to inc the idx
@@ -1058,7 +1190,14 @@ pub fn extract_update_message(tsbuf: &Vec<u8>, optional_parameters: &Option<Vec<
             pa_idx += 1;
             //println!("current_idx {}", current_idx);
 
-            let len = extract_u8_from_bytes(tsbuf, current_idx, current_idx + 1)?;
+            let len = if flags.is_extended_length() {
+                let len = extract_u16_from_bytes(tsbuf, current_idx, current_idx + 2)?;
+                current_idx += 1;
+                pa_idx += 1;
+                len
+            } else {
+                extract_u8_from_bytes(tsbuf, current_idx, current_idx + 1)? as u16
+            };
             current_idx += 1;
             if current_idx >= message_len as usize {return Err(MessageError::UpdateMessageLenAndIdxMismatch)}
             pa_idx += 1;
@@ -1069,7 +1208,7 @@ pub fn extract_update_message(tsbuf: &Vec<u8>, optional_parameters: &Option<Vec<
                 for x in 0..len as usize {
                     match tsbuf.get(current_idx + x) {
                         Some(byte) => { bytes.push(*byte); },
-                        None => { println!("{:#?}", MessageError::InvalidBufferIndex) } // should not return error, just pass
+                        None => { warn!(target: "codec", "{:?}", MessageError::InvalidBufferIndex) } // should not return error, just pass
                     }
                 }
                 bytes
@@ -1148,7 +1287,7 @@ pub fn extract_nlri_from_update_message(tsbuf: &Vec<u8>, message_len: usize, mut
     match NLRI::new(route_u32, prefix_len) {
         Ok(nl) => { nlri.push(nl); },
         Err(e) => {
-            println!("Error: {:#?}", e);
+            error!(target: "codec", "{:?}", e);
         }
     }
     // regardless we need to inc the idx
@@ -1240,6 +1379,13 @@ impl UpdateMessage {
 
     }
 
+    // one route with all of its attributes, as it sits in a RIB rather than as we'd advertise it
+    pub fn from_route(route: &RouteV4, capabilities: &Option<Vec<Capability>>) -> Result<Self, MessageError> {
+        let path_attributes = PathAttribute::all_from_route(route, capabilities);
+        let pa_len = path_attributes.iter().map(|pa| pa.pa_data_len).sum();
+        UpdateMessage::new(None, 0, None, pa_len, Some(path_attributes), Some(vec![route.nlri.clone()]), capabilities)
+    }
+
     pub fn convert_to_bytes(&self, capabilities: &Option<Vec<Capability>>) -> Vec<u8> {
         let mut message: Vec<u8> = vec![0xFF; 16]; // 16
 
@@ -1252,25 +1398,20 @@ impl UpdateMessage {
         len += 1; // 19
 
 
-        // withdrawn routes len.
-        let wr_len_bytes = self.withdrawn_route_len.to_be_bytes();
         len += 2; // 21
 
         // 21
 
         //variable withdrawn routes
-        let mut wr_vec_len: u16 = 0;
         let mut withdrawn_routes_bytes: Vec<u8> = Vec::new();
-        if self.withdrawn_route_len > 0 && self.withdrawn_routes.is_some() {
-            // can't trust anything since these updates may be generated by someone fuzzing
-            let wr_vec = self.withdrawn_routes.as_ref().unwrap();
-            wr_vec_len = wr_vec.len() as u16;
-            // TODO remove unwrap and handle
+        if let Some(wr_vec) = &self.withdrawn_routes {
             for wr in wr_vec {
-                withdrawn_routes_bytes.extend(wr.convert_to_bytes());
+                withdrawn_routes_bytes.extend(wr.convert_to_prefix_bytes());
             }
         }
-        len += wr_vec_len;
+        // withdrawn routes len is in bytes, not routes
+        let wr_len_bytes = (withdrawn_routes_bytes.len() as u16).to_be_bytes();
+        len += withdrawn_routes_bytes.len() as u16;
 
 
 
@@ -1292,7 +1433,7 @@ impl UpdateMessage {
                             path_att_bytes.extend(pa_bytes);
                         },
                         Err(e) => {
-                            println!("Error converting PA to bytes: {:#?}", e);
+                            error!(target: "codec", "Unable to convert PA to bytes: {:?}", e);
                         }
                     }
                 }
@@ -0,0 +1,179 @@
+use tracing::{debug, error, info};
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, Mutex};
+use tokio::sync::mpsc::Sender;
+use crate::config::MetricsConfig;
+use crate::errors::*;
+use crate::finite_state_machine::State as FsmState;
+use crate::neighbors::Neighbor;
+use crate::process::{BGPProcess, ProcessCommand};
+
+// Prometheus text format on /metrics. The counters live in Neighbor and BGPProcess, every scrape asks
+// the process loop to render them so nothing here needs its own locking.
+
+#[derive(Debug, Default, Clone)]
+pub struct BestPathTiming {
+    pub runs: u64,
+    pub total: Duration,
+    pub last: Duration,
+}
+
+impl BestPathTiming {
+    pub fn record(&mut self, duration: Duration) {
+        self.runs += 1;
+        self.total += duration;
+        self.last = duration;
+    }
+}
+
+pub async fn bind_metrics(metrics_config: &MetricsConfig) -> Result<TcpListener, ProcessError> {
+    let socket_addr = SocketAddr::new(metrics_config.address, metrics_config.port);
+    let listener = TcpListener::bind(socket_addr).await.map_err(|e| {
+        error!("Unable to bind metrics endpoint on {} - {}", socket_addr, e);
+        ProcessError::UnableToBindMetrics
+    })?;
+    info!("Metrics listening on {}/metrics", socket_addr);
+    Ok(listener)
+}
+
+pub async fn run_metrics(listener: TcpListener, tx_process_command: Sender<ProcessCommand>) {
+    let router = Router::new().route("/metrics", get(get_metrics)).with_state(tx_process_command);
+    if let Err(e) = axum::serve(listener, router).await {
+        error!("Metrics endpoint stopped - {}", e);
+    }
+}
+
+async fn get_metrics(State(tx): State<Sender<ProcessCommand>>) -> impl IntoResponse {
+    debug!("Metrics scrape");
+    let (tx_response, rx_response) = oneshot::channel();
+    if tx.send(ProcessCommand::Metrics(tx_response)).await.is_err() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "BGP process is shutting down"));
+    }
+    match rx_response.await {
+        Ok(text) => Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text)),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "BGP process dropped the request")),
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, v)).collect();
+    match labels.is_empty() {
+        true => { let _ = writeln!(out, "{} {}", name, value); },
+        false => { let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value); },
+    }
+}
+
+fn neighbor_family<F: Fn(&Neighbor, &str, &mut String)>(out: &mut String, neighbors: &[&Neighbor], name: &str, kind: &str, help: &str, f: F) {
+    family(out, name, kind, help);
+    for neighbor in neighbors {
+        f(neighbor, &neighbor.ip.to_string(), out);
+    }
+}
+
+pub async fn render_metrics(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, all_neighbors: &HashMap<Ipv4Addr, Neighbor>,
+                            running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>) -> String {
+    let mut out = String::new();
+    {
+        let bgp_proc = bgp_proc_arc.lock().await;
+        family(&mut out, "bgp_adj_rib_in_prefixes", "gauge", "Prefixes in the Adj-RIB-In of every neighbor together");
+        sample(&mut out, "bgp_adj_rib_in_prefixes", &[], bgp_proc.adj_rib_in.len());
+        family(&mut out, "bgp_adj_rib_in_paths", "gauge", "Paths in the Adj-RIB-In of every neighbor together");
+        sample(&mut out, "bgp_adj_rib_in_paths", &[], bgp_proc.adj_rib_in.values().map(|paths| paths.len()).sum::<usize>());
+        family(&mut out, "bgp_local_rib_prefixes", "gauge", "Prefixes with a best path in the Loc-RIB");
+        sample(&mut out, "bgp_local_rib_prefixes", &[], bgp_proc.local_rib.len());
+        let timing = &bgp_proc.best_path_timing;
+        family(&mut out, "bgp_best_path_duration_seconds", "summary", "Time spent choosing the best path for a prefix");
+        sample(&mut out, "bgp_best_path_duration_seconds_sum", &[], timing.total.as_secs_f64());
+        sample(&mut out, "bgp_best_path_duration_seconds_count", &[], timing.runs);
+        family(&mut out, "bgp_best_path_last_duration_seconds", "gauge", "Time the last best path run took");
+        sample(&mut out, "bgp_best_path_last_duration_seconds", &[], timing.last.as_secs_f64());
+    }
+
+    let mut running_guards = Vec::new();
+    for neighbor_arc in running_neighbors.values() {
+        running_guards.push(neighbor_arc.lock().await);
+    }
+    let mut neighbors: Vec<&Neighbor> = all_neighbors.values().chain(running_guards.iter().map(|guard| &**guard)).collect();
+    neighbors.sort_by_key(|n| n.ip);
+    render_neighbor_metrics(&mut out, &neighbors);
+    out
+}
+
+fn render_neighbor_metrics(out: &mut String, neighbors: &[&Neighbor]) {
+    neighbor_family(out, neighbors, "bgp_neighbor_state", "gauge", "1 for the FSM state the neighbor is in", |n, ip, out| {
+        for state in FsmState::ALL {
+            sample(out, "bgp_neighbor_state", &[("neighbor", ip), ("state", &state.to_string())], (n.fsm.state == state) as u8);
+        }
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_uptime_seconds", "gauge", "Time since the session was established, 0 when it is down", |n, ip, out| {
+        let uptime = n.established_for().map_or(0.0, |t| t.as_secs_f64());
+        sample(out, "bgp_neighbor_uptime_seconds", &[("neighbor", ip)], uptime);
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_messages_received_total", "counter", "Messages received by type", |n, ip, out| {
+        for (message_type, count) in n.stats.messages_received.by_type() {
+            sample(out, "bgp_neighbor_messages_received_total", &[("neighbor", ip), ("type", message_type)], count);
+        }
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_messages_sent_total", "counter", "Messages sent by type", |n, ip, out| {
+        for (message_type, count) in n.stats.messages_sent.by_type() {
+            sample(out, "bgp_neighbor_messages_sent_total", &[("neighbor", ip), ("type", message_type)], count);
+        }
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_update_prefixes_received_total", "counter", "Prefixes announced in received UPDATEs", |n, ip, out| {
+        sample(out, "bgp_neighbor_update_prefixes_received_total", &[("neighbor", ip)], n.stats.prefixes_received);
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_withdrawn_prefixes_received_total", "counter", "Prefixes withdrawn in received UPDATEs", |n, ip, out| {
+        sample(out, "bgp_neighbor_withdrawn_prefixes_received_total", &[("neighbor", ip)], n.stats.withdraws_received);
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_prefixes_accepted", "gauge", "Prefixes in the neighbor's Adj-RIB-In", |n, ip, out| {
+        sample(out, "bgp_neighbor_prefixes_accepted", &[("neighbor", ip)], n.prefix_counts().accepted);
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_prefixes_advertised", "gauge", "Prefixes in the neighbor's Adj-RIB-Out", |n, ip, out| {
+        sample(out, "bgp_neighbor_prefixes_advertised", &[("neighbor", ip)], n.prefix_counts().advertised);
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_notifications_received_total", "counter", "NOTIFICATIONs received by error code", |n, ip, out| {
+        for (code, count) in &n.stats.notifications_received {
+            sample(out, "bgp_neighbor_notifications_received_total", &[("neighbor", ip), ("code", code)], count);
+        }
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_notifications_sent_total", "counter", "NOTIFICATIONs sent by error code", |n, ip, out| {
+        for (code, count) in &n.stats.notifications_sent {
+            sample(out, "bgp_neighbor_notifications_sent_total", &[("neighbor", ip), ("code", code)], count);
+        }
+    });
+    neighbor_family(out, neighbors, "bgp_neighbor_flaps_total", "counter", "Times an Established session went down", |n, ip, out| {
+        sample(out, "bgp_neighbor_flaps_total", &[("neighbor", ip)], n.stats.flaps);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_format() {
+        let mut out = String::new();
+        family(&mut out, "bgp_neighbor_flaps_total", "counter", "Times an Established session went down");
+        sample(&mut out, "bgp_neighbor_flaps_total", &[("neighbor", "10.0.0.2")], 3);
+        sample(&mut out, "bgp_local_rib_prefixes", &[], 10);
+        assert_eq!(out, "# HELP bgp_neighbor_flaps_total Times an Established session went down\n\
+                         # TYPE bgp_neighbor_flaps_total counter\n\
+                         bgp_neighbor_flaps_total{neighbor=\"10.0.0.2\"} 3\n\
+                         bgp_local_rib_prefixes 10\n");
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use crate::messages::optional_parameters::Capability;
+
+// MRT (RFC 6396), the format bgpdump, bgpkit and the route collectors all read
+
+pub mod table_dump;
+pub mod bgp4mp;
+pub mod replay;
+
+pub const TABLE_DUMP_V2: u16 = 13;
+
+// MRT has 4 byte ASNs everywhere, encoding with this capability gets us that
+pub fn as4_capabilities() -> Option<Vec<Capability>> {
+    Some(vec![Capability::Extended4ByteASN(0)])
+}
+
+pub fn unix_seconds(at: SystemTime) -> u32 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32
+}
+
+// common header, then the body
+pub fn record(timestamp: u32, mrt_type: u16, subtype: u16, body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(12 + body.len());
+    record.extend(timestamp.to_be_bytes());
+    record.extend(mrt_type.to_be_bytes());
+    record.extend(subtype.to_be_bytes());
+    record.extend((body.len() as u32).to_be_bytes());
+    record.extend(body);
+    record
+}
+
+// e.g. rib.20261018.235400 like the route collectors name theirs, with .gz when compressed
+pub fn file_name(kind: &str, at: SystemTime, compress: bool) -> String {
+    numbered_file_name(kind, at, compress, 0)
+}
+
+// a second file of a kind in the same second gets _1, _2, ... after the time instead of replacing the first, it still
+// sorts after it
+pub fn unused_file_name(directory: &Path, kind: &str, at: SystemTime, compress: bool) -> String {
+    (0..).map(|number| numbered_file_name(kind, at, compress, number))
+        .find(|name| !directory.join(name).exists() && !directory.join(format!(".{}", name)).exists())
+        .unwrap_or_default()
+}
+
+fn numbered_file_name(kind: &str, at: SystemTime, compress: bool, number: u32) -> String {
+    let at: DateTime<Utc> = at.into();
+    let number = if number > 0 { format!("_{}", number) } else { String::new() };
+    let suffix = if compress { ".gz" } else { "" };
+    format!("{}.{}{}{}", kind, at.format("%Y%m%d.%H%M%S"), number, suffix)
+}
+
+pub enum MrtFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl MrtFile {
+    pub fn create(path: &Path, compress: bool) -> io::Result<Self> {
+        Ok(MrtFile::new(File::create(path)?, compress))
+    }
+
+    // picks up where an earlier writer left off, a gzip file then has several members which zcat and bgpdump read fine
+    pub fn append(path: &Path, compress: bool) -> io::Result<Self> {
+        Ok(MrtFile::new(OpenOptions::new().create(true).append(true).open(path)?, compress))
+    }
+
+    fn new(file: File, compress: bool) -> Self {
+        let file = BufWriter::new(file);
+        match compress {
+            true => MrtFile::Gzip(GzEncoder::new(file, Compression::default())),
+            false => MrtFile::Plain(file),
+        }
+    }
+
+    // a gzip file isn't readable until its trailer is written, so this has to be called
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            MrtFile::Plain(mut file) => file.flush(),
+            MrtFile::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for MrtFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MrtFile::Plain(file) => file.write(buf),
+            MrtFile::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MrtFile::Plain(file) => file.flush(),
+            MrtFile::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// the file shows up under its final name once it's complete, so a reader never gets half of it
+pub fn write_file<F: FnOnce(&mut MrtFile) -> io::Result<()>>(directory: &Path, name: &str, compress: bool, f: F) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let partial_path = directory.join(format!(".{}", name));
+    let mut file = MrtFile::create(&partial_path, compress)?;
+    f(&mut file)?;
+    file.finish()?;
+    let path = directory.join(name);
+    fs::rename(&partial_path, &path)?;
+    Ok(path)
+}
+
+// keeps the newest files of a kind, 0 keeps everything
+pub fn remove_old_files(directory: &Path, kind: &str, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let prefix = format!("{}.", kind);
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path())
+        .collect();
+    // the timestamps in the names sort the same way as the times
+    paths.sort();
+    let remove = paths.len().saturating_sub(keep);
+    for path in &paths[..remove] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
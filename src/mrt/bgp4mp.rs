@@ -0,0 +1,186 @@
+use tracing::{error, info};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use crate::config::MrtArchiveConfig;
+use crate::finite_state_machine::State;
+use crate::mrt::{self, MrtFile};
+
+// BGP4MP archive of the messages neighbors send and receive, plus their FSM transitions. Every neighbor with
+// archive_messages on hands its records to one writer thread, which starts a new "updates" file every
+// rotate_interval like the route collectors do.
+
+pub const BGP4MP: u16 = 16;
+// BGP4MP with microsecond timestamps, the same records as plain BGP4MP otherwise
+pub const BGP4MP_ET: u16 = 17;
+pub const BGP4MP_STATE_CHANGE: u16 = 0;
+pub const BGP4MP_MESSAGE: u16 = 1;
+pub const BGP4MP_STATE_CHANGE_AS4: u16 = 5;
+pub const BGP4MP_MESSAGE_AS4: u16 = 4;
+// the messages we sent
+pub const BGP4MP_MESSAGE_AS4_LOCAL: u16 = 7;
+const AFI_IPV4: u16 = 1;
+
+// the session a record belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bgp4mpPeer {
+    pub peer_as: u32,
+    pub local_as: u32,
+    pub peer_ip: Ipv4Addr,
+    pub local_ip: Ipv4Addr,
+}
+
+// a neighbor's handle on the archive, records are dropped while enabled is off
+#[derive(Debug, Clone)]
+pub struct MessageArchive {
+    pub tx: UnboundedSender<Vec<u8>>,
+    pub enabled: bool,
+    pub peer: Bgp4mpPeer,
+}
+
+impl MessageArchive {
+    pub fn new(tx: UnboundedSender<Vec<u8>>, peer_ip: Ipv4Addr, local_as: u32) -> Self {
+        MessageArchive { tx, enabled: false, peer: Bgp4mpPeer { peer_as: 0, local_as, peer_ip, local_ip: Ipv4Addr::UNSPECIFIED } }
+    }
+
+    // a full BGP message, marker included
+    pub fn record_received(&self, pdu: &[u8]) {
+        if self.enabled {
+            let _ = self.tx.send(message_record(SystemTime::now(), &self.peer, BGP4MP_MESSAGE_AS4, pdu));
+        }
+    }
+
+    pub fn record_sent(&self, pdu: &[u8]) {
+        if self.enabled {
+            let _ = self.tx.send(message_record(SystemTime::now(), &self.peer, BGP4MP_MESSAGE_AS4_LOCAL, pdu));
+        }
+    }
+
+    pub fn record_state_change(&self, old_state: State, new_state: State) {
+        if self.enabled {
+            let _ = self.tx.send(state_change_record(SystemTime::now(), &self.peer, old_state, new_state));
+        }
+    }
+}
+
+// RFC 6396 numbers the states from 1
+pub fn state_code(state: State) -> u16 {
+    match state {
+        State::Idle => 1,
+        State::Connect => 2,
+        State::Active => 3,
+        State::OpenSent => 4,
+        State::OpenConfirm => 5,
+        State::Established => 6,
+    }
+}
+
+fn peer_header(peer: &Bgp4mpPeer) -> Vec<u8> {
+    let mut body = Vec::with_capacity(20);
+    body.extend(peer.peer_as.to_be_bytes());
+    body.extend(peer.local_as.to_be_bytes());
+    // interface index, we don't track one
+    body.extend(0u16.to_be_bytes());
+    body.extend(AFI_IPV4.to_be_bytes());
+    body.extend(peer.peer_ip.octets());
+    body.extend(peer.local_ip.octets());
+    body
+}
+
+// the microseconds sit in front of the body and count towards its length
+fn et_record(at: SystemTime, subtype: u16, body: &[u8]) -> Vec<u8> {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut et_body = Vec::with_capacity(4 + body.len());
+    et_body.extend(since_epoch.subsec_micros().to_be_bytes());
+    et_body.extend(body);
+    mrt::record(since_epoch.as_secs() as u32, BGP4MP_ET, subtype, &et_body)
+}
+
+pub fn message_record(at: SystemTime, peer: &Bgp4mpPeer, subtype: u16, pdu: &[u8]) -> Vec<u8> {
+    let mut body = peer_header(peer);
+    body.extend(pdu);
+    et_record(at, subtype, &body)
+}
+
+pub fn state_change_record(at: SystemTime, peer: &Bgp4mpPeer, old_state: State, new_state: State) -> Vec<u8> {
+    let mut body = peer_header(peer);
+    body.extend(state_code(old_state).to_be_bytes());
+    body.extend(state_code(new_state).to_be_bytes());
+    et_record(at, BGP4MP_STATE_CHANGE_AS4, &body)
+}
+
+pub fn run_message_archive(config: MrtArchiveConfig) -> UnboundedSender<Vec<u8>> {
+    // unbounded so a neighbor never waits on the disk, and nothing gets left out of the archive
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let rotate_interval = config.rotate_interval.max(1) as u64;
+    std::thread::spawn(move || {
+        let mut current: Option<(u64, PathBuf, MrtFile)> = None;
+        while let Some(record) = rx.blocking_recv() {
+            // files start on rotate_interval boundaries, e.g. :00, :15, :30 and :45 for 900 seconds
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let period = now - now % rotate_interval;
+            if current.as_ref().is_none_or(|(started, _, _)| *started != period) {
+                if let Some((_, path, file)) = current.take() && let Err(e) = file.finish() {
+                    error!("Unable to finish MRT archive {} - {}", path.display(), e);
+                }
+                current = open_archive_file(&config, period);
+            }
+            let Some((_, path, file)) = current.as_mut() else { continue };
+            // everything that queued up meanwhile goes out in one go, then a flush so a crash loses little
+            let mut result = file.write_all(&record);
+            while let Ok(record) = rx.try_recv() && result.is_ok() {
+                result = file.write_all(&record);
+            }
+            if let Err(e) = result.and_then(|_| file.flush()) {
+                error!("Unable to write MRT archive {}, starting a new file - {}", path.display(), e);
+                current = None;
+            }
+        }
+        if let Some((_, path, file)) = current && let Err(e) = file.finish() {
+            error!("Unable to finish MRT archive {} - {}", path.display(), e);
+        }
+    });
+    tx
+}
+
+fn open_archive_file(config: &MrtArchiveConfig, period: u64) -> Option<(u64, PathBuf, MrtFile)> {
+    let name = mrt::file_name("updates", UNIX_EPOCH + Duration::from_secs(period), config.compress);
+    let path = config.directory.join(name);
+    // a restart inside the same period carries on with the file instead of truncating it
+    let opened = std::fs::create_dir_all(&config.directory).and_then(|_| MrtFile::append(&path, config.compress));
+    match opened {
+        Ok(file) => {
+            info!("Archiving BGP messages to {}", path.display());
+            if let Err(e) = mrt::remove_old_files(&config.directory, "updates", config.keep) {
+                error!("Unable to remove old MRT archives from {} - {}", config.directory.display(), e);
+            }
+            Some((period, path, file))
+        },
+        Err(e) => {
+            error!("Unable to create MRT archive {} - {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_record() {
+        let peer = Bgp4mpPeer { peer_as: 65001, local_as: 65000, peer_ip: Ipv4Addr::new(10, 0, 0, 2), local_ip: Ipv4Addr::new(10, 0, 0, 1) };
+        let keepalive = [vec![0xff; 16], vec![0, 19, 4]].concat();
+        let record = message_record(UNIX_EPOCH + Duration::from_micros(1_000_000_250), &peer, BGP4MP_MESSAGE_AS4, &keepalive);
+        assert_eq!(&record[..12], &[0, 0, 0x03, 0xe8, 0, 17, 0, 4, 0, 0, 0, 4 + 20 + 19]);
+        assert_eq!(&record[12..16], &250u32.to_be_bytes());
+        assert_eq!(&record[16..36], &[0, 0, 0xfd, 0xe9, 0, 0, 0xfd, 0xe8, 0, 0, 0, 1, 10, 0, 0, 2, 10, 0, 0, 1]);
+        assert_eq!(&record[36..], &keepalive[..]);
+
+        let record = state_change_record(UNIX_EPOCH, &peer, State::OpenConfirm, State::Established);
+        assert_eq!(&record[6..8], &BGP4MP_STATE_CHANGE_AS4.to_be_bytes());
+        assert_eq!(&record[record.len() - 4..], &[0, 5, 0, 6]);
+    }
+}
@@ -0,0 +1,589 @@
+use tracing::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use flate2::read::MultiGzDecoder;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep_until, Instant};
+use crate::channels::{ChannelWatcherMessage, NeighborChannel};
+use crate::config::{EffectiveMrtReplayConfig, MrtReplayConfig, NeighborSettings, ReplayTiming};
+use crate::errors::MessageError;
+use crate::messages::update::{AS, Aggregator, AsPath, AsPathSegment, AsPathSegmentType, AtomicAggregate, Communities, LocalPref, MultiExitDisc, NextHop, Origin};
+use crate::mrt::{self, TABLE_DUMP_V2};
+use crate::mrt::bgp4mp::{BGP4MP, BGP4MP_ET, BGP4MP_MESSAGE, BGP4MP_MESSAGE_AS4, BGP4MP_STATE_CHANGE, BGP4MP_STATE_CHANGE_AS4};
+use crate::mrt::table_dump::{PEER_INDEX_TABLE, RIB_IPV4_UNICAST};
+use crate::neighbors::{Neighbor, PeerType};
+use crate::process::BGPProcess;
+use crate::routes::{RouteV4, NLRI};
+
+// Replays an MRT file into the RIBs. Every peer in the file becomes a virtual neighbor under its own address and AS, a
+// Neighbor without a session that takes the replay's peer group settings, so its routes go through the same import
+// policy, plugin and script into the Adj-RIB-In and best path as a real neighbor's. A TABLE_DUMP_V2 gives each peer its
+// table, BGP4MP replays the UPDATEs and withdraws a peer's routes when its session leaves Established. Only IPv4
+// unicast is replayed, everything else in the file is skipped, the messages the recording router sent itself included.
+
+const AFI_IPV4: u16 = 1;
+const MESSAGE_TYPE_UPDATE: u8 = 2;
+const STATE_ESTABLISHED: u16 = 6;
+// a BGP message is at most 4096 bytes, this leaves room for a RIB entry with a path from every peer a collector has
+const MAX_RECORD_LEN: u32 = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+enum ReplayAction {
+    // the first time the file mentions a peer
+    Peer { ip: Ipv4Addr, as_num: u32 },
+    Announce { peer: Ipv4Addr, routes: Vec<RouteV4> },
+    Withdraw { peer: Ipv4Addr, nlris: Vec<NLRI> },
+    // the session left Established, everything the peer announced goes
+    Down { peer: Ipv4Addr },
+}
+
+#[derive(Debug)]
+struct ReplayRecord {
+    // since the epoch, from the MRT header
+    at: Duration,
+    action: ReplayAction,
+}
+
+// reads big endian fields off the front of a record, None once it runs out
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn ipv4(&mut self) -> Option<Ipv4Addr> {
+        Some(Ipv4Addr::from(self.u32()?))
+    }
+
+    fn nlri(&mut self) -> Option<Result<NLRI, ()>> {
+        let len = self.u8()?;
+        if len > 32 {
+            return None;
+        }
+        let mut octets = [0u8; 4];
+        let prefix = self.take((len as usize).div_ceil(8))?;
+        octets[..prefix.len()].copy_from_slice(prefix);
+        // a default route is well formed, NLRI just can't hold it
+        Some(NLRI::new(Ipv4Addr::from(octets), len).map_err(|_| ()))
+    }
+
+    // the prefixes NLRI can hold, None if the encoding is broken
+    fn nlris(&mut self) -> Option<Vec<NLRI>> {
+        let mut nlris = Vec::new();
+        while !self.bytes.is_empty() {
+            if let Ok(nlri) = self.nlri()? {
+                nlris.push(nlri);
+            }
+        }
+        Some(nlris)
+    }
+}
+
+// the attributes RouteV4 keeps, the others are skipped
+#[derive(Default)]
+struct Attributes {
+    origin: Option<Origin>,
+    as_path: Option<AsPath>,
+    next_hop: Option<NextHop>,
+    local_pref: Option<LocalPref>,
+    multi_exit_disc: Option<MultiExitDisc>,
+    atomic_aggregate: Option<AtomicAggregate>,
+    aggregator: Option<Aggregator>,
+    communities: Vec<u32>,
+}
+
+impl Attributes {
+    // as4 says how wide the ASNs in the AS_PATH should be, a path that only parses the other way is taken that way
+    fn parse(bytes: &[u8], as4: bool) -> Option<Attributes> {
+        let mut cursor = Cursor { bytes };
+        let mut attributes = Attributes::default();
+        while !cursor.bytes.is_empty() {
+            let flags = cursor.u8()?;
+            let type_code = cursor.u8()?;
+            let len = if flags & 0x10 != 0 { cursor.u16()? as usize } else { cursor.u8()? as usize };
+            let data = cursor.take(len)?.to_vec();
+            match (type_code, len) {
+                (1, 1) if data[0] <= 2 => attributes.origin = Some(Origin::from_u8(data[0])),
+                (2, _) => attributes.as_path = Some(parse_as_path(&data, as4)?),
+                (3, 4) => attributes.next_hop = Some(NextHop::from_vec_u8(&data)),
+                (4, 4) => attributes.multi_exit_disc = Some(MultiExitDisc::from_vec_u8(&data)),
+                (5, 4) => attributes.local_pref = Some(LocalPref::from_vec_u8(&data)),
+                (6, _) => attributes.atomic_aggregate = Some(AtomicAggregate::new()),
+                (7, 8) => attributes.aggregator = Some(Aggregator::from_vec_u8(&data, &mrt::as4_capabilities())),
+                (7, 6) => attributes.aggregator = Some(Aggregator::from_vec_u8(&data, &None)),
+                (8, _) if len % 4 == 0 => attributes.communities = Communities::from_vec_u8(&data).values,
+                (1..=8, _) => return None,
+                _ => {},
+            }
+        }
+        Some(attributes)
+    }
+
+    fn route(&self, nlri: NLRI) -> Option<RouteV4> {
+        let mut route = RouteV4::new(nlri, self.origin?, self.as_path.clone()?, self.next_hop?, self.local_pref, self.multi_exit_disc,
+                                     self.atomic_aggregate, self.aggregator);
+        route.communities = self.communities.clone();
+        Some(route)
+    }
+}
+
+// AsPath only holds one segment, the ones after it are dropped
+fn parse_as_path(data: &[u8], as4: bool) -> Option<AsPath> {
+    if data.is_empty() {
+        return Some(AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: 0, as_list: Vec::new() }));
+    }
+    let as4 = match (as_path_fits(data, 4), as_path_fits(data, 2)) {
+        (true, true) => as4,
+        (true, false) => true,
+        (false, true) => false,
+        (false, false) => return None,
+    };
+    let capabilities = if as4 { mrt::as4_capabilities() } else { None };
+    Some(AsPath::from_vec_u8(&data.to_vec(), &capabilities))
+}
+
+fn as_path_fits(data: &[u8], as_width: usize) -> bool {
+    let mut idx = 0;
+    while idx < data.len() {
+        let Some(&[segment_type, number_of_as]) = data.get(idx..idx + 2) else { return false };
+        if !(1..=2).contains(&segment_type) {
+            return false;
+        }
+        idx += 2 + number_of_as as usize * as_width;
+    }
+    idx == data.len()
+}
+
+// turns MRT records into replay actions, remembering the peers it has announced
+struct Decoder {
+    peers: Option<Vec<Ipv4Addr>>,
+    // the current TABLE_DUMP_V2's PEER_INDEX_TABLE, None for the peers that aren't replayed
+    peer_index: Vec<Option<Ipv4Addr>>,
+    seen: HashSet<Ipv4Addr>,
+}
+
+impl Decoder {
+    fn new(peers: Option<Vec<Ipv4Addr>>) -> Self {
+        Decoder { peers, peer_index: Vec::new(), seen: HashSet::new() }
+    }
+
+    fn wanted(&self, ip: Ipv4Addr) -> bool {
+        // our own routes sit at 0.0.0.0 in the dumps we write
+        !ip.is_unspecified() && self.peers.as_ref().is_none_or(|peers| peers.contains(&ip))
+    }
+
+    // a Peer action ahead of the first thing from a peer
+    fn peer(&mut self, ip: Ipv4Addr, as_num: u32, actions: &mut Vec<ReplayAction>) {
+        if self.seen.insert(ip) {
+            actions.push(ReplayAction::Peer { ip, as_num });
+        }
+    }
+
+    // None if the record is malformed, an empty Vec if there's nothing in it to replay
+    fn decode(&mut self, mrt_type: u16, subtype: u16, body: &[u8]) -> Option<Vec<ReplayAction>> {
+        let mut cursor = Cursor { bytes: body };
+        let mut actions = Vec::new();
+        match (mrt_type, subtype) {
+            (TABLE_DUMP_V2, PEER_INDEX_TABLE) => {
+                // collector id and view name
+                cursor.take(4)?;
+                let view_name_len = cursor.u16()? as usize;
+                cursor.take(view_name_len)?;
+                self.peer_index.clear();
+                for _ in 0..cursor.u16()? {
+                    let peer_type = cursor.u8()?;
+                    // router id
+                    cursor.take(4)?;
+                    let ip = if peer_type & 0x01 != 0 { cursor.take(16)?; None } else { Some(cursor.ipv4()?) };
+                    let as_num = if peer_type & 0x02 != 0 { cursor.u32()? } else { cursor.u16()? as u32 };
+                    let ip = ip.filter(|ip| self.wanted(*ip));
+                    if let Some(ip) = ip {
+                        self.peer(ip, as_num, &mut actions);
+                    }
+                    self.peer_index.push(ip);
+                }
+            },
+            (TABLE_DUMP_V2, RIB_IPV4_UNICAST) => {
+                // sequence number
+                cursor.take(4)?;
+                let Ok(nlri) = cursor.nlri()? else { return Some(actions) };
+                for _ in 0..cursor.u16()? {
+                    let peer_index = cursor.u16()? as usize;
+                    // originated time
+                    cursor.take(4)?;
+                    let attributes_len = cursor.u16()? as usize;
+                    let attributes = cursor.take(attributes_len)?;
+                    let Some(Some(peer)) = self.peer_index.get(peer_index).copied() else { continue };
+                    // TABLE_DUMP_V2 always has 4 byte ASNs
+                    if let Some(route) = Attributes::parse(attributes, true)?.route(nlri.clone()) {
+                        actions.push(ReplayAction::Announce { peer, routes: vec![route] });
+                    }
+                }
+            },
+            (BGP4MP | BGP4MP_ET, BGP4MP_MESSAGE | BGP4MP_MESSAGE_AS4 | BGP4MP_STATE_CHANGE | BGP4MP_STATE_CHANGE_AS4) => {
+                if mrt_type == BGP4MP_ET {
+                    cursor.take(4)?;
+                }
+                let as4 = matches!(subtype, BGP4MP_MESSAGE_AS4 | BGP4MP_STATE_CHANGE_AS4);
+                let peer_as = if as4 { cursor.u32()? } else { cursor.u16()? as u32 };
+                cursor.take(if as4 { 4 } else { 2 })?;
+                // interface index
+                cursor.take(2)?;
+                if cursor.u16()? != AFI_IPV4 {
+                    return Some(actions);
+                }
+                let peer = cursor.ipv4()?;
+                cursor.take(4)?;
+                if !self.wanted(peer) {
+                    return Some(actions);
+                }
+                if matches!(subtype, BGP4MP_STATE_CHANGE | BGP4MP_STATE_CHANGE_AS4) {
+                    let (old_state, new_state) = (cursor.u16()?, cursor.u16()?);
+                    if old_state == STATE_ESTABLISHED && new_state != STATE_ESTABLISHED && self.seen.contains(&peer) {
+                        actions.push(ReplayAction::Down { peer });
+                    }
+                    return Some(actions);
+                }
+                self.peer(peer, peer_as, &mut actions);
+                decode_update(&mut cursor, peer, as4, &mut actions)?;
+            },
+            _ => {},
+        }
+        Some(actions)
+    }
+}
+
+fn decode_update(cursor: &mut Cursor, peer: Ipv4Addr, as4: bool, actions: &mut Vec<ReplayAction>) -> Option<()> {
+    // marker
+    cursor.take(16)?;
+    let message_len = cursor.u16()? as usize;
+    if cursor.u8()? != MESSAGE_TYPE_UPDATE {
+        return Some(());
+    }
+    let mut message = Cursor { bytes: cursor.take(message_len.checked_sub(19)?)? };
+    let withdrawn_len = message.u16()? as usize;
+    let withdrawn = Cursor { bytes: message.take(withdrawn_len)? }.nlris()?;
+    let attributes_len = message.u16()? as usize;
+    let attributes = message.take(attributes_len)?;
+    let nlris = message.nlris()?;
+    if !withdrawn.is_empty() {
+        actions.push(ReplayAction::Withdraw { peer, nlris: withdrawn });
+    }
+    if !nlris.is_empty() {
+        let attributes = Attributes::parse(attributes, as4)?;
+        let routes: Vec<RouteV4> = nlris.into_iter().filter_map(|nlri| attributes.route(nlri)).collect();
+        actions.push(ReplayAction::Announce { peer, routes });
+    }
+    Some(())
+}
+
+// blocking, reads the whole file and hands the actions over one record at a time
+fn read_replay_file(config: &MrtReplayConfig, tx: mpsc::Sender<ReplayRecord>) -> io::Result<(usize, usize)> {
+    let mut file = BufReader::new(File::open(&config.file)?);
+    // gzipped or not, going by the magic number instead of the name
+    let mut reader: Box<dyn Read> = match file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        true => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        false => Box::new(file),
+    };
+    let mut decoder = Decoder::new(config.peers.clone());
+    let (mut records, mut malformed) = (0, 0);
+    let mut header = [0u8; 12];
+    loop {
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let mut cursor = Cursor { bytes: &header };
+        let (timestamp, mrt_type, subtype, len) = (cursor.u32().unwrap(), cursor.u16().unwrap(), cursor.u16().unwrap(), cursor.u32().unwrap());
+        records += 1;
+        if len > MAX_RECORD_LEN {
+            // a length nothing we read comes close to, skipped without buffering it
+            let skipped = io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+            if skipped < len as u64 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+            malformed += 1;
+            continue;
+        }
+        let mut body = vec![0u8; len as usize];
+        reader.read_exact(&mut body)?;
+        let mut at = Duration::from_secs(timestamp as u64);
+        if mrt_type == BGP4MP_ET && let Some(usec) = (Cursor { bytes: &body }).u32() {
+            at += Duration::from_micros(usec as u64);
+        }
+        let Some(actions) = decoder.decode(mrt_type, subtype, &body) else {
+            malformed += 1;
+            continue;
+        };
+        for action in actions {
+            if tx.blocking_send(ReplayRecord { at, action }).is_err() {
+                return Ok((records, malformed));
+            }
+        }
+    }
+    Ok((records, malformed))
+}
+
+// a Neighbor without a session, its routes take the same import policy, plugin and script as a real neighbor's
+fn virtual_neighbor(ip: Ipv4Addr, as_num: u32, settings: &NeighborSettings, bgp_proc: &BGPProcess, bgp_channel: NeighborChannel,
+                    tx_channel_watcher: &mpsc::Sender<ChannelWatcherMessage>) -> Result<Neighbor, MessageError> {
+    let mut neighbor = Neighbor::new(ip, AS::AS4(as_num), settings.hello_time, settings.hold_time, bgp_channel.peer_type.clone(),
+                                     bgp_proc.global_settings.clone(), bgp_channel, tx_channel_watcher.clone(), bgp_proc.tx_bgp_events.clone())?;
+    neighbor.apply_settings(settings)?;
+    // nobody wants what the BGP process sends a virtual neighbor, and holding on to a sender keeps its routes in the
+    // RIBs once the replay is over and the neighbor is gone
+    let mut rx = std::mem::replace(&mut neighbor.proc_channel.rx, mpsc::channel(1).1);
+    let tx = neighbor.proc_channel.tx.clone();
+    tokio::spawn(async move {
+        let _tx = tx;
+        while rx.recv().await.is_some() {}
+    });
+    Ok(neighbor)
+}
+
+// the BGP process warns about withdraws for prefixes it doesn't have, this leaves them out
+fn known_prefixes(neighbor: &Neighbor, nlris: impl IntoIterator<Item = NLRI>) -> Vec<NLRI> {
+    nlris.into_iter()
+        .filter(|nlri| neighbor.adj_rib_in.contains_key(nlri) || neighbor.adj_rib_in_pre_policy.as_ref().is_some_and(|pre_policy| pre_policy.contains_key(nlri)))
+        .collect()
+}
+
+pub fn run_replays(replays: Vec<EffectiveMrtReplayConfig>, bgp_proc_arc: &Arc<Mutex<BGPProcess>>,
+                   all_neighbors_channels_arc: &Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
+                   tx_channel_watcher: &mpsc::Sender<ChannelWatcherMessage>) {
+    for replay in replays {
+        tokio::spawn(run_replay(replay, Arc::clone(bgp_proc_arc), Arc::clone(all_neighbors_channels_arc), tx_channel_watcher.clone()));
+    }
+}
+
+async fn run_replay(emrc: EffectiveMrtReplayConfig, bgp_proc_arc: Arc<Mutex<BGPProcess>>,
+                    all_neighbors_channels_arc: Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
+                    tx_channel_watcher: mpsc::Sender<ChannelWatcherMessage>) {
+    let EffectiveMrtReplayConfig { replay: config, settings } = emrc;
+    info!("Replaying {} ({:?} timing)", config.file.display(), config.timing);
+    let my_as = bgp_proc_arc.lock().await.global_settings.my_as as u32;
+    let (tx_records, mut rx_records) = mpsc::channel::<ReplayRecord>(1024);
+    let reader_config = config.clone();
+    let reader = tokio::task::spawn_blocking(move || read_replay_file(&reader_config, tx_records));
+
+    let mut neighbors: HashMap<Ipv4Addr, Neighbor> = HashMap::new();
+    let mut started: Option<(Instant, Duration)> = None;
+    let (mut routes, mut withdraws) = (0, 0);
+    while let Some(record) = rx_records.recv().await {
+        if config.timing == ReplayTiming::Original {
+            let (start, first) = *started.get_or_insert((Instant::now(), record.at));
+            sleep_until(start + record.at.saturating_sub(first)).await;
+        }
+        match record.action {
+            ReplayAction::Peer { ip, as_num } => {
+                let peer_type = if as_num == my_as { PeerType::Internal } else { PeerType::External };
+                let (neighbors_channels, bgp_channel) = BGPProcess::create_neighbor_channels(&peer_type);
+                let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+                if all_neighbors_channels.contains_key(&ip) {
+                    warn!("{} is already a neighbor, skipping its routes in {}", ip, config.file.display());
+                    continue;
+                }
+                let neighbor = match virtual_neighbor(ip, as_num, &settings, &*bgp_proc_arc.lock().await, bgp_channel, &tx_channel_watcher) {
+                    Ok(neighbor) => neighbor,
+                    Err(e) => {
+                        warn!("Unable to replay {} as a virtual neighbor, skipping its routes in {} - {:?}", ip, config.file.display(), e);
+                        continue;
+                    }
+                };
+                all_neighbors_channels.insert(ip, neighbors_channels);
+                info!("Replaying {} as virtual neighbor {} AS {}", config.file.display(), ip, as_num);
+                neighbors.insert(ip, neighbor);
+            },
+            ReplayAction::Announce { peer, routes: announced } => {
+                let Some(neighbor) = neighbors.get_mut(&peer) else { continue };
+                routes += announced.len();
+                neighbor.import_routes(announced).await;
+            },
+            ReplayAction::Withdraw { peer, nlris } => {
+                let Some(neighbor) = neighbors.get_mut(&peer) else { continue };
+                let nlris = known_prefixes(neighbor, nlris);
+                if nlris.is_empty() {
+                    continue;
+                }
+                withdraws += nlris.len();
+                neighbor.withdraw_routes(nlris).await;
+            },
+            ReplayAction::Down { peer } => {
+                let Some(neighbor) = neighbors.get_mut(&peer) else { continue };
+                let mut nlris: HashSet<NLRI> = neighbor.adj_rib_in.keys().cloned().collect();
+                nlris.extend(neighbor.adj_rib_in_pre_policy.iter().flat_map(|pre_policy| pre_policy.keys().cloned()));
+                if nlris.is_empty() {
+                    continue;
+                }
+                withdraws += nlris.len();
+                neighbor.withdraw_routes(nlris.into_iter().collect()).await;
+            },
+        }
+    }
+    drop(rx_records);
+
+    match reader.await {
+        Ok(Ok((records, malformed))) => {
+            if malformed > 0 {
+                warn!("Skipped {} malformed records in {}", malformed, config.file.display());
+            }
+            info!("Finished replaying {} - {} records, {} routes and {} withdraws from {} virtual neighbors",
+                  config.file.display(), records, routes, withdraws, neighbors.len());
+        },
+        Ok(Err(e)) => error!("Unable to read {}, stopped replaying it - {}", config.file.display(), e),
+        Err(e) => error!("Replaying {} failed - {}", config.file.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::channels::ChannelMessage;
+    use crate::messages::update::{OriginType, UpdateMessage};
+    use crate::mrt::bgp4mp::{self, Bgp4mpPeer};
+    use crate::mrt::table_dump::{self, MrtPeer};
+    use crate::finite_state_machine::State;
+
+    fn route(prefix: &str, as_list: Vec<AS>) -> RouteV4 {
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: as_list.len() as u8, as_list });
+        RouteV4::new(NLRI::from_str(prefix).unwrap(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(10, 0, 0, 2)),
+                     None, Some(MultiExitDisc::new(50)), None, None)
+    }
+
+    #[test]
+    fn test_decode_replay_records() {
+        let peer_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let mut decoder = Decoder::new(None);
+
+        // our own routes at index 0 stay out of it
+        let peers = [MrtPeer { ip: Ipv4Addr::UNSPECIFIED, as_num: 65000, router_id: Ipv4Addr::new(10, 0, 0, 1) },
+                     MrtPeer { ip: peer_ip, as_num: 65001, router_id: peer_ip }];
+        let actions = decoder.decode(TABLE_DUMP_V2, PEER_INDEX_TABLE, &table_dump::peer_index_table(Ipv4Addr::new(10, 0, 0, 1), "", &peers)).unwrap();
+        assert_eq!(actions, [ReplayAction::Peer { ip: peer_ip, as_num: 65001 }]);
+        let dumped = route("192.0.2.0/24", vec![AS::AS4(65001), AS::AS4(4200000000)]);
+        let rib_entry = table_dump::rib_ipv4_unicast(0, &dumped.nlri, &[(0, &dumped), (1, &dumped)], 0);
+        let actions = decoder.decode(TABLE_DUMP_V2, RIB_IPV4_UNICAST, &rib_entry).unwrap();
+        assert_eq!(actions, [ReplayAction::Announce { peer: peer_ip, routes: vec![dumped.clone()] }]);
+
+        // an UPDATE off a 2 byte session, the peer was already announced by the table dump
+        let announced = route("198.51.100.0/24", vec![AS::AS2(65001)]);
+        let pdu = UpdateMessage::from_route(&announced, &None).unwrap().convert_to_bytes(&None);
+        let bgp4mp_peer = Bgp4mpPeer { peer_as: 65001, local_as: 65000, peer_ip, local_ip: Ipv4Addr::new(10, 0, 0, 1) };
+        let record = bgp4mp::message_record(SystemTime::now(), &bgp4mp_peer, BGP4MP_MESSAGE_AS4, &pdu);
+        let actions = decoder.decode(BGP4MP_ET, BGP4MP_MESSAGE_AS4, &record[12..]).unwrap();
+        assert_eq!(actions, [ReplayAction::Announce { peer: peer_ip, routes: vec![announced] }]);
+        // the same UPDATE going the other way is ours
+        assert_eq!(decoder.decode(BGP4MP_ET, bgp4mp::BGP4MP_MESSAGE_AS4_LOCAL, &record[12..]).unwrap(), []);
+
+        let record = bgp4mp::state_change_record(UNIX_EPOCH, &bgp4mp_peer, State::Established, State::Idle);
+        assert_eq!(decoder.decode(BGP4MP_ET, BGP4MP_STATE_CHANGE_AS4, &record[12..]).unwrap(), [ReplayAction::Down { peer: peer_ip }]);
+        assert_eq!(decoder.decode(BGP4MP_ET, BGP4MP_MESSAGE_AS4, &record[12..20]), None);
+    }
+
+    // a table dump from 10.0.0.2 AS 65001 with both routes
+    fn table_dump_file(routes: &[&RouteV4]) -> Vec<u8> {
+        let peers = [MrtPeer { ip: Ipv4Addr::new(10, 0, 0, 2), as_num: 65001, router_id: Ipv4Addr::new(10, 0, 0, 2) }];
+        let mut file = mrt::record(0, TABLE_DUMP_V2, PEER_INDEX_TABLE, &table_dump::peer_index_table(Ipv4Addr::new(10, 0, 0, 1), "", &peers));
+        for (sequence, route) in routes.iter().enumerate() {
+            let rib_entry = table_dump::rib_ipv4_unicast(sequence as u32, &route.nlri, &[(0, route)], 0);
+            file.extend(mrt::record(0, TABLE_DUMP_V2, RIB_IPV4_UNICAST, &rib_entry));
+        }
+        file
+    }
+
+    #[tokio::test]
+    async fn test_replay_goes_through_the_peer_group_import_policy() {
+        let dir = std::env::temp_dir().join(format!("bgprtr-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let accepted = route("192.0.2.0/24", vec![AS::AS4(65001)]);
+        let rejected = route("198.51.100.0/24", vec![AS::AS4(65001), AS::AS4(64999)]);
+        std::fs::write(dir.join("rib.mrt"), table_dump_file(&[&accepted, &rejected])).unwrap();
+        std::fs::write(dir.join("bgp.toml"), format!(r#"
+            neighbors_config = []
+            net_advertisements_config = []
+            [process_config]
+            my_as = 2
+            router_id = "1.1.1.1"
+            next_hop_ip = "10.0.0.1"
+            default_local_preference = 100
+            default_med = 0
+            [process_config.capabilities_config]
+            route_refresh_prestandard = false
+            route_refresh = true
+            enhanced_route_refresh = false
+            extended_4byte_asn = true
+            [process_config.capabilities_config.multi_protocol_extensions_config]
+            ipv4_unicast = true
+            ipv4_multicast = false
+            ipv4_vpn = false
+            ipv6_unicast = false
+            ipv6_multicast = false
+            ipv6_vpn = false
+            [[policies_config]]
+            name = "drop-64999"
+            default_action = "accept"
+            [[policies_config.terms]]
+            name = "from-64999"
+            match = {{ as_path = "_64999$" }}
+            action = "reject"
+            [[peer_groups_config]]
+            name = "replayed"
+            import_policy = "drop-64999"
+            [[mrt_replays_config]]
+            file = "{}"
+            peer_group = "replayed"
+        "#, dir.join("rib.mrt").display())).unwrap();
+        let bgp_proc = BGPProcess::new(dir.join("bgp.toml").to_str().unwrap()).unwrap();
+        let replay = bgp_proc.mrt_replays[0].clone();
+        let all_neighbors_channels_arc = BGPProcess::init_process_channels();
+        let (tx_channel_watcher, _rx_channel_watcher) = mpsc::channel(8);
+        run_replay(replay, Arc::new(Mutex::new(bgp_proc)), Arc::clone(&all_neighbors_channels_arc), tx_channel_watcher).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+        let virtual_neighbor = all_neighbors_channels.get_mut(&Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        assert!(matches!(virtual_neighbor.rx.try_recv(), Ok(ChannelMessage::Route(route)) if route.nlri == accepted.nlri));
+        assert!(virtual_neighbor.rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_oversized_record_is_malformed() {
+        let path = std::env::temp_dir().join(format!("bgprtr-replay-oversized-{}.mrt", std::process::id()));
+        let mut file = mrt::record(0, TABLE_DUMP_V2, RIB_IPV4_UNICAST, &vec![0; MAX_RECORD_LEN as usize + 1]);
+        file.extend(table_dump_file(&[]));
+        std::fs::write(&path, file).unwrap();
+        let config = MrtReplayConfig { file: path.clone(), timing: ReplayTiming::Fast, peers: None, peer_group: None };
+        let (tx, mut rx) = mpsc::channel(8);
+        let result = read_replay_file(&config, tx);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), (2, 1));
+        // the peer index table after it still gets read
+        assert!(matches!(rx.try_recv(), Ok(ReplayRecord { action: ReplayAction::Peer { as_num: 65001, .. }, .. })));
+    }
+}
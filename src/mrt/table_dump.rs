@@ -0,0 +1,252 @@
+use tracing::{error, info};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{oneshot, Mutex};
+use tokio::sync::mpsc::Sender;
+use tokio::time::{interval, MissedTickBehavior};
+use crate::config::MrtDumpConfig;
+use crate::control::as_to_u32;
+use crate::control::protocol::{ControlRequest, ControlResponse};
+use crate::messages::update::PathAttribute;
+use crate::mrt::{self, TABLE_DUMP_V2};
+use crate::neighbors::Neighbor;
+use crate::process::{BGPProcess, ProcessCommand};
+use crate::routes::{RouteV4, NLRI};
+
+// TABLE_DUMP_V2 snapshots of the RIBs. Every dump is two files, "rib" with the paths of every neighbor's
+// Adj-RIB-In and "loc-rib" with only the best paths, both with the same PEER_INDEX_TABLE up front.
+
+pub const PEER_INDEX_TABLE: u16 = 1;
+pub const RIB_IPV4_UNICAST: u16 = 2;
+// the peer entries always carry a v4 address and a 4 byte AS
+const PEER_TYPE_AS4: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MrtPeer {
+    pub ip: Ipv4Addr,
+    pub as_num: u32,
+    pub router_id: Ipv4Addr,
+}
+
+// what a dump needs, copied out of the process so the files can be written without holding any locks
+#[derive(Debug, Clone)]
+pub struct RibSnapshot {
+    pub taken_at: SystemTime,
+    pub collector_id: Ipv4Addr,
+    // index 0 is us, the routes we originate point at it
+    pub peers: Vec<MrtPeer>,
+    pub adj_rib_in: Vec<(NLRI, Vec<RouteV4>)>,
+    pub local_rib: Vec<(NLRI, RouteV4)>,
+}
+
+impl RibSnapshot {
+    fn peer_index(&self, learned_from: Option<Ipv4Addr>) -> Option<u16> {
+        match learned_from {
+            None => Some(0),
+            Some(ip) => self.peers.iter().skip(1).position(|peer| peer.ip == ip).map(|idx| idx as u16 + 1),
+        }
+    }
+}
+
+pub async fn take_snapshot(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, all_neighbors: &HashMap<Ipv4Addr, Neighbor>,
+                           running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>) -> RibSnapshot {
+    let mut neighbor_peers: Vec<MrtPeer> = all_neighbors.values().map(mrt_peer).collect();
+    for neighbor_arc in running_neighbors.values() {
+        neighbor_peers.push(mrt_peer(&*neighbor_arc.lock().await));
+    }
+    neighbor_peers.sort_by_key(|peer| peer.ip);
+
+    let bgp_proc = bgp_proc_arc.lock().await;
+    let global_settings = &bgp_proc.global_settings;
+    let mut peers = vec![MrtPeer { ip: Ipv4Addr::UNSPECIFIED, as_num: global_settings.my_as as u32, router_id: global_settings.identifier }];
+    peers.extend(neighbor_peers);
+    let mut adj_rib_in: Vec<(NLRI, Vec<RouteV4>)> = bgp_proc.adj_rib_in.iter().map(|(nlri, paths)| (nlri.clone(), paths.clone())).collect();
+    adj_rib_in.sort_by_key(|(nlri, _)| (nlri.prefix, nlri.len));
+    let mut local_rib: Vec<(NLRI, RouteV4)> = bgp_proc.local_rib.iter()
+        .filter_map(|(nlri, paths)| paths.first().map(|best| (nlri.clone(), best.clone())))
+        .collect();
+    local_rib.sort_by_key(|(nlri, _)| (nlri.prefix, nlri.len));
+    RibSnapshot { taken_at: SystemTime::now(), collector_id: global_settings.identifier, peers, adj_rib_in, local_rib }
+}
+
+fn mrt_peer(neighbor: &Neighbor) -> MrtPeer {
+    // the router id comes from the neighbor's Open, a neighbor we never talked to doesn't have one
+    let router_id = neighbor.peer_info().map_or(Ipv4Addr::UNSPECIFIED, |peer| peer.router_id);
+    MrtPeer { ip: neighbor.ip, as_num: as_to_u32(&neighbor.as_num), router_id }
+}
+
+pub fn peer_index_table(collector_id: Ipv4Addr, view_name: &str, peers: &[MrtPeer]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(collector_id.octets());
+    body.extend((view_name.len() as u16).to_be_bytes());
+    body.extend(view_name.as_bytes());
+    body.extend((peers.len() as u16).to_be_bytes());
+    for peer in peers {
+        body.push(PEER_TYPE_AS4);
+        body.extend(peer.router_id.octets());
+        body.extend(peer.ip.octets());
+        body.extend(peer.as_num.to_be_bytes());
+    }
+    body
+}
+
+// one prefix with a RIB entry per path, each entry names its peer by the index in the PEER_INDEX_TABLE
+pub fn rib_ipv4_unicast(sequence: u32, nlri: &NLRI, entries: &[(u16, &RouteV4)], originated: u32) -> Vec<u8> {
+    let capabilities = mrt::as4_capabilities();
+    let mut body = Vec::new();
+    body.extend(sequence.to_be_bytes());
+    body.extend(nlri.convert_to_prefix_bytes());
+    body.extend((entries.len() as u16).to_be_bytes());
+    for (peer_index, route) in entries {
+        let mut attributes = Vec::new();
+        for path_attribute in PathAttribute::all_from_route(route, &capabilities) {
+            match path_attribute.convert_to_bytes(&capabilities) {
+                Ok(bytes) => attributes.extend(bytes),
+                Err(e) => error!(target: "codec", "Unable to convert PA to bytes for the MRT dump: {:?}", e),
+            }
+        }
+        body.extend(peer_index.to_be_bytes());
+        body.extend(originated.to_be_bytes());
+        body.extend((attributes.len() as u16).to_be_bytes());
+        body.extend(attributes);
+    }
+    body
+}
+
+// blocking, run it off the runtime
+pub fn write_table_dump(config: &MrtDumpConfig, snapshot: &RibSnapshot) -> io::Result<Vec<PathBuf>> {
+    let timestamp = mrt::unix_seconds(snapshot.taken_at);
+    // we don't keep when a path arrived, every entry gets the time of the dump
+    let originated = timestamp;
+    let adj_rib_in_path = write_dump_file(config, snapshot, "rib", |out| {
+        let mut sequence = 0;
+        for (nlri, paths) in &snapshot.adj_rib_in {
+            let entries: Vec<(u16, &RouteV4)> = paths.iter()
+                .filter_map(|route| snapshot.peer_index(route.learned_from).map(|idx| (idx, route)))
+                .collect();
+            if entries.is_empty() {
+                continue;
+            }
+            out.write_all(&mrt::record(timestamp, TABLE_DUMP_V2, RIB_IPV4_UNICAST, &rib_ipv4_unicast(sequence, nlri, &entries, originated)))?;
+            sequence += 1;
+        }
+        Ok(())
+    })?;
+    let local_rib_path = write_dump_file(config, snapshot, "loc-rib", |out| {
+        let mut sequence = 0;
+        for (nlri, route) in &snapshot.local_rib {
+            let Some(idx) = snapshot.peer_index(route.learned_from) else { continue };
+            out.write_all(&mrt::record(timestamp, TABLE_DUMP_V2, RIB_IPV4_UNICAST, &rib_ipv4_unicast(sequence, nlri, &[(idx, route)], originated)))?;
+            sequence += 1;
+        }
+        Ok(())
+    })?;
+    Ok(vec![adj_rib_in_path, local_rib_path])
+}
+
+fn write_dump_file<F: FnOnce(&mut mrt::MrtFile) -> io::Result<()>>(config: &MrtDumpConfig, snapshot: &RibSnapshot, kind: &str, f: F) -> io::Result<PathBuf> {
+    let timestamp = mrt::unix_seconds(snapshot.taken_at);
+    let name = mrt::unused_file_name(&config.directory, kind, snapshot.taken_at, config.compress);
+    let path = mrt::write_file(&config.directory, &name, config.compress, |out| {
+        out.write_all(&mrt::record(timestamp, TABLE_DUMP_V2, PEER_INDEX_TABLE, &peer_index_table(snapshot.collector_id, kind, &snapshot.peers)))?;
+        f(out)
+    })?;
+    mrt::remove_old_files(&config.directory, kind, config.keep)?;
+    Ok(path)
+}
+
+// only one dump writes at a time, the ones asked for meanwhile wait their turn in order
+static DUMP_LOCK: Mutex<()> = Mutex::const_new(());
+
+// writes the files on a blocking thread without holding up the caller, their names only settle once it's their turn
+pub fn spawn_table_dump(config: MrtDumpConfig, snapshot: RibSnapshot) {
+    tokio::spawn(async move {
+        let _dump_guard = DUMP_LOCK.lock().await;
+        match tokio::task::spawn_blocking(move || write_table_dump(&config, &snapshot)).await {
+            Ok(Ok(paths)) => info!("Wrote the MRT dump to {:?}", paths),
+            Ok(Err(e)) => error!("Unable to write the MRT dump - {}", e),
+            Err(e) => error!("MRT dump failed - {}", e),
+        }
+    });
+}
+
+// periodic dumps go through the control request like the ones bgpctl asks for
+pub fn run_table_dump_timer(interval_secs: u32, tx_process_command: Sender<ProcessCommand>) {
+    tokio::spawn(async move {
+        let mut dump_timer = interval(Duration::from_secs(interval_secs as u64));
+        dump_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick is immediate, there's nothing worth dumping at startup
+        dump_timer.tick().await;
+        loop {
+            dump_timer.tick().await;
+            let (tx_response, rx_response) = oneshot::channel();
+            if tx_process_command.send(ProcessCommand::Control(ControlRequest::DumpRib, tx_response)).await.is_err() {
+                return;
+            }
+            match rx_response.await {
+                Ok(ControlResponse::Done(message)) => info!("{}", message),
+                Ok(ControlResponse::Error(message)) => error!("{}", message),
+                _ => {},
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::messages::update::*;
+
+    #[test]
+    fn test_write_table_dump() {
+        let nlri = NLRI::from_str("192.0.2.0/24").unwrap();
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: 1, as_list: vec![AS::AS2(65001)] });
+        let mut route = RouteV4::new(nlri.clone(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(10, 0, 0, 2)), None, None, None, None);
+        route.learned_from = Some(Ipv4Addr::new(10, 0, 0, 2));
+
+        let rib_entry = rib_ipv4_unicast(7, &nlri, &[(1, &route)], 1000);
+        assert_eq!(rib_entry, [
+            0, 0, 0, 7, 24, 192, 0, 2, 0, 1,
+            0, 1, 0, 0, 0x03, 0xe8, 0, 20,
+            0x40, 1, 1, 0,
+            // the 2 byte ASN comes out as 4 bytes
+            0x40, 2, 6, 2, 1, 0, 0, 0xfd, 0xe9,
+            0x40, 3, 4, 10, 0, 0, 2,
+        ]);
+
+        let directory = std::env::temp_dir().join(format!("bgprtr-mrt-test-{}", std::process::id()));
+        let config = MrtDumpConfig { directory: directory.clone(), interval: 0, keep: 1, compress: false };
+        let mut snapshot = RibSnapshot {
+            taken_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1000), collector_id: Ipv4Addr::new(10, 0, 0, 1),
+            peers: vec![MrtPeer { ip: Ipv4Addr::UNSPECIFIED, as_num: 65000, router_id: Ipv4Addr::new(10, 0, 0, 1) },
+                        MrtPeer { ip: Ipv4Addr::new(10, 0, 0, 2), as_num: 65001, router_id: Ipv4Addr::new(10, 0, 0, 2) }],
+            adj_rib_in: vec![(nlri.clone(), vec![route.clone()])],
+            local_rib: vec![(nlri, route)],
+        };
+        write_table_dump(&config, &snapshot).unwrap();
+        snapshot.taken_at += Duration::from_secs(60);
+        let paths = write_table_dump(&config, &snapshot).unwrap();
+        let mut names: Vec<String> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["loc-rib.19700101.001740", "rib.19700101.001740"]);
+
+        let bytes = std::fs::read(&paths[0]).unwrap();
+        // PEER_INDEX_TABLE first, then the one RIB_IPV4_UNICAST
+        assert_eq!(&bytes[4..8], &[0, 13, 0, 1]);
+        let peer_index_len = u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&bytes[12 + peer_index_len + 4..12 + peer_index_len + 8], &[0, 13, 0, 2]);
+
+        // a second dump in the same second doesn't replace the first, and it's the one keep holds on to
+        let paths = write_table_dump(&config, &snapshot).unwrap();
+        assert_eq!(paths[0].file_name().unwrap(), "rib.19700101.001740_1");
+        let mut names: Vec<String> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["loc-rib.19700101.001740_1", "rib.19700101.001740_1"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
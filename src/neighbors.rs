@@ -1,19 +1,24 @@
 
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use default::default;
 
 use std::mem::discriminant;
 
-//use tokio::net::TcpStream;
+use tokio::net::TcpStream;
 use tokio::net::tcp::*;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 use tokio::sync::{mpsc, broadcast};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, field, info, info_span, instrument, trace, warn, Instrument, Level, Span};
 use crate::sessions::*;
 use crate::finite_state_machine::*;
 use crate::timers::*;
@@ -22,14 +27,25 @@ use crate::errors::*;
 use crate::errors::BGPError::Message;
 use crate::errors::EventError::UnhandledEvent;
 use crate::routes::{RouteV4, NLRI};
+use crate::utils::hex_dump;
 use crate::finite_state_machine::events::Event;
 
-use crate::messages::{extract_messages_from_rec_data, parse_packet_type, BGPVersion, MessageType};
+use crate::messages::{extract_messages_from_rec_data, parse_packet_type, AddressFamily, BGPVersion, MessageType};
+use crate::messages::route_refresh::{extract_route_refresh_message, send_route_refresh, SAFI};
 use crate::messages::keepalive::{send_keepalive};
 use crate::messages::open::{extract_open_message, get_neighbor_ipv4_address_from_stream, send_open, send_update, OpenMessage};
 use crate::process::{BGPProcess, GlobalSettings };
 use crate::channels::*;
-use crate::messages::notification::extract_notification_message;
+use crate::config::{EffectiveNeighborConfig, NeighborSettings};
+use crate::control::as_to_u32;
+use crate::mrt::bgp4mp::MessageArchive;
+use crate::policy::{Policy, PolicyContext, NO_ADVERTISE, NO_EXPORT, NO_EXPORT_SUBCONFED};
+use crate::plugins::Plugin;
+use crate::scripting::Script;
+use crate::messages::notification::{extract_notification_message, send_notification, NotifErrorCeaseSubCode, NotifErrorCode, NotifErrorMsgHdrSubCode, NotifErrorOpenSubCode, NotifErrorSubCode, NotifErrorUpdateSubCode, NotificationMessage};
+use stats::{elapsed, format_duration, NeighborStats, PrefixCounts};
+
+pub mod stats;
 use crate::messages::optional_parameters::{is_4byte_asn_capability_present, Capability, OptionalParameters};
 
 #[derive(Debug)]
@@ -60,12 +76,23 @@ pub struct Neighbor {
     pub ip_type: IPType,
     pub global_settings: GlobalSettings,
     pub events: VecDeque<Event>,
-    // TODO adj-rib-in filters routes coming in, then generates events to loc-rib with the route, trigger best path calc here
+    // the routes from the neighbor that import_policy let in, as the policy left them
     pub adj_rib_in: HashMap<NLRI, RouteV4>,
-    // TODO adj-rib-out filters routes before they are sent to neighbor, generate event to send update
+    // the routes as the neighbor sent them, Some with soft_reconfiguration_inbound
+    pub adj_rib_in_pre_policy: Option<HashMap<NLRI, RouteV4>>,
+    // the routes we've sent the neighbor, after export_policy
     pub adj_rib_out: HashMap<NLRI, RouteV4>,
+    pub import_policy: Option<Arc<Policy>>,
+    pub export_policy: Option<Arc<Policy>>,
+    // run after the policies, the plugin first
+    pub import_plugin: Option<Plugin>,
+    pub export_plugin: Option<Plugin>,
+    pub import_script: Option<Arc<Script>>,
+    pub export_script: Option<Arc<Script>>,
     pub proc_channel: NeighborChannel,
     pub tx_channel_watcher: Sender<ChannelWatcherMessage>,
+    // state changes go out on this for anyone watching the daemon
+    pub tx_bgp_events: broadcast::Sender<BgpEvent>,
     // for the generate_events msg
     pub tx_event_channel_watcher: Option<Sender<ChannelWatcherMessage>>,
     // for the generate all events msg can't use this or else it will lock
@@ -73,50 +100,61 @@ pub struct Neighbor {
     //pub tcp_read_stream: Option<OwnedReadHalf>,
     pub tcp_write_stream: Option<OwnedWriteHalf>,
     pub negotiated_capabilities: Option<Vec<Capability>>,
+    // Some for neighbors built from a listen range, the peer's AS has to land in here
+    pub dynamic_as_range: Option<RangeInclusive<u16>>,
+    pub description: Option<String>,
+    pub next_hop_self: bool,
+    // the BGP proc hands us new connections through the channel, we hold it here until the old stream is gone
+    pub pending_tcp_stream: Option<TcpStream>,
+    // set when the neighbor is removed from the config, the neighbor loop ends once the session is down
+    pub shutdown_requested: bool,
+    // set by an operator through the control socket, the neighbor stays in Idle and drops connections until enabled
+    pub admin_shutdown: bool,
+    // counters and session history, for /metrics and the control socket
+    pub stats: NeighborStats,
+    // the Opens and the last NOTIFICATION as they went over the wire, BMP sends them in Peer Up and Peer Down
+    pub sent_open: Option<Vec<u8>>,
+    pub received_open: Option<Vec<u8>>,
+    pub received_notification: Option<Vec<u8>>,
+    pub sent_notification: Option<Vec<u8>>,
+    // BGP4MP records of everything sent and received, None unless process_config.mrt_archive is set
+    pub message_archive: Option<MessageArchive>,
+    // with passive off we connect to the peer ourselves, on the port we listen on, and hand the stream to the BGP proc
+    // like an accepted one. None for dynamic neighbors, they are only ever passive
+    pub tx_tcp_conn: Option<Sender<(TcpStream, SocketAddr)>>,
+    pub peer_port: u16,
 }
 
 
-pub async fn run_timer_loop(neighbor_arc: Arc<Mutex<Neighbor>>, peer_ip: Ipv4Addr) {
+pub async fn run_timer_loop(neighbor_arc: Arc<Mutex<Neighbor>>, peer_ip: Ipv4Addr) -> JoinHandle<()> {
     tokio::spawn( async move {
         loop {
             { neighbor_arc.lock().await.check_timers_and_generate_events().await; }
             // sleep outside of this so we don't hold a mutex
             sleep(Duration::from_millis(1000)).await;
         }
-    });
+    }.instrument(Span::current()))
 }
 
-pub async fn run_event_loop(neighbor_arc: Arc<Mutex<Neighbor>>, tcp_channel_tx: mpsc::Sender<TCPChannelMessage>, rx_event_channel_watcher: mpsc::Receiver<ChannelWatcherMessage>) {
+pub async fn run_event_loop(neighbor_arc: Arc<Mutex<Neighbor>>, tcp_channel_tx: mpsc::Sender<TCPChannelMessage>, rx_event_channel_watcher: mpsc::Receiver<ChannelWatcherMessage>) -> JoinHandle<()> {
     // The first event is the Automatic start from BGPProcess::generate_event_for_all_neighbors, the rest need to be async
     // I couldn't have generate_event_for_all_neigbhors provide a broadcast receiver because it would block but also would ruin the state of other neighbors
-    {
-        let mut neighbor = neighbor_arc.lock().await;
-        while let Some(event) = neighbor.events.pop_front() {
-            if let Err(e) = neighbor.handle_event(event, &tcp_channel_tx).await {
-                println!("Error: Unable to handle event {:#?}, skipping", e);
-            }
-        }
-    }
+    neighbor_arc.lock().await.handle_pending_events(&tcp_channel_tx).await;
     // neighbors should be up now so we can move  into task
     tokio::spawn( async move {
         let mut watcher = rx_event_channel_watcher;
         loop {
             if let Some(ChannelWatcherMessage::MessageWaiting) = watcher.recv().await {
-                let mut neighbor = neighbor_arc.lock().await;
-                while let Some(event) = neighbor.events.pop_front() {
-                    if let Err(e) = neighbor.handle_event(event, &tcp_channel_tx).await {
-                        println!("Error: Unable to handle event {:#?}, skipping", e);
-                    }
-                }
+                neighbor_arc.lock().await.handle_pending_events(&tcp_channel_tx).await;
             }
         }
-    });
+    }.instrument(Span::current()))
 }
 
 
 
 impl Neighbor {
-    pub fn is_4byte_asn_negotiated(&mut self) -> bool {
+    pub fn is_4byte_asn_negotiated(&self) -> bool {
         if let Some(opt) = &self.negotiated_capabilities {
             for o in opt {
                 match o {
@@ -146,7 +184,7 @@ impl Neighbor {
     }
 
     pub fn new(ip: Ipv4Addr, as_num: AS, keepalive_time_sec: u16, hold_time_sec: u16, peer_type: PeerType, settings: GlobalSettings,
-               neighbor_channel: NeighborChannel, tx_channel_watcher: Sender<ChannelWatcherMessage>) -> Result<Neighbor, MessageError> {
+               neighbor_channel: NeighborChannel, tx_channel_watcher: Sender<ChannelWatcherMessage>, tx_bgp_events: broadcast::Sender<BgpEvent>) -> Result<Neighbor, MessageError> {
         if keepalive_time_sec < 1 {
             return Err(MessageError::HelloTimeLessThanOne);
         }
@@ -171,27 +209,89 @@ impl Neighbor {
             // we'll update all neighbor from the BGP proc settings when anything changes.
             events: VecDeque::new(),
             adj_rib_in: HashMap::new(),
+            adj_rib_in_pre_policy: None,
             adj_rib_out: HashMap::new(),
+            import_policy: None,
+            export_policy: None,
+            import_plugin: None,
+            export_plugin: None,
+            import_script: None,
+            export_script: None,
             proc_channel: neighbor_channel,
             tx_channel_watcher,
+            tx_bgp_events,
             tx_event_channel_watcher: None,
             //tcp_read_stream: None,
             tcp_write_stream: None,
             negotiated_capabilities: None,
+            dynamic_as_range: None,
+            description: None,
+            next_hop_self: false,
+            pending_tcp_stream: None,
+            shutdown_requested: false,
+            admin_shutdown: false,
+            stats: NeighborStats::default(),
+            sent_open: None,
+            received_open: None,
+            received_notification: None,
+            sent_notification: None,
+            message_archive: None,
+            tx_tcp_conn: None,
+            peer_port: 179,
         })
     }
 
+    pub fn apply_settings(&mut self, settings: &NeighborSettings) -> Result<(), MessageError> {
+        self.set_keepalive_time(settings.hello_time)?;
+        self.set_hold_time(settings.hold_time)?;
+        self.description = settings.description.clone();
+        self.next_hop_self = settings.next_hop_self;
+        self.import_policy = settings.import_policy.clone();
+        self.export_policy = settings.export_policy.clone();
+        self.import_plugin = settings.import_plugin.clone();
+        self.export_plugin = settings.export_plugin.clone();
+        self.import_script = settings.import_script.clone();
+        self.export_script = settings.export_script.clone();
+        match settings.keeps_routes_as_received() {
+            true => { self.adj_rib_in_pre_policy.get_or_insert_default(); },
+            false => self.adj_rib_in_pre_policy = None,
+        }
+        if let Some(archive) = &mut self.message_archive {
+            archive.enabled = settings.archive_messages;
+        }
+        self.fsm.passive_tcp_establishment = settings.passive;
+        self.fsm.damp_peer_oscillations = settings.damp_peer_oscillations;
+        if let Some(idle_hold_time) = settings.idle_hold_time {
+            self.fsm.idle_hold_time = idle_hold_time;
+            self.fsm.initial_idle_hold_time = idle_hold_time;
+        }
+        if let Some(max_idle_hold_time) = settings.max_idle_hold_time {
+            self.fsm.max_idle_hold_time = max_idle_hold_time;
+        }
+        if let Some(idle_hold_stable_time) = settings.idle_hold_stable_time {
+            self.fsm.idle_hold_stable_time = idle_hold_stable_time;
+        }
+        self.fsm.delay_open = settings.delay_open;
+        if let Some(delay_open_time) = settings.delay_open_time {
+            self.fsm.delay_open_time = delay_open_time;
+        }
+        // our Open to this neighbor advertises its own capabilities instead of the global ones
+        let capabilities_config = settings.capabilities_config;
+        self.global_settings.optional_parameters = OptionalParameters::new(
+            capabilities_config.multi_protocol_extensions_config,
+            capabilities_config.route_refresh_prestandard,
+            capabilities_config.route_refresh,
+            capabilities_config.enhanced_route_refresh,
+            capabilities_config.extended_4byte_asn,
+            Some(self.global_settings.my_as as u32)
+        );
+        Ok(())
+    }
+
     pub async fn withdraw_routes_from_message(&mut self, update_message: UpdateMessage) -> Result<(), MessageError> {
 
         if let Some(withdrawn_routes) = update_message.withdrawn_routes {
-            for nlri in &withdrawn_routes {
-                println!("Withdrawing route {:#?} from adj_rib_in", nlri);
-                self.adj_rib_in.remove(nlri);
-                // TODO get rid of this and handle it better, for now I just want to see the routes coming to the BGP proc loc_rib
-            }
-            
-            self.proc_channel.withdraw_route(withdrawn_routes, &self.tx_channel_watcher).await;
-            
+            self.withdraw_routes(withdrawn_routes).await;
             Ok(())
         }
         else {
@@ -199,6 +299,74 @@ impl Neighbor {
         }
     }
 
+    fn policy_context(&self, import: bool) -> PolicyContext {
+        PolicyContext { neighbor: self.ip, peer_as: as_to_u32(&self.as_num), my_as: self.global_settings.my_as, import }
+    }
+
+    // None when the policy, the plugin or the script rejects the route
+    pub fn apply_import_policy(&self, mut route: RouteV4) -> Option<RouteV4> {
+        // the policy can match on it, the process sets it again when the VRPs change
+        route.rpki_state = self.global_settings.vrps.validate_route(&route, self.global_settings.my_as);
+        if let Some(policy) = &self.import_policy {
+            let result = policy.evaluate(&route, &self.policy_context(true));
+            if !result.accepted() {
+                debug!(target: "rib", "Import policy {} rejected {:?} from {}", policy.name(), route.nlri, self.ip);
+                return None
+            }
+            route = result.route;
+        }
+        if let Some(plugin) = &self.import_plugin {
+            route = plugin.apply(route, &self.policy_context(true), "import")?;
+        }
+        match &self.import_script {
+            Some(script) => script.apply(route, &self.policy_context(true), "Import"),
+            None => Some(route),
+        }
+    }
+
+    pub fn apply_export_policy(&self, mut route: RouteV4) -> Option<RouteV4> {
+        // it already has the route, it sent it to us
+        if route.learned_from == Some(self.ip) {
+            return None
+        }
+        // RFC 1997 well-known communities, we don't do confederations so no-export-subconfed is the same as no-export
+        let no_export = route.communities.iter().any(|community| *community == NO_EXPORT || *community == NO_EXPORT_SUBCONFED);
+        if route.communities.contains(&NO_ADVERTISE) || (no_export && self.peer_type == PeerType::External) {
+            return None
+        }
+        if self.peer_type == PeerType::External && route.learned_from.is_some() {
+            // a MED only means something to the AS next door, the policy can still set a new one
+            route.multi_exit_disc = None;
+        }
+        if let Some(policy) = &self.export_policy {
+            let result = policy.evaluate(&route, &self.policy_context(false));
+            if !result.accepted() {
+                debug!(target: "rib", "Export policy {} rejected {:?} to {}", policy.name(), route.nlri, self.ip);
+                return None
+            }
+            route = result.route;
+        }
+        if let Some(plugin) = &self.export_plugin {
+            route = plugin.apply(route, &self.policy_context(false), "export")?;
+        }
+        match &self.export_script {
+            Some(script) => script.apply(route, &self.policy_context(false), "Export"),
+            None => Some(route),
+        }
+    }
+
+    // drops them from the Adj-RIBs-In and tells the BGP process
+    pub async fn withdraw_routes(&mut self, withdrawn_routes: Vec<NLRI>) {
+        for nlri in &withdrawn_routes {
+            debug!(target: "rib", "Withdrawing route {:?} from adj_rib_in", nlri);
+            self.adj_rib_in.remove(nlri);
+            if let Some(adj_rib_in_pre_policy) = &mut self.adj_rib_in_pre_policy {
+                adj_rib_in_pre_policy.remove(nlri);
+            }
+        }
+        self.proc_channel.withdraw_route(withdrawn_routes, &self.tx_channel_watcher).await;
+    }
+
     pub async fn process_routes_from_update_message(&mut self, update_message: UpdateMessage) -> Result<(), MessageError> {
 
         if let Some(nlri_coll) = update_message.nlri {
@@ -256,6 +424,14 @@ impl Neighbor {
                     None
                 }
             };
+            let communities: Vec<u32> = {
+                let data = PathAttribute::get_pa_data_from_pa_vec(TypeCode::Communities, &path_attributes);
+                if let Some(PAdata::Communities(communities)) = data {
+                    communities.values
+                } else {
+                    Vec::new()
+                }
+            };
 
             // let as_path = PathAttribute::get_pa_data_from_pa_vec(TypeCode::AsPath, &path_attributes).ok_or_else(|| MessageError::MissingPathAttributes)?;
             // let next_hop = PathAttribute::get_pa_data_from_pa_vec(TypeCode::NextHop, &path_attributes).ok_or_else(|| MessageError::MissingPathAttributes)?;
@@ -263,15 +439,13 @@ impl Neighbor {
             // let multi_exit_disc = PathAttribute::get_pa_data_from_pa_vec(TypeCode::MultiExitDisc, &path_attributes);
             // let atomic_aggregate = PathAttribute::get_pa_data_from_pa_vec(TypeCode::AtomicAggregate, &path_attributes);
             // let aggregator = PathAttribute::get_pa_data_from_pa_vec(TypeCode::Aggregator, &path_attributes);
-            for nlri in &nlri_coll {
+            let routes = nlri_coll.iter().map(|nlri| {
                 // debating if I should do the checks here or move more logic into new()
-                let rt = RouteV4::new(nlri.clone(), origin.clone(), as_path.clone(), next_hop.clone(), local_pref.clone(), med.clone(), atomic_agg.clone(), agg.clone());
-                println!("Adding Route {:#?} to adj_rib_in", rt);
-                //self.routes_v4.push(rt);
-                self.adj_rib_in.insert(nlri.clone(), rt.clone());
-                // TODO get rid of this and handle it better, for now I just want to see the routes coming to the BGP proc loc_rib
-                self.proc_channel.send_route(rt, &self.tx_channel_watcher).await;
-            }
+                let mut rt = RouteV4::new(nlri.clone(), origin.clone(), as_path.clone(), next_hop.clone(), local_pref.clone(), med.clone(), atomic_agg.clone(), agg.clone());
+                rt.communities = communities.clone();
+                rt
+            }).collect();
+            self.import_routes(routes).await;
             Ok(())
         }
         else {
@@ -279,27 +453,153 @@ impl Neighbor {
         }
     }
 
+    // the routes as the neighbor sent them, through the import filters into the Adj-RIBs-In and on to the BGP process
+    pub async fn import_routes(&mut self, routes: Vec<RouteV4>) {
+        let mut rejected = Vec::new();
+        for rt in routes {
+            let nlri = rt.nlri.clone();
+            if let Some(adj_rib_in_pre_policy) = &mut self.adj_rib_in_pre_policy {
+                adj_rib_in_pre_policy.insert(nlri.clone(), rt.clone());
+            }
+            let Some(rt) = self.apply_import_policy(rt) else {
+                // the path it replaces may have been let in, it has to go now
+                if self.adj_rib_in.remove(&nlri).is_some() {
+                    rejected.push(nlri);
+                }
+                continue;
+            };
+            debug!(target: "rib", "Adding Route {:?} to adj_rib_in", rt);
+            self.adj_rib_in.insert(nlri, rt.clone());
+            // TODO get rid of this and handle it better, for now I just want to see the routes coming to the BGP proc loc_rib
+            self.proc_channel.send_route(rt, &self.tx_channel_watcher).await;
+        }
+        if !rejected.is_empty() {
+            self.proc_channel.withdraw_route(rejected, &self.tx_channel_watcher).await;
+        }
+    }
+
+    // drains the event queue, publishing every state change on the way
+    pub async fn handle_pending_events(&mut self, tcp_channel_tx: &mpsc::Sender<TCPChannelMessage>) {
+        while let Some(event) = self.events.pop_front() {
+            let old_state = self.fsm.state;
+            let event_name = event.name();
+            // worked out before handling since the event is consumed
+            let mut down_reason = self.peer_down_reason(&event);
+            self.sync_archive_peer();
+            self.sent_notification = None;
+            if let Err(e) = self.handle_event(event, tcp_channel_tx).await {
+                error!(target: "fsm", "Unable to handle event {:?}, skipping", e);
+            }
+            if let Some(pdu) = self.sent_notification.take() && !self.shutdown_requested {
+                down_reason = PeerDownReason::LocalNotification(pdu);
+            }
+            if self.fsm.state != old_state {
+                self.stats.record_transition(old_state, self.fsm.state, event_name);
+                self.sync_archive_peer();
+                if let Some(archive) = &self.message_archive {
+                    archive.record_state_change(old_state, self.fsm.state);
+                }
+                // nobody watching is fine
+                let _ = self.tx_bgp_events.send(BgpEvent::PeerStateChanged { peer_ip: self.ip, as_num: self.as_num, old_state, new_state: self.fsm.state });
+                if self.fsm.state == State::Established {
+                    self.send_peer_up();
+                }
+                else if old_state == State::Established && let Some(peer) = self.peer_info() {
+                    let _ = self.tx_bgp_events.send(BgpEvent::PeerDown { peer, reason: down_reason });
+                }
+            }
+        }
+    }
+
+    // the peer AS is only known for sure after its Open and the local address changes with every connection
+    fn sync_archive_peer(&mut self) {
+        let local_ip = match self.tcp_write_stream.as_ref().and_then(|s| s.local_addr().ok()) {
+            Some(SocketAddr::V4(addr)) => *addr.ip(),
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        if let Some(archive) = &mut self.message_archive {
+            archive.peer.peer_as = as_to_u32(&self.as_num);
+            archive.peer.local_ip = local_ip;
+        }
+    }
+
+    fn peer_down_reason(&self, event: &Event) -> PeerDownReason {
+        if self.shutdown_requested {
+            return PeerDownReason::Deconfigured;
+        }
+        match event {
+            Event::NotifMsg(_) | Event::NotifMsgVerErr => match &self.received_notification {
+                Some(pdu) => PeerDownReason::RemoteNotification(pdu.clone()),
+                None => PeerDownReason::RemoteNoNotification,
+            },
+            Event::TcpConnectionFails => PeerDownReason::RemoteNoNotification,
+            _ => PeerDownReason::LocalNoNotification(event.number()),
+        }
+    }
+
+    // None until the peer's Open has been seen
+    pub fn peer_info(&self) -> Option<PeerInfo> {
+        let received_open = self.received_open.as_ref()?;
+        let router_id: [u8; 4] = received_open.get(24..28)?.try_into().ok()?;
+        Some(PeerInfo { ip: self.ip, as_num: self.as_num, router_id: Ipv4Addr::from(router_id), four_byte_asn: self.is_4byte_asn_negotiated() })
+    }
+
+    // the routes as the neighbor sent them, None when import filters may have changed them and no copy was kept
+    pub fn adj_rib_in_as_received(&self) -> Option<&HashMap<NLRI, RouteV4>> {
+        match &self.adj_rib_in_pre_policy {
+            Some(adj_rib_in_pre_policy) => Some(adj_rib_in_pre_policy),
+            None if self.import_policy.is_none() && self.import_plugin.is_none() && self.import_script.is_none() => Some(&self.adj_rib_in),
+            None => None,
+        }
+    }
+
+    // what a BMP station needs to announce the session, None while it isn't up
+    pub fn peer_up_event(&self) -> Option<BgpEvent> {
+        let stream = self.tcp_write_stream.as_ref()?;
+        let (local, remote) = (stream.local_addr().ok()?, stream.peer_addr().ok()?);
+        Some(BgpEvent::PeerUp { peer: self.peer_info()?, local, remote, sent_open: self.sent_open.clone()?, received_open: self.received_open.clone()? })
+    }
+
+    fn send_peer_up(&self) {
+        match self.peer_up_event() {
+            Some(event) => { let _ = self.tx_bgp_events.send(event); },
+            None => warn!("Established without both Opens or a stream, not announcing the session"),
+        }
+    }
+
+    // the state the event arrived in goes on every line logged while handling it
+    #[instrument(name = "event", target = "fsm", skip_all, fields(state = ?self.fsm.state))]
     pub async fn handle_event(&mut self, event: Event, tcp_channel_tx: &mpsc::Sender<TCPChannelMessage>) -> Result<(), BGPError> {
-        println!("Handling event {:#?} in state {:#?}", event, self.fsm.state);
+        debug!(target: "fsm", "Handling event {:?}", event);
         match self.fsm.state {
             State::Idle => {
                 // no connections being attempted or accepted
                 match &event {
+                    Event::AutomaticStart | Event::AutomaticStartWithPassiveTcpEstablishment if self.fsm.is_damped() => {
+                        info!(target: "fsm", "Neighbor is damped, ignoring {:?}", event);
+                        Ok(())
+                    },
+                    // an operator shut the neighbor down, only a ManualStart from enabling it brings it back
+                    Event::AutomaticStart | Event::AutomaticStartWithPassiveTcpEstablishment | Event::AutomaticStartWithDampPeerOscillations |
+                    Event::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment if self.admin_shutdown => {
+                        info!(target: "fsm", "Neighbor is shut down, ignoring {:?}", event);
+                        Ok(())
+                    },
                     Event::ManualStart => {
                         self.fsm.connect_retry_counter = 0;
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
-                        // TODO start TCP listener or initial TCP connection here or return result to do it
+                        self.connect_to_peer();
                         self.fsm.state = State::Connect;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
 
                     },
                     Event::AutomaticStart => {
                         self.fsm.connect_retry_counter = 0;
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
-                        // TODO start TCP listener or initial TCP connection here or return result to do it
+                        self.connect_to_peer();
                         self.fsm.state = State::Connect;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::ManualStartWithPassiveTcpEstablishment => {
@@ -307,7 +607,7 @@ impl Neighbor {
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
                         // We are already listening for TCP connections so we should just go straight to active
                         self.fsm.state = State::Active;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStartWithPassiveTcpEstablishment => {
@@ -315,26 +615,44 @@ impl Neighbor {
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
                         // We are already listening for TCP connections so we should just go straight to active
                         self.fsm.state = State::Active;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     // ManualStop and AutomaticStop are ignored in Idle
                     // these next 3 events are for preventing peer oscillations
                     Event::AutomaticStartWithDampPeerOscillations => {
-                        //TODO use for damp peer oscillations
+                        if self.fsm.is_damped() {
+                            // stay in Idle, IdleHoldTimerExpires will start us again
+                            info!(target: "fsm", "Neighbor is damped, ignoring {:?}", event);
+                            return Ok(())
+                        }
+                        self.fsm.connect_retry_counter = 0;
+                        self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
+                        self.connect_to_peer();
+                        self.fsm.state = State::Connect;
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment => {
-                        //TODO use for damp peer oscillations
+                        if self.fsm.is_damped() {
+                            // stay in Idle, IdleHoldTimerExpires will start us again
+                            info!(target: "fsm", "Neighbor is damped, ignoring {:?}", event);
+                            return Ok(())
+                        }
+                        self.fsm.connect_retry_counter = 0;
+                        self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
+                        self.fsm.state = State::Active;
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::IdleHoldTimerExpires => {
                         self.fsm.idle_hold_timer.stop();
-                        self.generate_event(Event::AutomaticStartWithPassiveTcpEstablishment);
+                        let event = self.fsm.automatic_start_event();
+                        self.generate_event(event);
                         Ok(())
                     },
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
                 }
@@ -353,20 +671,22 @@ impl Neighbor {
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
                         self.fsm.delay_open_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
-                        // TODO continue to listen for connection or try to connect to peer
+                        self.connect_to_peer();
                         // stay in Connect
                         Ok(())
                     },
                     Event::DelayOpenTimerExpires => {
-                        // TODO send Open
+                        self.fsm.connect_retry_timer.stop();
+                        self.fsm.delay_open_timer.stop();
+                        self.send_open_message().await?;
                         if self.fsm.hold_time < 240 {
                             self.fsm.hold_timer.start(240);
                         }
                         else {
                             self.fsm.hold_timer.start(self.fsm.hold_time);
                         }
-                        // TODO continue to listen for connection and stay in Connect
                         self.fsm.state = State::OpenSent;
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::TcpConnectionValid => {
@@ -385,36 +705,7 @@ impl Neighbor {
                         }
                         else {
                             self.fsm.connect_retry_timer.stop();
-                            // TODO make a helper function here for the optional param
-                            let mut capabilities: Vec<Capability> = Vec::new();
-                            if let Some(cap_vec) = &self.negotiated_capabilities {
-                                for cap in cap_vec {
-                                    capabilities.push(cap.clone());
-                                }
-                            }
-
-                            let optional_parameters = if self.negotiated_capabilities.is_some() {
-                                Some(OptionalParameters {capabilities})
-                            } else {None};
-
-
-                            // TODO handle the opt_param_len somewhere, right now I am only looking to test the AS4 capability
-                            let opt_param_len: u8 = if optional_parameters.is_some() {
-                                8
-                            } else {
-                                0
-                            };
-
-
-                            let open_message = OpenMessage::new(self.global_settings.version, self.global_settings.my_as, self.fsm.hold_time, self.global_settings.identifier, opt_param_len, optional_parameters)?;
-                            match self.tcp_write_stream.as_mut() {
-                                Some(tcp_write_stream) => {
-                                    send_open(tcp_write_stream, open_message).await?;
-                                },
-                                None => {
-                                    println!("Unable to use Neighbor's tcp_write_stream because it's None");
-                                }
-                            }
+                            self.send_open_message().await?;
                             if self.fsm.hold_time < 240 {
                                 self.fsm.hold_timer.start(240);
                             }
@@ -427,7 +718,7 @@ impl Neighbor {
                     },
                     Event::TcpConnectionFails => {
 
-                        if self.fsm.delay_open_timer.is_running()? {
+                        if self.fsm.is_delay_open_timer_running() {
                             self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
                             self.fsm.delay_open_timer.stop();
                             // TODO continue to listen for TCP conns
@@ -444,38 +735,41 @@ impl Neighbor {
                         }
                         Ok(())
                     },
-                    Event::BGPOpenWithDelayOpenTimerRunning => {
+                    Event::BGPOpenWithDelayOpenTimerRunning(msg) => {
                         self.fsm.connect_retry_timer.stop();
-                        // TODO complete BGP init?
                         self.fsm.delay_open_timer.stop();
-                        // TODO send Open
-                        // TODO send Keepalive
-                        if self.fsm.hold_time != 0 {
-                            self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
-                            self.fsm.hold_timer.start(self.fsm.hold_time);
-                        }
-                        else {
-                            self.fsm.keepalive_timer.stop();
-                            self.fsm.hold_timer.stop();
+                        // the peer's Open beat our DelayOpenTimer, so complete the BGP init with it before we answer
+                        self.process_open_message(&msg, tcp_channel_tx).await?;
+                        self.send_open_message().await?;
+                        match self.tcp_write_stream.as_mut() {
+                            Some(tcp_write_stream) => {
+                                let bytes = send_keepalive(tcp_write_stream).await?;
+                                self.record_sent(MessageType::Keepalive, &bytes);
+                            },
+                            None => {
+                                warn!("Unable to use Neighbor's tcp_write_stream because it's None");
+                            }
                         }
                         self.fsm.state = State::OpenConfirm;
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
                         if self.fsm.send_notification_without_open {
-                            // TODO send notification
+                            let (error, error_subcode) = header_or_open_error(&event);
+                            self.send_notification(error, error_subcode).await;
                         }
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO dampen peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
                         Ok(())
                     },
                     Event::NotifMsgVerErr => {
-                        if self.fsm.delay_open_timer.is_running()? {
+                        if self.fsm.is_delay_open_timer_running() {
                             self.fsm.connect_retry_timer.stop();
                             self.fsm.delay_open_timer.stop();
                             send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
@@ -484,7 +778,7 @@ impl Neighbor {
                             self.fsm.connect_retry_timer.stop();
                             self.fsm.connect_retry_counter += 1;
                             if self.fsm.damp_peer_oscillations {
-                                // TODO dampen peer
+                                self.fsm.damp_peer();
                             }
                         }
                         self.fsm.state = State::Idle;
@@ -499,13 +793,13 @@ impl Neighbor {
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO dampen peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
                         Ok(())
                     }
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
 
@@ -516,8 +810,9 @@ impl Neighbor {
 
                 match event {
                     Event::ManualStop => {
-                        if self.fsm.delay_open_timer.is_running()? && self.fsm.send_notification_without_open {
-                            // TODO send notification
+                        if self.fsm.is_delay_open_timer_running() && self.fsm.send_notification_without_open {
+                            let cease = self.manual_stop_cease();
+                            self.send_notification(NotifErrorCode::Cease, cease).await;
                         }
                         // TODO release all resources
                         self.fsm.delay_open_timer.stop();
@@ -525,21 +820,21 @@ impl Neighbor {
                         self.fsm.connect_retry_counter = 0;
                         self.fsm.connect_retry_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::ConnectRetryTimerExpires => {
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
-                        // TODO continue to listen for connection or try to connect to peer
+                        self.connect_to_peer();
                         self.fsm.state = State::Connect;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::DelayOpenTimerExpires => {
                         self.fsm.connect_retry_timer.stop();
                         self.fsm.delay_open_timer.stop();
 
-                        // TODO send open
+                        self.send_open_message().await?;
 
                         if self.fsm.hold_time < 240 {
                             self.fsm.hold_timer.start(240);
@@ -549,7 +844,7 @@ impl Neighbor {
                         }
 
                         self.fsm.state = State::OpenSent;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::TcpConnectionValid => {
@@ -568,37 +863,7 @@ impl Neighbor {
                         }
                         else {
                             self.fsm.connect_retry_timer.stop();
-                            // TODO make a helper function here for the optional param
-                            let mut capabilities: Vec<Capability> = Vec::new();
-
-                            // We need to send our capabilities and not the negotiated onces because we have not seen the peer's Open yet
-
-                            for cap in &self.global_settings.optional_parameters.capabilities {
-                                capabilities.push(cap.clone());
-                            }
-
-
-                            let optional_parameters = if !capabilities.is_empty() {
-                                Some(OptionalParameters {capabilities})
-                            } else {None};
-
-
-                            // TODO handle the opt_param_len somewhere, right now I am only looking to test the AS4 capability
-                            let opt_param_len: u8 = if optional_parameters.is_some() {
-                                8
-                            } else {
-                                0
-                            };
-
-                            let open_message = OpenMessage::new(self.global_settings.version, self.global_settings.my_as, self.fsm.hold_time, self.global_settings.identifier, opt_param_len, optional_parameters)?;
-                            match self.tcp_write_stream.as_mut() {
-                                Some(tcp_write_stream) => {
-                                    send_open(tcp_write_stream, open_message).await?;
-                                },
-                                None => {
-                                    println!("Unable to use Neighbor's tcp_write_stream because it's None");
-                                }
-                            }
+                            self.send_open_message().await?;
 
                             if self.fsm.hold_time < 240 {
                                 self.fsm.hold_timer.start(240);
@@ -607,7 +872,7 @@ impl Neighbor {
                                 self.fsm.hold_timer.start(self.fsm.hold_time);
                             }
                             self.fsm.state = State::OpenSent;
-                            println!("Moving to {:#?}", self.fsm.state);
+                            info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         }
                         Ok(())
                     },
@@ -617,50 +882,53 @@ impl Neighbor {
                         // TODO release BGP resources
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO
+                            self.fsm.damp_peer();
                         }
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
-                        if !self.fsm.passive_tcp_establishment {
+                        // damp_peer already started it with the backed off time
+                        if !self.fsm.passive_tcp_establishment && !self.fsm.damp_peer_oscillations {
                             self.fsm.idle_hold_timer.start(self.fsm.idle_hold_time);
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
-                    Event::BGPOpenWithDelayOpenTimerRunning => {
+                    Event::BGPOpenWithDelayOpenTimerRunning(msg) => {
                         self.fsm.connect_retry_timer.stop();
                         self.fsm.delay_open_timer.stop();
-                        // TODO complete BGP init?
-                        // TODO send open
-                        // TODO send keepalive
-                        if self.fsm.hold_time != 0 {
-                            self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
-                            self.fsm.hold_timer.start(self.fsm.hold_time);
-                        }
-                        else {
-                            self.fsm.keepalive_timer.stop();
-                            self.fsm.hold_timer.stop();
+                        // the peer's Open beat our DelayOpenTimer, so complete the BGP init with it before we answer
+                        self.process_open_message(&msg, tcp_channel_tx).await?;
+                        self.send_open_message().await?;
+                        match self.tcp_write_stream.as_mut() {
+                            Some(tcp_write_stream) => {
+                                let bytes = send_keepalive(tcp_write_stream).await?;
+                                self.record_sent(MessageType::Keepalive, &bytes);
+                            },
+                            None => {
+                                warn!("Unable to use Neighbor's tcp_write_stream because it's None");
+                            }
                         }
                         self.fsm.state = State::OpenConfirm;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
                         if self.fsm.send_notification_without_open {
-                            // TODO send notification
+                            let (error, error_subcode) = header_or_open_error(&event);
+                            self.send_notification(error, error_subcode).await;
                         }
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO dampen peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::NotifMsgVerErr => {
-                        if self.fsm.delay_open_timer.is_running()? {
+                        if self.fsm.is_delay_open_timer_running() {
                             self.fsm.connect_retry_timer.stop();
                             self.fsm.delay_open_timer.stop();
                             send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
@@ -669,11 +937,11 @@ impl Neighbor {
                             self.fsm.connect_retry_timer.stop();
                             self.fsm.connect_retry_counter += 1;
                             if self.fsm.damp_peer_oscillations {
-                                // TODO dampen peer
+                                self.fsm.damp_peer();
                             }
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStop | Event::HoldTimerExpires | Event::KeepaliveTimerExpires |
@@ -683,14 +951,14 @@ impl Neighbor {
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO dampen peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
 
@@ -699,36 +967,37 @@ impl Neighbor {
             State::OpenSent => {
                 match event {
                     Event::ManualStop => {
-                        // TODO send notification with Cease
+                        let cease = self.manual_stop_cease();
+                        self.send_notification(NotifErrorCode::Cease, cease).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter = 0;
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStop => {
-                        // TODO send notification with Cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::Unknown)).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::HoldTimerExpires => {
-                        // TODO send notification with Hold Timer Expired
+                        self.send_notification(NotifErrorCode::HoldTimerExpired, NotifErrorSubCode::HoldTimerExpired).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::TcpConnectionValid | Event::TcpCRAcked | Event::TcpConnectionConfirmed => {
@@ -744,7 +1013,7 @@ impl Neighbor {
                         self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
                         // listen for a conn
                         self.fsm.state = State::Active;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::OpenMsg(msg) => {
@@ -752,65 +1021,44 @@ impl Neighbor {
                         self.fsm.connect_retry_timer.stop();
                         match self.tcp_write_stream.as_mut() {
                             Some(tcp_write_stream) => {
-                                send_keepalive(tcp_write_stream).await?;
+                                let bytes = send_keepalive(tcp_write_stream).await?;
+                                self.record_sent(MessageType::Keepalive, &bytes);
                             },
                             None => {
-                                println!("Unable to use Neighbor's tcp_write_stream because it's None");
+                                warn!("Unable to use Neighbor's tcp_write_stream because it's None");
                             }
                         }
-                        // TODO validate the open message and our settings (version, as, hold, ident, optional param)
-                        self.process_optional_parameters(&msg);
-
-                        if let AS::AS2(as_num) = &self.as_num {
-                            if *as_num != msg.as_number {
-                                println!("ERROR: Neighbor AS number in Open message does not match the AS number in our neighbor config");
-                                // TODO generate notification
-                                send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, None).await;
-                                self.fsm.state = State::Idle;
-                                return Err(NeighborError::ASNumMismatch.into());
-                            }
-                        }
-
-                        if msg.hold_time < self.fsm.hold_time {
-                            self.fsm.hold_time = msg.hold_time;
-                        }
-                        if self.fsm.hold_time == 0 {
-                            self.fsm.hold_timer.stop();
-                            self.fsm.keepalive_timer.stop();
-                        }
-                        else {
-                            self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
-                            self.fsm.hold_timer.start(self.fsm.hold_time);
-                        }
+                        self.process_open_message(&msg, tcp_channel_tx).await?;
                         self.fsm.state = State::OpenConfirm;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
-                      // TODO send Notification with error code
+                        let (error, error_subcode) = header_or_open_error(&event);
+                        self.send_notification(error, error_subcode).await;
                         self.fsm.connect_retry_timer.stop();
                         // release resources
                         // drop TCP conn
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
 
                     },
                     Event::OpenCollisionDump => {
-                        // TODO send Notification with Cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::ConnectionCollisionResolution)).await;
                         self.fsm.connect_retry_timer.stop();
                         // rlease bgp resources
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::NotifMsgVerErr => {
@@ -818,26 +1066,26 @@ impl Neighbor {
                         // rlease bgp resources
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::ConnectRetryTimerExpires | Event::KeepaliveTimerExpires | Event::DelayOpenTimerExpires |
-                    Event::IdleHoldTimerExpires | Event::BGPOpenWithDelayOpenTimerRunning | Event::NotifMsg(_) | Event::RouteRefreshMsg(_) |
+                    Event::IdleHoldTimerExpires | Event::BGPOpenWithDelayOpenTimerRunning(_) | Event::NotifMsg(_) | Event::RouteRefreshMsg(_) |
                     Event::KeepAliveMsg | Event::UpdateMsg(_) | Event::UpdateMsgErr => {
-                        // TODO send Notification with error code FSM error
+                        self.send_notification(NotifErrorCode::FSM, NotifErrorSubCode::FSM).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO dampen peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
 
                     },
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
 
@@ -846,36 +1094,37 @@ impl Neighbor {
             State::OpenConfirm => {
                 match event {
                     Event::ManualStop => {
-                        // TODO send notification with Cease
+                        let cease = self.manual_stop_cease();
+                        self.send_notification(NotifErrorCode::Cease, cease).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter = 0;
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStop => {
-                        // TODO send notification with Cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::Unknown)).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::HoldTimerExpires => {
-                        // TODO send notification with Hold Timer Expired
+                        self.send_notification(NotifErrorCode::HoldTimerExpired, NotifErrorSubCode::HoldTimerExpired).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::KeepaliveTimerExpires => {
@@ -897,20 +1146,21 @@ impl Neighbor {
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
-                        if !self.fsm.passive_tcp_establishment {
+                        // damp_peer already started it with the backed off time
+                        if !self.fsm.passive_tcp_establishment && !self.fsm.damp_peer_oscillations {
                             self.fsm.idle_hold_timer.start(self.fsm.idle_hold_time);
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::NotifMsgVerErr => {
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::OpenMsg(msg) => {
@@ -925,55 +1175,57 @@ impl Neighbor {
                         Ok(())
                     },
                     Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
-                        // TODO send Notification with error code
+                        let (error, error_subcode) = header_or_open_error(&event);
+                        self.send_notification(error, error_subcode).await;
                         self.fsm.connect_retry_timer.stop();
                         // release resources
                         // drop TCP conn
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::OpenCollisionDump => {
-                        // TODO send notification with Cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::ConnectionCollisionResolution)).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::KeepAliveMsg => {
                         self.fsm.hold_timer.start(self.fsm.hold_time);
-                        println!("Setting neighbor {:#?} state to Established", self.ip);
+                        info!(target: "fsm", "Session is Established");
                         // TODO Need to confirm that we will always receive a Keepalive on neighbor coming up even if holdtime is 0
                         self.fsm.state = State::Established;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        self.fsm.established_at = Some(Instant::now());
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         self.proc_channel.bring_up(&self.tx_channel_watcher).await?;
                         Ok(())
                     },
                     Event::ConnectRetryTimerExpires | Event::DelayOpenTimerExpires | Event::IdleHoldTimerExpires |
-                    Event::BGPOpenWithDelayOpenTimerRunning | Event::UpdateMsg(_) | Event::UpdateMsgErr => {
-                        // TODO send Notification with code of FSM error
+                    Event::BGPOpenWithDelayOpenTimerRunning(_) | Event::UpdateMsg(_) | Event::UpdateMsgErr => {
+                        self.send_notification(NotifErrorCode::FSM, NotifErrorSubCode::FSM).await;
                         self.fsm.connect_retry_timer.stop();
                         // release resources
                         // drop TCP conn
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
 
@@ -982,7 +1234,8 @@ impl Neighbor {
             State::Established => {
                 match &event {
                     Event::ManualStop => {
-                        // TODO send notification with Cease
+                        let cease = self.manual_stop_cease();
+                        self.send_notification(NotifErrorCode::Cease, cease).await;
                         self.fsm.connect_retry_timer.stop();
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
@@ -991,53 +1244,54 @@ impl Neighbor {
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::AutomaticStop => {
-                        // TODO send notification with Cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::Unknown)).await;
                         self.fsm.connect_retry_timer.stop();
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         // self.channel.take_down().await?;
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::HoldTimerExpires => {
-                        // TODO send notification with error code hold timer expired
+                        self.send_notification(NotifErrorCode::HoldTimerExpired, NotifErrorSubCode::HoldTimerExpired).await;
                         self.fsm.connect_retry_timer.stop();
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         // self.channel.take_down().await?;
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::KeepaliveTimerExpires => {
                         match self.tcp_write_stream.as_mut() {
                             Some(tcp_write_stream) => {
-                                send_keepalive(tcp_write_stream).await?;
+                                let bytes = send_keepalive(tcp_write_stream).await?;
+                                self.record_sent(MessageType::Keepalive, &bytes);
                             },
                             None => {
-                                println!("Unable to use Neighbor's tcp_write_stream because it's None");
+                                warn!("Unable to use Neighbor's tcp_write_stream because it's None");
                             }
                         }
                         if self.fsm.hold_time > 0 {
                             self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
                         } else {
-                            println!("hold_time is 0, skipping Keepalive");
+                            debug!(target: "fsm", "hold_time is 0, skipping Keepalive");
                         }
                         Ok(())
                     },
@@ -1063,19 +1317,19 @@ impl Neighbor {
                         Ok(())
                     },
                     Event::OpenCollisionDump => {
-                        // TODO send Notification with cease
+                        self.send_notification(NotifErrorCode::Cease, NotifErrorSubCode::Cease(NotifErrorCeaseSubCode::ConnectionCollisionResolution)).await;
                         self.fsm.connect_retry_timer.stop();
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         // self.channel.take_down().await?;
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::NotifMsg(msg) => {
@@ -1083,11 +1337,14 @@ impl Neighbor {
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
+                        if self.fsm.damp_peer_oscillations {
+                            self.fsm.damp_peer();
+                        }
                         // self.channel.take_down().await?;
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::TcpConnectionFails | Event::NotifMsgVerErr  => {
@@ -1095,21 +1352,25 @@ impl Neighbor {
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
+                        if self.fsm.damp_peer_oscillations {
+                            self.fsm.damp_peer();
+                        }
                         // self.channel.take_down().await?;
-                        if !self.fsm.passive_tcp_establishment {
+                        // damp_peer already started it with the backed off time
+                        if !self.fsm.passive_tcp_establishment && !self.fsm.damp_peer_oscillations {
                             self.fsm.idle_hold_timer.start(self.fsm.idle_hold_time);
                         }
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::KeepAliveMsg => {
                         if self.fsm.hold_time > 0 {
                             self.fsm.hold_timer.start(self.fsm.hold_time);
                         } else {
-                            println!("Received Keepalive, but our hold_time is 0, skipping Keepalive");
+                            debug!(target: "fsm", "Received Keepalive, but our hold_time is 0, skipping Keepalive");
                         }
                         // stay in Established
                         Ok(())
@@ -1124,7 +1385,7 @@ impl Neighbor {
                             self.process_routes_from_update_message(msg.clone()).await?;
                         }
                         else {
-                            println!("WARNING: msg.nlri and msg.withdrawn_routes are both None in Event::UpdateMsg, likely a parsing issue or fuzzing");
+                            warn!(target: "codec", "msg.nlri and msg.withdrawn_routes are both None in Event::UpdateMsg, likely a parsing issue or fuzzing");
                         }
 
                         if self.fsm.hold_time > 0 {
@@ -1134,45 +1395,49 @@ impl Neighbor {
                         Ok(())
                     },
                     Event::UpdateMsgErr => {
-                        // TODO send Notification with an update error
+                        self.send_notification(NotifErrorCode::UpdateMessage, NotifErrorSubCode::Update(NotifErrorUpdateSubCode::Unknown)).await;
                         self.fsm.connect_retry_timer.stop();
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                            // TODO damp peer
+                            self.fsm.damp_peer();
                         }
                         // self.channel.take_down().await?;
                         self.fsm.keepalive_timer.stop();
                         self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                        println!("Moving to {:#?}", self.fsm.state);
+                        info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                         Ok(())
                     },
                     Event::RouteRefreshMsg(msg) => {
-                        // TODO handle route refresh
+                        // the peer wants everything again, e.g. it changed its inbound policy
+                        info!(target: "fsm", "Received route refresh for {:?} {:?}, re-sending adj_rib_out", msg.afi, msg.safi);
+                        self.generate_event(Event::SendUpdateMsg);
                         Ok(())
                     },
                    Event::ConnectRetryTimerExpires | Event::DelayOpenTimerExpires | Event::IdleHoldTimerExpires |
-                        Event::BGPOpenWithDelayOpenTimerRunning | Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
+                        Event::BGPOpenWithDelayOpenTimerRunning(_) | Event::BGPHeaderErr | Event::BGPOpenMsgErr => {
 
-                        // TODO send Notification with an update error
+                        self.send_notification(NotifErrorCode::FSM, NotifErrorSubCode::FSM).await;
                         self.fsm.connect_retry_timer.stop();
                         // TODO delete all routes for this conn.
                         send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, Some(event.clone())).await;
                         self.fsm.connect_retry_counter += 1;
                         if self.fsm.damp_peer_oscillations {
-                        // TODO damp peer
+                        self.fsm.damp_peer();
                         }
                        // self.channel.take_down().await?;
                        self.fsm.keepalive_timer.stop();
                        self.fsm.hold_timer.stop();
                         self.fsm.state = State::Idle;
-                       println!("Moving to {:#?}", self.fsm.state);
+                       info!(target: "fsm", "Moving to {:?}", self.fsm.state);
                        Ok(())
                    },
                     Event::SendUpdateMsg => {
-                        if let Some(tcp_write_stream) = &mut self.tcp_write_stream {
+                        if self.tcp_write_stream.is_some() {
+                            // built up front, recording each one that goes out needs the whole neighbor
+                            let mut updates: Vec<UpdateMessage> = Vec::new();
                             // TODO consolidate update messages when multiple NLRI have the same path attributes
                             // let use_4byte_asn = if let Some(op) = &self.negotiated_capabilities {
                             //     if op.contains(&Capability::Extended4ByteASN) {
@@ -1192,39 +1457,59 @@ impl Neighbor {
                                 pa_len += as_path.pa_data_len;
                                 path_attributes.push(as_path);
 
-                                let next_hop = PathAttribute::new_next_hop(route.next_hop);
+                                let next_hop = if self.next_hop_self {
+                                    PathAttribute::new_next_hop_from_ipv4(self.global_settings.next_hop_ip)
+                                } else {
+                                    PathAttribute::new_next_hop(route.next_hop)
+                                };
                                 pa_len += next_hop.pa_data_len;
                                 path_attributes.push(next_hop);
 
-                                // match self.peer_type {
-                                //     PeerType::Internal => {
-                                //         // TODO local pref
-                                //     },
-                                //     PeerType::External => {
-                                //
-                                //     }
-                                //     // TODO MED ,atomic_agregate, aggregator
-                                // }
+                                if let Some(med) = route.multi_exit_disc {
+                                    let med = PathAttribute::new_multi_exit_disc(med);
+                                    pa_len += med.pa_data_len;
+                                    path_attributes.push(med);
+                                }
+
+                                // local pref never leaves the AS
+                                if self.peer_type == PeerType::Internal && let Some(local_pref) = route.local_pref {
+                                    let local_pref = PathAttribute::new_local_pref(local_pref);
+                                    pa_len += local_pref.pa_data_len;
+                                    path_attributes.push(local_pref);
+                                }
+
+                                if !route.communities.is_empty() {
+                                    let communities = PathAttribute::new_communities(route.communities.clone());
+                                    pa_len += communities.pa_data_len;
+                                    path_attributes.push(communities);
+                                }
+
+                                // TODO atomic_agregate, aggregator
 
                                 match UpdateMessage::new(None, 0, None, pa_len, Some(path_attributes), Some(vec![route.nlri.clone()]), &self.negotiated_capabilities) {
-                                    Ok(message) => {
-                                        if let Err(e) = send_update(tcp_write_stream, message, &self.negotiated_capabilities).await {
-                                            println!("ERROR: Unable to send Update Message to neighbor in State::Established and Event::SendUpdateMsg - {:#?}", e);
-                                        };
-                                    },
+                                    Ok(message) => updates.push(message),
                                     Err(e) => {
-                                        println!("ERROR: {:#?}", e);
+                                        error!("{:?}", e);
                                     }
                                 }
 
                             }
+                            for message in updates {
+                                let Some(tcp_write_stream) = self.tcp_write_stream.as_mut() else { break };
+                                match send_update(tcp_write_stream, message, &self.negotiated_capabilities).await {
+                                    Ok(bytes) => {
+                                        self.record_sent(MessageType::Update, &bytes);
+                                    },
+                                    Err(e) => error!("Unable to send Update Message to neighbor in State::Established and Event::SendUpdateMsg - {:?}", e),
+                                }
+                            }
                             return Ok(());
                         }
                         Err(NeighborError::TCPConnDied.into())
 
                     },
                     _ => {
-                        println!("Unhandled event in {:?} - {:#?}",self.fsm.state, event);
+                        warn!(target: "fsm", "Unhandled event in {:?} - {:?}", self.fsm.state, event);
                         Err(EventError::UnhandledEvent.into())
                     }
                 }
@@ -1234,8 +1519,125 @@ impl Neighbor {
 
 
 
+    pub async fn send_open_message(&mut self) -> Result<(), BGPError> {
+        // We need to send our capabilities and not the negotiated ones because we may not have seen the peer's Open yet
+        let capabilities: Vec<Capability> = self.global_settings.optional_parameters.capabilities.clone();
+
+        let optional_parameters = if !capabilities.is_empty() {
+            Some(OptionalParameters {capabilities})
+        } else {None};
+
+        // TODO handle the opt_param_len somewhere, right now I am only looking to test the AS4 capability
+        let opt_param_len: u8 = if optional_parameters.is_some() {
+            8
+        } else {
+            0
+        };
+
+        let open_message = OpenMessage::new(self.global_settings.version, self.global_settings.my_as, self.fsm.hold_time, self.global_settings.identifier, opt_param_len, optional_parameters)?;
+        match self.tcp_write_stream.as_mut() {
+            Some(tcp_write_stream) => {
+                self.sent_open = Some(open_message.convert_to_bytes()?);
+                let bytes = send_open(tcp_write_stream, open_message).await?;
+                self.record_sent(MessageType::Open, &bytes);
+            },
+            None => {
+                warn!("Unable to use Neighbor's tcp_write_stream because it's None");
+            }
+        }
+        Ok(())
+    }
+
+    // every message we send is counted and, with archive_messages, goes into the BGP4MP archive
+    fn record_sent(&mut self, message_type: MessageType, bytes: &[u8]) {
+        self.stats.messages_sent.add(&message_type);
+        if let Some(archive) = &self.message_archive {
+            archive.record_sent(bytes);
+        }
+    }
+
+    // the session goes down right after, a NOTIFICATION that can't be written is only logged
+    pub async fn send_notification(&mut self, error: NotifErrorCode, error_subcode: NotifErrorSubCode) {
+        let Some(tcp_write_stream) = self.tcp_write_stream.as_mut() else {
+            return
+        };
+        let message = match NotificationMessage::new(error, error_subcode, None) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Unable to build Notification: {:?}", e);
+                return
+            }
+        };
+        match send_notification(tcp_write_stream, &message).await {
+            Ok(bytes) => {
+                self.record_sent(MessageType::Notification, &bytes);
+                self.stats.record_notification_sent(&message);
+                self.sent_notification = Some(bytes);
+            },
+            Err(e) => warn!("Unable to send Notification {:?} {:?} to {}: {:?}", message.error, message.error_subcode, self.ip, e),
+        }
+    }
+
+    // Cease with the reason an operator would want the peer to see
+    fn manual_stop_cease(&self) -> NotifErrorSubCode {
+        NotifErrorSubCode::Cease(if self.shutdown_requested {
+            NotifErrorCeaseSubCode::PeerDeconfigured
+        } else if self.admin_shutdown {
+            NotifErrorCeaseSubCode::AdministrativeShutdown
+        } else {
+            NotifErrorCeaseSubCode::AdministrativeReset
+        })
+    }
+
+    // validates the peer's Open against our settings and negotiates the capabilities and timers
+    pub async fn process_open_message(&mut self, msg: &OpenMessage, tcp_channel_tx: &mpsc::Sender<TCPChannelMessage>) -> Result<(), BGPError> {
+        // TODO validate the open message and our settings (version, as, hold, ident, optional param)
+        self.process_optional_parameters(msg);
+
+        if let Some(as_range) = &self.dynamic_as_range {
+            if !as_range.contains(&msg.as_number) {
+                error!("Dynamic neighbor {} sent AS {} which is outside of its listen range's {:?}", self.ip, msg.as_number, as_range);
+                self.send_notification(NotifErrorCode::OpenMessage, NotifErrorSubCode::Open(NotifErrorOpenSubCode::BadPeerAS)).await;
+                send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, None).await;
+                self.fsm.state = State::Idle;
+                return Err(NeighborError::ASNumMismatch.into());
+            }
+            self.as_num = AS::AS4(msg.as_number as u32);
+            Span::current().record("asn", msg.as_number);
+            // the listen range may hold our own AS along with others, only now do we know which one it is
+            let peer_type = if msg.as_number == self.global_settings.my_as { PeerType::Internal } else { PeerType::External };
+            if peer_type != self.peer_type {
+                info!("Dynamic neighbor {} is {:?}", self.ip, peer_type);
+                self.peer_type = peer_type.clone();
+                self.proc_channel.set_peer_type(peer_type, &self.tx_channel_watcher).await?;
+            }
+        }
+        else if let AS::AS2(as_num) = &self.as_num {
+            if *as_num != msg.as_number {
+                error!("Neighbor AS number in Open message does not match the AS number in our neighbor config");
+                self.send_notification(NotifErrorCode::OpenMessage, NotifErrorSubCode::Open(NotifErrorOpenSubCode::BadPeerAS)).await;
+                send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx, self.fsm.state, None).await;
+                self.fsm.state = State::Idle;
+                return Err(NeighborError::ASNumMismatch.into());
+            }
+        }
+
+        if msg.hold_time < self.fsm.hold_time {
+            self.fsm.hold_time = msg.hold_time;
+        }
+        if self.fsm.hold_time == 0 {
+            self.fsm.hold_timer.stop();
+            self.fsm.keepalive_timer.stop();
+        }
+        else {
+            self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
+            self.fsm.hold_timer.start(self.fsm.hold_time);
+        }
+        Ok(())
+    }
+
     pub async fn handle_update_message(&mut self, tsbuf: &Vec<u8>) -> Result<(), BGPError> {
-        println!("Handling update message");
+        debug!("Handling update message");
         if !self.is_established() {
             return Err(NeighborError::NeighborIPNotEstablished.into())
         }
@@ -1247,6 +1649,14 @@ impl Neighbor {
 
 
 
+    pub fn prefix_counts(&self) -> PrefixCounts {
+        PrefixCounts { received: self.stats.prefixes_received, accepted: self.adj_rib_in.len(), advertised: self.adj_rib_out.len() }
+    }
+
+    pub fn established_for(&self) -> Option<Duration> {
+        self.stats.established_since.filter(|_| self.is_established()).map(elapsed)
+    }
+
     pub fn is_established(&self) -> bool {
         if self.fsm.state == State::Established { true }
         else { false }
@@ -1255,65 +1665,76 @@ impl Neighbor {
     pub async fn check_timers_and_generate_events(&mut self) {
 
         //println!("executing check_timers_and_generate_events");
+        self.fsm.reset_idle_hold_time_if_stable();
         if let Ok(true) = self.fsm.connect_retry_timer.is_elapsed() {
-            println!("connect_retry_timer elapsed for neighbor {:#?}, generating event", self.ip);
+            debug!(target: "fsm", "connect_retry_timer elapsed, generating event");
             self.generate_event(Event::ConnectRetryTimerExpires);
         }
         if let Ok(true) = self.fsm.hold_timer.is_elapsed() {
-            println!("hold_timer elapsed for neighbor {:#?}, generating event", self.ip);
+            debug!(target: "fsm", "hold_timer elapsed, generating event");
             self.generate_event(Event::HoldTimerExpires);
         }
         if let Ok(true) = self.fsm.keepalive_timer.is_elapsed() {
-            println!("keepalive_timer elapsed for neighbor {:#?}, generating event", self.ip);
+            debug!(target: "fsm", "keepalive_timer elapsed, generating event");
             self.generate_event(Event::KeepaliveTimerExpires);
         }
         if let Ok(true) = self.fsm.delay_open_timer.is_elapsed() {
-            println!("delay_open_timer elapsed for neighbor {:#?}, generating event", self.ip);
+            debug!(target: "fsm", "delay_open_timer elapsed, generating event");
             self.generate_event(Event::DelayOpenTimerExpires);
         }
         if let Ok(true) = self.fsm.idle_hold_timer.is_elapsed() {
-            println!("idle_hold_timer elapsed for neighbor {:#?}, generating event", self.ip);
+            debug!(target: "fsm", "idle_hold_timer elapsed, generating event");
             self.generate_event(Event::IdleHoldTimerExpires);
         }
     }
 
     pub fn generate_event(&mut self, event: Event) {
-        // todo handle error or switch to regular mpsc
         match self.tx_event_channel_watcher.as_ref() {
-            Some(tx) => {tx.try_send(ChannelWatcherMessage::MessageWaiting).unwrap()}
-            None => {println!("generate_event failed for {:?}, tx_event_channel_watcher is none", event)}
+            // Full only means a wakeup is already waiting, the event loop drains every queued event when it gets it
+            Some(tx) => if let Err(TrySendError::Closed(_)) = tx.try_send(ChannelWatcherMessage::MessageWaiting) {
+                warn!(target: "fsm", "generate_event failed for {:?}, the event loop is gone", event)
+            },
+            None => {warn!(target: "fsm", "generate_event failed for {:?}, tx_event_channel_watcher is none", event)}
         }
         self.events.push_back(event);
     }
 
     pub fn generate_event_from_message(&mut self, tsbuf: &Vec<u8>, message_type: MessageType) -> Result<(), BGPError> {
-        println!("{}", message_type);
+        debug!(target: "codec", "Received {}", message_type);
+        self.stats.messages_received.add(&message_type);
         match message_type {
             MessageType::Open => {
                 let received_msg = extract_open_message(tsbuf)?;
-                println!("Generating Event::OpenMsg for neighbor {:#?}", self.ip);
-                self.generate_event(Event::OpenMsg(received_msg));
+                if self.fsm.is_delay_open_timer_running() && matches!(self.fsm.state, State::Connect | State::Active) {
+                    debug!(target: "fsm", "Generating Event::BGPOpenWithDelayOpenTimerRunning");
+                    self.generate_event(Event::BGPOpenWithDelayOpenTimerRunning(received_msg));
+                } else {
+                    debug!(target: "fsm", "Generating Event::OpenMsg");
+                    self.generate_event(Event::OpenMsg(received_msg));
+                }
             },
             MessageType::Update => {
                 let received_msg = extract_update_message(tsbuf, &self.negotiated_capabilities)?;
-                println!("Generating Event::UpdateMsg for neighbor {:#?}", self.ip);
+                self.stats.prefixes_received += received_msg.nlri.as_ref().map_or(0, |nlri| nlri.len()) as u64;
+                self.stats.withdraws_received += received_msg.withdrawn_routes.as_ref().map_or(0, |nlri| nlri.len()) as u64;
+                debug!(target: "fsm", "Generating Event::UpdateMsg");
                 self.generate_event(Event::UpdateMsg(received_msg));
             },
             MessageType::Notification => {
                 // TODO create the func
                 let received_msg = extract_notification_message(tsbuf)?;
-                println!("Generating Event::NotifMsg for neighbor {:#?}", self.ip);
+                self.stats.record_notification_received(&received_msg);
+                debug!(target: "fsm", "Generating Event::NotifMsg");
                 self.generate_event(Event::NotifMsg(received_msg));
             },
             MessageType::Keepalive => {
-                println!("Generating Event::KeepAliveMsg for neighbor {:#?}", self.ip);
+                debug!(target: "fsm", "Generating Event::KeepAliveMsg");
                 self.generate_event(Event::KeepAliveMsg);
             },
             MessageType::RouteRefresh => {
-                // TODO create the func
-                //let received_msg = extract_route_refresh_message(tsbuf)?;
-                println!("Generating Event::RouteRefreshMsg for neighbor {:#?}", self.ip);
-                //self.generate_event(Event::RouteRefreshMsg(received_msg));
+                let received_msg = extract_route_refresh_message(tsbuf)?;
+                debug!(target: "fsm", "Generating Event::RouteRefreshMsg");
+                self.generate_event(Event::RouteRefreshMsg(received_msg));
             }
         }
         Ok(())
@@ -1324,34 +1745,322 @@ impl Neighbor {
         self.generate_event(Event::SendUpdateMsg);
     }
 
-    pub async fn recv_routes_from_bgp_proc(&mut self) {
-        while let Ok(ChannelMessage::Route(route)) = self.proc_channel.rx.try_recv() {
-            self.insert_routes_in_adj_rib_out(route);
+    pub async fn recv_messages_from_bgp_proc(&mut self) {
+        while let Ok(msg) = self.proc_channel.rx.try_recv() {
+            match msg {
+                ChannelMessage::Route(route) => {
+                    let nlri = route.nlri.clone();
+                    let learned_from = route.learned_from;
+                    match self.apply_export_policy(route) {
+                        Some(route) => self.insert_routes_in_adj_rib_out(route),
+                        // only take back what we sent for this same path, another one may have made it out
+                        None => if self.adj_rib_out.get(&nlri).is_some_and(|sent| sent.learned_from == learned_from) {
+                            self.withdraw_routes_from_adj_rib_out(vec![nlri]).await;
+                        },
+                    }
+                },
+                ChannelMessage::WithdrawRoute(nlri_vec) => {
+                    self.withdraw_routes_from_adj_rib_out(nlri_vec).await;
+                },
+                ChannelMessage::TcpEstablished(tcp_stream) => {
+                    self.pending_tcp_stream = Some(tcp_stream);
+                },
+                ChannelMessage::UpdateConfig(config, reset_session) => {
+                    self.update_config(*config, reset_session).await;
+                },
+                ChannelMessage::Shutdown => {
+                    info!("Neighbor {} was removed from the config, shutting it down", self.ip);
+                    self.shutdown_requested = true;
+                    self.generate_event(Event::ManualStop);
+                },
+                ChannelMessage::NeighborUp | ChannelMessage::PeerType(_) => {
+                    error!("Neighbor {} got a ChannelMessage::NeighborUp or PeerType from the BGP proc, skipping", self.ip);
+                }
+            }
+        }
+    }
+
+    pub async fn update_config(&mut self, config: EffectiveNeighborConfig, reset_session: bool) {
+        info!("Applying new config to neighbor {}, reset_session: {}", self.ip, reset_session);
+        // a plugin or script counts as changed when its files changed on disk, not just when it's a different file
+        let import_policy_changed = self.import_policy != config.settings.import_policy || self.import_plugin != config.settings.import_plugin
+            || self.import_script != config.settings.import_script;
+        let export_policy_changed = self.export_policy != config.settings.export_policy || self.export_plugin != config.settings.export_plugin
+            || self.export_script != config.settings.export_script;
+        let soft_reconfiguration_started = self.adj_rib_in_pre_policy.is_none() && config.settings.keeps_routes_as_received();
+        self.as_num = AS::AS4(config.as_num as u32);
+        self.peer_type = if config.as_num == self.global_settings.my_as {
+            PeerType::Internal
+        } else {
+            PeerType::External
+        };
+        let keepalive_time = self.fsm.keepalive_time;
+        let hold_time = self.fsm.hold_time;
+        if let Err(e) = self.apply_settings(&config.settings) {
+            error!("Unable to apply new settings to neighbor {} - {:?}", self.ip, e);
+        }
+        if reset_session {
+            // the new capabilities/hold time only get negotiated in a new Open, so bounce the session
+            self.reset_session();
+            return
+        }
+        // the hold time may have been lowered by the peer's Open, it stays until the next session
+        self.fsm.hold_time = hold_time;
+        // the keepalive interval is ours alone, a running timer picks it up right away
+        if self.fsm.keepalive_time != keepalive_time && self.fsm.keepalive_timer.is_running().unwrap_or(false) {
+            self.fsm.keepalive_timer.start(self.fsm.keepalive_time);
+        }
+        if !self.is_established() {
+            return
+        }
+        if export_policy_changed {
+            // the BGP proc sends us its whole RIB again and the new policy goes over every route
+            info!("Export filters for neighbor {} changed, re-evaluating the routes we send it", self.ip);
+            if let Err(e) = self.proc_channel.bring_up(&self.tx_channel_watcher).await {
+                error!("Unable to ask the BGP proc for the routes of neighbor {} - {:?}", self.ip, e);
+            }
+        }
+        if import_policy_changed && !soft_reconfiguration_started && self.adj_rib_in_pre_policy.is_some() {
+            info!("Import filters for neighbor {} changed, running them over the routes it sent", self.ip);
+            self.reimport_adj_rib_in().await;
+        }
+        else if import_policy_changed {
+            // what we kept was filtered by the old policy, the peer has to send it all again
+            match self.request_route_refresh().await {
+                Ok(()) => info!("Import filters for neighbor {} changed, asked it for its routes again", self.ip),
+                Err(e) => warn!("Import filters for neighbor {} changed but it can't send its routes again ({:?}), \
+                                 clear the neighbor to run the new policy over them", self.ip, e),
+            }
+        }
+        else if soft_reconfiguration_started && let Err(e) = self.request_route_refresh().await {
+            // without a refresh the copy only fills up with what the peer sends from now on
+            warn!("Soft reconfiguration inbound for neighbor {} only keeps the routes it sends from now on ({:?}), \
+                   clear the neighbor to keep all of them", self.ip, e);
+        }
+    }
+
+    pub fn reset_session(&mut self) {
+        self.generate_event(Event::ManualStop);
+        let event = self.fsm.automatic_start_event();
+        self.generate_event(event);
+    }
+
+    pub fn shutdown(&mut self) {
+        self.admin_shutdown = true;
+        self.generate_event(Event::ManualStop);
+    }
+
+    // false when it wasn't shut down, a running or damped neighbor is left alone
+    pub fn enable(&mut self) -> bool {
+        if !self.admin_shutdown {
+            return false
+        }
+        self.admin_shutdown = false;
+        if self.fsm.passive_tcp_establishment {
+            self.generate_event(Event::ManualStartWithPassiveTcpEstablishment);
+        } else {
+            self.generate_event(Event::ManualStart);
+        }
+        true
+    }
+
+    // soft in, asks the peer to send us its routes again instead of resetting the session
+    pub async fn request_route_refresh(&mut self) -> Result<(), BGPError> {
+        if !self.is_established() {
+            return Err(NeighborError::NeighborIPNotEstablished.into())
+        }
+        let route_refresh_negotiated = self.negotiated_capabilities.as_ref()
+            .is_some_and(|caps| caps.iter().any(|cap| matches!(cap, Capability::RouteRefresh | Capability::EnhancedRouteRefresh)));
+        if !route_refresh_negotiated {
+            return Err(NeighborError::RouteRefreshNotNegotiated.into())
+        }
+        match &mut self.tcp_write_stream {
+            Some(tcp_write_stream) => {
+                let bytes = send_route_refresh(tcp_write_stream, AddressFamily::IPv4, SAFI::Unicast).await?;
+                self.record_sent(MessageType::RouteRefresh, &bytes);
+                Ok(())
+            },
+            None => Err(NeighborError::TCPConnDied.into()),
+        }
+    }
+
+    // soft in, from our copy of the neighbor's routes when we keep one
+    pub async fn soft_reset_in(&mut self) -> Result<(), BGPError> {
+        if self.adj_rib_in_pre_policy.is_none() {
+            return self.request_route_refresh().await
+        }
+        self.reimport_adj_rib_in().await;
+        Ok(())
+    }
+
+    // the VRPs changed, only an import policy that matches on rpki cares. apply_settings keeps the routes as received for
+    // those, so the policy runs over them again without the peer
+    pub async fn revalidate_in(&mut self) {
+        if self.import_policy.as_ref().is_some_and(|policy| policy.uses_rpki()) {
+            self.reimport_adj_rib_in().await;
+        }
+    }
+
+    // runs the import filters over adj_rib_in_pre_policy again, the BGP proc only hears about what came out different
+    async fn reimport_adj_rib_in(&mut self) {
+        let Some(adj_rib_in_pre_policy) = &self.adj_rib_in_pre_policy else {
+            return
+        };
+        let mut changed = Vec::new();
+        let mut rejected = Vec::new();
+        for (nlri, route) in adj_rib_in_pre_policy {
+            match self.apply_import_policy(route.clone()) {
+                Some(route) if self.adj_rib_in.get(nlri) != Some(&route) => changed.push(route),
+                None if self.adj_rib_in.contains_key(nlri) => rejected.push(nlri.clone()),
+                _ => {},
+            }
+        }
+        info!("Re-ran the import filters over {} routes from neighbor {}, {} changed and {} rejected",
+              adj_rib_in_pre_policy.len(), self.ip, changed.len(), rejected.len());
+        for nlri in &rejected {
+            self.adj_rib_in.remove(nlri);
+        }
+        for route in changed {
+            self.adj_rib_in.insert(route.nlri.clone(), route.clone());
+            self.proc_channel.send_route(route, &self.tx_channel_watcher).await;
+        }
+        if !rejected.is_empty() {
+            self.proc_channel.withdraw_route(rejected, &self.tx_channel_watcher).await;
+        }
+    }
+
+    // soft out, re-sends everything in adj_rib_out
+    pub fn resend_adj_rib_out(&mut self) -> Result<(), BGPError> {
+        if !self.is_established() {
+            return Err(NeighborError::NeighborIPNotEstablished.into())
+        }
+        self.generate_event(Event::SendUpdateMsg);
+        Ok(())
+    }
+
+    pub async fn withdraw_routes_from_adj_rib_out(&mut self, nlri_vec: Vec<NLRI>) {
+        let withdrawn_routes: Vec<NLRI> = nlri_vec.into_iter().filter(|nlri| self.adj_rib_out.remove(nlri).is_some()).collect();
+        if withdrawn_routes.is_empty() || !self.is_established() {
+            return
+        }
+        if let Some(tcp_write_stream) = &mut self.tcp_write_stream {
+            match UpdateMessage::new(None, 0, Some(withdrawn_routes), 0, None, None, &self.negotiated_capabilities) {
+                Ok(message) => {
+                    match send_update(tcp_write_stream, message, &self.negotiated_capabilities).await {
+                        Ok(bytes) => {
+                            self.record_sent(MessageType::Update, &bytes);
+                        },
+                        Err(e) => error!("Unable to send withdraw Update Message to neighbor {} - {:?}", self.ip, e),
+                    }
+                },
+                Err(e) => {
+                    error!("{:?}", e);
+                }
+            }
         }
     }
 
     pub fn reestablish_neighbor_streams(&mut self) -> Option<(OwnedReadHalf,OwnedWriteHalf)> {
-        println!("in reestablish_neighbor_streams");
-        if let Some(new_tcp_stream) = self.proc_channel.recv_tcp_conn_from_bgp_proc() {
+        trace!("in reestablish_neighbor_streams");
+        if let Some(new_tcp_stream) = self.pending_tcp_stream.take() {
+            if self.admin_shutdown {
+                info!(target: "fsm", "Neighbor is shut down, dropping new TCP connection");
+                return None
+            }
+            if self.fsm.is_damped() {
+                // don't let a flapping peer back in until the IdleHoldTimer expires, otherwise it re-sends its full table every time
+                info!(target: "fsm", "Neighbor is damped, dropping new TCP connection");
+                return None
+            }
             let (tcp_r_stream, tcp_wr_stream) = new_tcp_stream.into_split();
-            println!("Got new TCP stream, splitting into Read and Write halves");
+            debug!("Got new TCP stream, splitting into Read and Write halves");
             return Some((tcp_r_stream, tcp_wr_stream))
         }
 
         None
     }
 
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic_as_range.is_some()
+    }
+
+    pub fn should_tear_down(&self) -> bool {
+        // dynamic neighbors only live as long as their session, a reconnect gets a fresh neighbor from the listen range
+        self.is_dynamic() || self.shutdown_requested
+    }
+
+    pub async fn withdraw_all_routes(&mut self) {
+        let withdrawn_routes: Vec<NLRI> = self.adj_rib_in.drain().map(|(nlri, _)| nlri).collect();
+        if let Some(adj_rib_in_pre_policy) = &mut self.adj_rib_in_pre_policy {
+            adj_rib_in_pre_policy.clear();
+        }
+        if !withdrawn_routes.is_empty() {
+            self.proc_channel.withdraw_route(withdrawn_routes, &self.tx_channel_watcher).await;
+        }
+    }
+
     pub fn  generate_events_for_closed_tcp_connection(&mut self) {
-        if self.is_established() {
+        // in Idle we've already let go of the connection, the next one starts us again when it's adopted
+        if self.fsm.state != State::Idle {
             self.generate_event(Event::TcpConnectionFails);
         }
-        else if self.fsm.state == State::Idle && self.fsm.passive_tcp_establishment {
-            self.generate_event(Event::AutomaticStartWithPassiveTcpEstablishment);
+    }
+
+    // RFC 4271 8.2.2, in Connect we initiate the TCP connection unless PassiveTcpEstablishment is set. It goes to the
+    // BGP proc like an accepted connection, so whichever side connects first comes back to us as the pending stream.
+    pub fn connect_to_peer(&self) {
+        if self.fsm.passive_tcp_establishment || self.admin_shutdown || self.is_dynamic() {
+            return
+        }
+        // the peer got there first
+        if self.pending_tcp_stream.is_some() || self.events.iter().any(|event| matches!(event, Event::TcpCRAcked)) {
+            return
         }
+        let Some(tx_tcp_conn) = self.tx_tcp_conn.clone() else {
+            return
+        };
+        let peer = SocketAddr::new(IpAddr::V4(self.ip), self.peer_port);
+        let connect_retry_time = Duration::from_secs(self.fsm.connect_retry_time as u64);
+        debug!(target: "fsm", "Connecting to {}", peer);
+        tokio::spawn(async move {
+            match tokio::time::timeout(connect_retry_time, TcpStream::connect(peer)).await {
+                Ok(Ok(tcp_stream)) => {
+                    if let Err(e) = tx_tcp_conn.send((tcp_stream, peer)).await {
+                        error!("Unable to hand the connection to {} to the BGP proc - {:?}", peer, e);
+                    }
+                },
+                Ok(Err(e)) => info!(target: "fsm", "Unable to connect to {} - {}", peer, e),
+                Err(_) => info!(target: "fsm", "Timed out connecting to {}", peer),
+            }
+        }.instrument(Span::current()));
+    }
+
+    // a configured neighbor has no timer loop until its first connection, the BGP proc calls this every second instead
+    pub fn connect_if_due(&mut self) {
+        if self.fsm.passive_tcp_establishment || self.admin_shutdown || matches!(self.fsm.connect_retry_timer.is_running(), Ok(true)) {
+            return
+        }
+        self.fsm.connect_retry_timer.start(self.fsm.connect_retry_time);
+        self.connect_to_peer();
     }
 
     pub fn process_neighbor_message(&mut self, msg: &Vec<u8>, tsbuf: &Vec<u8>) -> Result<(), BGPError> {
         let message_type = parse_packet_type(msg)?;
+        self.sync_archive_peer();
+        if let Some(archive) = &self.message_archive {
+            archive.record_received(msg);
+        }
+        match message_type {
+            MessageType::Open => self.received_open = Some(msg.clone()),
+            MessageType::Notification => self.received_notification = Some(msg.clone()),
+            // copying every UPDATE only pays off when something like BMP is listening
+            MessageType::Update if self.is_established() && self.tx_bgp_events.receiver_count() > 0 => {
+                if let Some(peer) = self.peer_info() {
+                    let _ = self.tx_bgp_events.send(BgpEvent::UpdateReceived { peer, pdu: msg.clone() });
+                }
+            },
+            _ => {},
+        }
         self.generate_event_from_message(&tsbuf, message_type)?;
         Ok(())
     }
@@ -1359,12 +2068,12 @@ impl Neighbor {
     pub fn process_optional_parameters(&mut self, msg: &OpenMessage) {
         // compare our capabilities and theirs, populate negotiated capabilities
         if let Some(optional_parameters) = &msg.optional_parameters {
-            println!("Processing optional parameters");
+            debug!("Processing optional parameters");
             self.negotiated_capabilities = Some(Vec::new());
             for cap_a in &optional_parameters.capabilities {
                 for cap_b in &self.global_settings.optional_parameters.capabilities {
                     if discriminant(cap_a) == discriminant(cap_b) {
-                        println!("Found capability {:#?} match, adding it to self.negotiated_capabilities", cap_a);
+                        debug!("Found capability {:?} match, adding it to self.negotiated_capabilities", cap_a);
                         self.negotiated_capabilities.as_mut().unwrap().push(cap_b.clone());
                     }
                 }
@@ -1379,6 +2088,24 @@ impl Neighbor {
 }
 // end impl Neighbor
 
+// the header for the Display rows below, together they read like "show bgp summary"
+pub fn summary_header() -> String {
+    format!("{:<15} {:>1} {:>10} {:>7} {:>7} {:>8}  {}", "Neighbor", "V", "AS", "MsgRcvd", "MsgSent", "Up/Down", "State/PfxRcd")
+}
+
+impl fmt::Display for Neighbor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let up_down = self.stats.last_state_change.map_or("never".to_string(), |t| format_duration(elapsed(t)));
+        let state_or_prefixes = match (self.is_established(), self.admin_shutdown) {
+            (true, _) => self.adj_rib_in.len().to_string(),
+            (false, true) => format!("{:?} (Admin)", self.fsm.state),
+            (false, false) => format!("{:?}", self.fsm.state),
+        };
+        write!(f, "{:<15} {:>1} {:>10} {:>7} {:>7} {:>8}  {}", self.ip, self.global_settings.version.to_u8(), self.as_num.to_string(),
+               self.stats.messages_received.total(), self.stats.messages_sent.total(), up_down, state_or_prefixes)
+    }
+}
+
 
 
 
@@ -1397,14 +2124,22 @@ impl Neighbor {
 // }
 
 
+// the events don't say which check failed, so the subcode is left unspecific
+fn header_or_open_error(event: &Event) -> (NotifErrorCode, NotifErrorSubCode) {
+    match event {
+        Event::BGPOpenMsgErr => (NotifErrorCode::OpenMessage, NotifErrorSubCode::Open(NotifErrorOpenSubCode::Unknown)),
+        _ => (NotifErrorCode::MessageHeader, NotifErrorSubCode::MsgHdr(NotifErrorMsgHdrSubCode::Unknown)),
+    }
+}
+
 pub async fn send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx: &Sender<TCPChannelMessage>, state: State, event: Option<Event>) {
     if let Err(e) = tcp_channel_tx.send(TCPChannelMessage::DropTCP).await {
         match event {
             Some(e) => {
-                println!("Unable to send TCPChannelMessage::DropTCP in state {:#?} and event {:#?}", state, e);
+                warn!("Unable to send TCPChannelMessage::DropTCP in state {:?} and event {:?}", state, e);
             },
             None => {
-                println!("Unable to send TCPChannelMessage::DropTCP in state {:#?}", state);
+                warn!("Unable to send TCPChannelMessage::DropTCP in state {:?}", state);
             }
         }
     }
@@ -1437,7 +2172,21 @@ pub async fn send_tcp_drop_signal_to_neighbor_loop(tcp_channel_tx: &Sender<TCPCh
 //     }
 // }
 
-pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighbor: Neighbor, peer_ip: Ipv4Addr, rx_event_channel_watcher: Receiver<ChannelWatcherMessage>) -> Result<(), BGPError> {
+// the BGP proc keeps a clone of neighbor_arc so the control socket can look at the neighbor while it runs
+pub async fn run_neighbor_loop(tcp_stream: tokio::net::TcpStream, neighbor_arc: Arc<Mutex<Neighbor>>, peer_ip: Ipv4Addr, rx_event_channel_watcher: Receiver<ChannelWatcherMessage>) -> Result<(), BGPError> {
+    // everything the neighbor logs, including its timer and event tasks, runs inside this span
+    // dynamic neighbors learn their AS from the Open, it gets recorded then
+    let span = info_span!("neighbor", peer = %peer_ip, asn = field::Empty);
+    {
+        let neighbor = neighbor_arc.lock().await;
+        if !neighbor.is_dynamic() {
+            span.record("asn", field::display(neighbor.as_num));
+        }
+    }
+    neighbor_loop(tcp_stream, neighbor_arc, peer_ip, rx_event_channel_watcher).instrument(span).await
+}
+
+async fn neighbor_loop(mut tcp_stream: tokio::net::TcpStream, neighbor_arc: Arc<Mutex<Neighbor>>, peer_ip: Ipv4Addr, rx_event_channel_watcher: Receiver<ChannelWatcherMessage>) -> Result<(), BGPError> {
     //pub async fn run(&mut self, tcp_stream: TcpStream) {
 
     // setup channel to be used for signaling TCP dropping
@@ -1450,17 +2199,15 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
     let (mut tcp_read, mut tcp_write) = tcp_stream.into_split();
     let mut tcp_read_stream: Option<OwnedReadHalf> = Some(tcp_read);
     // we only store the write half of the stream in the neighbor, not the read half. This is done to prevent locking the neighbor.
-    neighbor.tcp_write_stream = Some(tcp_write);
-
-    let neighbor_arc = Arc::new(Mutex::new(neighbor));
+    neighbor_arc.lock().await.tcp_write_stream = Some(tcp_write);
 
     // generates time events
-    run_timer_loop(Arc::clone(&neighbor_arc), peer_ip).await;
+    let timer_loop_handle = run_timer_loop(Arc::clone(&neighbor_arc), peer_ip).await;
 
     // pops and handles events
 
     //rx_event_channel_watcher comes from bgp run_process_loop because it's needed there due to a generate_event call
-    run_event_loop(Arc::clone(&neighbor_arc), tcp_channel_tx, rx_event_channel_watcher).await;
+    let event_loop_handle = run_event_loop(Arc::clone(&neighbor_arc), tcp_channel_tx, rx_event_channel_watcher).await;
 
     let mut is_tcp_stream_active = true;
 
@@ -1483,24 +2230,38 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
         if let Ok(TCPChannelMessage::DropTCP) = tcp_channel_rx.try_recv() {
             // Consider this is just a message being received, it's not worth refactoring.
             // If this function was expensive, in terms of CPU time, then we'd reconsider.
-            println!("Dropping TCP connection due to received signal TCPChannelMessage::DropTCP");
+            debug!("Dropping TCP connection due to received signal TCPChannelMessage::DropTCP");
             tcp_read_stream = None;
             neighbor_arc.lock().await.tcp_write_stream = None;
             is_tcp_stream_active = false;
         }
 
 
+        neighbor_arc.lock().await.recv_messages_from_bgp_proc().await;
+
+        if !is_tcp_stream_active && neighbor_arc.lock().await.should_tear_down() {
+            info!("Session for neighbor {} ended, tearing it down", peer_ip);
+            neighbor_arc.lock().await.withdraw_all_routes().await;
+            timer_loop_handle.abort();
+            event_loop_handle.abort();
+            return Ok(())
+        }
+
         if !is_tcp_stream_active {
             let mut n = neighbor_arc.lock().await;
             if let Some((new_tcp_read_stream, new_tcp_write_stream)) = n.reestablish_neighbor_streams() {
                 tcp_read_stream = Some(new_tcp_read_stream);
                 n.tcp_write_stream = Some(new_tcp_write_stream);
                 is_tcp_stream_active = true;
+                // a session that went back to Idle has to be started again before it can take the connection
+                if n.fsm.state == State::Idle {
+                    let event = n.fsm.automatic_start_event();
+                    n.generate_event(event);
+                }
                 n.generate_event(Event::TcpCRAcked);
             }
         }
 
-        neighbor_arc.lock().await.recv_routes_from_bgp_proc().await;
 
 
         tsbuf.clear();
@@ -1519,27 +2280,31 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
                     Ok(0) => {
                         {
                             // Per docs "Ok(0) indicates the stream’s read half is closed and will no longer yield data."
-                           neighbor_arc.lock().await.generate_events_for_closed_tcp_connection();
-                            //println!("matched Ok(0) inside of tcp_read_stream.try_read_buf(&mut tsbuf), generating Event::TcpConnectionFails");
+                            debug!("TCP connection closed by the peer, generating Event::TcpConnectionFails");
+                            let mut neighbor = neighbor_arc.lock().await;
+                            neighbor.generate_events_for_closed_tcp_connection();
+                            // nothing more comes from this one, the next connection arrives as a pending stream
+                            tcp_read_stream = None;
+                            neighbor.tcp_write_stream = None;
+                            is_tcp_stream_active = false;
                         }
                     },
                     Ok(size) => {
-                        println!("Read {} bytes from the stream. ", size);
-                        let hex = tsbuf[..size]
-                            .iter()
-                            .map(|b| format!("{:02X} ", b))
-                            .collect::<String>();
-                        println!("Data read from the stream: {}", hex);
+                        debug!(target: "codec", "Read {} bytes from the stream", size);
+                        // building the hex string is expensive, only do it when someone is going to see it
+                        if tracing::enabled!(target: "codec", Level::TRACE) {
+                            trace!(target: "codec", "Data read from the stream: {}", hex_dump(&tsbuf[..size]));
+                        }
                         // min valid bgp msg len is 19 bytes
                         if tsbuf.len() < 19 {
-                            println!("Data in stream too low to be a valid message, skipping: {}", hex);
+                            warn!(target: "codec", "Data in stream too low to be a valid message, skipping {} bytes", size);
                             continue;
                         }
 
                         let messages = match extract_messages_from_rec_data(&tsbuf[..size]) {
                             Ok(msgs) => msgs,
                             Err(e) => {
-                                println!("Error extracting messages: {:#?}, skipping", e);
+                                error!(target: "codec", "Unable to extract messages: {:?}, skipping", e);
                                 continue;
                             }
                         };
@@ -1547,7 +2312,7 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
                         let mut neighbor = neighbor_arc.lock().await;
                         for msg in &messages {
                             if let Err(e) =  neighbor.process_neighbor_message(&msg, &tsbuf) {
-                                println!("Error: {:#?}, skipping message {:#?}", e, msg);
+                                error!(target: "codec", "{:?}, skipping message {:?}", e, msg);
                             }
                         }
                     },
@@ -1560,16 +2325,12 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
                         else {
                             //return Err(NeighborError::TCPConnDied.into());
                             {
+                                error!("Unable to use TCP Stream -  {:?}, generating Event::TcpConnectionFails", e);
                                 let mut neighbor = neighbor_arc.lock().await;
-                                if neighbor.is_established() {
-                                    println!("Error : Unable to use TCP Stream -  {:#?}, generating Event::TcpConnectionFails", e);
-                                    neighbor.generate_event(Event::TcpConnectionFails);
-                                }
-                                else if neighbor.fsm.state == State::Idle && neighbor.fsm.passive_tcp_establishment {
-                                    neighbor.generate_event(Event::AutomaticStartWithPassiveTcpEstablishment);
-                                }
-
-
+                                neighbor.generate_events_for_closed_tcp_connection();
+                                tcp_read_stream = None;
+                                neighbor.tcp_write_stream = None;
+                                is_tcp_stream_active = false;
                             }
                         }
                     }
@@ -1578,7 +2339,8 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
                 }
             },
             None => {
-                //println!("No TCP read stream in run_neighbor_loop, will not attempt to read");
+                // waiting on a new connection, check for one again shortly instead of spinning
+                sleep(Duration::from_millis(100)).await;
                 continue;
             }
         }
@@ -1586,3 +2348,301 @@ pub async fn run_neighbor_loop(mut tcp_stream: tokio::net::TcpStream, mut neighb
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_neighbor() -> Neighbor {
+        let global_settings = GlobalSettings {
+            my_as: 65000,
+            identifier: Ipv4Addr::new(192, 0, 2, 1),
+            next_hop_ip: Ipv4Addr::new(192, 0, 2, 1),
+            version: BGPVersion::V4,
+            default_local_preference: 100,
+            default_med: 0,
+            optional_parameters: OptionalParameters { capabilities: Vec::new() },
+            vrps: Default::default(),
+        };
+        let (_, neighbor_channel) = BGPProcess::create_neighbor_channels(&PeerType::External);
+        let (tx_channel_watcher, _) = mpsc::channel(1);
+        Neighbor::new(Ipv4Addr::new(192, 0, 2, 2), AS::AS2(65001), 30, 90, PeerType::External, global_settings, neighbor_channel,
+                      tx_channel_watcher, broadcast::channel(16).0).unwrap()
+    }
+
+    // the neighbor gets one end of a loopback connection, the test reads what it sends from the other
+    async fn connected_neighbor() -> (Neighbor, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut neighbor = test_neighbor();
+        neighbor.tcp_write_stream = Some(stream.into_split().1);
+        (neighbor, peer)
+    }
+
+    async fn read_message_types(peer: &mut TcpStream, count: usize) -> Vec<MessageType> {
+        use tokio::io::AsyncReadExt;
+        let mut message_types = Vec::new();
+        for _ in 0..count {
+            let mut header = [0u8; 19];
+            peer.read_exact(&mut header).await.unwrap();
+            let mut body = vec![0u8; u16::from_be_bytes([header[16], header[17]]) as usize - 19];
+            peer.read_exact(&mut body).await.unwrap();
+            message_types.push(parse_packet_type(&header.to_vec()).unwrap());
+        }
+        message_types
+    }
+
+    fn peer_open() -> Vec<u8> {
+        OpenMessage::new(BGPVersion::V4, 65001, 90, Ipv4Addr::new(192, 0, 2, 2), 0, None).unwrap().convert_to_bytes().unwrap()
+    }
+
+    fn delay_open_neighbor(neighbor: &mut Neighbor) {
+        neighbor.fsm.delay_open = true;
+        neighbor.fsm.delay_open_time = 5;
+        neighbor.fsm.state = State::Connect;
+    }
+
+    #[tokio::test]
+    async fn test_open_received_while_delay_open_timer_runs() {
+        let (mut neighbor, mut peer) = connected_neighbor().await;
+        delay_open_neighbor(&mut neighbor);
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::TcpCRAcked, &tcp_channel_tx).await.unwrap();
+        // the connection is up but our Open waits for the timer
+        assert_eq!(neighbor.fsm.state, State::Connect);
+        assert!(neighbor.fsm.is_delay_open_timer_running());
+        assert!(neighbor.sent_open.is_none());
+
+        neighbor.generate_event_from_message(&peer_open(), MessageType::Open).unwrap();
+        let event = neighbor.events.pop_front().unwrap();
+        assert!(matches!(event, Event::BGPOpenWithDelayOpenTimerRunning(_)));
+        neighbor.handle_event(event, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::OpenConfirm);
+        assert!(!neighbor.fsm.is_delay_open_timer_running());
+        assert_eq!(read_message_types(&mut peer, 2).await, vec![MessageType::Open, MessageType::Keepalive]);
+        assert_eq!(neighbor.stats.messages_sent.total(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delay_open_timer_expires() {
+        let (mut neighbor, mut peer) = connected_neighbor().await;
+        delay_open_neighbor(&mut neighbor);
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::TcpCRAcked, &tcp_channel_tx).await.unwrap();
+        neighbor.handle_event(Event::DelayOpenTimerExpires, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::OpenSent);
+        assert!(!neighbor.fsm.is_delay_open_timer_running());
+        // the large hold time RFC 4271 suggests until the peer's Open shows up
+        assert_eq!(neighbor.fsm.hold_timer.max_time, Duration::from_secs(240));
+        assert_eq!(read_message_types(&mut peer, 1).await, vec![MessageType::Open]);
+    }
+
+    #[tokio::test]
+    async fn test_notification_version_error_with_delay_open_timer_running() {
+        let (mut neighbor, _peer) = connected_neighbor().await;
+        delay_open_neighbor(&mut neighbor);
+        neighbor.fsm.damp_peer_oscillations = true;
+        let (tcp_channel_tx, mut tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::TcpCRAcked, &tcp_channel_tx).await.unwrap();
+        neighbor.handle_event(Event::NotifMsgVerErr, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::Idle);
+        assert!(!neighbor.fsm.is_delay_open_timer_running());
+        assert!(matches!(tcp_channel_rx.try_recv(), Ok(TCPChannelMessage::DropTCP)));
+        // with the timer running the peer never saw our Open, so it doesn't count as a failed attempt
+        assert_eq!(neighbor.fsm.connect_retry_counter, 0);
+        assert!(!neighbor.fsm.is_damped());
+        assert!(neighbor.sent_open.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_shutdown_sends_cease() {
+        use tokio::io::AsyncReadExt;
+        let (mut neighbor, mut peer) = connected_neighbor().await;
+        neighbor.fsm.state = State::Established;
+        neighbor.received_open = Some(peer_open());
+        let mut rx_bgp_events = neighbor.tx_bgp_events.subscribe();
+        let (tcp_channel_tx, mut tcp_channel_rx) = mpsc::channel(16);
+        neighbor.shutdown();
+        neighbor.handle_pending_events(&tcp_channel_tx).await;
+        assert_eq!(neighbor.fsm.state, State::Idle);
+        assert!(matches!(tcp_channel_rx.try_recv(), Ok(TCPChannelMessage::DropTCP)));
+
+        let mut notification = [0u8; 21];
+        peer.read_exact(&mut notification).await.unwrap();
+        assert_eq!(parse_packet_type(&notification.to_vec()).unwrap(), MessageType::Notification);
+        // Cease, Administrative Shutdown
+        assert_eq!(notification[19..], [6, 2]);
+        assert_eq!(neighbor.stats.notifications_sent.get("Cease"), Some(&1));
+        assert_eq!(neighbor.stats.last_notification_sent.as_ref().unwrap().subcode, "Cease(AdministrativeShutdown)");
+
+        // BMP tells the station we sent it
+        loop {
+            if let BgpEvent::PeerDown { reason, .. } = rx_bgp_events.try_recv().unwrap() {
+                assert_eq!(reason, PeerDownReason::LocalNotification(notification.to_vec()));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_enable_only_a_shut_down_neighbor() {
+        let mut neighbor = test_neighbor();
+        assert!(!neighbor.enable());
+        assert!(neighbor.events.is_empty());
+        neighbor.shutdown();
+        neighbor.events.clear();
+        assert!(neighbor.enable());
+        assert!(!neighbor.admin_shutdown);
+        assert_eq!(neighbor.events.len(), 1);
+        assert!(!neighbor.enable());
+    }
+
+    #[tokio::test]
+    async fn test_damped_flap_waits_the_logged_idle_hold_time() {
+        let mut neighbor = test_neighbor();
+        neighbor.fsm.damp_peer_oscillations = true;
+        neighbor.fsm.passive_tcp_establishment = false;
+        neighbor.fsm.state = State::Established;
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::TcpConnectionFails, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::Idle);
+        // damped for the 5 seconds it logged, the next flap gets the doubled 10
+        assert_eq!(neighbor.fsm.idle_hold_timer.max_time, Duration::from_secs(5));
+        assert_eq!(neighbor.fsm.idle_hold_time, 10);
+    }
+
+    #[tokio::test]
+    async fn test_connect_retry_timer_connects_to_non_passive_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut neighbor = test_neighbor();
+        neighbor.ip = Ipv4Addr::LOCALHOST;
+        neighbor.peer_port = listener.local_addr().unwrap().port();
+        neighbor.fsm.passive_tcp_establishment = false;
+        neighbor.fsm.state = State::Active;
+        let (tx_tcp_conn, mut rx_tcp_conn) = mpsc::channel(1);
+        neighbor.tx_tcp_conn = Some(tx_tcp_conn);
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::ConnectRetryTimerExpires, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::Connect);
+        let _accepted = listener.accept().await.unwrap();
+        // the connection goes to the BGP proc like an accepted one
+        let (_, sa) = rx_tcp_conn.recv().await.unwrap();
+        assert_eq!(sa, listener.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_passive_neighbor_does_not_connect() {
+        let mut neighbor = test_neighbor();
+        let (tx_tcp_conn, mut rx_tcp_conn) = mpsc::channel(1);
+        neighbor.tx_tcp_conn = Some(tx_tcp_conn);
+        neighbor.connect_if_due();
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.handle_event(Event::ManualStart, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.fsm.state, State::Connect);
+        drop(neighbor);
+        // nothing was spawned holding a sender, so the channel is just closed
+        assert!(rx_tcp_conn.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_generate_event_with_a_wakeup_already_waiting() {
+        let mut neighbor = test_neighbor();
+        let (tx_event_channel_watcher, _rx_event_channel_watcher) = mpsc::channel(1);
+        neighbor.tx_event_channel_watcher = Some(tx_event_channel_watcher);
+        // a closed connection queues events while the event loop is still asleep
+        neighbor.fsm.state = State::Established;
+        neighbor.generate_event(Event::KeepaliveTimerExpires);
+        neighbor.generate_events_for_closed_tcp_connection();
+        assert_eq!(neighbor.events.len(), 2);
+    }
+
+    fn test_route(prefix: &str) -> RouteV4 {
+        use std::str::FromStr;
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: 1, as_list: vec![AS::AS2(65001)] });
+        RouteV4::new(NLRI::from_str(prefix).unwrap(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(192, 0, 2, 2)),
+                     None, None, None, None)
+    }
+
+    #[test]
+    fn test_adj_rib_in_as_received() {
+        let mut neighbor = test_neighbor();
+        let route = test_route("198.51.100.0/24");
+        neighbor.adj_rib_in.insert(route.nlri.clone(), route.clone());
+        // nothing filters its routes, so what we have is what it sent
+        assert_eq!(neighbor.adj_rib_in_as_received(), Some(&neighbor.adj_rib_in));
+        let policies = crate::policy::compile_candidate_policies("[[policies_config]]\nname = \"all\"\ndefault_action = \"accept\"\nterms = []").unwrap();
+        neighbor.import_policy = policies.get("all").cloned();
+        assert_eq!(neighbor.adj_rib_in_as_received(), None);
+        neighbor.adj_rib_in_pre_policy = Some(HashMap::from([(route.nlri.clone(), route)]));
+        assert_eq!(neighbor.adj_rib_in_as_received(), neighbor.adj_rib_in_pre_policy.as_ref());
+    }
+
+    // what the neighbor sent the BGP proc since the last call, routes and withdrawn prefixes
+    fn sent_to_process(process_end: &mut NeighborChannel) -> (Vec<RouteV4>, Vec<NLRI>) {
+        let mut routes = Vec::new();
+        let mut withdrawn = Vec::new();
+        while let Ok(message) = process_end.rx.try_recv() {
+            match message {
+                ChannelMessage::Route(route) => routes.push(route),
+                ChannelMessage::WithdrawRoute(nlri_vec) => withdrawn.extend(nlri_vec),
+                _ => {},
+            }
+        }
+        (routes, withdrawn)
+    }
+
+    #[tokio::test]
+    async fn test_soft_reset_in_from_the_routes_as_received() {
+        let mut neighbor = test_neighbor();
+        let (mut process_end, neighbor_channel) = BGPProcess::create_neighbor_channels(&PeerType::External);
+        neighbor.proc_channel = neighbor_channel;
+        let (tx_channel_watcher, _rx_channel_watcher) = mpsc::channel(64);
+        neighbor.tx_channel_watcher = tx_channel_watcher;
+        neighbor.adj_rib_in_pre_policy = Some(HashMap::new());
+        let policies = crate::policy::compile_candidate_policies("[[policies_config]]\nname = \"all\"\nterms = []").unwrap();
+        neighbor.import_policy = policies.get("all").cloned();
+
+        let (unchanged, customer, blackholed) = (test_route("192.0.2.0/24"), test_route("198.51.100.0/24"), test_route("10.66.0.0/24"));
+        neighbor.import_routes(vec![unchanged.clone(), customer.clone(), blackholed.clone()]).await;
+        assert_eq!(sent_to_process(&mut process_end).0.len(), 3);
+
+        let policies = crate::policy::compile_candidate_policies(r#"
+            [[prefix_lists_config]]
+            name = "customers"
+            prefixes = [{ prefix = "198.51.100.0/24" }]
+            [[prefix_lists_config]]
+            name = "blackholed"
+            prefixes = [{ prefix = "10.66.0.0/24" }]
+            [[policies_config]]
+            name = "all"
+            [[policies_config.terms]]
+            name = "blackholed"
+            match = { prefix_list = "blackholed" }
+            action = "reject"
+            [[policies_config.terms]]
+            name = "customers"
+            match = { prefix_list = "customers" }
+            set = { local_pref = 200 }
+            action = "accept"
+        "#).unwrap();
+        neighbor.import_policy = policies.get("all").cloned();
+        neighbor.soft_reset_in().await.unwrap();
+        // 192.0.2.0/24 came out the same, the BGP proc doesn't hear about it again
+        let (routes, withdrawn) = sent_to_process(&mut process_end);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].nlri, customer.nlri);
+        assert_eq!(routes[0].local_pref.as_ref().map(|local_pref| local_pref.value), Some(200));
+        assert_eq!(withdrawn, vec![blackholed.nlri.clone()]);
+        assert!(!neighbor.adj_rib_in.contains_key(&blackholed.nlri));
+        // the rejected route is still kept as received, a policy that lets it in again brings it back
+        assert_eq!(neighbor.adj_rib_in_pre_policy.as_ref().unwrap().len(), 3);
+
+        neighbor.withdraw_routes(vec![customer.nlri.clone(), blackholed.nlri.clone()]).await;
+        assert_eq!(sent_to_process(&mut process_end).1, vec![customer.nlri.clone(), blackholed.nlri.clone()]);
+        let kept: Vec<&NLRI> = neighbor.adj_rib_in_pre_policy.as_ref().unwrap().keys().collect();
+        assert_eq!(kept, vec![&unchanged.nlri]);
+        neighbor.soft_reset_in().await.unwrap();
+        assert_eq!(sent_to_process(&mut process_end), (Vec::new(), Vec::new()));
+    }
+}
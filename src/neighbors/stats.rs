@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+use crate::finite_state_machine::State;
+use crate::messages::MessageType;
+use crate::messages::notification::NotificationMessage;
+
+// how many FSM transitions a neighbor remembers
+pub const MAX_TRANSITIONS: usize = 16;
+
+#[derive(Debug, Default, Clone)]
+pub struct MessageCounts {
+    pub open: u64,
+    pub update: u64,
+    pub notification: u64,
+    pub keepalive: u64,
+    pub route_refresh: u64,
+}
+
+impl MessageCounts {
+    pub fn add(&mut self, message_type: &MessageType) {
+        match message_type {
+            MessageType::Open => self.open += 1,
+            MessageType::Update => self.update += 1,
+            MessageType::Notification => self.notification += 1,
+            MessageType::Keepalive => self.keepalive += 1,
+            MessageType::RouteRefresh => self.route_refresh += 1,
+        }
+    }
+
+    pub fn by_type(&self) -> [(&'static str, u64); 5] {
+        [("open", self.open), ("update", self.update), ("notification", self.notification), ("keepalive", self.keepalive), ("route_refresh", self.route_refresh)]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.by_type().iter().map(|(_, count)| count).sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefixCounts {
+    // every prefix the neighbor announced, accepted is what's in its Adj-RIB-In now
+    pub received: u64,
+    pub accepted: usize,
+    pub advertised: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct StateTransition {
+    pub at: SystemTime,
+    pub from: State,
+    pub to: State,
+    pub event: &'static str,
+}
+
+impl fmt::Display for StateTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} -> {:?} on {}, {} ago", self.from, self.to, self.event, format_duration(elapsed(self.at)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NotificationRecord {
+    pub at: SystemTime,
+    pub code: String,
+    pub subcode: String,
+}
+
+impl NotificationRecord {
+    pub fn new(msg: &NotificationMessage) -> Self {
+        NotificationRecord { at: SystemTime::now(), code: format!("{:?}", msg.error), subcode: format!("{:?}", msg.error_subcode) }
+    }
+}
+
+impl fmt::Display for NotificationRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}, {} ago", self.code, self.subcode, format_duration(elapsed(self.at)))
+    }
+}
+
+// kept across session resets, they start over when the neighbor is removed from the config
+#[derive(Debug, Default, Clone)]
+pub struct NeighborStats {
+    pub messages_received: MessageCounts,
+    pub messages_sent: MessageCounts,
+    // prefixes carried in the UPDATEs, not the messages
+    pub prefixes_received: u64,
+    pub withdraws_received: u64,
+    // keyed by the error code's name
+    pub notifications_received: BTreeMap<String, u64>,
+    pub notifications_sent: BTreeMap<String, u64>,
+    pub last_notification_received: Option<NotificationRecord>,
+    pub last_notification_sent: Option<NotificationRecord>,
+    // sessions that went down after reaching Established
+    pub flaps: u64,
+    pub established_since: Option<SystemTime>,
+    pub last_state_change: Option<SystemTime>,
+    // oldest first
+    pub transitions: VecDeque<StateTransition>,
+}
+
+impl NeighborStats {
+    pub fn record_transition(&mut self, from: State, to: State, event: &'static str) {
+        let now = SystemTime::now();
+        if to == State::Established {
+            self.established_since = Some(now);
+        } else if from == State::Established {
+            self.established_since = None;
+            self.flaps += 1;
+        }
+        self.last_state_change = Some(now);
+        if self.transitions.len() == MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(StateTransition { at: now, from, to, event });
+    }
+
+    pub fn record_notification_received(&mut self, msg: &NotificationMessage) {
+        let record = NotificationRecord::new(msg);
+        *self.notifications_received.entry(record.code.clone()).or_default() += 1;
+        self.last_notification_received = Some(record);
+    }
+
+    pub fn record_notification_sent(&mut self, msg: &NotificationMessage) {
+        let record = NotificationRecord::new(msg);
+        *self.notifications_sent.entry(record.code.clone()).or_default() += 1;
+        self.last_notification_sent = Some(record);
+    }
+}
+
+// the clock going backwards shows up as no time at all
+pub fn elapsed(at: SystemTime) -> Duration {
+    at.elapsed().unwrap_or_default()
+}
+
+// 00:12:34 under a day, 3d04h after that, like most routers print it
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 86400 {
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else {
+        format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_transition() {
+        let mut stats = NeighborStats::default();
+        stats.record_transition(State::OpenConfirm, State::Established, "KeepAliveMsg");
+        assert!(stats.established_since.is_some());
+        stats.record_transition(State::Established, State::Idle, "HoldTimerExpires");
+        assert!(stats.established_since.is_none());
+        assert_eq!(stats.flaps, 1);
+        for _ in 0..MAX_TRANSITIONS {
+            stats.record_transition(State::Idle, State::Connect, "AutomaticStart");
+        }
+        assert_eq!(stats.transitions.len(), MAX_TRANSITIONS);
+        assert_eq!(stats.transitions.front().unwrap().event, "AutomaticStart");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "01:02:05");
+        assert_eq!(format_duration(Duration::from_secs(3 * 86400 + 4 * 3600 + 5)), "3d04h");
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use crate::config::{Config, PluginConfig, WasmConfig};
+use crate::config::validation::PathSegment;
+use crate::file_watch::WatchedFile;
+use crate::policy::{PolicyContext, PolicyProblem, RouteView};
+use crate::routes::RouteV4;
+
+// import_plugin/export_plugin, filters in WebAssembly modules that run after the neighbor's policy. ABI version 1,
+// a module exports:
+//   memory
+//   alloc(len: i32) -> i32      a buffer we write the input into, the filter owns it from then on
+//   dealloc(ptr: i32, len: i32)  we call it on the output once we've read it
+//   the filters, (ptr: i32, len: i32) -> i64 taking the input and returning where the output is as ptr << 32 | len
+// the input is JSON, {"abi": 1, "direction": "import", "neighbor": {"address": "10.0.0.2", "as": 65001, "local_as": 65000},
+// "route": {...}} with the route as a RouteView, and the output is {"action": "reject"}, {"action": "accept"} or
+// {"action": "accept", "route": {...}} with the route changed. the only import a module gets is bgprtr.log(ptr: i32, len: i32)
+// for a UTF-8 line in our log. each call gets the fuel from [process_config.wasm], a module that traps, runs out or
+// answers with something we can't read rejects the route and starts over from a fresh instance
+
+pub const ABI_VERSION: u32 = 1;
+
+pub type Plugins = HashMap<PathBuf, Arc<PluginModule>>;
+
+#[derive(Serialize)]
+struct NeighborView {
+    address: std::net::Ipv4Addr,
+    #[serde(rename = "as")]
+    as_num: u32,
+    local_as: u16,
+}
+
+#[derive(Serialize)]
+struct FilterInput<'a> {
+    abi: u32,
+    direction: &'a str,
+    neighbor: NeighborView,
+    route: RouteView,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum FilterOutput {
+    Accept {
+        #[serde(default)]
+        route: Option<RouteView>,
+    },
+    Reject,
+}
+
+pub struct PluginModule {
+    // with its mtime when we loaded it
+    file: WatchedFile,
+    limits: WasmConfig,
+    // exports with the filter signature
+    filters: Vec<String>,
+    #[cfg(feature = "wasm")]
+    module: wasmi::Module,
+    // calls from every neighbor using the module take turns on one instance, None after a trap until the next call
+    #[cfg(feature = "wasm")]
+    instance: std::sync::Mutex<Option<engine::PluginInstance>>,
+}
+
+impl fmt::Debug for PluginModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PluginModule({})", self.file.path.display())
+    }
+}
+
+// same as scripts, a reload keeps the Arc for a module that didn't change
+impl PartialEq for PluginModule {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PluginModule {
+    pub fn has_filter(&self, function: &str) -> bool {
+        self.filters.iter().any(|filter| filter == function)
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn load(_path: &Path, _limits: WasmConfig) -> Result<Self, String> {
+        Err("bgprtr was built without the wasm feature".to_string())
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn call(&self, _function: &str, _input: &[u8]) -> Result<Vec<u8>, String> {
+        Err("bgprtr was built without the wasm feature".to_string())
+    }
+}
+
+// a neighbor's import_plugin or export_plugin
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plugin {
+    pub module: Arc<PluginModule>,
+    pub function: String,
+}
+
+impl fmt::Display for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.module.file.path.display(), self.function)
+    }
+}
+
+impl Plugin {
+    fn run(&self, route: &RouteV4, context: &PolicyContext, direction: &str) -> Result<Option<RouteV4>, String> {
+        let input = FilterInput {
+            abi: ABI_VERSION,
+            direction,
+            neighbor: NeighborView { address: context.neighbor, as_num: context.peer_as, local_as: context.my_as },
+            route: RouteView::from_route(route),
+        };
+        let input = serde_json::to_vec(&input).map_err(|e| e.to_string())?;
+        let output = self.module.call(&self.function, &input)?;
+        match serde_json::from_slice(&output).map_err(|e| format!("unable to read the filter's answer - {}", e))? {
+            FilterOutput::Reject => Ok(None),
+            FilterOutput::Accept { route: None } => Ok(Some(route.clone())),
+            FilterOutput::Accept { route: Some(view) } => view.apply_to(route).map(Some),
+        }
+    }
+
+    // None when the filter rejects the route, direction is "import" or "export"
+    pub fn apply(&self, route: RouteV4, context: &PolicyContext, direction: &str) -> Option<RouteV4> {
+        match self.run(&route, context, direction) {
+            Ok(Some(route)) => Some(route),
+            Ok(None) => {
+                debug!(target: "rib", "Plugin {} rejected {:?} on {} for {}", self, route.nlri, direction, context.neighbor);
+                None
+            },
+            Err(e) => {
+                warn!("Plugin {} failed on {:?} on {} for {}, rejecting it - {}", self, route.nlri, direction, context.neighbor, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod engine {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing::info;
+    use wasmi::core::ValType;
+    use wasmi::{Caller, Engine, Extern, ExternType, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+    // an answer bigger than this is a broken filter, not a route
+    const MAX_OUTPUT: usize = 1 << 20;
+
+    pub struct PluginState {
+        limits: StoreLimits,
+        name: String,
+    }
+
+    pub struct PluginInstance {
+        store: Store<PluginState>,
+        instance: Instance,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        dealloc: TypedFunc<(i32, i32), ()>,
+    }
+
+    fn instantiate(path: &Path, module: &Module, limits: &WasmConfig) -> Result<PluginInstance, String> {
+        let state = PluginState {
+            limits: StoreLimitsBuilder::new().memory_size(limits.max_memory.saturating_mul(1 << 20)).instances(1).memories(1).build(),
+            name: path.display().to_string(),
+        };
+        let mut store = Store::new(module.engine(), state);
+        store.limiter(|state| &mut state.limits);
+        let mut linker = Linker::new(module.engine());
+        linker.func_wrap("bgprtr", "log", |caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+            let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else { return };
+            let data = memory.data(&caller);
+            if let Some(line) = data.get(ptr as u32 as usize..).and_then(|data| data.get(..len as u32 as usize)) {
+                info!(target: "plugin", "{}: {}", caller.data().name, String::from_utf8_lossy(line));
+            }
+        }).map_err(|e| e.to_string())?;
+        let instance = linker.instantiate(&mut store, module).and_then(|pre| pre.start(&mut store))
+            .map_err(|e| format!("unable to instantiate {} - {}", path.display(), e))?;
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| format!("{} doesn't export its memory", path.display()))?;
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(|e| format!("{} needs alloc(i32) -> i32 - {}", path.display(), e))?;
+        let dealloc = instance.get_typed_func(&store, "dealloc").map_err(|e| format!("{} needs dealloc(i32, i32) - {}", path.display(), e))?;
+        Ok(PluginInstance { store, instance, memory, alloc, dealloc })
+    }
+
+    impl PluginInstance {
+        fn call(&mut self, function: &str, input: &[u8], fuel: u64) -> Result<Vec<u8>, String> {
+            let store = &mut self.store;
+            store.set_fuel(fuel).map_err(|e| e.to_string())?;
+            let len = i32::try_from(input.len()).map_err(|_| "the route is too big for the module".to_string())?;
+            let ptr = self.alloc.call(&mut *store, len).map_err(|e| format!("alloc failed - {}", e))?;
+            self.memory.write(&mut *store, ptr as u32 as usize, input).map_err(|e| format!("alloc gave us a bad buffer - {}", e))?;
+            let filter: TypedFunc<(i32, i32), i64> = self.instance.get_typed_func(&*store, function).map_err(|e| e.to_string())?;
+            let packed = filter.call(&mut *store, (ptr, len)).map_err(|e| e.to_string())? as u64;
+            let (out_ptr, out_len) = ((packed >> 32) as usize, packed as u32 as usize);
+            if out_len > MAX_OUTPUT {
+                return Err(format!("the answer is {} bytes", out_len))
+            }
+            let mut output = vec![0; out_len];
+            self.memory.read(&*store, out_ptr, &mut output).map_err(|e| format!("the answer is outside the module's memory - {}", e))?;
+            self.dealloc.call(&mut *store, (out_ptr as i32, out_len as i32)).map_err(|e| format!("dealloc failed - {}", e))?;
+            Ok(output)
+        }
+    }
+
+    impl PluginModule {
+        pub fn load(path: &Path, limits: WasmConfig) -> Result<Self, String> {
+            let file = WatchedFile::new(path);
+            let wasm = std::fs::read(path).map_err(|e| format!("unable to read plugin {} - {}", path.display(), e))?;
+            let mut config = wasmi::Config::default();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config);
+            let module = Module::new(&engine, &wasm).map_err(|e| format!("plugin {} isn't a valid module - {}", path.display(), e))?;
+            let filters = module.exports().filter_map(|export| match export.ty() {
+                ExternType::Func(ty) if ty.params() == [ValType::I32, ValType::I32] && ty.results() == [ValType::I64] => Some(export.name().to_string()),
+                _ => None,
+            }).collect();
+            // one up front so a module with imports we don't have or without alloc fails here and not on the first route
+            let instance = instantiate(path, &module, &limits)?;
+            Ok(PluginModule { file, limits, filters, module, instance: Mutex::new(Some(instance)) })
+        }
+
+        pub(super) fn call(&self, function: &str, input: &[u8]) -> Result<Vec<u8>, String> {
+            let mut slot = self.instance.lock().unwrap();
+            let instance = match slot.as_mut() {
+                Some(instance) => instance,
+                None => slot.insert(instantiate(&self.file.path, &self.module, &self.limits)?),
+            };
+            let result = instance.call(function, input, self.limits.fuel);
+            if result.is_err() {
+                // its memory could be anything after a trap
+                *slot = None;
+            }
+            result
+        }
+    }
+}
+
+// every module the neighbors and peer groups refer to, anything unchanged since `previous` was loaded is kept as it is
+pub fn compile_plugins(config: &Config, previous: &Plugins) -> (Plugins, Vec<PolicyProblem>) {
+    let limits = config.process_config.wasm;
+    let neighbors = config.neighbors_config.iter().enumerate()
+        .flat_map(|(idx, nc)| [("neighbors_config", idx, "import_plugin", &nc.import_plugin), ("neighbors_config", idx, "export_plugin", &nc.export_plugin)]);
+    let peer_groups = config.peer_groups_config.iter().enumerate()
+        .flat_map(|(idx, pg)| [("peer_groups_config", idx, "import_plugin", &pg.import_plugin), ("peer_groups_config", idx, "export_plugin", &pg.export_plugin)]);
+
+    let mut plugins = Plugins::new();
+    let mut problems = Vec::new();
+    for (table, idx, key, plugin_config) in neighbors.chain(peer_groups) {
+        let Some(PluginConfig { module, function }) = plugin_config else { continue };
+        if !plugins.contains_key(module) {
+            match previous.get(module) {
+                Some(loaded) if loaded.limits == limits && loaded.file.changed().is_none() => {
+                    plugins.insert(module.clone(), loaded.clone());
+                },
+                _ => match PluginModule::load(module, limits) {
+                    Ok(loaded) => {
+                        plugins.insert(module.clone(), Arc::new(loaded));
+                    },
+                    Err(message) => {
+                        problems.push(PolicyProblem { path: vec![PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key(key), PathSegment::Key("module")], message });
+                        continue
+                    }
+                }
+            }
+        }
+        if let Some(loaded) = plugins.get(module) && !loaded.has_filter(function) {
+            problems.push(PolicyProblem {
+                path: vec![PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key(key), PathSegment::Key("function")],
+                message: format!("{} doesn't export a filter {}(i32, i32) -> i64", module.display(), function),
+            });
+        }
+    }
+    (plugins, problems)
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use crate::messages::update::{AsPath, AsPathSegment, AsPathSegmentType, NextHop, Origin, OriginType, AS};
+    use crate::routes::NLRI;
+
+    // rejects everything whose input mentions 192.0.2., accepts the rest with a fixed route
+    const MODULE: &str = r#"
+        (module
+          (import "bgprtr" "log" (func $log (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "192.0.2.")
+          (data (i32.const 16) "{\"action\":\"reject\"}")
+          (data (i32.const 64) "{\"action\":\"accept\",\"route\":{\"prefix\":\"x\",\"next_hop\":\"10.9.9.9\",\"as_path\":[65000,65001],\"origin\":\"egp\",\"med\":5,\"local_pref\":null,\"communities\":[\"no-export\"]}}")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "dealloc") (param i32 i32))
+          (func (export "spin") (param i32 i32) (result i64) (loop (br 0)) (i64.const 0))
+          (func (export "filter") (param $ptr i32) (param $len i32) (result i64) (local $i i32)
+            (call $log (i32.const 0) (i32.const 8))
+            (block $done
+              (loop $next
+                (br_if $done (i32.gt_s (local.get $i) (i32.sub (local.get $len) (i32.const 8))))
+                (if (i64.eq (i64.load (i32.add (local.get $ptr) (local.get $i))) (i64.load (i32.const 0)))
+                  (then (return (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 19)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 157))))
+    "#;
+
+    fn route(prefix: &str) -> RouteV4 {
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: 1, as_list: vec![AS::AS4(65001)] });
+        RouteV4::new(NLRI::from_str(prefix).unwrap(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(10, 0, 0, 2)),
+                     None, None, None, None)
+    }
+
+    #[test]
+    fn test_run_plugin() {
+        let path = std::env::temp_dir().join(format!("bgprtr-plugin-{}.wasm", std::process::id()));
+        std::fs::write(&path, wat::parse_str(MODULE).unwrap()).unwrap();
+        let module = Arc::new(PluginModule::load(&path, WasmConfig { fuel: 100_000, max_memory: 1 }).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(module.has_filter("filter") && module.has_filter("spin") && !module.has_filter("alloc"));
+        let context = PolicyContext { neighbor: Ipv4Addr::new(10, 0, 0, 2), peer_as: 65001, my_as: 65000, import: true };
+        let plugin = Plugin { module: module.clone(), function: "filter".to_string() };
+
+        assert_eq!(plugin.run(&route("192.0.2.0/24"), &context, "import"), Ok(None));
+        let accepted = plugin.run(&route("198.51.100.0/24"), &context, "import").unwrap().unwrap();
+        assert_eq!(accepted.nlri, NLRI::from_str("198.51.100.0/24").unwrap());
+        assert_eq!(accepted.next_hop.ipv4addr(), Ipv4Addr::new(10, 9, 9, 9));
+        assert_eq!(accepted.as_path.as_path_segment.number_of_as, 2);
+        assert_eq!(accepted.origin.origin_type, OriginType::EGP);
+        assert_eq!(accepted.communities, vec![crate::policy::NO_EXPORT]);
+
+        // out of fuel, and the next call gets a fresh instance
+        let spin = Plugin { module, function: "spin".to_string() };
+        assert!(spin.run(&route("198.51.100.0/24"), &context, "export").is_err());
+        assert!(plugin.run(&route("198.51.100.0/24"), &context, "export").unwrap().is_some());
+    }
+}
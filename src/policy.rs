@@ -0,0 +1,533 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::config::{AsPathListConfig, PolicyAction, PolicyConfig, PolicyOrigin, PolicyTermConfig, PrefixListConfig};
+use crate::config::validation::PathSegment;
+use crate::control::as_to_u32;
+use crate::file_watch::{self, FileChange, WatchedFile};
+use crate::messages::update::{LocalPref, MultiExitDisc, NextHop, OriginType, AS};
+use crate::routes::{RouteV4, NLRI};
+
+pub mod lists;
+
+use lists::{AsPathList, PrefixList};
+
+// named route policies, compiled once from policies_config and shared by every neighbor that uses them
+
+pub type Policies = HashMap<String, Arc<Policy>>;
+
+// RFC 1997 well-known communities
+pub const NO_EXPORT: u32 = 0xFFFF_FF01;
+pub const NO_ADVERTISE: u32 = 0xFFFF_FF02;
+pub const NO_EXPORT_SUBCONFED: u32 = 0xFFFF_FF03;
+
+pub fn parse_community(community: &str) -> Result<u32, String> {
+    match community {
+        "no-export" => return Ok(NO_EXPORT),
+        "no-advertise" => return Ok(NO_ADVERTISE),
+        "no-export-subconfed" => return Ok(NO_EXPORT_SUBCONFED),
+        _ => {},
+    }
+    let (high, low) = community.split_once(':').ok_or_else(|| format!("community {} isn't <as>:<value>", community))?;
+    match (high.parse::<u16>(), low.parse::<u16>()) {
+        (Ok(high), Ok(low)) => Ok((high as u32) << 16 | low as u32),
+        _ => Err(format!("community {} has to be two numbers from 0 to 65535", community)),
+    }
+}
+
+// the other way around, well-known ones by name
+pub fn format_community(community: u32) -> String {
+    match community {
+        NO_EXPORT => "no-export".to_string(),
+        NO_ADVERTISE => "no-advertise".to_string(),
+        NO_EXPORT_SUBCONFED => "no-export-subconfed".to_string(),
+        _ => format!("{}:{}", community >> 16, community & 0xFFFF),
+    }
+}
+
+fn parse_communities(communities: &Option<Vec<String>>) -> Result<Vec<u32>, String> {
+    communities.iter().flatten().map(|community| parse_community(community)).collect()
+}
+
+// the path the way as_path regexes see it, e.g. "65001 65002 65003"
+pub fn as_path_string(route: &RouteV4) -> String {
+    route.as_path.as_path_segment.as_list.iter().map(|as_num| as_num.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+// _ is the Cisco/Junos shorthand for an ASN boundary, e.g. _65001_ matches 65001 anywhere in the path
+fn compile_as_path_regex(as_path: &str) -> Result<Regex, String> {
+    Regex::new(&as_path.replace('_', "(?:^|$| )")).map_err(|e| format!("as_path {} isn't a valid regex - {}", as_path, e))
+}
+
+// a route the way plugins (and anything else outside the daemon) see it, as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteView {
+    pub prefix: String,
+    pub next_hop: Ipv4Addr,
+    pub as_path: Vec<u32>,
+    pub origin: PolicyOrigin,
+    pub med: Option<u32>,
+    pub local_pref: Option<u32>,
+    #[serde(default)]
+    pub communities: Vec<String>,
+    #[serde(default)]
+    pub learned_from: Option<Ipv4Addr>,
+}
+
+impl RouteView {
+    pub fn from_route(route: &RouteV4) -> Self {
+        RouteView {
+            prefix: format!("{}/{}", route.nlri.prefix, route.nlri.len),
+            next_hop: route.next_hop.ipv4addr(),
+            as_path: route.as_path.as_path_segment.as_list.iter().map(as_to_u32).collect(),
+            origin: match route.origin.origin_type {
+                OriginType::IGP => PolicyOrigin::Igp,
+                OriginType::EGP => PolicyOrigin::Egp,
+                OriginType::Incomplete => PolicyOrigin::Incomplete,
+            },
+            med: route.multi_exit_disc.as_ref().map(|med| med.value),
+            local_pref: route.local_pref.as_ref().map(|local_pref| local_pref.value),
+            communities: route.communities.iter().map(|community| format_community(*community)).collect(),
+            learned_from: route.learned_from,
+        }
+    }
+
+    // the attributes on top of `route`, prefix and learned_from are only there to look at
+    pub fn apply_to(&self, route: &RouteV4) -> Result<RouteV4, String> {
+        let mut route = route.clone();
+        route.next_hop = NextHop::new(self.next_hop);
+        let segment = &mut route.as_path.as_path_segment;
+        segment.number_of_as = u8::try_from(self.as_path.len()).map_err(|_| "as_path can't be longer than 255 ASNs".to_string())?;
+        segment.as_list = self.as_path.iter().map(|as_num| AS::AS4(*as_num)).collect();
+        route.origin.origin_type = origin_type(self.origin);
+        route.multi_exit_disc = self.med.map(MultiExitDisc::new);
+        route.local_pref = self.local_pref.map(LocalPref::new);
+        route.communities = self.communities.iter().map(|community| parse_community(community)).collect::<Result<_, _>>()?;
+        Ok(route)
+    }
+}
+
+fn origin_type(origin: PolicyOrigin) -> OriginType {
+    match origin {
+        PolicyOrigin::Igp => OriginType::IGP,
+        PolicyOrigin::Egp => OriginType::EGP,
+        PolicyOrigin::Incomplete => OriginType::Incomplete,
+    }
+}
+
+// what a policy gets to know about the route besides its attributes
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyContext {
+    // the neighbor we're importing from or exporting to
+    pub neighbor: Ipv4Addr,
+    pub peer_as: u32,
+    pub my_as: u16,
+    // prepend repeats the neighbor's AS on import and ours on export
+    pub import: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyResult {
+    pub action: PolicyAction,
+    // the term that took the action, None when the route fell through to default_action
+    pub term: Option<String>,
+    // the route with every set applied, only worth anything when accepted
+    pub route: RouteV4,
+}
+
+impl PolicyResult {
+    pub fn accepted(&self) -> bool {
+        self.action == PolicyAction::Accept
+    }
+}
+
+#[derive(Debug)]
+struct Term {
+    config: PolicyTermConfig,
+    prefix_list: Option<Arc<PrefixList>>,
+    as_path: Option<Regex>,
+    as_path_list: Option<Arc<AsPathList>>,
+    community: Vec<u32>,
+    set_communities: Option<Vec<u32>>,
+    add_communities: Vec<u32>,
+    remove_communities: Vec<u32>,
+}
+
+impl Term {
+    fn matches(&self, route: &RouteV4, context: &PolicyContext) -> bool {
+        let conditions = &self.config.conditions;
+        if let Some(prefix_list) = &self.prefix_list && !prefix_list.matches(&route.nlri) {
+            return false
+        }
+        if let Some(as_path) = &self.as_path && !as_path.is_match(&as_path_string(route)) {
+            return false
+        }
+        if let Some(as_path_list) = &self.as_path_list && !as_path_list.matches(route) {
+            return false
+        }
+        if !self.community.is_empty() && !self.community.iter().any(|community| route.communities.contains(community)) {
+            return false
+        }
+        if let Some(origin) = conditions.origin && route.origin.origin_type != origin_type(origin) {
+            return false
+        }
+        if let Some(next_hop) = &conditions.next_hop && !next_hop.iter().any(|nlri| nlri.contains(route.next_hop.ipv4addr())) {
+            return false
+        }
+        if let Some(neighbor) = &conditions.neighbor && !neighbor.contains(&context.neighbor) {
+            return false
+        }
+        if let Some(rpki) = conditions.rpki && route.rpki_state != rpki {
+            return false
+        }
+        true
+    }
+
+    fn apply(&self, route: &mut RouteV4, context: &PolicyContext) {
+        let set = &self.config.set;
+        if let Some(local_pref) = set.local_pref {
+            route.local_pref = Some(LocalPref::new(local_pref));
+        }
+        if let Some(med) = set.med {
+            route.multi_exit_disc = Some(MultiExitDisc::new(med));
+        }
+        if let Some(communities) = &self.set_communities {
+            route.communities = communities.clone();
+        }
+        for community in &self.add_communities {
+            if !route.communities.contains(community) {
+                route.communities.push(*community);
+            }
+        }
+        route.communities.retain(|community| !self.remove_communities.contains(community));
+        if let Some(next_hop) = set.next_hop {
+            route.next_hop = NextHop::new(next_hop);
+        }
+        if let Some(prepend) = set.prepend {
+            let segment = &mut route.as_path.as_path_segment;
+            let prepended = prepend.min(u8::MAX - segment.number_of_as);
+            let as_num = if context.import { context.peer_as } else { context.my_as as u32 };
+            segment.as_list.splice(0..0, std::iter::repeat_n(AS::AS4(as_num), prepended as usize));
+            segment.number_of_as += prepended;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    config: PolicyConfig,
+    terms: Vec<Term>,
+}
+
+// the regexes can't be compared, but they come straight from the config
+impl PartialEq for Policy {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config && self.terms.len() == other.terms.len()
+            && self.terms.iter().zip(&other.terms).all(|(a, b)| a.prefix_list == b.prefix_list && a.as_path_list == b.as_path_list)
+    }
+}
+
+impl Policy {
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    // its routes have to go through it again when the VRPs change
+    pub fn uses_rpki(&self) -> bool {
+        self.terms.iter().any(|term| term.config.conditions.rpki.is_some())
+    }
+
+    pub fn evaluate(&self, route: &RouteV4, context: &PolicyContext) -> PolicyResult {
+        let mut route = route.clone();
+        for term in &self.terms {
+            if !term.matches(&route, context) {
+                continue
+            }
+            term.apply(&mut route, context);
+            if let Some(action) = term.config.action {
+                return PolicyResult { action, term: Some(term.config.name.clone()), route }
+            }
+        }
+        PolicyResult { action: self.config.default_action, term: None, route }
+    }
+
+    // evaluates without applying anything, so a policy can be tried on routes before a neighbor uses it
+    pub fn dry_run<'a>(&self, routes: impl IntoIterator<Item = &'a RouteV4>, context: impl Fn(&RouteV4) -> PolicyContext) -> Vec<DryRun> {
+        routes.into_iter()
+            .map(|route| DryRun { before: route.clone(), result: self.evaluate(route, &context(route)) })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRun {
+    pub before: RouteV4,
+    pub result: PolicyResult,
+}
+
+// something in prefix_lists_config or policies_config that keeps it from compiling
+#[derive(Debug)]
+pub struct PolicyProblem {
+    pub path: Vec<PathSegment<'static>>,
+    pub message: String,
+}
+
+fn compile_term(idx: usize, term_idx: usize, config: &PolicyTermConfig, prefix_lists: &HashMap<&str, Arc<PrefixList>>,
+                as_path_lists: &HashMap<&str, Arc<AsPathList>>, problems: &mut Vec<PolicyProblem>) -> Option<Term> {
+    let mut report = |section: &'static str, key: &'static str, message: String| {
+        problems.push(PolicyProblem {
+            path: vec![PathSegment::Key("policies_config"), PathSegment::Index(idx), PathSegment::Key("terms"), PathSegment::Index(term_idx),
+                       PathSegment::Key(section), PathSegment::Key(key)],
+            message,
+        });
+    };
+    let conditions = &config.conditions;
+    let set = &config.set;
+    let prefix_list = match &conditions.prefix_list {
+        Some(name) => match prefix_lists.get(name.as_str()) {
+            Some(prefix_list) => Some(prefix_list.clone()),
+            None => {
+                report("match", "prefix_list", format!("prefix list {} is not configured in prefix_lists_config", name));
+                None
+            }
+        },
+        None => None,
+    };
+    let as_path = conditions.as_path.as_deref().map(compile_as_path_regex).transpose()
+        .map_err(|e| report("match", "as_path", e)).ok().flatten();
+    let as_path_list = match &conditions.as_path_list {
+        Some(name) => match as_path_lists.get(name.as_str()) {
+            Some(as_path_list) => Some(as_path_list.clone()),
+            None => {
+                report("match", "as_path_list", format!("AS path list {} is not configured in as_path_lists_config", name));
+                None
+            }
+        },
+        None => None,
+    };
+    let community = parse_communities(&conditions.community).map_err(|e| report("match", "community", e)).ok();
+    let set_communities = set.communities.as_ref().map(|_| parse_communities(&set.communities)).transpose()
+        .map_err(|e| report("set", "communities", e)).ok();
+    let add_communities = parse_communities(&set.add_communities).map_err(|e| report("set", "add_communities", e)).ok();
+    let remove_communities = parse_communities(&set.remove_communities).map_err(|e| report("set", "remove_communities", e)).ok();
+
+    let broken = (conditions.prefix_list.is_some() && prefix_list.is_none()) || (conditions.as_path.is_some() && as_path.is_none())
+        || (conditions.as_path_list.is_some() && as_path_list.is_none());
+    if broken {
+        return None
+    }
+    Some(Term {
+        config: config.clone(),
+        prefix_list,
+        as_path,
+        as_path_list,
+        community: community?,
+        set_communities: set_communities?,
+        add_communities: add_communities?,
+        remove_communities: remove_communities?,
+    })
+}
+
+// a policy with a problem is left out entirely, half of one could let through what it was meant to stop
+pub fn compile_policies(prefix_lists_config: &[PrefixListConfig], as_path_lists_config: &[AsPathListConfig],
+                        policies_config: &[PolicyConfig]) -> (Policies, Vec<PolicyProblem>) {
+    let mut problems = Vec::new();
+    let mut prefix_lists = HashMap::new();
+    for (idx, config) in prefix_lists_config.iter().enumerate() {
+        let problem = |key: &'static str, message: String| PolicyProblem {
+            path: vec![PathSegment::Key("prefix_lists_config"), PathSegment::Index(idx), PathSegment::Key(key)],
+            message,
+        };
+        if prefix_lists.contains_key(config.name.as_str()) {
+            problems.push(problem("name", format!("prefix list {} is configured more than once", config.name)));
+            continue
+        }
+        let (entries, file) = match lists::load_prefixes(config) {
+            Ok(loaded) => loaded,
+            Err((key, message)) => {
+                problems.push(problem(key, message));
+                continue
+            }
+        };
+        match PrefixList::from_config(&entries) {
+            Ok(mut prefix_list) => {
+                prefix_list.file = file;
+                prefix_lists.insert(config.name.as_str(), Arc::new(prefix_list));
+            },
+            Err((entry_idx, message)) => problems.push(match file {
+                Some(file) => problem("file", format!("{} entry {} - {}", file.path.display(), entry_idx + 1, message)),
+                None => PolicyProblem {
+                    path: vec![PathSegment::Key("prefix_lists_config"), PathSegment::Index(idx), PathSegment::Key("prefixes"), PathSegment::Index(entry_idx)],
+                    message,
+                },
+            }),
+        }
+    }
+
+    let mut as_path_lists = HashMap::new();
+    for (idx, config) in as_path_lists_config.iter().enumerate() {
+        let problem = |key: &'static str, message: String| PolicyProblem {
+            path: vec![PathSegment::Key("as_path_lists_config"), PathSegment::Index(idx), PathSegment::Key(key)],
+            message,
+        };
+        if as_path_lists.contains_key(config.name.as_str()) {
+            problems.push(problem("name", format!("AS path list {} is configured more than once", config.name)));
+            continue
+        }
+        match lists::load_asns(config) {
+            Ok((asns, file)) => {
+                as_path_lists.insert(config.name.as_str(), Arc::new(AsPathList::new(asns, file)));
+            },
+            Err((key, message)) => problems.push(problem(key, message)),
+        }
+    }
+
+    let mut policies = Policies::new();
+    for (idx, config) in policies_config.iter().enumerate() {
+        if policies.contains_key(&config.name) {
+            problems.push(PolicyProblem {
+                path: vec![PathSegment::Key("policies_config"), PathSegment::Index(idx), PathSegment::Key("name")],
+                message: format!("policy {} is configured more than once", config.name),
+            });
+            continue
+        }
+        let problems_before = problems.len();
+        let mut term_names = HashSet::new();
+        let mut terms = Vec::with_capacity(config.terms.len());
+        for (term_idx, term_config) in config.terms.iter().enumerate() {
+            if !term_names.insert(term_config.name.as_str()) {
+                problems.push(PolicyProblem {
+                    path: vec![PathSegment::Key("policies_config"), PathSegment::Index(idx), PathSegment::Key("terms"), PathSegment::Index(term_idx),
+                               PathSegment::Key("name")],
+                    message: format!("term {} is in policy {} more than once", term_config.name, config.name),
+                });
+            }
+            if let Some(term) = compile_term(idx, term_idx, term_config, &prefix_lists, &as_path_lists, &mut problems) {
+                terms.push(term);
+            }
+        }
+        if problems.len() == problems_before {
+            policies.insert(config.name.clone(), Arc::new(Policy { config: config.clone(), terms }));
+        }
+    }
+    (policies, problems)
+}
+
+// list files the policies use that changed on disk since they were read
+pub fn changed_files(policies: &Policies) -> Vec<FileChange> {
+    file_watch::sort_changes(policies.values()
+        .flat_map(|policy| &policy.terms)
+        .flat_map(|term| [term.prefix_list.as_ref().and_then(|list| list.file.as_ref()), term.as_path_list.as_ref().and_then(|list| list.file.as_ref())])
+        .flatten()
+        .filter_map(WatchedFile::changed))
+}
+
+// the policy part of a config file, the rest of it is ignored
+#[derive(Debug, Deserialize)]
+struct CandidatePolicies {
+    #[serde(default)]
+    prefix_lists_config: Vec<PrefixListConfig>,
+    #[serde(default)]
+    as_path_lists_config: Vec<AsPathListConfig>,
+    #[serde(default)]
+    policies_config: Vec<PolicyConfig>,
+}
+
+// policies from a config that isn't running yet, all or nothing since they're only used to try things out
+pub fn compile_candidate_policies(config: &str) -> Result<Policies, String> {
+    let candidate: CandidatePolicies = toml::from_str(config).map_err(|e| format!("Unable to parse the policies - {}", e.message()))?;
+    let (policies, problems) = compile_policies(&candidate.prefix_lists_config, &candidate.as_path_lists_config, &candidate.policies_config);
+    match problems.is_empty() {
+        true => Ok(policies),
+        false => Err(problems.into_iter().map(|problem| problem.message).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::messages::update::{AsPath, AsPathSegment, AsPathSegmentType, Origin};
+    use crate::rpki::RpkiState;
+
+    const POLICIES: &str = r#"
+        [[prefix_lists_config]]
+        name = "customers"
+        prefixes = [{ prefix = "198.51.100.0/22", ge = 23, le = 24 }, { prefix = "203.0.113.0/24" }]
+
+        [[policies_config]]
+        name = "from-upstream"
+        default_action = "reject"
+
+        [[policies_config.terms]]
+        name = "drop-invalid"
+        match = { rpki = "invalid" }
+        action = "reject"
+
+        [[policies_config.terms]]
+        name = "tag"
+        match = { as_path = "^65001_" }
+        set = { add_communities = ["65000:1"], prepend = 2 }
+
+        [[policies_config.terms]]
+        name = "customers"
+        match = { prefix_list = "customers", community = ["65000:1"] }
+        set = { local_pref = 200, remove_communities = ["65000:1"] }
+        action = "accept"
+    "#;
+
+    fn route(prefix: &str, as_list: Vec<u32>) -> RouteV4 {
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: as_list.len() as u8,
+                                                  as_list: as_list.into_iter().map(AS::AS4).collect() });
+        RouteV4::new(NLRI::from_str(prefix).unwrap(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(10, 0, 0, 2)),
+                     None, None, None, None)
+    }
+
+    #[test]
+    fn test_evaluate_policy() {
+        let policies = compile_candidate_policies(POLICIES).unwrap();
+        let policy = &policies["from-upstream"];
+        let context = PolicyContext { neighbor: Ipv4Addr::new(10, 0, 0, 2), peer_as: 65001, my_as: 65000, import: true };
+
+        let result = policy.evaluate(&route("198.51.101.0/24", vec![65001, 65010]), &context);
+        assert!(result.accepted());
+        assert_eq!(result.term.as_deref(), Some("customers"));
+        assert_eq!(result.route.local_pref, Some(LocalPref::new(200)));
+        assert!(result.route.communities.is_empty());
+        assert_eq!(as_path_string(&result.route), "65001 65001 65001 65010");
+        // on export it's our own AS that goes in front
+        let export = PolicyContext { import: false, ..context };
+        assert_eq!(as_path_string(&policy.evaluate(&route("198.51.101.0/24", vec![65001, 65010]), &export).route), "65000 65000 65001 65010");
+
+        // 65001 isn't first in the path, so the community the customers term wants never gets added
+        let result = policy.evaluate(&route("198.51.101.0/24", vec![65010, 65001]), &context);
+        assert!(!result.accepted());
+        assert_eq!(result.term, None);
+
+        // a /22 is outside ge 23
+        assert!(!policy.evaluate(&route("198.51.100.0/22", vec![65001]), &context).accepted());
+        assert!(policy.evaluate(&route("203.0.113.0/24", vec![65001]), &context).accepted());
+        let mut invalid = route("203.0.113.0/24", vec![65001]);
+        invalid.rpki_state = RpkiState::Invalid;
+        assert_eq!(policy.evaluate(&invalid, &context).term.as_deref(), Some("drop-invalid"));
+        assert!(policy.uses_rpki());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let policies = compile_candidate_policies(POLICIES).unwrap();
+        let context = |route: &RouteV4| PolicyContext { neighbor: route.learned_from.unwrap_or(Ipv4Addr::UNSPECIFIED), peer_as: 65001, my_as: 65000, import: true };
+        let routes = vec![route("203.0.113.0/24", vec![65001]), route("192.0.2.0/24", vec![65001])];
+        let results = policies["from-upstream"].dry_run(&routes, context);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].before, routes[0]);
+        assert_eq!(results[0].result.term.as_deref(), Some("customers"));
+        assert_eq!(results[0].result.route.local_pref, Some(LocalPref::new(200)));
+        assert!(!results[1].result.accepted());
+
+        let error = compile_candidate_policies("[[policies_config]]\nname = \"x\"\ndefault_action = \"accept\"\nterms = [{ name = \"a\", match = { prefix_list = \"missing\" } }]").unwrap_err();
+        assert!(error.contains("missing"), "{}", error);
+    }
+}
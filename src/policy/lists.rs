@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use crate::config::{AsPathListConfig, ListFileFormat, PrefixListConfig, PrefixListEntryConfig};
+use crate::control::as_to_u32;
+use crate::file_watch::WatchedFile;
+use crate::routes::{RouteV4, NLRI};
+
+// Prefix and AS path lists for policy terms, written out in the config or kept in files generated from IRR data, e.g.
+//   bgpq4 -j -l customers AS-EXAMPLE > customers.json
+//   bgpq4 -j -f 65001 -l customer-asns AS-EXAMPLE > customer-asns.json
+//   bgpq4 -F "%n/%l\n" AS-EXAMPLE > customers.txt
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct TrieNode {
+    // indexes into nodes, the root is 0 so 0 also means no child
+    children: [u32; 2],
+    // bit n is set when a prefix of length n inside this node's prefix matches
+    lengths: u64,
+}
+
+// a binary trie on the prefix bits, a lookup visits at most 33 nodes however long the list is
+#[derive(Debug, Clone)]
+pub struct PrefixList {
+    nodes: Vec<TrieNode>,
+    pub file: Option<WatchedFile>,
+}
+
+// the same entries in the same order, a file that was only touched doesn't count as a change
+impl PartialEq for PrefixList {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes
+    }
+}
+
+fn bit(prefix: u32, depth: u8) -> usize {
+    (prefix >> (31 - depth) & 1) as usize
+}
+
+impl PrefixList {
+    pub fn from_config(entries: &[PrefixListEntryConfig]) -> Result<Self, (usize, String)> {
+        let mut prefix_list = PrefixList { nodes: vec![TrieNode::default()], file: None };
+        for (idx, entry) in entries.iter().enumerate() {
+            let len = entry.nlri.len;
+            // ge on its own runs up to /32, le on its own starts at the prefix's own length
+            let (ge, le) = match (entry.ge, entry.le) {
+                (None, None) => (len, len),
+                (Some(ge), None) => (ge, 32),
+                (None, Some(le)) => (len, le),
+                (Some(ge), Some(le)) => (ge, le),
+            };
+            if ge < len || le < ge || le > 32 {
+                return Err((idx, format!("ge {} and le {} have to satisfy {} <= ge <= le <= 32", ge, le, len)));
+            }
+            prefix_list.insert(&entry.nlri, ge, le);
+        }
+        Ok(prefix_list)
+    }
+
+    fn insert(&mut self, nlri: &NLRI, ge: u8, le: u8) {
+        let prefix = u32::from(nlri.prefix);
+        let mut node = 0;
+        for depth in 0..nlri.len {
+            let bit = bit(prefix, depth);
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(TrieNode::default());
+                self.nodes[node].children[bit] = (self.nodes.len() - 1) as u32;
+            }
+            node = self.nodes[node].children[bit] as usize;
+        }
+        self.nodes[node].lengths |= (u64::MAX >> (63 - le)) & (u64::MAX << ge);
+    }
+
+    pub fn matches(&self, nlri: &NLRI) -> bool {
+        let prefix = u32::from(nlri.prefix);
+        let mut node = &self.nodes[0];
+        for depth in 0..nlri.len {
+            if node.lengths & 1 << nlri.len != 0 {
+                return true
+            }
+            match node.children[bit(prefix, depth)] {
+                0 => return false,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+        node.lengths & 1 << nlri.len != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsPathList {
+    asns: HashSet<u32>,
+    pub file: Option<WatchedFile>,
+}
+
+impl PartialEq for AsPathList {
+    fn eq(&self, other: &Self) -> bool {
+        self.asns == other.asns
+    }
+}
+
+impl AsPathList {
+    pub fn new(asns: Vec<u32>, file: Option<WatchedFile>) -> Self {
+        AsPathList { asns: asns.into_iter().collect(), file }
+    }
+
+    // routes we originate have an empty path and never match
+    pub fn matches(&self, route: &RouteV4) -> bool {
+        route.as_path.as_path_segment.as_list.last().is_some_and(|as_num| self.asns.contains(&as_to_u32(as_num)))
+    }
+}
+
+// the entries and the file they came from, or the key in the list's config the error belongs to
+type Loaded<T> = Result<(Vec<T>, Option<WatchedFile>), (&'static str, String)>;
+
+pub fn load_prefixes(config: &PrefixListConfig) -> Loaded<PrefixListEntryConfig> {
+    load(&config.prefixes, &config.file, config.format, parse_prefixes)
+}
+
+pub fn load_asns(config: &AsPathListConfig) -> Loaded<u32> {
+    load(&config.asns, &config.file, config.format, parse_asns)
+}
+
+// a file that doesn't parse fails as a whole, nothing in it gets used
+fn load<T: Clone>(inline: &[T], file: &Option<PathBuf>, format: Option<ListFileFormat>, parse: fn(&str, ListFileFormat) -> Result<Vec<T>, String>) -> Loaded<T> {
+    let Some(path) = file else {
+        return match format {
+            Some(_) => Err(("format", "format only applies to a list in a file".to_string())),
+            None => Ok((inline.to_vec(), None)),
+        }
+    };
+    if !inline.is_empty() {
+        return Err(("file", "a list comes from either its entries or a file, not both".to_string()))
+    }
+    let watched = WatchedFile::new(path);
+    let content = fs::read_to_string(path).map_err(|e| ("file", format!("unable to read {} - {}", path.display(), e)))?;
+    let format = format.unwrap_or(match path.extension().is_some_and(|extension| extension == "json") {
+        true => ListFileFormat::Bgpq4Json,
+        false => ListFileFormat::Plain,
+    });
+    let entries = parse(&content, format).map_err(|e| ("file", format!("{} - {}", path.display(), e)))?;
+    Ok((entries, Some(watched)))
+}
+
+// what bgpq4 -j writes for a prefix list, exact is implied by leaving out both lengths
+#[derive(Debug, Deserialize)]
+struct Bgpq4Prefix {
+    prefix: String,
+    #[serde(rename = "greater-equal")]
+    greater_equal: Option<u8>,
+    #[serde(rename = "less-equal")]
+    less_equal: Option<u8>,
+}
+
+// bgpq4 -j wraps the list in an object keyed by its -l name
+fn bgpq4_list<T: DeserializeOwned>(content: &str) -> Result<Vec<T>, String> {
+    let lists: HashMap<String, Vec<T>> = serde_json::from_str(content).map_err(|e| format!("not bgpq4 JSON - {}", e))?;
+    let mut lists = lists.into_values();
+    match (lists.next(), lists.next()) {
+        (Some(list), None) => Ok(list),
+        _ => Err("has to hold exactly one list, the way bgpq4 -j writes it".to_string()),
+    }
+}
+
+// plain files have no end to check for, but a half written one is at least refused when it's still empty
+fn plain_lines(content: &str) -> Result<Vec<(usize, &str)>, String> {
+    let lines: Vec<(usize, &str)> = content.lines().enumerate()
+        .map(|(idx, line)| (idx + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+    match lines.is_empty() {
+        true => Err("is empty, use bgpq4 JSON for a list that can be empty".to_string()),
+        false => Ok(lines),
+    }
+}
+
+fn parse_prefixes(content: &str, format: ListFileFormat) -> Result<Vec<PrefixListEntryConfig>, String> {
+    match format {
+        ListFileFormat::Bgpq4Json => bgpq4_list::<Bgpq4Prefix>(content)?.into_iter().enumerate().map(|(idx, entry)| {
+            let nlri = NLRI::from_str(&entry.prefix).map_err(|e| format!("entry {} - {}", idx + 1, e))?;
+            Ok(PrefixListEntryConfig { nlri, ge: entry.greater_equal, le: entry.less_equal })
+        }).collect(),
+        ListFileFormat::Plain => plain_lines(content)?.into_iter()
+            .map(|(line_no, line)| parse_plain_prefix(line).map_err(|e| format!("line {} - {}", line_no, e)))
+            .collect(),
+    }
+}
+
+// "192.0.2.0/24", optionally followed by "ge 24" and/or "le 32"
+fn parse_plain_prefix(line: &str) -> Result<PrefixListEntryConfig, String> {
+    let mut words = line.split_whitespace();
+    let nlri = NLRI::from_str(words.next().unwrap_or_default())?;
+    let mut entry = PrefixListEntryConfig { nlri, ge: None, le: None };
+    while let Some(word) = words.next() {
+        let len = words.next().and_then(|len| len.parse::<u8>().ok()).ok_or_else(|| format!("{} needs a prefix length", word))?;
+        match word {
+            "ge" => entry.ge = Some(len),
+            "le" => entry.le = Some(len),
+            _ => return Err(format!("expected ge or le, not {}", word)),
+        }
+    }
+    Ok(entry)
+}
+
+fn parse_asns(content: &str, format: ListFileFormat) -> Result<Vec<u32>, String> {
+    match format {
+        ListFileFormat::Bgpq4Json => bgpq4_list(content),
+        ListFileFormat::Plain => plain_lines(content)?.into_iter()
+            .flat_map(|(line_no, line)| line.split_whitespace().map(move |word| (line_no, word)))
+            .map(|(line_no, word)| {
+                let asn = word.strip_prefix("AS").or_else(|| word.strip_prefix("as")).unwrap_or(word);
+                asn.parse::<u32>().map_err(|_| format!("line {} - {} is not an ASN", line_no, word))
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_list_files() {
+        let json = r#"{"customers": [
+            {"prefix": "192.0.2.0/24", "exact": true},
+            {"prefix": "198.51.100.0/22", "exact": false, "greater-equal": 23, "less-equal": 24}
+        ]}"#;
+        let entries = parse_prefixes(json, ListFileFormat::Bgpq4Json).unwrap();
+        let prefix_list = PrefixList::from_config(&entries).unwrap();
+        let matches = |prefix: &str| prefix_list.matches(&NLRI::from_str(prefix).unwrap());
+        assert!(matches("192.0.2.0/24"));
+        assert!(!matches("192.0.2.0/25"));
+        assert!(matches("198.51.101.0/24"));
+        assert!(matches("198.51.102.0/23"));
+        assert!(!matches("198.51.100.0/22"));
+        assert!(!matches("203.0.113.0/24"));
+
+        let plain = "# AS-EXAMPLE\n192.0.2.0/24\n\n198.51.100.0/22 le 24\n";
+        let entries = parse_prefixes(plain, ListFileFormat::Plain).unwrap();
+        assert_eq!(entries[1].le, Some(24));
+        assert!(parse_prefixes("192.0.2.0/24\n192.0.2/24\n", ListFileFormat::Plain).unwrap_err().starts_with("line 2"));
+        assert!(parse_prefixes("\n# nothing yet\n", ListFileFormat::Plain).is_err());
+        assert!(parse_prefixes(r#"{"a": [], "b": []}"#, ListFileFormat::Bgpq4Json).is_err());
+
+        assert_eq!(parse_asns(r#"{"NN": [65001, 65002]}"#, ListFileFormat::Bgpq4Json).unwrap(), vec![65001, 65002]);
+        assert_eq!(parse_asns("AS65001 65002\n65003\n", ListFileFormat::Plain).unwrap(), vec![65001, 65002, 65003]);
+
+        // 100k /24s in a trie, every one matches and nothing next to them does
+        let entries: Vec<PrefixListEntryConfig> = (0..100_000u32)
+            .map(|n| PrefixListEntryConfig { nlri: NLRI { prefix: Ipv4Addr::from(0x0A00_0000 + (n << 8)), len: 24 }, ge: None, le: None })
+            .collect();
+        let prefix_list = PrefixList::from_config(&entries).unwrap();
+        assert!(entries.iter().all(|entry| prefix_list.matches(&entry.nlri)));
+        assert!(!prefix_list.matches(&NLRI { prefix: Ipv4Addr::new(10, 0, 0, 0), len: 16 }));
+        assert!(!prefix_list.matches(&NLRI { prefix: Ipv4Addr::from(0x0A00_0000 + (100_000 << 8)), len: 24 }));
+    }
+}
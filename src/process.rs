@@ -1,39 +1,180 @@
 
+use tracing::{debug, error, info, trace, warn};
 use crate::messages::update::MultiExitDisc;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use std::str::FromStr;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::net::tcp::OwnedReadHalf;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
 use tokio::sync::{mpsc, broadcast};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
+use tokio::signal::unix::{signal, SignalKind};
 use crate::config::*;
 use crate::errors::*;
 use crate::finite_state_machine::events::Event;
 use crate::messages::BGPVersion;
 use crate::utils::*;
 use crate::messages::update::AS::AS4;
-use crate::{neighbors, process};
-use crate::channels::{ChannelWatcherMessage, ChannelMessage, NeighborChannel};
+use crate::{control, neighbors, process};
+use crate::control::protocol::{ControlRequest, ControlResponse};
+use crate::channels::{BgpEvent, ChannelWatcherMessage, ChannelMessage, NeighborChannel};
 use crate::messages::update::{AsPath, AsPathSegment, AsPathSegmentType, LocalPref, NextHop, Origin, OriginType, AS};
 use crate::neighbors::{Neighbor, PeerType};
+use crate::metrics::{self, BestPathTiming};
+use crate::bmp::{self, PeerStatistics};
+use crate::mrt::{self, bgp4mp::{self, MessageArchive}};
+use crate::policy::{self, Policies};
+use crate::plugins::Plugins;
+use crate::scripting::{self, Scripts};
+use crate::rpki::{RpkiState, Vrps};
 use crate::routes::{RouteV4, NLRI};
 use crate::messages::optional_parameters::*;
 
-async fn start_tcp(address: String, port: String) -> TcpListener {
-    let listener = TcpListener::bind(address + ":" + &port).await;
-    match listener {
-        Ok(tcp) => {
-            println!("TCP server started on port {} ", port);
-            tcp
-        },
-        Err(e) => {
-            panic!("Unable to bind port {}, error is {}", port, e);
+fn resolve_neighbors_config(config: &Config, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Vec<EffectiveNeighborConfig> {
+    let default_capabilities = config.process_config.capabilities_config;
+    let mut configured_neighbors = Vec::new();
+    for nc in &config.neighbors_config {
+        match nc.resolve(&config.peer_groups_config, default_capabilities, policies, plugins, scripts) {
+            Ok(enc) => {
+                debug!("{}", enc);
+                configured_neighbors.push(enc);
+            },
+            Err(e) => {
+                error!("Unable to resolve config for neighbor {} - {:?}, skipping", nc.ip, e);
+            }
+        }
+    }
+    configured_neighbors
+}
+
+fn resolve_listen_ranges_config(config: &Config, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Vec<EffectiveListenRangeConfig> {
+    let default_capabilities = config.process_config.capabilities_config;
+    let mut configured_listen_ranges = Vec::new();
+    for lr in &config.listen_ranges_config {
+        match lr.resolve(&config.peer_groups_config, default_capabilities, policies, plugins, scripts) {
+            Ok(elr) => {
+                debug!("{}", elr);
+                configured_listen_ranges.push(elr);
+            },
+            Err(e) => {
+                error!("Unable to resolve config for listen range {:?} - {:?}, skipping", lr.nlri, e);
+            }
         }
     }
+    configured_listen_ranges
+}
+
+fn resolve_mrt_replays_config(config: &Config, policies: &Policies, plugins: &Plugins, scripts: &Scripts) -> Vec<EffectiveMrtReplayConfig> {
+    let default_capabilities = config.process_config.capabilities_config;
+    let mut mrt_replays = Vec::new();
+    for replay in &config.mrt_replays_config {
+        match replay.resolve(&config.peer_groups_config, default_capabilities, policies, plugins, scripts) {
+            Ok(emrc) => mrt_replays.push(emrc),
+            Err(e) => {
+                error!("Unable to resolve config for replaying {} - {:?}, skipping", replay.file.display(), e);
+            }
+        }
+    }
+    mrt_replays
+}
+
+async fn start_tcp(listen_config: &ListenConfig) -> Result<Vec<TcpListener>, ProcessError> {
+    let mut listeners = Vec::new();
+    if !listen_config.passive {
+        info!("Passive mode is off, not listening for TCP connections");
+        return Ok(listeners)
+    }
+    for address in &listen_config.addresses {
+        let socket_addr = SocketAddr::new(*address, listen_config.port);
+        match TcpListener::bind(socket_addr).await {
+            Ok(tcp) => {
+                info!("TCP server started on {}", socket_addr);
+                listeners.push(tcp);
+            },
+            Err(e) => {
+                error!("Unable to bind {}, error is {}", socket_addr, e);
+                return Err(ProcessError::UnableToBindListener)
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+fn run_accept_loop(listener: TcpListener, tx_tcp_conn: Sender<(TcpStream, SocketAddr)>) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((tcp_stream, sa)) => {
+                    if tx_tcp_conn.send((tcp_stream, sa)).await.is_err() {
+                        error!("Process loop is gone, closing listener");
+                        return;
+                    }
+                },
+                Err(e) => {
+                    error!("Unable to accept TCP connection - {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+// things outside the process loop can ask it to do, e.g. a reload on SIGHUP
+#[derive(Debug)]
+pub enum ProcessCommand {
+    ReloadConfig,
+    // from the control socket, the answer goes back on the oneshot
+    Control(ControlRequest, oneshot::Sender<ControlResponse>),
+    // a /metrics scrape, answered with the rendered text
+    Metrics(oneshot::Sender<String>),
+    // a BMP station connected and needs the current state as events
+    BmpSnapshot(oneshot::Sender<Vec<BgpEvent>>),
+    BmpStatistics(oneshot::Sender<Vec<PeerStatistics>>),
+    // an RPKI cache changed the VRPs, every route gets validated again
+    RpkiUpdated,
+}
+
+// stands in for the process loop in the tests of the things that talk to it, every command goes to answer
+#[cfg(test)]
+pub fn fake_process_loop(mut answer: impl FnMut(ProcessCommand) + Send + 'static) -> Sender<ProcessCommand> {
+    let (tx_process_command, mut rx_process_command) = mpsc::channel(8);
+    tokio::spawn(async move {
+        while let Some(command) = rx_process_command.recv().await {
+            answer(command);
+        }
+    });
+    tx_process_command
+}
+
+// the APIs only send control requests
+#[cfg(test)]
+pub fn fake_control_loop(answer: impl Fn(ControlRequest) -> ControlResponse + Send + 'static) -> Sender<ProcessCommand> {
+    fake_process_loop(move |command| {
+        if let ProcessCommand::Control(request, tx_response) = command {
+            let _ = tx_response.send(answer(request));
+        }
+    })
+}
+
+pub fn run_reload_signal_loop(tx_process_command: Sender<ProcessCommand>) {
+    tokio::spawn(async move {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Unable to listen for SIGHUP, config reload on signal is disabled - {}", e);
+                return;
+            }
+        };
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading config");
+            if tx_process_command.send(ProcessCommand::ReloadConfig).await.is_err() {
+                return;
+            }
+        }
+    });
 }
 
 #[derive(Debug, Clone)]
@@ -44,7 +185,9 @@ pub struct GlobalSettings {
     pub version: BGPVersion,
     pub default_local_preference: u32,
     pub default_med: u32,
-    pub optional_parameters: OptionalParameters
+    pub optional_parameters: OptionalParameters,
+    // filled by the RTR clients, neighbors validate against it before their import policy
+    pub vrps: Vrps,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -58,23 +201,47 @@ enum BestPathResult {
 pub struct BGPProcess {
     pub global_settings: GlobalSettings,
     //pub neighbors: HashMap<Ipv4Addr, Neighbor>,
-    pub configured_neighbors: Vec<NeighborConfig>,
+    pub configured_neighbors: Vec<EffectiveNeighborConfig>,
     pub configured_networks: Vec<NetAdvertisementsConfig>,
+    // announced through the control socket, a reload leaves these alone
+    pub announced_networks: Vec<NLRI>,
+    pub listen_config: ListenConfig,
+    pub configured_listen_ranges: Vec<EffectiveListenRangeConfig>,
+    // kept around for reloads
+    pub config_file_name: String,
+    pub process_config: ProcessConfig,
     // TODO changes to loc-rib generate events to all neighbors to send update
     pub adj_rib_in: HashMap<NLRI, Vec<RouteV4>>,
     pub local_rib: HashMap<NLRI, Vec<RouteV4>>,
+    pub tx_bgp_events: broadcast::Sender<BgpEvent>,
+    // exported on /metrics
+    pub best_path_timing: BestPathTiming,
+    pub bmp_servers: Vec<BmpServerConfig>,
+    // the BGP4MP writer thread, None unless process_config.mrt_archive is set
+    pub tx_message_archive: Option<UnboundedSender<Vec<u8>>>,
+    // set once the process loop is running, neighbors with passive off hand the connections they open back through it
+    pub tx_tcp_conn: Option<Sender<(TcpStream, SocketAddr)>>,
+    // MRT files replayed as virtual neighbors at startup
+    pub mrt_replays: Vec<EffectiveMrtReplayConfig>,
+    // compiled from policies_config, what bgpctl test policy runs against by default
+    pub policies: Policies,
+    // WebAssembly modules by path, reloaded when the file changed
+    pub plugins: Plugins,
+    // import/export scripts by path, reloads reuse the ones that didn't change
+    pub scripts: Scripts,
+    pub rpki_config: RpkiConfig,
     //pub neighbors_channels: HashMap<Ipv4Addr, NeighborChannel>, // moved to it's own var so we can lock it separately from the bgp proc
 }
 
 impl BGPProcess {
 
-    pub fn new(config_file_name: &str) -> Self {
-        let config = read_config_file(config_file_name);
+    pub fn new(config_file_name: &str) -> Result<Self, ConfigError> {
+        let LoadedConfig { config, policies, plugins, scripts } = load_config_file(config_file_name, &Plugins::new(), &Scripts::new())?;
 
         let global_settings = GlobalSettings {
             my_as: config.process_config.my_as,
-            identifier: Ipv4Addr::from_str(&config.process_config.router_id).unwrap(),
-            next_hop_ip: Ipv4Addr::from_str(&config.process_config.next_hop_ip).unwrap(),
+            identifier: Ipv4Addr::from_str(&config.process_config.router_id).map_err(|_| ConfigError::BadRouterId)?,
+            next_hop_ip: Ipv4Addr::from_str(&config.process_config.next_hop_ip).map_err(|_| ConfigError::BadNextHopIP)?,
             default_local_preference: config.process_config.default_local_preference,
             default_med: config.process_config.default_med,
             version: BGPVersion::V4,
@@ -85,18 +252,40 @@ impl BGPProcess {
                 config.process_config.capabilities_config.enhanced_route_refresh,
                 config.process_config.capabilities_config.extended_4byte_asn,
                 Some(config.process_config.my_as as u32)
-            )
+            ),
+            vrps: Vrps::default(),
         };
 
-        BGPProcess {
+        // fold the peer groups into every neighbor and listen range up front so nothing else has to care about them
+        let configured_neighbors = resolve_neighbors_config(&config, &policies, &plugins, &scripts);
+        let configured_listen_ranges = resolve_listen_ranges_config(&config, &policies, &plugins, &scripts);
+        let mrt_replays = resolve_mrt_replays_config(&config, &policies, &plugins, &scripts);
+        let tx_message_archive = config.process_config.mrt_archive.clone().map(bgp4mp::run_message_archive);
+
+        Ok(BGPProcess {
             global_settings,
             //neighbors: HashMap::new(),
-            configured_neighbors: config.neighbors_config,
+            configured_neighbors,
             configured_networks: config.net_advertisements_config,
+            announced_networks: Vec::new(),
+            listen_config: config.process_config.listen.clone(),
+            configured_listen_ranges,
+            config_file_name: config_file_name.to_string(),
+            process_config: config.process_config,
             adj_rib_in: HashMap::new(),
             local_rib: HashMap::new(),
+            tx_bgp_events: broadcast::channel(1024).0,
+            best_path_timing: BestPathTiming::default(),
+            bmp_servers: config.bmp_servers_config,
+            tx_message_archive,
+            tx_tcp_conn: None,
+            mrt_replays,
+            policies,
+            plugins,
+            scripts,
+            rpki_config: config.rpki_config,
             //neighbors_channels: HashMap::new(),
-        }
+        })
     }
 
     pub fn calc_best_path() {
@@ -151,10 +340,9 @@ impl BGPProcess {
 
     }
 
-    pub fn get_neighbor_config(&self, ipv4addr: Ipv4Addr) -> Result<NeighborConfig, NeighborError> {
-        let ip = ipv4addr.to_string();
+    pub fn get_neighbor_config(&self, ipv4addr: Ipv4Addr) -> Result<EffectiveNeighborConfig, NeighborError> {
         for cn in &self.configured_neighbors {
-            if ip == cn.ip {
+            if ipv4addr == cn.ip {
                 return Ok(cn.clone());
             }
         }
@@ -217,17 +405,19 @@ impl BGPProcess {
             return Err(NeighborError::ConfiguredNeighborsEmpty)
         }
         for cn in &self.configured_neighbors {
-            // TODO refactor string comparison out as it's inefficient
-            if peer_ip.to_string() == cn.ip {
-                 println!("Validated neighbor IP is in configured neighbors");
+            if peer_ip == cn.ip {
+                 trace!("Validated neighbor IP is in configured neighbors");
                 return Ok(())
             }
-            else {
-                return Err(NeighborError::NeighborIPNotRecognized)
-            }
-
         }
-       Err(NeighborError::ConfiguredNeighborsEmpty)
+       Err(NeighborError::NeighborIPNotRecognized)
+    }
+
+    pub fn get_listen_range_for_ip(&self, peer_ip: Ipv4Addr) -> Option<&EffectiveListenRangeConfig> {
+        // longest match wins if ranges overlap
+        self.configured_listen_ranges.iter()
+            .filter(|lr| lr.nlri.contains(peer_ip))
+            .max_by_key(|lr| lr.nlri.len)
     }
 
     // pub fn handle_tcp_event(&mut self) {
@@ -247,21 +437,72 @@ impl BGPProcess {
         AsPath::new(as_path_segment)
     }
 
-    fn populate_local_rib_from_config(&mut self) {
-        for configured_network in &self.configured_networks {
-            println!("populating local rib from config");
-            println!("configured_network is {:#?}", configured_network);
-            let nlri = configured_network.nlri.clone();
-            let origin = Origin::new(OriginType::IGP);
-            let as_path = self.generate_local_as_path_for_advertisement();
-            let next_hop = NextHop::new(self.global_settings.next_hop_ip);
-            let local_pref = Some(LocalPref::new(self.global_settings.default_local_preference));
-            let med = Some(MultiExitDisc::new(self.global_settings.default_med));
-            let atomic_aggregate = None;
-            let aggregator = None;
-            let new_route = RouteV4::new(nlri.clone(), origin, as_path, next_hop , local_pref, med, atomic_aggregate, aggregator);
+    pub fn is_local_network(&self, nlri: &NLRI) -> bool {
+        self.configured_networks.iter().any(|n| &n.nlri == nlri) || self.announced_networks.contains(nlri)
+    }
 
-            self.adj_rib_in.insert(nlri, vec![new_route]);
+    // puts our own routes in the RIB and hands them to every neighbor
+    pub async fn announce_local_networks(&mut self, all_neighbors_channels: &HashMap<Ipv4Addr, NeighborChannel>, nlris: Vec<NLRI>) {
+        let new_routes: Vec<RouteV4> = nlris.into_iter().map(|nlri| self.generate_local_route(nlri)).collect();
+        for route in &new_routes {
+            self.insert_local_route(route.clone());
+        }
+        for neighbor_channel in all_neighbors_channels.values() {
+            for route in &new_routes {
+                if neighbor_channel.tx.send(ChannelMessage::Route(route.clone())).await.is_err() {
+                    error!("Unable to send new routes to a neighbor, its channel is down");
+                    break;
+                }
+            }
+        }
+    }
+
+    pub async fn withdraw_local_networks(&mut self, all_neighbors_channels: &HashMap<Ipv4Addr, NeighborChannel>, nlris: Vec<NLRI>) {
+        for nlri in &nlris {
+            // paths other neighbors sent us for the same prefix stay
+            if let Some(route_paths) = self.adj_rib_in.get_mut(nlri) {
+                route_paths.retain(|path| path.learned_from.is_some());
+                if route_paths.is_empty() {
+                    self.adj_rib_in.remove(nlri);
+                }
+            }
+            // no neighbor is involved, so compare everything
+            self.calculate_best_path(nlri, &PeerType::Internal);
+        }
+        for neighbor_channel in all_neighbors_channels.values() {
+            if neighbor_channel.tx.send(ChannelMessage::WithdrawRoute(nlris.clone())).await.is_err() {
+                error!("Unable to send withdrawn routes to a neighbor, its channel is down");
+            }
+        }
+    }
+
+    fn generate_local_route(&self, nlri: NLRI) -> RouteV4 {
+        let origin = Origin::new(OriginType::IGP);
+        let as_path = self.generate_local_as_path_for_advertisement();
+        let next_hop = NextHop::new(self.global_settings.next_hop_ip);
+        let local_pref = Some(LocalPref::new(self.global_settings.default_local_preference));
+        let med = Some(MultiExitDisc::new(self.global_settings.default_med));
+        let atomic_aggregate = None;
+        let aggregator = None;
+        RouteV4::new(nlri, origin, as_path, next_hop , local_pref, med, atomic_aggregate, aggregator)
+    }
+
+    fn insert_local_route(&mut self, mut route: RouteV4) {
+        let nlri = route.nlri.clone();
+        route.rpki_state = self.global_settings.vrps.validate_route(&route, self.global_settings.my_as);
+        let route_paths = self.adj_rib_in.entry(nlri.clone()).or_default();
+        route_paths.retain(|path| path.learned_from.is_some());
+        route_paths.push(route);
+        self.calculate_best_path(&nlri, &PeerType::Internal);
+    }
+
+    fn populate_local_rib_from_config(&mut self) {
+        let configured_networks = self.configured_networks.clone();
+        for configured_network in configured_networks {
+            debug!(target: "rib", "populating local rib from config");
+            debug!(target: "rib", "configured_network is {:?}", configured_network);
+            let new_route = self.generate_local_route(configured_network.nlri.clone());
+            self.insert_local_route(new_route);
         }
     }
 
@@ -276,79 +517,364 @@ impl BGPProcess {
         Arc::new(Mutex::new(all_neighbors_channels))
     }
 
-    pub async fn run_process_loop(bgp_proc: Arc<Mutex<BGPProcess>>, address: &str, port: &str) {
+    pub async fn run_process_loop(bgp_proc: Arc<Mutex<BGPProcess>>, mut rx_process_command: Receiver<ProcessCommand>) -> Result<(), BGPError> {
         let bgp_proc_arc = Arc::clone(&bgp_proc);
+        // bind first so we fail before spawning anything if the addresses are bad or in use
+        let listen_config = bgp_proc_arc.lock().await.listen_config.clone();
+        let listeners = start_tcp(&listen_config).await?;
+        let (tx_tcp_conn, mut rx_tcp_conn) = mpsc::channel::<(TcpStream, SocketAddr)>(16);
+        for listener in listeners {
+            run_accept_loop(listener, tx_tcp_conn.clone());
+        }
+        bgp_proc_arc.lock().await.tx_tcp_conn = Some(tx_tcp_conn.clone());
+
         // init
         BGPProcess::populate_local_rib_from_config_arc(&bgp_proc_arc).await;
         let all_neighbors_channels_arc = BGPProcess::init_process_channels();
         let (tx_channel_watcher, rx_channel_watcher) = mpsc::channel::<ChannelWatcherMessage>(1);
         //let (tx_all_event_channel_watcher, rx_all_event_channel_watcher) = broadcast::channel::<ChannelWatcherMessage>(1);
-        let mut all_neighbors = BGPProcess::populate_neighbors_from_config(&bgp_proc, &all_neighbors_channels_arc, tx_channel_watcher).await;
+        let mut all_neighbors = BGPProcess::populate_neighbors_from_config(&bgp_proc, &all_neighbors_channels_arc, tx_channel_watcher.clone()).await;
         BGPProcess::run_recv_message_channel_loop(Arc::clone(&bgp_proc), Arc::clone(&all_neighbors_channels_arc), rx_channel_watcher).await;
-        BGPProcess::generate_event_for_all_neighbors(&mut all_neighbors, Event::AutomaticStartWithPassiveTcpEstablishment).await;
+        // after the configured neighbors so a file can't take over one of their addresses
+        let mrt_replays = bgp_proc_arc.lock().await.mrt_replays.clone();
+        mrt::replay::run_replays(mrt_replays, &bgp_proc_arc, &all_neighbors_channels_arc, &tx_channel_watcher);
+        BGPProcess::generate_automatic_start_for_all_neighbors(&mut all_neighbors).await;
+        // neighbors move out of all_neighbors once they get a connection, this keeps a handle on them
+        let mut running_neighbors: HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>> = HashMap::new();
+        // edited scripts and their data files get picked up like a reload
+        let script_check_interval = bgp_proc_arc.lock().await.process_config.scripting.check_interval;
+        let mut script_check = tokio::time::interval(Duration::from_secs(script_check_interval.max(1) as u64));
+        let mut tried_file_changes = Vec::new();
+        // neighbors with passive off that haven't had a connection yet retry theirs from here
+        let mut connect_check = tokio::time::interval(Duration::from_secs(1));
         //
 
-
-        let listener = start_tcp(address.to_string(), port.to_string()).await;
+        // we keep tx_tcp_conn alive here, so with passive off this just waits for commands instead of returning
         loop {
-            // TODO handle config sync between proc and neighbors, maybe use an event based thing or just cycle through the neighbors and update
             // TODO generate events here for for overall process (also do it in neighbor run)
-            match listener.accept().await {
-                Ok((mut tcp_stream, sa)) => {
-                    println!("TCP connection established from {}", sa.ip().to_string());
-                    let peer_ip = match get_neighbor_ipv4_address_from_socket(tcp_stream.peer_addr()) {
-                        Ok(ip) => ip,
-                        Err(e) => {
-                            println!("Error: TCP Socket error -  {:#?}, skipping", e);
-                            continue;
-                        }
+            tokio::select! {
+                Some((tcp_stream, sa)) = rx_tcp_conn.recv() => {
+                    BGPProcess::handle_tcp_connection(&bgp_proc_arc, &mut all_neighbors, &mut running_neighbors, &all_neighbors_channels_arc, &tx_channel_watcher, tcp_stream, sa).await;
+                },
+                Some(command) = rx_process_command.recv() => {
+                    match command {
+                        ProcessCommand::ReloadConfig => {
+                            if let Err(e) = BGPProcess::reload_config(&bgp_proc_arc, &mut all_neighbors, &all_neighbors_channels_arc, &tx_channel_watcher).await {
+                                error!("Unable to reload config, keeping the running config - {:?}", e);
+                            }
+                        },
+                        ProcessCommand::Control(request, tx_response) => {
+                            let response = control::handle_control_request(request, &bgp_proc_arc, &mut all_neighbors, &mut running_neighbors, &all_neighbors_channels_arc,
+                                                                          &tx_channel_watcher).await;
+                            // bgpctl may have given up already, nothing to do about it
+                            let _ = tx_response.send(response);
+                        },
+                        ProcessCommand::Metrics(tx_response) => {
+                            let _ = tx_response.send(metrics::render_metrics(&bgp_proc_arc, &all_neighbors, &running_neighbors).await);
+                        },
+                        ProcessCommand::BmpSnapshot(tx_response) => {
+                            let _ = tx_response.send(bmp::snapshot(&bgp_proc_arc, &running_neighbors).await);
+                        },
+                        ProcessCommand::BmpStatistics(tx_response) => {
+                            let _ = tx_response.send(bmp::statistics(&bgp_proc_arc, &running_neighbors).await);
+                        },
+                        ProcessCommand::RpkiUpdated => {
+                            BGPProcess::revalidate_routes(&bgp_proc_arc, &running_neighbors).await;
+                        },
+                    }
+                },
+                _ = connect_check.tick() => {
+                    for neighbor in all_neighbors.values_mut() {
+                        neighbor.connect_if_due();
+                    }
+                },
+                _ = script_check.tick(), if script_check_interval > 0 => {
+                    let changed = {
+                        let bgp_proc = bgp_proc_arc.lock().await;
+                        let mut changed = scripting::changed_files(&bgp_proc.scripts);
+                        changed.extend(policy::changed_files(&bgp_proc.policies));
+                        changed
                     };
-                    {
-                        let bgp  = bgp_proc_arc.lock().await;
-                        if let Err(e) = bgp.validate_neighbor_ip_is_configured(peer_ip) {
-                            println!("Error: Unable to validate neighbor IP: {:#?}, skipping", e);
-                            continue;
+                    // a change that didn't load stays out until the files change again
+                    if !changed.is_empty() && changed != tried_file_changes {
+                        info!("Scripts or list files changed on disk, reloading config - {:?}", changed.iter().map(|(path, _)| path).collect::<Vec<_>>());
+                        if let Err(e) = BGPProcess::reload_config(&bgp_proc_arc, &mut all_neighbors, &all_neighbors_channels_arc, &tx_channel_watcher).await {
+                            error!("Unable to reload config, keeping the running config, scripts and lists - {:?}", e);
                         }
                     }
+                    tried_file_changes = changed;
+                },
+                else => break,
+            }
+        }
+        Ok(())
+    }
 
-                    //let all_neighbors_arc = Arc::clone(&all_neighbors);
-                    let mut neighbor = match all_neighbors.remove(&peer_ip) {
-                        Some(n) => n,
-                        None => {
-                            // TODO split the first connection vs resuming/passing a new connection to neighbor into two different funcs
-                            println!("Unable to get neighbor object from all_neighbors, which means the neighbor loop is already running");
-                            {
-                                let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
-                                println!("unlocked all_neighbors_channels_arc");
-                                if let Some(neighbor_channel) =  all_neighbors_channels.get_mut(&peer_ip) {
-                                    println!("Got Some(neighbor_channel)");
-                                    match neighbor_channel.send_tcp_conn_to_neighbor(tcp_stream) {
-                                        Ok(_) => {println!("Sent TCP connection in neighbor channel")}
-                                        Err(e) => {println!("ERROR: Unable to send TCP connection in channel - {:#?}", e)}
-                                    }
-                                }
-                            }
-                            continue;
-                        }
-                    };
-                    println!("Extracted neighbor from hashmap");
-                    let (tx_event_channel_watcher, rx_event_channel_watcher) = mpsc::channel::<ChannelWatcherMessage>(5);
-                    neighbor.tx_event_channel_watcher = Some(tx_event_channel_watcher);
-                    neighbor.generate_event(Event::TcpCRAcked);
-                    println!("Generated Event::TcpCRAcked");
-                    tokio::spawn(async move {
-                        println!("Moving neighbor to async task and executing run_neighbor_loop");
-                        // get the neighbor and pass the tcp conn
-                        if let Err(e) = neighbors::run_neighbor_loop(tcp_stream, neighbor, peer_ip, rx_event_channel_watcher).await {
-                            println!("Error: Unable to continue run() for neighbor {:#?} - {:#?}", peer_ip, e);
-                        }
-                    });
+    // the process validates its paths again, neighbors whose import policy matches on rpki run it again
+    async fn revalidate_routes(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, running_neighbors: &HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>) {
+        {
+            let mut bgp_proc = bgp_proc_arc.lock().await;
+            let changed = bgp_proc.revalidate_adj_rib_in();
+            info!("Validated the Adj-RIB-In against {} VRPs, {} prefixes changed state", bgp_proc.global_settings.vrps.count(), changed);
+        }
+        // what they re-import comes back through the recv loop, which needs the process lock
+        for neighbor_arc in running_neighbors.values() {
+            neighbor_arc.lock().await.revalidate_in().await;
+        }
+    }
+
+    fn revalidate_adj_rib_in(&mut self) -> usize {
+        let (vrps, my_as) = (self.global_settings.vrps.clone(), self.global_settings.my_as);
+        let mut changed = Vec::new();
+        for (nlri, route_paths) in &mut self.adj_rib_in {
+            let mut state_changed = false;
+            for route in route_paths {
+                let rpki_state = vrps.validate_route(route, my_as);
+                state_changed |= route.rpki_state != rpki_state;
+                route.rpki_state = rpki_state;
+            }
+            if state_changed {
+                changed.push(nlri.clone());
+            }
+        }
+        for nlri in &changed {
+            self.calculate_best_path(nlri, &PeerType::Internal);
+        }
+        changed.len()
+    }
+
+    async fn handle_tcp_connection(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>,
+                                   running_neighbors: &mut HashMap<Ipv4Addr, Arc<Mutex<Neighbor>>>,
+                                   all_neighbors_channels_arc: &Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
+                                   tx_channel_watcher: &Sender<ChannelWatcherMessage>, tcp_stream: TcpStream, sa: SocketAddr) {
+        info!("TCP connection established from {}", sa.ip());
+        let peer_ip = match get_neighbor_ipv4_address_from_socket(tcp_stream.peer_addr()) {
+            Ok(ip) => ip,
+            Err(e) => {
+                error!("TCP Socket error -  {:?}, skipping", e);
+                return;
+            }
+        };
+        // the configured neighbors aren't counted against a listen range's max_neighbors
+        let (listen_range, configured_neighbor_ips) = {
+            let bgp  = bgp_proc_arc.lock().await;
+            let configured_neighbor_ips: Vec<Ipv4Addr> = bgp.configured_neighbors.iter().map(|cn| cn.ip).collect();
+            let listen_range = match bgp.validate_neighbor_ip_is_configured(peer_ip) {
+                Ok(_) => None,
+                Err(e) => match bgp.get_listen_range_for_ip(peer_ip) {
+                    Some(lr) => Some(lr.clone()),
+                    None => {
+                        error!("Unable to validate neighbor IP: {:?}, skipping", e);
+                        return;
+                    }
+                }
+            };
+            (listen_range, configured_neighbor_ips)
+        };
+
+        //let all_neighbors_arc = Arc::clone(&all_neighbors);
+        let mut neighbor = match all_neighbors.remove(&peer_ip) {
+            Some(n) => n,
+            None => {
+                // TODO split the first connection vs resuming/passing a new connection to neighbor into two different funcs
+                let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+                trace!("unlocked all_neighbors_channels_arc");
+                if let Some(neighbor_channel) = all_neighbors_channels.get(&peer_ip)
+                    && neighbor_channel.tx.is_closed() && listen_range.is_some() {
+                    // dynamic neighbor already went away, the recv loop just hasn't cleaned up after it yet
+                    all_neighbors_channels.remove(&peer_ip);
+                }
+                if let Some(neighbor_channel) =  all_neighbors_channels.get_mut(&peer_ip) {
+                    debug!("Unable to get neighbor object from all_neighbors, which means the neighbor loop is already running");
+                    match neighbor_channel.send_tcp_conn_to_neighbor(tcp_stream) {
+                        Ok(_) => {debug!("Sent TCP connection in neighbor channel")}
+                        Err(e) => {error!("Unable to send TCP connection in channel - {:?}", e)}
+                    }
+                    return;
+                }
+                let Some(listen_range) = listen_range else { return };
+                if let Some(max_neighbors) = listen_range.max_neighbors {
+                    let dynamic_neighbor_count = all_neighbors_channels.keys()
+                        .filter(|ip| listen_range.nlri.contains(**ip) && !configured_neighbor_ips.contains(*ip))
+                        .count();
+                    if dynamic_neighbor_count >= max_neighbors {
+                        warn!("Listen range {:?} already has {} neighbors, dropping connection from {}", listen_range.nlri, dynamic_neighbor_count, peer_ip);
+                        return;
+                    }
+                }
+                let (global_settings, tx_bgp_events, tx_message_archive) = {
+                    let bgp_proc = bgp_proc_arc.lock().await;
+                    (bgp_proc.global_settings.clone(), bgp_proc.tx_bgp_events.clone(), bgp_proc.tx_message_archive.clone())
+                };
+                match BGPProcess::create_dynamic_neighbor(peer_ip, &listen_range, global_settings, &mut all_neighbors_channels, tx_channel_watcher.clone(), tx_bgp_events,
+                                                          tx_message_archive) {
+                    Ok(mut neighbor) => {
+                        info!("Created dynamic neighbor {} from listen range {:?}", peer_ip, listen_range.nlri);
+                        let event = neighbor.fsm.automatic_start_event();
+                        neighbor.events.push_back(event);
+                        neighbor
+                    },
+                    Err(e) => {
+                        error!("Unable to create dynamic neighbor {} - {:?}", peer_ip, e);
+                        return;
+                    }
+                }
+            }
+        };
+        if neighbor.admin_shutdown {
+            info!("Neighbor {} is shut down, dropping connection", peer_ip);
+            all_neighbors.insert(peer_ip, neighbor);
+            return;
+        }
+        trace!("Extracted neighbor from hashmap");
+        let (tx_event_channel_watcher, rx_event_channel_watcher) = mpsc::channel::<ChannelWatcherMessage>(5);
+        neighbor.tx_event_channel_watcher = Some(tx_event_channel_watcher);
+        neighbor.generate_event(Event::TcpCRAcked);
+        trace!("Generated Event::TcpCRAcked");
+        let neighbor_arc = Arc::new(Mutex::new(neighbor));
+        running_neighbors.insert(peer_ip, Arc::clone(&neighbor_arc));
+        tokio::spawn(async move {
+            trace!("Moving neighbor to async task and executing run_neighbor_loop");
+            // get the neighbor and pass the tcp conn
+            if let Err(e) = neighbors::run_neighbor_loop(tcp_stream, neighbor_arc, peer_ip, rx_event_channel_watcher).await {
+                error!("Unable to continue run() for neighbor {:?} - {:?}", peer_ip, e);
+            }
+        });
+    }
+
+    pub async fn reload_config(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>,
+                               all_neighbors_channels_arc: &Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
+                               tx_channel_watcher: &Sender<ChannelWatcherMessage>) -> Result<(), BGPError> {
+        // diff the new config against the running one and only touch what changed, every other session stays up
+        let (config_file_name, previous_plugins, previous_scripts) = {
+            let bgp_proc = bgp_proc_arc.lock().await;
+            (bgp_proc.config_file_name.clone(), bgp_proc.plugins.clone(), bgp_proc.scripts.clone())
+        };
+        let LoadedConfig { config, policies, plugins, scripts } = load_config_file(&config_file_name, &previous_plugins, &previous_scripts)?;
+        let new_neighbors = resolve_neighbors_config(&config, &policies, &plugins, &scripts);
+        let new_listen_ranges = resolve_listen_ranges_config(&config, &policies, &plugins, &scripts);
+
+        // same lock order as the recv loop, channels first
+        let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
+        let mut bgp_proc = bgp_proc_arc.lock().await;
+        let old_process_config = &bgp_proc.process_config;
+        let new_process_config = &config.process_config;
+        if old_process_config.my_as != new_process_config.my_as || old_process_config.router_id != new_process_config.router_id
+            || old_process_config.next_hop_ip != new_process_config.next_hop_ip
+            || old_process_config.default_local_preference != new_process_config.default_local_preference
+            || old_process_config.default_med != new_process_config.default_med || old_process_config.listen != new_process_config.listen
+            || old_process_config.http_api != new_process_config.http_api || old_process_config.grpc_api != new_process_config.grpc_api
+            || old_process_config.metrics != new_process_config.metrics || old_process_config.mrt_dump != new_process_config.mrt_dump
+            || old_process_config.mrt_archive != new_process_config.mrt_archive
+            || old_process_config.scripting.check_interval != new_process_config.scripting.check_interval {
+            // global capabilities are fine, they come through the neighbors' resolved settings, and so are the script and plugin limits
+            warn!("process_config changes other than capabilities_config and the scripting/wasm limits need a restart, ignoring them");
+        }
+        if bgp_proc.bmp_servers != config.bmp_servers_config {
+            warn!("bmp_servers_config changes need a restart, ignoring them");
+        }
+        if !bgp_proc.mrt_replays.iter().map(|emrc| &emrc.replay).eq(&config.mrt_replays_config) {
+            warn!("mrt_replays_config changes need a restart, ignoring them");
+        }
+        if bgp_proc.rpki_config.caches != config.rpki_config.caches {
+            warn!("rpki_config cache changes need a restart, ignoring them");
+        }
+        let global_settings = bgp_proc.global_settings.clone();
+
+        for old_nc in &bgp_proc.configured_neighbors {
+            if new_neighbors.iter().any(|nc| nc.ip == old_nc.ip) {
+                continue;
+            }
+            info!("Reload: removing neighbor {}", old_nc.ip);
+            if all_neighbors.remove(&old_nc.ip).is_some() {
+                // never got a connection, nothing is running for it yet
+                all_neighbors_channels.remove(&old_nc.ip);
+            }
+            else if let Some(neighbor_channel) = all_neighbors_channels.get(&old_nc.ip)
+                && let Err(e) = neighbor_channel.send_shutdown_to_neighbor() {
+                error!("Unable to send shutdown to neighbor {} - {:?}", old_nc.ip, e);
+            }
+        }
+
+        for nc in &new_neighbors {
+            let old_nc = bgp_proc.configured_neighbors.iter().find(|old_nc| old_nc.ip == nc.ip);
+            if old_nc == Some(nc) {
+                continue;
+            }
+            let is_running = old_nc.is_some() && !all_neighbors.contains_key(&nc.ip);
+            if let Some(old_nc) = old_nc && is_running {
+                // anything that goes in the Open can only change with a new session, the keepalive interval isn't in it
+                let reset_session = old_nc.as_num != nc.as_num || old_nc.settings.capabilities_config != nc.settings.capabilities_config
+                    || old_nc.settings.hold_time != nc.settings.hold_time;
+                info!("Reload: updating neighbor {}, reset_session: {}", nc.ip, reset_session);
+                if let Some(neighbor_channel) = all_neighbors_channels.get_mut(&nc.ip) {
+                    neighbor_channel.peer_type = if global_settings.my_as == nc.as_num { PeerType::Internal } else { PeerType::External };
+                    if let Err(e) = neighbor_channel.send_config_to_neighbor(nc.clone(), reset_session) {
+                        error!("Unable to send new config to neighbor {} - {:?}", nc.ip, e);
+                    }
+                }
+                continue;
+            }
+            if old_nc.is_none() && let Some(neighbor_channel) = all_neighbors_channels.get(&nc.ip) {
+                // a dynamic neighbor from a listen range has this IP, the configured one takes over
+                if let Err(e) = neighbor_channel.send_shutdown_to_neighbor() {
+                    error!("Unable to send shutdown to neighbor {} - {:?}", nc.ip, e);
+                }
+            }
+            info!("Reload: adding neighbor {}", nc.ip);
+            match BGPProcess::create_neighbor(nc, &bgp_proc, &mut all_neighbors_channels, tx_channel_watcher.clone()) {
+                Ok(mut neighbor) => {
+                    let event = neighbor.fsm.automatic_start_event();
+                    neighbor.events.push_back(event);
+                    all_neighbors.insert(nc.ip, neighbor);
                 },
                 Err(e) => {
-                    println!("Error: TCP Stream {:#?}", e);
+                    error!("Unable to create neighbor {} from config - {:?}", nc.ip, e);
                 }
             }
         }
+
+        // a policy change comes out as changed settings above, the neighbor runs its routes through the new policy itself
+        // networks announced from the control socket stay up even if the config stops listing them
+        let new_networks = config.net_advertisements_config;
+        let withdrawn_networks: Vec<NLRI> = bgp_proc.configured_networks.iter()
+            .filter(|n| !new_networks.contains(n) && !bgp_proc.announced_networks.contains(&n.nlri))
+            .map(|n| n.nlri.clone())
+            .collect();
+        let added_networks: Vec<NLRI> = new_networks.iter()
+            .filter(|n| !bgp_proc.configured_networks.contains(n) && !bgp_proc.announced_networks.contains(&n.nlri))
+            .map(|n| n.nlri.clone())
+            .collect();
+        if !withdrawn_networks.is_empty() {
+            info!("Reload: withdrawing {:?}", withdrawn_networks);
+            bgp_proc.withdraw_local_networks(&all_neighbors_channels, withdrawn_networks).await;
+        }
+        if !added_networks.is_empty() {
+            info!("Reload: announcing {:?}", added_networks);
+            bgp_proc.announce_local_networks(&all_neighbors_channels, added_networks).await;
+        }
+
+        bgp_proc.configured_neighbors = new_neighbors;
+        bgp_proc.configured_listen_ranges = new_listen_ranges;
+        bgp_proc.configured_networks = new_networks;
+        bgp_proc.process_config.capabilities_config = config.process_config.capabilities_config;
+        bgp_proc.process_config.scripting = config.process_config.scripting;
+        bgp_proc.process_config.wasm = config.process_config.wasm;
+        bgp_proc.policies = policies;
+        bgp_proc.plugins = plugins;
+        bgp_proc.scripts = scripts;
+        let best_path_changed = bgp_proc.rpki_config.reject_invalid != config.rpki_config.reject_invalid
+            || bgp_proc.rpki_config.prefer_valid != config.rpki_config.prefer_valid;
+        bgp_proc.rpki_config.reject_invalid = config.rpki_config.reject_invalid;
+        bgp_proc.rpki_config.prefer_valid = config.rpki_config.prefer_valid;
+        if best_path_changed {
+            let nlris: Vec<NLRI> = bgp_proc.adj_rib_in.keys().cloned().collect();
+            for nlri in &nlris {
+                bgp_proc.calculate_best_path(nlri, &PeerType::Internal);
+            }
+        }
+        info!("Reloaded config from {}", config_file_name);
+        Ok(())
     }
 
     pub async fn populate_neighbors_from_config(bgp_proc_arc: &Arc<Mutex<BGPProcess>>, all_neighbors_channels_arc: &Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>,
@@ -357,63 +883,92 @@ impl BGPProcess {
         let bgp_proc = bgp_proc_arc.lock().await;
         let mut all_neighbors = HashMap::new();
         all_neighbors.reserve(2); // 2 seems sensible, a good compromise between size and efficiency
-        let my_as = bgp_proc.global_settings.my_as.clone();
-        let global_settings = bgp_proc.global_settings.clone();
+        let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
         for nc in &bgp_proc.configured_neighbors {
-            match Ipv4Addr::from_str(&nc.ip) {
-                Ok(ip) => {
-                    let peer_type = if my_as == nc.as_num {
-                        PeerType::Internal
-                    } else {
-                        PeerType::External
-                    };
-                    let (tx_to_bgp, rx_from_neighbor)  = mpsc::channel::<ChannelMessage>(65535);
-                    let (tx_to_neighbor, rx_from_bgp) = mpsc::channel::<ChannelMessage>(65535);
-                    let neighbors_channels = NeighborChannel {
-                        rx: rx_from_neighbor,
-                        tx: tx_to_neighbor,
-                        peer_type: peer_type.clone()
-                        //is_active: true
-                    };
-                    let bgp_channel = NeighborChannel {
-                        rx: rx_from_bgp,
-                        tx: tx_to_bgp,
-                        peer_type: peer_type.clone()
-                        //is_active: true,
-                    };
-                    // need to use a temp HashMap because we already borrowed bgp_proc as mutable
-                    {
-                        let mut all_neighbors_channels = all_neighbors_channels_arc.lock().await;
-                        all_neighbors_channels.insert(ip, neighbors_channels);
-                    }
-
-                    match Neighbor::new(ip, AS::AS4(nc.as_num as u32), nc.hello_time, nc.hold_time, peer_type, global_settings.clone(), bgp_channel, tx_channel_watcher.clone()) {
-                        Ok(neighbor) => {
-                            all_neighbors.insert(ip, neighbor);
-                        },
-                        Err(e) => {
-                            println!("Error: Unable to create neighbor (IP) {:#?} from Config, ERROR: {:#?}", ip, e);
-                        }
-                    }
+            match BGPProcess::create_neighbor(nc, &bgp_proc, &mut all_neighbors_channels, tx_channel_watcher.clone()) {
+                Ok(neighbor) => {
+                    all_neighbors.insert(nc.ip, neighbor);
                 },
-                Err(_) => {
-                    println!("Error: Unable to convert config string to IP, skipping");
-                    continue;
+                Err(e) => {
+                    error!("Unable to create neighbor {} from config - {:?}", nc.ip, e);
                 }
             }
         }
-        println!();
-        println!("Populated the following BGP neighbors from config {:#?}", all_neighbors);
-        println!();
+        debug!("Populated the following BGP neighbors from config {:?}", all_neighbors.keys());
 
         all_neighbors
     }
 
 
-    pub async fn generate_event_for_all_neighbors(all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>, event: Event) {
-        println!("Generating event {:#?} for all neighbors", event);
-        for n in all_neighbors {
-            n.1.events.push_back(event.clone());
+    pub fn create_neighbor(nc: &EffectiveNeighborConfig, bgp_proc: &BGPProcess, all_neighbors_channels: &mut HashMap<Ipv4Addr, NeighborChannel>,
+                           tx_channel_watcher: Sender<ChannelWatcherMessage>) -> Result<Neighbor, MessageError> {
+        let global_settings = bgp_proc.global_settings.clone();
+        let peer_type = if global_settings.my_as == nc.as_num {
+            PeerType::Internal
+        } else {
+            PeerType::External
+        };
+        let (neighbors_channels, bgp_channel) = BGPProcess::create_neighbor_channels(&peer_type);
+        let my_as = global_settings.my_as as u32;
+        let mut neighbor = Neighbor::new(nc.ip, AS::AS4(nc.as_num as u32), nc.settings.hello_time, nc.settings.hold_time, peer_type, global_settings, bgp_channel, tx_channel_watcher,
+                                         bgp_proc.tx_bgp_events.clone())?;
+        // before apply_settings, which turns it on for neighbors with archive_messages
+        neighbor.message_archive = bgp_proc.tx_message_archive.clone().map(|tx| MessageArchive::new(tx, nc.ip, my_as));
+        neighbor.apply_settings(&nc.settings)?;
+        // we connect to the peer on the port we listen on
+        neighbor.tx_tcp_conn = bgp_proc.tx_tcp_conn.clone();
+        neighbor.peer_port = bgp_proc.listen_config.port;
+        all_neighbors_channels.insert(nc.ip, neighbors_channels);
+        Ok(neighbor)
+    }
+
+    pub fn create_neighbor_channels(peer_type: &PeerType) -> (NeighborChannel, NeighborChannel) {
+        // returns (our end for all_neighbors_channels, the neighbor's end)
+        let (tx_to_bgp, rx_from_neighbor)  = mpsc::channel::<ChannelMessage>(65535);
+        let (tx_to_neighbor, rx_from_bgp) = mpsc::channel::<ChannelMessage>(65535);
+        let neighbors_channels = NeighborChannel {
+            rx: rx_from_neighbor,
+            tx: tx_to_neighbor,
+            peer_type: peer_type.clone()
+            //is_active: true
+        };
+        let bgp_channel = NeighborChannel {
+            rx: rx_from_bgp,
+            tx: tx_to_bgp,
+            peer_type: peer_type.clone()
+            //is_active: true,
+        };
+        (neighbors_channels, bgp_channel)
+    }
+
+    pub fn create_dynamic_neighbor(peer_ip: Ipv4Addr, listen_range: &EffectiveListenRangeConfig, global_settings: GlobalSettings,
+                                   all_neighbors_channels: &mut HashMap<Ipv4Addr, NeighborChannel>, tx_channel_watcher: Sender<ChannelWatcherMessage>,
+                                   tx_bgp_events: broadcast::Sender<BgpEvent>, tx_message_archive: Option<UnboundedSender<Vec<u8>>>) -> Result<Neighbor, MessageError> {
+        let min_as = listen_range.min_as;
+        let max_as = listen_range.max_as;
+        // we don't know the peer's AS until its Open shows up, process_open_message settles it for ranges that hold our AS and others
+        let peer_type = if min_as == global_settings.my_as && max_as == global_settings.my_as {
+            PeerType::Internal
+        } else {
+            PeerType::External
+        };
+        let (neighbors_channels, bgp_channel) = BGPProcess::create_neighbor_channels(&peer_type);
+        let my_as = global_settings.my_as as u32;
+        let mut neighbor = Neighbor::new(peer_ip, AS::AS4(min_as as u32), listen_range.settings.hello_time, listen_range.settings.hold_time, peer_type, global_settings, bgp_channel, tx_channel_watcher, tx_bgp_events)?;
+        neighbor.message_archive = tx_message_archive.map(|tx| MessageArchive::new(tx, peer_ip, my_as));
+        neighbor.apply_settings(&listen_range.settings)?;
+        neighbor.fsm.accept_connections_unconfigured_peers = true;
+        neighbor.dynamic_as_range = Some(min_as..=max_as);
+        all_neighbors_channels.insert(peer_ip, neighbors_channels);
+        Ok(neighbor)
+    }
+
+    pub async fn generate_automatic_start_for_all_neighbors(all_neighbors: &mut HashMap<Ipv4Addr, Neighbor>) {
+        // the start event depends on each neighbor's passive and damping settings
+        for n in all_neighbors.values_mut() {
+            let event = n.fsm.automatic_start_event();
+            debug!(target: "fsm", "Generating event {:?} for neighbor {}", event, n.ip);
+            n.events.push_back(event);
         }
     }
 
@@ -429,7 +984,7 @@ impl BGPProcess {
 
     fn compare_route_local_pref(curr_best_path: &RouteV4, candidate_best_path: &RouteV4, def_local_pref: u32) -> BestPathResult {
         // prefer higher local pref
-        println!("comparing route local pref");
+        trace!(target: "rib", "comparing route local pref");
 
         let candidate_path_local_pref = if candidate_best_path.local_pref.is_some() {
             candidate_best_path.local_pref.as_ref().unwrap().value }
@@ -462,7 +1017,7 @@ impl BGPProcess {
         // TODO come back and handle multiple ASPathSegmentType objects here once I refactor that code.
 
         // Right now, this assumes only one SegmentType can be present
-        println!("comparing route as path");
+        trace!(target: "rib", "comparing route as path");
         let candidate_path_as_path_len = candidate_best_path.as_path.as_path_segment.number_of_as;
         let curr_best_path_as_path_len = curr_best_path.as_path.as_path_segment.number_of_as;
 
@@ -494,6 +1049,20 @@ impl BGPProcess {
 
         //BestPathResult::CurrentPath
     }
+    fn compare_route_rpki(curr_best_path: &RouteV4, candidate_best_path: &RouteV4) -> BestPathResult {
+        // prefer valid, then not found, then invalid
+        let rank = |route: &RouteV4| match route.rpki_state {
+            RpkiState::Valid => 0,
+            RpkiState::NotFound => 1,
+            RpkiState::Invalid => 2,
+        };
+        match rank(candidate_best_path).cmp(&rank(curr_best_path)) {
+            std::cmp::Ordering::Less => BestPathResult::CandidatePath,
+            std::cmp::Ordering::Greater => BestPathResult::CurrentPath,
+            std::cmp::Ordering::Equal => BestPathResult::Tie,
+        }
+    }
+
     fn compare_route_med(curr_best_path: &RouteV4, candidate_best_path: &RouteV4) -> BestPathResult {
         // if routes are from same neighbor AS, then prefer lowest MED, missing MED means 0, ignore confed sub as
         // assume that we already checked the neighbor AS if we made it this far
@@ -514,6 +1083,158 @@ impl BGPProcess {
 
     }
 
+    // picks the best of the paths we have for nlri and keeps it in the local RIB, watchers hear about it when it changes
+    // peer_type is the kind of neighbor whose update triggered the calc
+    pub fn calculate_best_path(&mut self, nlri: &NLRI, peer_type: &PeerType) {
+        let started = Instant::now();
+        let mut best_path: Option<RouteV4> = None;
+        if let Some(all_paths_for_rt) = self.adj_rib_in.get(nlri) {
+            for candidate_path in all_paths_for_rt {
+                if self.rpki_config.reject_invalid && candidate_path.rpki_state == RpkiState::Invalid {
+                    debug!(target: "rib", "Path from {:?} for {:?} is RPKI invalid, skipping", candidate_path.learned_from, nlri);
+                    continue;
+                }
+                if best_path.is_none() {
+                    best_path = Some(candidate_path.clone());
+                } else {
+                    let curr_best_path = best_path.as_ref().unwrap();
+                    // TODO finish compare
+
+                    let my_asn = self.global_settings.my_as;
+                    if *peer_type == PeerType::External {
+                        match BGPProcess::is_my_asn_in_ebgp_path(my_asn, &candidate_path) {
+                            Ok(result) => {
+                                if result {
+                                    debug!(target: "rib", "EBGP route has our ASN in path, skipping");
+                                    continue;
+                                }
+                            },
+                            Err (err) => {
+                                debug!(target: "rib", "Unable to check if our ASN in ebgp path due to ASN parsing, skipping");
+                                continue;
+                            }
+                        }
+                    }
+
+                    let mut all_results = Vec::new();
+
+                    // TODO I think I will implement weight as an attribute because it's very useful, just not now
+                    //
+
+                    if self.rpki_config.prefer_valid {
+                        all_results.push(BGPProcess::compare_route_rpki(curr_best_path, candidate_path));
+                    }
+                    all_results.push(BGPProcess::compare_route_local_pref(curr_best_path, candidate_path, self.global_settings.default_local_preference));
+                    all_results.push(BGPProcess::compare_route_as_path(curr_best_path, candidate_path));
+                    all_results.push(BGPProcess::compare_route_origin(curr_best_path, candidate_path));
+                    if *peer_type == PeerType::Internal {
+                        all_results.push(BGPProcess::compare_route_med(curr_best_path, candidate_path));
+
+                    }
+                    let mut move_to_next_route: bool = false;
+                    // the first attribute that isn't a tie decides
+                    for res in all_results {
+                        match res {
+                                BestPathResult::CandidatePath => {
+                                    best_path = Some(candidate_path.clone());
+                                    move_to_next_route = true;
+                                    break;
+                                },
+                                BestPathResult::CurrentPath => {
+                                    move_to_next_route = true;
+                                    break;
+                                },
+                                BestPathResult::Tie => {
+                                    // move on to next att.
+                                }
+                        }
+                    }
+
+                    if move_to_next_route { continue; }
+
+                    // match BGPProcess::compare_route_local_pref(curr_best_path, candidate_path, self.global_settings.default_local_preference) {
+                    //     BestPathResult::CandidatePath => {
+                    //         best_path = Some(candidate_path.clone());
+                    //         continue;
+                    //     },
+                    //     BestPathResult::CurrentPath => {
+                    //         continue;
+                    //     },
+                    //     BestPathResult::Tie => {
+                    //         // move on to next att.
+                    //     }
+                    // }
+
+                    // not going to implement prefer locally originated (Cisco) or prefer lowest accumulated IGP route (Juniper) for now
+
+                    // match BGPProcess::compare_route_as_path(curr_best_path, candidate_path) {
+                    //     BestPathResult::CandidatePath => {
+                    //         best_path = Some(candidate_path.clone());
+                    //         continue;
+                    //     },
+                    //     BestPathResult::CurrentPath => {
+                    //         continue;
+                    //     },
+                    //     BestPathResult::Tie => {
+                    //         // move on to next att.
+                    //     }
+                    // }
+
+                    // match BGPProcess::compare_route_origin(curr_best_path, candidate_path) {
+                    //     BestPathResult::CandidatePath => {
+                    //         best_path = Some(candidate_path.clone());
+                    //         continue;
+                    //     },
+                    //     BestPathResult::CurrentPath => {
+                    //         continue;
+                    //     },
+                    //     BestPathResult::Tie => {
+                    //         // move on to next att.
+                    //     }
+                    // }
+
+                    // if *peer_type == PeerType::Internal {
+                    //     match BGPProcess::compare_route_med(curr_best_path, candidate_path) {
+                    //         BestPathResult::CandidatePath => {
+                    //             best_path = Some(candidate_path.clone());
+                    //             continue;
+                    //         },
+                    //         BestPathResult::CurrentPath => {
+                    //             continue;
+                    //         },
+                    //         BestPathResult::Tie => {
+                    //             // move on to next att.
+                    //         }
+                    //     }
+                    // }
+
+                    // if ibgp peer sent you this route
+                    // if they didn't originate it
+                    // consider the external AS in the AS path for comparing MED
+                    // if they originated it or aggregated it
+                    // then use the local AS for comparing MED
+
+
+                }
+            }
+        }
+        self.best_path_timing.record(started.elapsed());
+        self.set_best_path(nlri, best_path);
+    }
+
+    fn set_best_path(&mut self, nlri: &NLRI, best_path: Option<RouteV4>) {
+        if self.local_rib.get(nlri).and_then(|paths| paths.first()) == best_path.as_ref() {
+            return;
+        }
+        match &best_path {
+            Some(route) => { self.local_rib.insert(nlri.clone(), vec![route.clone()]); },
+            None => { self.local_rib.remove(nlri); },
+        }
+        debug!(target: "rib", "Best path for {:?} is now {:?}", nlri, best_path);
+        // nobody watching is fine
+        let _ = self.tx_bgp_events.send(BgpEvent::BestPathChanged { nlri: nlri.clone(), best_path });
+    }
+
     pub async fn run_recv_message_channel_loop(bgp_proc_arc: Arc<Mutex<BGPProcess>>, mut all_neighbors_channels_arc: Arc<Mutex<HashMap<Ipv4Addr, NeighborChannel>>>, rx_channel_watcher: Receiver<ChannelWatcherMessage>) {
         // TODO need to refactor this so we don't loop to unlock the all_neighbors_channels_arc
         // maybe pass a MessageReady event that we await on
@@ -528,128 +1249,7 @@ impl BGPProcess {
                     // go through every nlri and find the best metrics
                     let mut bgp_proc = bgp_proc_arc.lock().await;
                     while let Some((rt, peer_type)) = routes_need_best_path_calc.pop() {
-                        if let Some(all_paths_for_rt) = bgp_proc.adj_rib_in.get(&rt) {
-                            let mut best_path: Option<RouteV4> = None;
-                            let best_path_exists = if best_path.is_none() {false} else {true};
-                            for candidate_path in all_paths_for_rt {
-                                if !best_path_exists {
-                                    best_path = Some(candidate_path.clone());
-                                } else {
-                                    let curr_best_path = best_path.as_ref().unwrap();
-                                    // TODO finish compare
-
-                                    let my_asn = bgp_proc.global_settings.my_as;
-                                    if peer_type == PeerType::External {
-                                        match BGPProcess::is_my_asn_in_ebgp_path(my_asn, &candidate_path) {
-                                            Ok(result) => {
-                                                if result {
-                                                    println!("EBGP route has our ASN in path, skipping");
-                                                    continue;
-                                                }
-                                            },
-                                            Err (err) => {
-                                                println!("Unable to check if our ASN in ebgp path due to ASN parsing, skipping");
-                                                continue;
-                                            }
-                                        }
-                                    }
-
-                                    let mut all_results = Vec::new();
-
-                                    // TODO I think I will implement weight as an attribute because it's very useful, just not now
-                                    //
-
-                                    all_results.push(BGPProcess::compare_route_local_pref(curr_best_path, candidate_path, bgp_proc.global_settings.default_local_preference));
-                                    all_results.push(BGPProcess::compare_route_as_path(curr_best_path, candidate_path));
-                                    all_results.push(BGPProcess::compare_route_origin(curr_best_path, candidate_path));
-                                    if peer_type == PeerType::Internal {
-                                        all_results.push(BGPProcess::compare_route_med(curr_best_path, candidate_path));
-
-                                    }
-                                    let mut move_to_next_route: bool = false;
-                                    for res in all_results {
-                                        match res {
-                                                BestPathResult::CandidatePath => {
-                                                    best_path = Some(candidate_path.clone());
-                                                    move_to_next_route = true;
-                                                },
-                                                BestPathResult::CurrentPath => {
-                                                    move_to_next_route = true;
-                                                },
-                                                BestPathResult::Tie => {
-                                                    // move on to next att.
-                                                }
-                                        }
-                                    }
-
-                                    if move_to_next_route { continue; }
-
-                                    // match BGPProcess::compare_route_local_pref(curr_best_path, candidate_path, bgp_proc.global_settings.default_local_preference) {
-                                    //     BestPathResult::CandidatePath => {
-                                    //         best_path = Some(candidate_path.clone());
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::CurrentPath => {
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::Tie => {
-                                    //         // move on to next att.
-                                    //     }
-                                    // }
-
-                                    // not going to implement prefer locally originated (Cisco) or prefer lowest accumulated IGP route (Juniper) for now
-
-                                    // match BGPProcess::compare_route_as_path(curr_best_path, candidate_path) {
-                                    //     BestPathResult::CandidatePath => {
-                                    //         best_path = Some(candidate_path.clone());
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::CurrentPath => {
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::Tie => {
-                                    //         // move on to next att.
-                                    //     }
-                                    // }
-
-                                    // match BGPProcess::compare_route_origin(curr_best_path, candidate_path) {
-                                    //     BestPathResult::CandidatePath => {
-                                    //         best_path = Some(candidate_path.clone());
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::CurrentPath => {
-                                    //         continue;
-                                    //     },
-                                    //     BestPathResult::Tie => {
-                                    //         // move on to next att.
-                                    //     }
-                                    // }
-
-                                    // if peer_type == PeerType::Internal {
-                                    //     match BGPProcess::compare_route_med(curr_best_path, candidate_path) {
-                                    //         BestPathResult::CandidatePath => {
-                                    //             best_path = Some(candidate_path.clone());
-                                    //             continue;
-                                    //         },
-                                    //         BestPathResult::CurrentPath => {
-                                    //             continue;
-                                    //         },
-                                    //         BestPathResult::Tie => {
-                                    //             // move on to next att.
-                                    //         }
-                                    //     }
-                                    // }
-
-                                    // if ibgp peer sent you this route
-                                    // if they didn't originate it
-                                    // consider the external AS in the AS path for comparing MED
-                                    // if they originated it or aggregated it
-                                    // then use the local AS for comparing MED
-
-
-                                }
-                            }
-                        }
+                        bgp_proc.calculate_best_path(&rt, &peer_type);
                     }
                 }
 
@@ -661,40 +1261,48 @@ impl BGPProcess {
                     for (neighbor_ip, route_channel) in &mut *all_neighbors_channels {
                         while let Ok(msg) = route_channel.rx.try_recv() {
                             match msg {
-                                ChannelMessage::Route(route) => {
-                                    // for now, I will just test adding it to the bgp loc_rib
+                                ChannelMessage::Route(mut route) => {
                                     // if an entry for the NLRI exists, add the route path too it don't overwrite
                                     {
                                         let nlri = route.nlri.clone();
+                                        route.learned_from = Some(*neighbor_ip);
                                         // store route here so we know which to run bestpath for later
                                         routes_need_best_path_calc.push((nlri.clone(), route_channel.peer_type.clone()));
                                         let mut bgp_proc = bgp_proc_arc.lock().await;
+                                        route.rpki_state = bgp_proc.global_settings.vrps.validate_route(&route, bgp_proc.global_settings.my_as);
                                         match bgp_proc.adj_rib_in.get_mut(&nlri) {
                                             Some(route_paths) => {
+                                                // a new path from the same neighbor replaces its old one
+                                                route_paths.retain(|path| path.learned_from != route.learned_from);
                                                 route_paths.push(route);
                                             }
                                             None => {
                                                 bgp_proc.adj_rib_in.insert(nlri, vec![route]);
                                             }
                                         }
-                                        println!("Adding route to BGP ADJ RIB IN");
-                                        println!("Current BGP ADJ RIB IN is {:#?}", bgp_proc.adj_rib_in);
+                                        debug!(target: "rib", "Adding route to BGP ADJ RIB IN");
+                                        trace!(target: "rib", "Current BGP ADJ RIB IN is {:?}", bgp_proc.adj_rib_in);
                                     }
                                     //path_changed = true;
                                 },
                                 ChannelMessage::WithdrawRoute(nlri_vec) => {
                                     let mut bgp_proc = bgp_proc_arc.lock().await;
                                     for nlri in nlri_vec {
-                                        // store route here so we know which to run bestpath for later
+                                        // store route here so we know which to run bestpath for later, it takes care of the local RIB
                                         routes_need_best_path_calc.push((nlri.clone(), route_channel.peer_type.clone()));
                                         // continue with withdraw
-                                        println!("Removing route from BGP Local RIB");
-                                        if let None =  bgp_proc.local_rib.remove(&nlri) {
-                                            println!("Attempted to remove {:#?} from the BGP local RIB but was unable to find the route", nlri);
+                                        debug!(target: "rib", "Removing {:?} from {} from BGP ADJ RIB IN", nlri, neighbor_ip);
+                                        let Some(route_paths) = bgp_proc.adj_rib_in.get_mut(&nlri) else {
+                                            warn!(target: "rib", "Attempted to remove {:?} from the BGP ADJ RIB IN but was unable to find the route", nlri);
+                                            continue;
+                                        };
+                                        route_paths.retain(|path| path.learned_from != Some(*neighbor_ip));
+                                        if route_paths.is_empty() {
+                                            bgp_proc.adj_rib_in.remove(&nlri);
                                         }
                                     }
                                     // TODO trigger sending a withdraw message
-                                    println!("Current BGP Local RIB is {:#?}", bgp_proc.local_rib);
+                                    trace!(target: "rib", "Current BGP ADJ RIB IN is {:?}", bgp_proc.adj_rib_in);
                                     //path_changed = true;
                                 }
                                 // ChannelMessage::NeighborDown => {
@@ -704,18 +1312,32 @@ impl BGPProcess {
                                     // Allow the BGP proc to send messages (routes) to the Neighbor task
                                     let mut bgp_proc = bgp_proc_arc.lock().await;
                                     for (_nlri, route_vec) in &bgp_proc.adj_rib_in {
-                                        println!("Received ChannelMessage::NeighborUp, sending route_vec - {:#?}", route_vec);
+                                        trace!(target: "rib", "Received ChannelMessage::NeighborUp, sending route_vec - {:?}", route_vec);
                                         route_channel.send_route_vec(route_vec).await;
                                     }
 
                                 },
+                                ChannelMessage::PeerType(peer_type) => {
+                                    route_channel.peer_type = peer_type;
+                                },
                                 ChannelMessage::TcpEstablished(tcp_stream) => {
                                     panic!("We should never get a NeighborChannel::TcpEstablished from Neighbor to BGP proc");
                                 }
+                                ChannelMessage::UpdateConfig(..) | ChannelMessage::Shutdown => {
+                                    panic!("We should never get a NeighborChannel::UpdateConfig or Shutdown from Neighbor to BGP proc");
+                                }
 
                             }
                         }
                     }
+                    // dynamic neighbors drop their end of the channel when their session ends
+                    all_neighbors_channels.retain(|neighbor_ip, route_channel| {
+                        if route_channel.rx.is_closed() && route_channel.rx.is_empty() {
+                            info!("Removing channel for torn down neighbor {}", neighbor_ip);
+                            return false
+                        }
+                        true
+                    });
                 }
 
             }
@@ -723,3 +1345,124 @@ impl BGPProcess {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::finite_state_machine::State;
+
+    // every neighbor is (last octet of 10.0.0.x, hold_time)
+    fn config_with_neighbors(neighbors: &[(u8, u16)]) -> String {
+        let mut config = r#"
+            net_advertisements_config = []
+            [process_config]
+            my_as = 2
+            router_id = "1.1.1.1"
+            next_hop_ip = "10.0.0.1"
+            default_local_preference = 100
+            default_med = 0
+            [process_config.capabilities_config]
+            route_refresh_prestandard = false
+            route_refresh = true
+            enhanced_route_refresh = false
+            extended_4byte_asn = true
+            [process_config.capabilities_config.multi_protocol_extensions_config]
+            ipv4_unicast = true
+            ipv4_multicast = false
+            ipv4_vpn = false
+            ipv6_unicast = false
+            ipv6_multicast = false
+            ipv6_vpn = false
+        "#.to_string();
+        for (octet, hold_time) in neighbors {
+            config.push_str(&format!("[[neighbors_config]]\nip = \"10.0.0.{}\"\nas_num = 1\nhello_time = 30\nhold_time = {}\n", octet, hold_time));
+        }
+        config
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_neighbor_with_our_own_as_is_internal() {
+        let path = std::env::temp_dir().join(format!("bgprtr-dynamic-{}.toml", std::process::id()));
+        let mut config = format!("neighbors_config = []\n{}", config_with_neighbors(&[]));
+        config.push_str("[[listen_ranges_config]]\nprefix = \"10.0.5.0/24\"\nmin_as = 1\nmax_as = 100\nhello_time = 30\nhold_time = 90\n");
+        std::fs::write(&path, config).unwrap();
+        let bgp_proc = BGPProcess::new(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let bgp_proc = bgp_proc.unwrap();
+        let peer_ip = Ipv4Addr::new(10, 0, 5, 1);
+        let mut all_neighbors_channels = HashMap::new();
+        let (tx_channel_watcher, _rx_channel_watcher) = mpsc::channel(8);
+        let mut neighbor = BGPProcess::create_dynamic_neighbor(peer_ip, &bgp_proc.configured_listen_ranges[0], bgp_proc.global_settings.clone(),
+                                                               &mut all_neighbors_channels, tx_channel_watcher, bgp_proc.tx_bgp_events.clone(), None).unwrap();
+        // the range holds AS 2 along with others, so it starts out as eBGP
+        assert_eq!(neighbor.peer_type, PeerType::External);
+
+        let open = crate::messages::open::OpenMessage::new(BGPVersion::V4, 2, 90, peer_ip, 0, None).unwrap();
+        let (tcp_channel_tx, _tcp_channel_rx) = mpsc::channel(16);
+        neighbor.process_open_message(&open, &tcp_channel_tx).await.unwrap();
+        assert_eq!(neighbor.peer_type, PeerType::Internal);
+        assert_eq!(neighbor.proc_channel.peer_type, PeerType::Internal);
+        // and best path hears about it before any of its routes
+        assert!(matches!(all_neighbors_channels.get_mut(&peer_ip).unwrap().rx.try_recv(), Ok(ChannelMessage::PeerType(PeerType::Internal))));
+    }
+
+    #[tokio::test]
+    async fn test_reload_only_touches_changed_neighbors() {
+        let path = std::env::temp_dir().join(format!("bgprtr-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, config_with_neighbors(&[(2, 90), (3, 90), (4, 90), (5, 90)])).unwrap();
+        let bgp_proc_arc = Arc::new(Mutex::new(BGPProcess::new(path.to_str().unwrap()).unwrap()));
+        let all_neighbors_channels_arc = BGPProcess::init_process_channels();
+        let (tx_channel_watcher, _rx_channel_watcher) = mpsc::channel(1);
+        let mut all_neighbors = BGPProcess::populate_neighbors_from_config(&bgp_proc_arc, &all_neighbors_channels_arc, tx_channel_watcher.clone()).await;
+        // .2, .3 and .5 have sessions, so they left all_neighbors, .4 is still waiting for its first connection
+        let mut unchanged = all_neighbors.remove(&Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        let mut changed = all_neighbors.remove(&Ipv4Addr::new(10, 0, 0, 3)).unwrap();
+        let mut removed = all_neighbors.remove(&Ipv4Addr::new(10, 0, 0, 5)).unwrap();
+
+        std::fs::write(&path, config_with_neighbors(&[(2, 90), (3, 180), (4, 90), (6, 90)])).unwrap();
+        let result = BGPProcess::reload_config(&bgp_proc_arc, &mut all_neighbors, &all_neighbors_channels_arc, &tx_channel_watcher).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert!(unchanged.proc_channel.rx.try_recv().is_err());
+        // the hold time goes in the Open, so that session starts over
+        assert!(matches!(changed.proc_channel.rx.try_recv(), Ok(ChannelMessage::UpdateConfig(nc, true)) if nc.settings.hold_time == 180));
+        assert!(matches!(removed.proc_channel.rx.try_recv(), Ok(ChannelMessage::Shutdown)));
+        // .4 is the same neighbor as before, .6 is new and waits for its automatic start to be handled
+        assert!(all_neighbors[&Ipv4Addr::new(10, 0, 0, 4)].events.is_empty());
+        assert_eq!(all_neighbors[&Ipv4Addr::new(10, 0, 0, 6)].events.len(), 1);
+        assert_eq!(all_neighbors.len(), 2);
+        let configured: Vec<Ipv4Addr> = bgp_proc_arc.lock().await.configured_neighbors.iter().map(|nc| nc.ip).collect();
+        assert_eq!(configured, vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3), Ipv4Addr::new(10, 0, 0, 4), Ipv4Addr::new(10, 0, 0, 6)]);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_the_session_for_a_new_hello_time() {
+        let path = std::env::temp_dir().join(format!("bgprtr-reload-hello-{}.toml", std::process::id()));
+        std::fs::write(&path, config_with_neighbors(&[(2, 90)])).unwrap();
+        let bgp_proc_arc = Arc::new(Mutex::new(BGPProcess::new(path.to_str().unwrap()).unwrap()));
+        let all_neighbors_channels_arc = BGPProcess::init_process_channels();
+        let (tx_channel_watcher, _rx_channel_watcher) = mpsc::channel(1);
+        let mut all_neighbors = BGPProcess::populate_neighbors_from_config(&bgp_proc_arc, &all_neighbors_channels_arc, tx_channel_watcher.clone()).await;
+        let mut neighbor = all_neighbors.remove(&Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+
+        std::fs::write(&path, config_with_neighbors(&[(2, 90)]).replace("hello_time = 30", "hello_time = 10")).unwrap();
+        let result = BGPProcess::reload_config(&bgp_proc_arc, &mut all_neighbors, &all_neighbors_channels_arc, &tx_channel_watcher).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        let Ok(ChannelMessage::UpdateConfig(nc, false)) = neighbor.proc_channel.rx.try_recv() else {
+            panic!("Expected the new config without a session reset");
+        };
+
+        // the peer's Open lowered the hold time to 30
+        neighbor.fsm.state = State::Established;
+        neighbor.fsm.hold_time = 30;
+        neighbor.fsm.keepalive_timer.start(neighbor.fsm.keepalive_time);
+        neighbor.update_config(*nc, false).await;
+        assert_eq!(neighbor.fsm.state, State::Established);
+        assert!(neighbor.events.is_empty());
+        assert_eq!(neighbor.fsm.hold_time, 30);
+        assert_eq!(neighbor.fsm.keepalive_time, 10);
+        assert_eq!(neighbor.fsm.keepalive_timer.max_time, Duration::from_secs(10));
+    }
+}
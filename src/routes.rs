@@ -3,6 +3,7 @@ use std::str::FromStr;
 
 use crate::errors::{BGPError, ProcessError};
 use crate::messages::update::*;
+use crate::rpki::RpkiState;
 
 use serde::Deserialize;
 
@@ -36,6 +37,19 @@ impl NLRI {
         Ok(nlri)
     }
 
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX << (32 - self.len as u32);
+        (ip.to_bits() & mask) == (self.prefix.to_bits() & mask)
+    }
+
+    // only the significant bytes of the prefix go on the wire, e.g. 10.1.0.0/24 is 24 10 1 0
+    pub fn convert_to_prefix_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.len];
+        let prefix_byte_len = (self.len as usize).div_ceil(8);
+        bytes.extend(&self.prefix.octets()[..prefix_byte_len]);
+        bytes
+    }
+
     pub fn convert_to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend(self.len.to_be_bytes());
@@ -77,7 +91,7 @@ impl TryFrom<String> for NLRI {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct RouteV4 {
     // route_cast: RouteCast,
     // Maybe at some point I'll try handling multicast routes once. It's pretty rare to need BGP's multicast AF.
@@ -93,6 +107,11 @@ pub struct RouteV4 {
     pub multi_exit_disc: Option<MultiExitDisc>,
     pub atomic_aggregate: Option<AtomicAggregate>,
     pub aggregator: Option<Aggregator>,
+    pub communities: Vec<u32>,
+    // the neighbor we got it from, None for our own routes
+    pub learned_from: Option<Ipv4Addr>,
+    // set against the VRPs when the route comes in and again whenever they change
+    pub rpki_state: RpkiState,
 }
 
 impl RouteV4 {
@@ -108,8 +127,34 @@ impl RouteV4 {
             local_pref,
             multi_exit_disc,
             atomic_aggregate,
-            aggregator
+            aggregator,
+            communities: Vec::new(),
+            learned_from: None,
+            rpki_state: RpkiState::NotFound,
         }
 
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nlri_contains() {
+        let nlri = NLRI::from_str("10.0.0.0/24").unwrap();
+        assert!(nlri.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(nlri.contains(Ipv4Addr::new(10, 0, 0, 255)));
+        assert!(!nlri.contains(Ipv4Addr::new(10, 0, 1, 1)));
+
+        let host = NLRI::from_str("10.0.0.5/32").unwrap();
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 5)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 6)));
+    }
+
+    #[test]
+    fn test_nlri_convert_to_prefix_bytes() {
+        assert_eq!(NLRI::from_str("10.1.0.0/24").unwrap().convert_to_prefix_bytes(), vec![24, 10, 1, 0]);
+        assert_eq!(NLRI::from_str("10.128.0.0/9").unwrap().convert_to_prefix_bytes(), vec![9, 10, 128]);
+        assert_eq!(NLRI::from_str("1.1.1.1/32").unwrap().convert_to_prefix_bytes(), vec![32, 1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use serde::Deserialize;
+use crate::control::as_to_u32;
+use crate::messages::update::AsPathSegmentType;
+use crate::routes::{RouteV4, NLRI};
+
+pub mod rtr;
+
+// RFC 6811 route origin validation against the VRPs (validated ROA payloads) the RPKI caches in rpki_config give us over RTR.
+// Every path in the Adj-RIB-In carries its state, the process sets it again for all of them whenever a cache sends changes.
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RpkiState {
+    Valid,
+    Invalid,
+    // no VRP covers the prefix, also what everything is without caches
+    #[default]
+    NotFound,
+}
+
+impl RpkiState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RpkiState::Valid => "valid",
+            RpkiState::Invalid => "invalid",
+            RpkiState::NotFound => "not_found",
+        }
+    }
+}
+
+impl FromStr for RpkiState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "valid" => Ok(RpkiState::Valid),
+            "invalid" => Ok(RpkiState::Invalid),
+            "not_found" => Ok(RpkiState::NotFound),
+            _ => Err(format!("{} is not an RPKI state, use valid, invalid or not_found", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Vrp {
+    pub prefix: Ipv4Addr,
+    pub len: u8,
+    pub max_len: u8,
+    pub asn: u32,
+}
+
+// how a cache is doing, for bgpctl show rpki
+#[derive(Debug, Clone, Default)]
+pub struct CacheStatus {
+    pub connected: bool,
+    pub version: u8,
+    pub session_id: Option<u16>,
+    pub serial: Option<u32>,
+    pub vrps: usize,
+    pub last_update: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct VrpTable {
+    // kept apart per cache, so one that expires only takes its own VRPs with it
+    caches: HashMap<SocketAddr, HashSet<Vrp>>,
+    status: HashMap<SocketAddr, CacheStatus>,
+    // the union of them by prefix and length, the prefix masked
+    index: HashMap<(u32, u8), Vec<(u8, u32)>>,
+    len: usize,
+}
+
+impl VrpTable {
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+        for vrp in self.caches.values().flatten().collect::<HashSet<_>>() {
+            self.index.entry((mask(vrp.prefix.to_bits(), vrp.len), vrp.len)).or_default().push((vrp.max_len, vrp.asn));
+        }
+        self.len = self.index.values().map(Vec::len).sum();
+    }
+}
+
+fn mask(prefix: u32, len: u8) -> u32 {
+    prefix & u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+}
+
+// shared by the RTR clients that fill it, the process and every neighbor's import policy
+#[derive(Debug, Clone, Default)]
+pub struct Vrps(Arc<RwLock<VrpTable>>);
+
+impl Vrps {
+    // false when the cache sent what we already had
+    pub fn replace(&self, cache: SocketAddr, vrps: HashSet<Vrp>) -> bool {
+        let mut table = self.0.write().unwrap();
+        table.status.entry(cache).or_default().vrps = vrps.len();
+        if table.caches.get(&cache) == Some(&vrps) {
+            return false
+        }
+        table.caches.insert(cache, vrps);
+        table.rebuild_index();
+        true
+    }
+
+    pub fn remove(&self, cache: SocketAddr) -> bool {
+        let mut table = self.0.write().unwrap();
+        table.status.entry(cache).or_default().vrps = 0;
+        if table.caches.remove(&cache).is_none() {
+            return false
+        }
+        table.rebuild_index();
+        true
+    }
+
+    pub fn update_status(&self, cache: SocketAddr, update: impl FnOnce(&mut CacheStatus)) {
+        update(self.0.write().unwrap().status.entry(cache).or_default());
+    }
+
+    pub fn status(&self, cache: SocketAddr) -> CacheStatus {
+        self.0.read().unwrap().status.get(&cache).cloned().unwrap_or_default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.read().unwrap().len
+    }
+
+    // RFC 6811 section 2, None is an origin no VRP can match, e.g. a path ending in an AS_SET
+    pub fn validate(&self, nlri: &NLRI, origin_as: Option<u32>) -> RpkiState {
+        let table = self.0.read().unwrap();
+        let mut covered = false;
+        for len in 0..=nlri.len {
+            let Some(vrps) = table.index.get(&(mask(nlri.prefix.to_bits(), len), len)) else {
+                continue
+            };
+            covered = true;
+            if vrps.iter().any(|(max_len, asn)| nlri.len <= *max_len && Some(*asn) == origin_as && *asn != 0) {
+                return RpkiState::Valid
+            }
+        }
+        if covered { RpkiState::Invalid } else { RpkiState::NotFound }
+    }
+
+    // an empty path is one of ours, so the origin is our own AS
+    pub fn validate_route(&self, route: &RouteV4, my_as: u16) -> RpkiState {
+        let segment = &route.as_path.as_path_segment;
+        let origin_as = match (&segment.segment_type, segment.as_list.last()) {
+            (AsPathSegmentType::ASSet, _) => None,
+            (AsPathSegmentType::AsSequence, Some(as_num)) => Some(as_to_u32(as_num)),
+            (AsPathSegmentType::AsSequence, None) => Some(my_as as u32),
+        };
+        self.validate(&route.nlri, origin_as)
+    }
+}
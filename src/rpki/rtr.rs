@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+use crate::config::RpkiCacheConfig;
+use crate::process::ProcessCommand;
+use super::{mask, Vrp, Vrps};
+
+// RPKI-to-Router client (RFC 8210). Every cache gets a Reset Query for the full set, then Serial Queries for the changes
+// when it sends a Serial Notify or the refresh interval runs out. A cache that only speaks version 0 (RFC 6810) says so
+// in its first answer and we reconnect with that. The VRPs only change at an End of Data, so the process never sees half an update.
+
+const PROTOCOL_VERSION: u8 = 1;
+
+// PDU types
+const SERIAL_NOTIFY: u8 = 0;
+const SERIAL_QUERY: u8 = 1;
+const RESET_QUERY: u8 = 2;
+const CACHE_RESPONSE: u8 = 3;
+const IPV4_PREFIX: u8 = 4;
+const IPV6_PREFIX: u8 = 6;
+const END_OF_DATA: u8 = 7;
+const CACHE_RESET: u8 = 8;
+const ROUTER_KEY: u8 = 9;
+const ERROR_REPORT: u8 = 10;
+
+// Error Report codes
+const CORRUPT_DATA: u16 = 0;
+const NO_DATA_AVAILABLE: u16 = 2;
+const UNSUPPORTED_PROTOCOL_VERSION: u16 = 4;
+const UNSUPPORTED_PDU_TYPE: u16 = 5;
+const WITHDRAWAL_OF_UNKNOWN_RECORD: u16 = 6;
+const DUPLICATE_ANNOUNCEMENT: u16 = 7;
+
+// anything bigger is a broken stream, the longest real PDU is an Error Report
+const MAX_PDU_LEN: usize = 64 * 1024;
+
+// seconds, RFC 8210 section 6, a version 1 cache sends its own with every End of Data
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Timers {
+    refresh: u32,
+    retry: u32,
+    expire: u32,
+}
+
+const DEFAULT_TIMERS: Timers = Timers { refresh: 3600, retry: 600, expire: 7200 };
+
+#[derive(Debug, PartialEq)]
+enum Pdu {
+    SerialNotify,
+    CacheResponse { session_id: u16 },
+    Ipv4Prefix { announce: bool, vrp: Vrp },
+    EndOfData { session_id: u16, serial: u32, timers: Option<Timers> },
+    CacheReset,
+    ErrorReport { code: u16, text: String },
+    // IPv6 prefixes and router keys, nothing here uses them
+    Ignored,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Query {
+    Reset,
+    Serial,
+}
+
+#[derive(Debug)]
+enum SessionError {
+    Io(io::Error),
+    // something the cache sent that we can't take, it gets an Error Report with the PDU before we hang up
+    Protocol(u16, Vec<u8>, String),
+    // the cache speaks an older version, the number is the one to use
+    Downgrade(u8),
+    // the cache sent an Error Report
+    Cache(u16, String),
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "{}", e),
+            SessionError::Protocol(code, _, message) => write!(f, "{} (sent error code {})", message, code),
+            SessionError::Downgrade(version) => write!(f, "the cache only speaks version {}", version),
+            SessionError::Cache(NO_DATA_AVAILABLE, _) => write!(f, "the cache has no data yet"),
+            SessionError::Cache(code, text) => write!(f, "the cache sent error code {} - {}", code, text),
+        }
+    }
+}
+
+pub fn run_rtr_clients(caches: &[RpkiCacheConfig], vrps: &Vrps, tx_process_command: &Sender<ProcessCommand>) {
+    for cache in caches {
+        let client = RtrClient {
+            cache: SocketAddr::new(cache.address, cache.port),
+            vrps: vrps.clone(),
+            tx_process_command: tx_process_command.clone(),
+            version: PROTOCOL_VERSION,
+            session_id: None,
+            serial: 0,
+            timers: DEFAULT_TIMERS,
+            current: HashSet::new(),
+            last_update: None,
+        };
+        tokio::spawn(client.run());
+    }
+}
+
+struct RtrClient {
+    cache: SocketAddr,
+    vrps: Vrps,
+    tx_process_command: Sender<ProcessCommand>,
+    version: u8,
+    // None until the first End of Data, or after the cache told us to start over
+    session_id: Option<u16>,
+    serial: u32,
+    timers: Timers,
+    // what the cache gave us at the last End of Data
+    current: HashSet<Vrp>,
+    last_update: Option<Instant>,
+}
+
+impl RtrClient {
+    async fn run(mut self) {
+        loop {
+            self.expire().await;
+            let result = match TcpStream::connect(self.cache).await {
+                Ok(stream) => {
+                    info!("Connected to RPKI cache {}", self.cache);
+                    let version = self.version;
+                    self.vrps.update_status(self.cache, |status| {
+                        status.connected = true;
+                        status.version = version;
+                    });
+                    let result = self.run_session(stream).await;
+                    self.vrps.update_status(self.cache, |status| status.connected = false);
+                    result
+                },
+                Err(e) => Err(SessionError::Io(e)),
+            };
+            match result {
+                Ok(()) => info!("RPKI cache {} closed the connection", self.cache),
+                Err(SessionError::Downgrade(version)) => {
+                    info!("RPKI cache {} only speaks RTR version {}, reconnecting with it", self.cache, version);
+                    self.version = version;
+                    self.session_id = None;
+                    continue;
+                },
+                Err(e) => warn!("Lost RPKI cache {} - {}", self.cache, e),
+            }
+            if self.tx_process_command.is_closed() {
+                return;
+            }
+            sleep(Duration::from_secs(self.timers.retry.max(1) as u64)).await;
+        }
+    }
+
+    // RFC 8210 section 6, the VRPs of a cache we couldn't reach for the expire interval are dropped
+    async fn expire(&mut self) {
+        let expire = Duration::from_secs(self.timers.expire as u64);
+        if self.last_update.is_none_or(|last_update| last_update.elapsed() < expire) {
+            return
+        }
+        warn!("No data from RPKI cache {} for {} seconds, dropping its {} VRPs", self.cache, self.timers.expire, self.current.len());
+        self.last_update = None;
+        self.session_id = None;
+        self.current.clear();
+        if self.vrps.remove(self.cache) {
+            let _ = self.tx_process_command.send(ProcessCommand::RpkiUpdated).await;
+        }
+    }
+
+    async fn run_session(&mut self, stream: TcpStream) -> Result<(), SessionError> {
+        let (mut read_stream, mut write_stream) = stream.into_split();
+        let result = self.read_loop(&mut read_stream, &mut write_stream).await;
+        if let Err(SessionError::Protocol(code, pdu, message)) = &result {
+            let _ = write_stream.write_all(&error_report(self.version, *code, pdu, message)).await;
+        }
+        result
+    }
+
+    async fn read_loop(&mut self, read_stream: &mut OwnedReadHalf, write_stream: &mut OwnedWriteHalf) -> Result<(), SessionError> {
+        // picks up where the last connection left off if the cache still has that session, a Cache Reset says it doesn't
+        let mut query = match self.session_id {
+            Some(session_id) => {
+                write_stream.write_all(&serial_query(self.version, session_id, self.serial)).await?;
+                Some(Query::Serial)
+            },
+            None => {
+                write_stream.write_all(&reset_query(self.version)).await?;
+                Some(Query::Reset)
+            },
+        };
+        // between a Cache Response and its End of Data
+        let mut pending: Option<HashSet<Vrp>> = None;
+        let mut first = true;
+        let mut buf = Vec::new();
+        let mut read_buf = [0u8; 4096];
+        let refresh = sleep(Duration::from_secs(self.timers.refresh.max(1) as u64));
+        tokio::pin!(refresh);
+        loop {
+            while let Some(raw) = take_pdu(&mut buf)? {
+                let version = raw[0];
+                if version != self.version {
+                    // RFC 8210 section 7, only the first answer can tell us to go down a version
+                    if first && version < self.version {
+                        return Err(SessionError::Downgrade(version));
+                    }
+                    return Err(SessionError::Protocol(UNSUPPORTED_PROTOCOL_VERSION, raw, format!("version {} PDU in a version {} session", version, self.version)));
+                }
+                first = false;
+                let pdu = parse_pdu(&raw).map_err(|(code, message)| SessionError::Protocol(code, raw.clone(), message))?;
+                let unexpected = |raw: Vec<u8>| SessionError::Protocol(CORRUPT_DATA, raw, "PDU out of order".to_string());
+                match pdu {
+                    Pdu::SerialNotify => {
+                        if query.is_none() && let Some(session_id) = self.session_id {
+                            write_stream.write_all(&serial_query(self.version, session_id, self.serial)).await?;
+                            query = Some(Query::Serial);
+                        }
+                    },
+                    Pdu::CacheResponse { session_id } => {
+                        pending = match (query, self.session_id) {
+                            (Some(Query::Reset), _) => Some(HashSet::new()),
+                            (Some(Query::Serial), Some(ours)) if ours == session_id => Some(self.current.clone()),
+                            _ => return Err(unexpected(raw)),
+                        };
+                    },
+                    Pdu::Ipv4Prefix { announce, vrp } => {
+                        let Some(pending) = pending.as_mut() else {
+                            return Err(unexpected(raw));
+                        };
+                        if announce && !pending.insert(vrp) {
+                            return Err(SessionError::Protocol(DUPLICATE_ANNOUNCEMENT, raw, format!("{:?} announced twice", vrp)));
+                        }
+                        if !announce && !pending.remove(&vrp) {
+                            return Err(SessionError::Protocol(WITHDRAWAL_OF_UNKNOWN_RECORD, raw, format!("{:?} withdrawn but never announced", vrp)));
+                        }
+                    },
+                    Pdu::EndOfData { session_id, serial, timers } => {
+                        let Some(vrps) = pending.take() else {
+                            return Err(unexpected(raw));
+                        };
+                        query = None;
+                        self.commit(session_id, serial, timers, vrps).await;
+                        refresh.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(self.timers.refresh.max(1) as u64));
+                    },
+                    Pdu::CacheReset => {
+                        if query != Some(Query::Serial) || pending.is_some() {
+                            return Err(unexpected(raw));
+                        }
+                        debug!("RPKI cache {} has no changes for serial {}, asking for everything", self.cache, self.serial);
+                        self.session_id = None;
+                        write_stream.write_all(&reset_query(self.version)).await?;
+                        query = Some(Query::Reset);
+                    },
+                    Pdu::ErrorReport { code, text } => return Err(SessionError::Cache(code, text)),
+                    Pdu::Ignored => {},
+                }
+            }
+            tokio::select! {
+                read = read_stream.read(&mut read_buf) => {
+                    let read = read?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    buf.extend_from_slice(&read_buf[..read]);
+                },
+                _ = &mut refresh, if query.is_none() => {
+                    if let Some(session_id) = self.session_id {
+                        write_stream.write_all(&serial_query(self.version, session_id, self.serial)).await?;
+                        query = Some(Query::Serial);
+                    }
+                    refresh.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(self.timers.refresh.max(1) as u64));
+                },
+            }
+        }
+    }
+
+    async fn commit(&mut self, session_id: u16, serial: u32, timers: Option<Timers>, vrps: HashSet<Vrp>) {
+        self.session_id = Some(session_id);
+        self.serial = serial;
+        if let Some(timers) = timers {
+            self.timers = timers;
+        }
+        self.last_update = Some(Instant::now());
+        let changed = self.vrps.replace(self.cache, vrps.clone());
+        self.current = vrps;
+        self.vrps.update_status(self.cache, |status| {
+            status.session_id = Some(session_id);
+            status.serial = Some(serial);
+            status.last_update = Some(SystemTime::now());
+        });
+        if !changed {
+            debug!("RPKI cache {} is at serial {}, no changes", self.cache, serial);
+            return
+        }
+        info!("RPKI cache {} is at serial {} with {} VRPs", self.cache, serial, self.current.len());
+        let _ = self.tx_process_command.send(ProcessCommand::RpkiUpdated).await;
+    }
+}
+
+// the next whole PDU off the front of buf, None until all of it is there
+fn take_pdu(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, SessionError> {
+    if buf.len() < 8 {
+        return Ok(None)
+    }
+    let len = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if !(8..=MAX_PDU_LEN).contains(&len) {
+        return Err(SessionError::Protocol(CORRUPT_DATA, buf[..8].to_vec(), format!("PDU length {}", len)));
+    }
+    if buf.len() < len {
+        return Ok(None)
+    }
+    Ok(Some(buf.drain(..len).collect()))
+}
+
+fn parse_pdu(raw: &[u8]) -> Result<Pdu, (u16, String)> {
+    let session_id = u16::from_be_bytes([raw[2], raw[3]]);
+    let body = &raw[8..];
+    let u32_at = |idx: usize| u32::from_be_bytes(body[idx..idx + 4].try_into().unwrap());
+    match (raw[1], body.len()) {
+        (SERIAL_NOTIFY, 4) => Ok(Pdu::SerialNotify),
+        (CACHE_RESPONSE, 0) => Ok(Pdu::CacheResponse { session_id }),
+        (IPV4_PREFIX, 12) => {
+            let (len, max_len) = (body[1], body[2]);
+            if max_len > 32 || len > max_len {
+                return Err((CORRUPT_DATA, format!("prefix length {} with max length {}", len, max_len)))
+            }
+            let vrp = Vrp { prefix: Ipv4Addr::from(mask(u32_at(4), len)), len, max_len, asn: u32_at(8) };
+            Ok(Pdu::Ipv4Prefix { announce: body[0] & 1 == 1, vrp })
+        },
+        (IPV6_PREFIX, 24) | (ROUTER_KEY, _) => Ok(Pdu::Ignored),
+        // version 0 has no timers
+        (END_OF_DATA, 4) => Ok(Pdu::EndOfData { session_id, serial: u32_at(0), timers: None }),
+        (END_OF_DATA, 16) => Ok(Pdu::EndOfData {
+            session_id,
+            serial: u32_at(0),
+            timers: Some(Timers { refresh: u32_at(4), retry: u32_at(8), expire: u32_at(12) }),
+        }),
+        (CACHE_RESET, 0) => Ok(Pdu::CacheReset),
+        (ERROR_REPORT, len) if len >= 8 => {
+            // the PDU that caused it, then the text
+            let text_at = 4 + u32_at(0) as usize;
+            let text = body.get(text_at..text_at + 4)
+                .and_then(|text_len| body.get(text_at + 4..text_at + 4 + u32::from_be_bytes(text_len.try_into().unwrap()) as usize))
+                .map(|text| String::from_utf8_lossy(text).to_string())
+                .unwrap_or_default();
+            Ok(Pdu::ErrorReport { code: session_id, text })
+        },
+        (SERIAL_NOTIFY | CACHE_RESPONSE | IPV4_PREFIX | IPV6_PREFIX | END_OF_DATA | CACHE_RESET | ERROR_REPORT, len) => {
+            Err((CORRUPT_DATA, format!("PDU type {} can't have {} bytes after the header", raw[1], len)))
+        },
+        (pdu_type, _) => Err((UNSUPPORTED_PDU_TYPE, format!("unknown PDU type {}", pdu_type))),
+    }
+}
+
+fn pdu(version: u8, pdu_type: u8, session_id: u16, body: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![version, pdu_type];
+    pdu.extend(session_id.to_be_bytes());
+    pdu.extend((8 + body.len() as u32).to_be_bytes());
+    pdu.extend(body);
+    pdu
+}
+
+fn reset_query(version: u8) -> Vec<u8> {
+    pdu(version, RESET_QUERY, 0, &[])
+}
+
+fn serial_query(version: u8, session_id: u16, serial: u32) -> Vec<u8> {
+    pdu(version, SERIAL_QUERY, session_id, &serial.to_be_bytes())
+}
+
+fn error_report(version: u8, code: u16, bad_pdu: &[u8], text: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend((bad_pdu.len() as u32).to_be_bytes());
+    body.extend(bad_pdu);
+    body.extend((text.len() as u32).to_be_bytes());
+    body.extend(text.as_bytes());
+    pdu(version, ERROR_REPORT, code, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use crate::routes::NLRI;
+    use crate::rpki::RpkiState;
+
+    fn prefix(announce: bool, prefix: Ipv4Addr, len: u8, max_len: u8, asn: u32) -> Vec<u8> {
+        let mut body = vec![announce as u8, len, max_len, 0];
+        body.extend(prefix.octets());
+        body.extend(asn.to_be_bytes());
+        pdu(PROTOCOL_VERSION, IPV4_PREFIX, 0, &body)
+    }
+
+    fn end_of_data(serial: u32) -> Vec<u8> {
+        let mut body = serial.to_be_bytes().to_vec();
+        for seconds in [3600u32, 600, 7200] {
+            body.extend(seconds.to_be_bytes());
+        }
+        pdu(PROTOCOL_VERSION, END_OF_DATA, 7, &body)
+    }
+
+    async fn read_query(stream: &mut TcpStream) -> Vec<u8> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut query = header.to_vec();
+        query.resize(u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize, 0);
+        stream.read_exact(&mut query[8..]).await.unwrap();
+        query
+    }
+
+    fn validate(vrps: &Vrps, prefix: &str, origin_as: u32) -> RpkiState {
+        vrps.validate(&NLRI::from_str(prefix).unwrap(), Some(origin_as))
+    }
+
+    #[tokio::test]
+    async fn test_rtr_session() {
+        // stands in for the cache
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cache = RpkiCacheConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: listener.local_addr().unwrap().port() };
+        let (tx_process_command, mut rx_process_command) = mpsc::channel(8);
+        let vrps = Vrps::default();
+        run_rtr_clients(std::slice::from_ref(&cache), &vrps, &tx_process_command);
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        assert_eq!(read_query(&mut stream).await, reset_query(PROTOCOL_VERSION));
+        let mut response = pdu(PROTOCOL_VERSION, CACHE_RESPONSE, 7, &[]);
+        response.extend(prefix(true, Ipv4Addr::new(192, 0, 2, 0), 24, 24, 65001));
+        response.extend(prefix(true, Ipv4Addr::new(10, 0, 0, 0), 8, 16, 65002));
+        response.extend(end_of_data(1));
+        // split mid-PDU, the client has to put it back together
+        let (first, rest) = response.split_at(13);
+        stream.write_all(first).await.unwrap();
+        stream.flush().await.unwrap();
+        stream.write_all(rest).await.unwrap();
+        assert!(matches!(rx_process_command.recv().await, Some(ProcessCommand::RpkiUpdated)));
+        assert_eq!(vrps.count(), 2);
+        assert_eq!(validate(&vrps, "192.0.2.0/24", 65001), RpkiState::Valid);
+        assert_eq!(validate(&vrps, "192.0.2.0/24", 65003), RpkiState::Invalid);
+        assert_eq!(validate(&vrps, "10.1.0.0/16", 65002), RpkiState::Valid);
+        // longer than max_len
+        assert_eq!(validate(&vrps, "10.1.1.0/24", 65002), RpkiState::Invalid);
+        assert_eq!(validate(&vrps, "198.51.100.0/24", 65001), RpkiState::NotFound);
+
+        // a notify gets a serial query, the changes go on top of what the client has
+        stream.write_all(&pdu(PROTOCOL_VERSION, SERIAL_NOTIFY, 7, &2u32.to_be_bytes())).await.unwrap();
+        assert_eq!(read_query(&mut stream).await, serial_query(PROTOCOL_VERSION, 7, 1));
+        let mut response = pdu(PROTOCOL_VERSION, CACHE_RESPONSE, 7, &[]);
+        response.extend(prefix(false, Ipv4Addr::new(192, 0, 2, 0), 24, 24, 65001));
+        response.extend(end_of_data(2));
+        stream.write_all(&response).await.unwrap();
+        assert!(matches!(rx_process_command.recv().await, Some(ProcessCommand::RpkiUpdated)));
+        assert_eq!(validate(&vrps, "192.0.2.0/24", 65001), RpkiState::NotFound);
+        assert_eq!(validate(&vrps, "10.0.0.0/8", 65002), RpkiState::Valid);
+        let status = vrps.status(SocketAddr::new(cache.address, cache.port));
+        assert_eq!((status.connected, status.session_id, status.serial, status.vrps), (true, Some(7), Some(2), 1));
+
+        // withdrawing something it never had is an error, reported back before the client hangs up
+        let bad = prefix(false, Ipv4Addr::new(203, 0, 113, 0), 24, 24, 65001);
+        stream.write_all(&pdu(PROTOCOL_VERSION, SERIAL_NOTIFY, 7, &3u32.to_be_bytes())).await.unwrap();
+        read_query(&mut stream).await;
+        stream.write_all(&pdu(PROTOCOL_VERSION, CACHE_RESPONSE, 7, &[])).await.unwrap();
+        stream.write_all(&bad).await.unwrap();
+        let report = read_query(&mut stream).await;
+        assert_eq!((report[1], u16::from_be_bytes([report[2], report[3]])), (ERROR_REPORT, WITHDRAWAL_OF_UNKNOWN_RECORD));
+        assert_eq!(&report[12..12 + bad.len()], &bad[..]);
+        // the VRPs from the last End of Data stay
+        assert_eq!(vrps.count(), 1);
+    }
+}
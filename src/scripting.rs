@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+use crate::config::{Config, ScriptingConfig};
+use crate::config::validation::PathSegment;
+use crate::file_watch::{self, FileChange, WatchedFile};
+use crate::policy::{PolicyContext, PolicyProblem};
+use crate::routes::RouteV4;
+
+// import_script/export_script, Rhai scripts that see a route after the neighbor's policy and accept, reject or change it.
+// a script runs once per route with `route` (a map of its attributes) and `neighbor` in scope:
+//   false rejects the route, true or nothing accepts `route` with whatever the script changed in it,
+//   and returning a map accepts that instead
+// scripts can't reach files or the network, load_data("x.json") reads JSON next to the script and nothing else,
+// and every run gets the operation budget from [process_config.scripting]. a script that errors or runs out of
+// budget rejects the route it was on
+
+pub type Scripts = HashMap<PathBuf, Arc<Script>>;
+
+pub struct Script {
+    // with its mtime when we compiled it
+    file: WatchedFile,
+    limits: ScriptingConfig,
+    // the data files it loaded so far, with their mtime at the time
+    data_files: Arc<Mutex<Vec<WatchedFile>>>,
+    #[cfg(feature = "scripting")]
+    engine: rhai::Engine,
+    #[cfg(feature = "scripting")]
+    ast: rhai::AST,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", self.file.path.display())
+    }
+}
+
+// a reload keeps the same Arc unless something changed, so a new one means the neighbor has to re-run its routes
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Script {
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    // the script or one of its data files changed on disk since we loaded them
+    pub fn changed_files(&self) -> Vec<FileChange> {
+        let data_files = self.data_files.lock().unwrap();
+        file_watch::sort_changes(std::iter::once(&self.file).chain(data_files.iter()).filter_map(WatchedFile::changed))
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub fn load(_path: &Path, _limits: ScriptingConfig) -> Result<Self, String> {
+        Err("bgprtr was built without the scripting feature".to_string())
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn run(&self, _route: &RouteV4, _context: &PolicyContext) -> Result<Option<RouteV4>, String> {
+        Err("bgprtr was built without the scripting feature".to_string())
+    }
+
+    // None when the script rejects the route, direction only goes in the logs
+    pub fn apply(&self, route: RouteV4, context: &PolicyContext, direction: &str) -> Option<RouteV4> {
+        match self.run(&route, context) {
+            Ok(Some(route)) => Some(route),
+            Ok(None) => {
+                debug!(target: "rib", "{} script {} rejected {:?} for {}", direction, self.file.path.display(), route.nlri, context.neighbor);
+                None
+            },
+            Err(e) => {
+                warn!("{} script {} failed on {:?} for {}, rejecting it - {}", direction, self.file.path.display(), route.nlri, context.neighbor, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::path::Component;
+    use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+    use tracing::info;
+    use crate::messages::update::{LocalPref, MultiExitDisc, NextHop, OriginType, AS};
+    use crate::control::as_to_u32;
+    use crate::policy::{format_community, parse_community};
+
+    fn sandboxed_engine(path: &Path, limits: &ScriptingConfig, data_files: &Arc<Mutex<Vec<WatchedFile>>>) -> Engine {
+        let mut engine = Engine::new();
+        // no import from files
+        engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+        engine.set_max_operations(limits.max_operations);
+        engine.set_max_call_levels(limits.max_call_levels);
+        engine.set_max_string_size(limits.max_string_size);
+        engine.set_max_array_size(limits.max_array_size);
+        engine.set_max_map_size(limits.max_map_size);
+        let name = path.display().to_string();
+        engine.on_print(move |s| info!(target: "script", "{}: {}", name, s));
+        let name = path.display().to_string();
+        engine.on_debug(move |s, _, pos| debug!(target: "script", "{} {}: {}", name, pos, s));
+
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let data_files = data_files.clone();
+        // parsed once, the read only copy keeps one route's run from changing what the next one sees
+        let cache: Mutex<HashMap<PathBuf, Dynamic>> = Mutex::new(HashMap::new());
+        engine.register_fn("load_data", move |name: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+            if !Path::new(name).components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(format!("load_data only reads files next to the script, not {}", name).into());
+            }
+            let file = dir.join(name);
+            if let Some(data) = cache.lock().unwrap().get(&file) {
+                return Ok(data.clone())
+            }
+            let watched = WatchedFile::new(&file);
+            let json = std::fs::read_to_string(&file).map_err(|e| format!("unable to read {} - {}", file.display(), e))?;
+            // the script's own limits are for what it builds, not for our data
+            let data = Dynamic::from_map(Engine::new_raw().parse_json(&json, true)?).into_shared().into_read_only();
+            data_files.lock().unwrap().push(watched);
+            cache.lock().unwrap().insert(file, data.clone());
+            Ok(data)
+        });
+        engine
+    }
+
+    impl Script {
+        pub fn load(path: &Path, limits: ScriptingConfig) -> Result<Self, String> {
+            let file = WatchedFile::new(path);
+            let source = std::fs::read_to_string(path).map_err(|e| format!("unable to read script {} - {}", path.display(), e))?;
+            let data_files = Arc::new(Mutex::new(Vec::new()));
+            let engine = sandboxed_engine(path, &limits, &data_files);
+            let ast = engine.compile(&source).map_err(|e| format!("script {} doesn't compile - {}", path.display(), e))?;
+            Ok(Script { file, limits, data_files, engine, ast })
+        }
+
+        pub(super) fn run(&self, route: &RouteV4, context: &PolicyContext) -> Result<Option<RouteV4>, String> {
+            let mut scope = Scope::new();
+            scope.push("route", route_to_map(route));
+            scope.push_constant("neighbor", context.neighbor.to_string());
+            let result: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast).map_err(|e| e.to_string())?;
+            let view = match result.as_bool() {
+                Ok(false) => return Ok(None),
+                Ok(true) => scope.get_value::<Map>("route").ok_or("route isn't a map anymore")?,
+                Err(_) if result.is_unit() => scope.get_value::<Map>("route").ok_or("route isn't a map anymore")?,
+                Err(_) => result.try_cast::<Map>().ok_or("the script has to end with true, false or a route")?,
+            };
+            map_to_route(route, &view).map(Some)
+        }
+    }
+
+    fn route_to_map(route: &RouteV4) -> Map {
+        let mut map = Map::new();
+        map.insert("prefix".into(), format!("{}/{}", route.nlri.prefix, route.nlri.len).into());
+        map.insert("next_hop".into(), route.next_hop.ipv4addr().to_string().into());
+        let as_path: Array = route.as_path.as_path_segment.as_list.iter().map(|as_num| Dynamic::from_int(as_to_u32(as_num) as i64)).collect();
+        map.insert("as_path".into(), as_path.into());
+        let origin = match route.origin.origin_type {
+            OriginType::IGP => "igp",
+            OriginType::EGP => "egp",
+            OriginType::Incomplete => "incomplete",
+        };
+        map.insert("origin".into(), origin.into());
+        map.insert("med".into(), route.multi_exit_disc.as_ref().map_or(Dynamic::UNIT, |med| Dynamic::from_int(med.value as i64)));
+        map.insert("local_pref".into(), route.local_pref.as_ref().map_or(Dynamic::UNIT, |local_pref| Dynamic::from_int(local_pref.value as i64)));
+        let communities: Array = route.communities.iter().map(|community| format_community(*community).into()).collect();
+        map.insert("communities".into(), communities.into());
+        map.insert("learned_from".into(), route.learned_from.map_or(Dynamic::UNIT, |ip| ip.to_string().into()));
+        map
+    }
+
+    // () clears the attribute
+    fn optional_u32(key: &str, value: &Dynamic) -> Result<Option<u32>, String> {
+        if value.is_unit() {
+            return Ok(None)
+        }
+        value.as_int().ok().and_then(|value| u32::try_from(value).ok()).map(Some)
+            .ok_or_else(|| format!("{} has to be a number from 0 to {} or ()", key, u32::MAX))
+    }
+
+    // prefix and learned_from are only there to look at
+    fn map_to_route(original: &RouteV4, map: &Map) -> Result<RouteV4, String> {
+        let mut route = original.clone();
+        if let Some(next_hop) = map.get("next_hop") {
+            let next_hop = next_hop.clone().into_string().ok().and_then(|next_hop| next_hop.parse::<Ipv4Addr>().ok())
+                .ok_or("next_hop has to be an IPv4 address")?;
+            route.next_hop = NextHop::new(next_hop);
+        }
+        if let Some(as_path) = map.get("as_path") {
+            let as_list = as_path.as_array_ref().map_err(|_| "as_path has to be an array of ASNs")?.iter()
+                .map(|as_num| as_num.as_int().ok().and_then(|as_num| u32::try_from(as_num).ok()).map(AS::AS4))
+                .collect::<Option<Vec<AS>>>().ok_or("as_path has to be an array of ASNs")?;
+            let segment = &mut route.as_path.as_path_segment;
+            segment.number_of_as = u8::try_from(as_list.len()).map_err(|_| "as_path can't be longer than 255 ASNs")?;
+            segment.as_list = as_list;
+        }
+        if let Some(origin) = map.get("origin") {
+            route.origin.origin_type = match origin.clone().into_string().as_deref() {
+                Ok("igp") => OriginType::IGP,
+                Ok("egp") => OriginType::EGP,
+                Ok("incomplete") => OriginType::Incomplete,
+                _ => return Err("origin has to be igp, egp or incomplete".to_string()),
+            };
+        }
+        if let Some(med) = map.get("med") {
+            route.multi_exit_disc = optional_u32("med", med)?.map(MultiExitDisc::new);
+        }
+        if let Some(local_pref) = map.get("local_pref") {
+            route.local_pref = optional_u32("local_pref", local_pref)?.map(LocalPref::new);
+        }
+        if let Some(communities) = map.get("communities") {
+            let communities = communities.as_array_ref().map_err(|_| "communities has to be an array")?;
+            route.communities = communities.iter().map(|community| match community.clone().into_string() {
+                Ok(community) => parse_community(&community),
+                Err(_) => Err("communities has to hold strings like \"65000:1\"".to_string()),
+            }).collect::<Result<Vec<u32>, String>>()?;
+        }
+        Ok(route)
+    }
+}
+
+// every script the neighbors and peer groups refer to. anything unchanged since `previous` was loaded is kept as it is
+pub fn compile_scripts(config: &Config, previous: &Scripts) -> (Scripts, Vec<PolicyProblem>) {
+    let limits = config.process_config.scripting;
+    let neighbors = config.neighbors_config.iter().enumerate()
+        .flat_map(|(idx, nc)| [("neighbors_config", idx, "import_script", &nc.import_script), ("neighbors_config", idx, "export_script", &nc.export_script)]);
+    let peer_groups = config.peer_groups_config.iter().enumerate()
+        .flat_map(|(idx, pg)| [("peer_groups_config", idx, "import_script", &pg.import_script), ("peer_groups_config", idx, "export_script", &pg.export_script)]);
+
+    let mut scripts = Scripts::new();
+    let mut problems = Vec::new();
+    for (table, idx, key, path) in neighbors.chain(peer_groups) {
+        let Some(path) = path else { continue };
+        if scripts.contains_key(path) {
+            continue
+        }
+        if let Some(script) = previous.get(path) && script.limits == limits && script.changed_files().is_empty() {
+            scripts.insert(path.clone(), script.clone());
+            continue
+        }
+        match Script::load(path, limits) {
+            Ok(script) => {
+                scripts.insert(path.clone(), Arc::new(script));
+            },
+            Err(message) => problems.push(PolicyProblem {
+                path: vec![PathSegment::Key(table), PathSegment::Index(idx), PathSegment::Key(key)],
+                message,
+            }),
+        }
+    }
+    (scripts, problems)
+}
+
+// what changed on disk across all of them
+pub fn changed_files(scripts: &Scripts) -> Vec<FileChange> {
+    file_watch::sort_changes(scripts.values().flat_map(|script| script.changed_files()))
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use crate::messages::update::{AsPath, AsPathSegment, AsPathSegmentType, NextHop, Origin, OriginType, AS};
+    use crate::routes::NLRI;
+
+    const SCRIPT: &str = r#"
+        let regions = load_data("regions.json");
+        if route.prefix == "192.0.2.0/24" { return false; }
+        let origin_as = route.as_path[route.as_path.len() - 1];
+        let region = regions[origin_as.to_string()];
+        if region != () { route.communities += region; }
+        route.local_pref = 150;
+        route.as_path.insert(0, 65000);
+    "#;
+
+    fn route(prefix: &str) -> RouteV4 {
+        let as_path = AsPath::new(AsPathSegment { segment_type: AsPathSegmentType::AsSequence, number_of_as: 2,
+                                                  as_list: vec![AS::AS4(65001), AS::AS4(64496)] });
+        RouteV4::new(NLRI::from_str(prefix).unwrap(), Origin::new(OriginType::IGP), as_path, NextHop::new(Ipv4Addr::new(10, 0, 0, 2)),
+                     None, None, None, None)
+    }
+
+    #[test]
+    fn test_run_script() {
+        let dir = std::env::temp_dir().join(format!("bgprtr-scripting-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("import.rhai"), SCRIPT).unwrap();
+        std::fs::write(dir.join("regions.json"), r#"{ "64496": "65000:276" }"#).unwrap();
+        let limits = ScriptingConfig { max_operations: 10_000, ..ScriptingConfig::default() };
+        let script = Script::load(&dir.join("import.rhai"), limits).unwrap();
+        let context = PolicyContext { neighbor: Ipv4Addr::new(10, 0, 0, 2), peer_as: 65001, my_as: 65000, import: true };
+
+        let result = script.run(&route("198.51.100.0/24"), &context).unwrap().unwrap();
+        assert_eq!(result.communities, vec![65000 << 16 | 276]);
+        assert_eq!(result.local_pref.map(|local_pref| local_pref.value), Some(150));
+        assert_eq!(result.as_path.as_path_segment.number_of_as, 3);
+        assert_eq!(script.run(&route("192.0.2.0/24"), &context), Ok(None));
+        assert_eq!(script.changed_files(), vec![]);
+
+        // out of budget is an error, which rejects the route
+        std::fs::write(dir.join("loop.rhai"), "loop {}").unwrap();
+        let script = Script::load(&dir.join("loop.rhai"), limits).unwrap();
+        assert!(script.run(&route("198.51.100.0/24"), &context).is_err());
+        std::fs::write(dir.join("escape.rhai"), r#"load_data("../etc/passwd")"#).unwrap();
+        let script = Script::load(&dir.join("escape.rhai"), limits).unwrap();
+        assert!(script.run(&route("198.51.100.0/24"), &context).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -52,11 +52,17 @@ pub fn get_neighbor_ipv4_address_from_socket(peer_ip: io::Result<SocketAddr>) ->
         Ok(ip) => {
             match ip.ip() {
                 IpAddr::V4(ip4) => Ok(ip4),
-                IpAddr::V6(ip6) => Err(NeighborError::NeighborIsIPV6)
+                // a dual-stack listener hands us v4 peers as ::ffff:a.b.c.d
+                IpAddr::V6(ip6) => ip6.to_ipv4_mapped().ok_or(NeighborError::NeighborIsIPV6)
             }
         },
         Err(_) => {
             Err(NeighborError::NeighborIPNotRecognized)
         }
     }
-}
\ No newline at end of file
+}
+
+// "FF FF 00 13 ..." for packet traces
+pub fn hex_dump(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X} ", b)).collect::<String>()
+}